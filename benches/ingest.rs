@@ -0,0 +1,70 @@
+#[macro_use]
+extern crate bencher;
+extern crate hotmic;
+
+use bencher::Bencher;
+use hotmic::Receiver;
+
+// How many samples are sent each time around, before the receiver processes them all.
+const BATCH: usize = 1_024;
+
+fn update_count(b: &mut Bencher) {
+    let mut receiver = Receiver::<&'static str>::builder().capacity(BATCH).build();
+    let sink = receiver.get_sink();
+
+    b.iter(|| {
+        for _ in 0..BATCH {
+            sink.update_count("requests", 1);
+        }
+        receiver.poll_ready();
+    });
+}
+
+fn update_timing(b: &mut Bencher) {
+    let mut receiver = Receiver::<&'static str>::builder().capacity(BATCH).build();
+    let sink = receiver.get_sink();
+    let start = sink.clock().raw();
+
+    b.iter(|| {
+        for _ in 0..BATCH {
+            sink.update_timing("latency", start, start + 1_000);
+        }
+        receiver.poll_ready();
+    });
+}
+
+fn update_timing_with_count(b: &mut Bencher) {
+    let mut receiver = Receiver::<&'static str>::builder().capacity(BATCH).build();
+    let sink = receiver.get_sink();
+    let start = sink.clock().raw();
+
+    b.iter(|| {
+        for _ in 0..BATCH {
+            sink.update_timing_with_count("latency", start, start + 1_000, 64);
+        }
+        receiver.poll_ready();
+    });
+}
+
+fn update_timing_string_keys(b: &mut Bencher) {
+    let mut receiver = Receiver::<String>::builder().capacity(BATCH).build();
+    let sink = receiver.get_sink();
+    let start = sink.clock().raw();
+    let key = "latency".to_owned();
+
+    b.iter(|| {
+        for _ in 0..BATCH {
+            sink.update_timing(key.clone(), start, start + 1_000);
+        }
+        receiver.poll_ready();
+    });
+}
+
+benchmark_group!(
+    benches,
+    update_count,
+    update_timing,
+    update_timing_with_count,
+    update_timing_string_keys
+);
+benchmark_main!(benches);
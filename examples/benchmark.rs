@@ -1,15 +1,19 @@
 #[macro_use]
 extern crate log;
 extern crate env_logger;
+extern crate fnv;
 extern crate getopts;
 extern crate hdrhistogram;
 extern crate hotmic;
 
+use fnv::FnvBuildHasher;
 use getopts::Options;
 use hdrhistogram::Histogram;
 use hotmic::{Receiver, Sink};
 use std::{
+    collections::hash_map::RandomState,
     env,
+    hash::BuildHasher,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
@@ -20,6 +24,7 @@ use std::{
 
 struct Generator {
     stats: Sink<&'static str>,
+    keys: Arc<Vec<&'static str>>,
     t0: Option<u64>,
     gauge: u64,
     hist: Histogram<u64>,
@@ -27,9 +32,10 @@ struct Generator {
 }
 
 impl Generator {
-    fn new(stats: Sink<&'static str>, done: Arc<AtomicBool>) -> Generator {
+    fn new(stats: Sink<&'static str>, keys: Arc<Vec<&'static str>>, done: Arc<AtomicBool>) -> Generator {
         Generator {
             stats,
+            keys,
             t0: None,
             gauge: 0,
             hist: Histogram::<u64>::new_with_bounds(1, u64::max_value(), 3).unwrap(),
@@ -38,6 +44,7 @@ impl Generator {
     }
 
     fn run(&mut self) {
+        let mut key_index = 0;
         loop {
             if self.done.load(Ordering::Relaxed) {
                 break;
@@ -48,12 +55,13 @@ impl Generator {
 
             if let Some(t0) = self.t0 {
                 let start = self.stats.clock().now();
-                let _ = self.stats.update_timing("ok", t0, t1);
+                let _ = self.stats.update_timing(self.keys[key_index], t0, t1);
                 let _ = self.stats.update_gauge("total", self.gauge);
                 let delta = self.stats.clock().now() - start;
                 self.hist.saturating_record(delta);
             }
             self.t0 = Some(t1);
+            key_index = (key_index + 1) % self.keys.len();
         }
     }
 }
@@ -84,6 +92,8 @@ pub fn opts() -> Options {
     opts.optopt("p", "producers", "number of producers", "INTEGER");
     opts.optopt("c", "capacity", "maximum number of unprocessed items", "INTEGER");
     opts.optopt("b", "batch-size", "maximum number of items in a batch", "INTEGER");
+    opts.optopt("k", "keys", "number of distinct metric keys to update", "INTEGER");
+    opts.optopt("", "hasher", "hasher to use for metric storage: fnv or sip", "STRING");
     opts.optflag("h", "help", "print this help menu");
 
     opts
@@ -132,15 +142,47 @@ fn main() {
         .unwrap_or_else(|| "1".to_owned())
         .parse()
         .unwrap();
+    let num_keys: usize = matches
+        .opt_str("keys")
+        .unwrap_or_else(|| "1".to_owned())
+        .parse()
+        .unwrap();
+    let hasher = matches.opt_str("hasher").unwrap_or_else(|| "fnv".to_owned());
 
     info!("producers: {}", producers);
     info!("capacity: {}", capacity);
     info!("batch size: {}", batch_size);
+    info!("keys: {}", num_keys);
+    info!("hasher: {}", hasher);
+
+    let config = Receiver::builder().capacity(capacity).batch_size(batch_size);
+    match hasher.as_str() {
+        "fnv" => {
+            run(
+                config.build_with_hasher::<FnvBuildHasher>(),
+                seconds,
+                producers,
+                num_keys,
+            )
+        },
+        "sip" => run(config.build_with_hasher::<RandomState>(), seconds, producers, num_keys),
+        _ => error!("Unknown hasher: {}", hasher),
+    }
+}
 
-    let mut receiver = Receiver::builder().capacity(capacity).batch_size(batch_size).build();
+fn run<H>(mut receiver: Receiver<&'static str, H>, seconds: u64, producers: usize, num_keys: usize)
+where
+    H: BuildHasher + Default + Send + 'static,
+{
+    // Build a set of keys resembling real-world metric names, which are longer than the short keys
+    // that FNV is best suited for.
+    let keys = (0..num_keys.max(1))
+        .map(|i| &*Box::leak(format!("http.server.handlers.v1_api_request_{}.latency", i).into_boxed_str()))
+        .collect::<Vec<&'static str>>();
+    let keys = Arc::new(keys);
 
     let sink = receiver.get_sink();
-    let sink = sink.scoped(&["alpha", "pools", "primary"]);
+    let sink = sink.scoped(&["alpha", "pools", "primary"]).unwrap();
 
     info!("sink configured");
 
@@ -150,9 +192,10 @@ fn main() {
 
     for _ in 0..producers {
         let s = sink.clone();
+        let k = keys.clone();
         let d = done.clone();
         let handle = thread::spawn(move || {
-            Generator::new(s, d).run();
+            Generator::new(s, k, d).run();
         });
 
         handles.push(handle);
@@ -166,7 +209,10 @@ fn main() {
     });
 
     // Poll the controller to figure out the sample rate.
-    let ok_key = "alpha.pools.primary.ok".to_owned();
+    let ok_keys = keys
+        .iter()
+        .map(|key| format!("alpha.pools.primary.{}", key))
+        .collect::<Vec<_>>();
     let total_key = "alpha.pools.primary.total".to_owned();
 
     let mut total = 0;
@@ -183,8 +229,10 @@ fn main() {
         snapshot_hist.saturating_record(duration_as_nanos(end - start) as u64);
 
         let snapshot = snapshot.unwrap().into_simple();
-        if let Some(t) = snapshot.count(&ok_key) {
-            turn_total += t as u64;
+        for ok_key in &ok_keys {
+            if let Some(t) = snapshot.count(ok_key) {
+                turn_total += t as u64;
+            }
         }
 
         if let Some(t) = snapshot.gauge(&total_key) {
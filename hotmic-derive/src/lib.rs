@@ -0,0 +1,138 @@
+//! Derive macros for [hotmic](https://docs.rs/hotmic).
+//!
+//! These are re-exported by hotmic itself when its `derive` feature is enabled, and so shouldn't
+//! need to be depended on directly.
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{parse_macro_input, Attribute, Data, DeriveInput, Error, Fields, Lit, Meta, NestedMeta};
+
+/// Derives `Display` for an enum of metric keys, so that it can be used as the key type of a
+/// receiver.
+///
+/// Each variant is displayed as its name in snake case, so `QueriesExecuted` is displayed as
+/// `queries_executed`.  A variant can be given a different name with `#[metric(rename = "...")]`.
+/// Only enums whose variants have no fields are supported.
+///
+/// ```ignore
+/// #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, MetricName)]
+/// enum Metric {
+///     QueriesExecuted,
+///     #[metric(rename = "db.pool.size")]
+///     PoolSize,
+/// }
+/// ```
+#[proc_macro_derive(MetricName, attributes(metric))]
+pub fn derive_metric_name(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand_metric_name(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand_metric_name(input: &DeriveInput) -> Result<proc_macro2::TokenStream, Error> {
+    let data = match &input.data {
+        Data::Enum(data) => data,
+        _ => {
+            return Err(Error::new(
+                Span::call_site(),
+                "MetricName can only be derived for enums",
+            ))
+        },
+    };
+
+    let ident = &input.ident;
+    let mut arms = Vec::new();
+    for variant in &data.variants {
+        match variant.fields {
+            Fields::Unit => {},
+            _ => {
+                return Err(Error::new_spanned(
+                    variant,
+                    "MetricName can only be derived for variants without fields",
+                ))
+            },
+        }
+
+        let name = match rename(&variant.attrs)? {
+            Some(name) => name,
+            None => snake_case(&variant.ident.to_string()),
+        };
+        let variant = &variant.ident;
+        arms.push(quote! { #ident::#variant => #name });
+    }
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::std::fmt::Display for #ident #ty_generics #where_clause {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                let name = match *self {
+                    #(#arms,)*
+                };
+                f.write_str(name)
+            }
+        }
+    })
+}
+
+/// Gets the name given by a `#[metric(rename = "...")]` attribute, if there is one.
+fn rename(attrs: &[Attribute]) -> Result<Option<String>, Error> {
+    let mut name = None;
+    for attr in attrs {
+        if attr.path.segments.len() != 1 || attr.path.segments[0].ident != "metric" {
+            continue;
+        }
+
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            meta => return Err(Error::new_spanned(meta, "expected `#[metric(rename = \"...\")]`")),
+        };
+        for nested in list.nested {
+            match nested {
+                NestedMeta::Meta(Meta::NameValue(ref nv)) if nv.ident == "rename" => {
+                    match &nv.lit {
+                        Lit::Str(s) if !s.value().is_empty() => name = Some(s.value()),
+                        lit => return Err(Error::new_spanned(lit, "expected a non-empty string")),
+                    }
+                },
+                nested => return Err(Error::new_spanned(nested, "unknown metric attribute")),
+            }
+        }
+    }
+    Ok(name)
+}
+
+/// Converts a variant name from camel case to snake case, keeping acronyms together, so
+/// `HTTPRequests` becomes `http_requests`.
+fn snake_case(name: &str) -> String {
+    let chars = name.chars().collect::<Vec<_>>();
+    let mut converted = String::with_capacity(name.len() + 4);
+    for (i, c) in chars.iter().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            let prev = chars[i - 1];
+            let next_lower = chars.get(i + 1).map(|n| n.is_lowercase()).unwrap_or(false);
+            if prev.is_lowercase() || prev.is_numeric() || (prev.is_uppercase() && next_lower) {
+                converted.push('_');
+            }
+        }
+        converted.extend(c.to_lowercase());
+    }
+    converted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::snake_case;
+
+    #[test]
+    fn test_snake_case() {
+        assert_eq!(snake_case("Requests"), "requests");
+        assert_eq!(snake_case("QueriesExecuted"), "queries_executed");
+        assert_eq!(snake_case("HTTPRequests"), "http_requests");
+        assert_eq!(snake_case("Http2Streams"), "http2_streams");
+        assert_eq!(snake_case("already_snake"), "already_snake");
+    }
+}
@@ -0,0 +1,48 @@
+use crate::{
+    control::{Controller, SnapshotError},
+    data::Snapshot,
+};
+
+/// Aggregates snapshots from multiple receivers.
+///
+/// In some applications, independent subsystems each run their own [`Receiver`](crate::Receiver),
+/// but a single, combined view of all metrics is still desired, such as when exporting.  A
+/// [`SnapshotCollector`] holds a [`Controller`] for each of these receivers, and merges their
+/// snapshots together on demand.
+///
+/// Each controller is registered with a prefix, which is prepended to the names of all of its
+/// metrics, using `.` as the separator.  An empty prefix leaves the metric names untouched.
+#[derive(Clone, Default)]
+pub struct SnapshotCollector {
+    controllers: Vec<(String, Controller)>,
+}
+
+impl SnapshotCollector {
+    /// Creates an empty [`SnapshotCollector`].
+    pub fn new() -> SnapshotCollector { Default::default() }
+
+    /// Adds a controller to this collector, under the given prefix.
+    pub fn add<S: Into<String>>(&mut self, prefix: S, controller: Controller) {
+        self.controllers.push((prefix.into(), controller));
+    }
+
+    /// Number of controllers registered with this collector.
+    pub fn len(&self) -> usize { self.controllers.len() }
+
+    /// Whether or not this collector has any registered controllers.
+    pub fn is_empty(&self) -> bool { self.controllers.is_empty() }
+
+    /// Retrieves a snapshot from every registered controller, and merges them into one.
+    ///
+    /// Controllers are queried in the order they were added.  If any of them fail to provide a
+    /// snapshot, the error is returned and no partial snapshot is produced.
+    pub fn get_snapshot(&self) -> Result<Snapshot, SnapshotError> {
+        let mut snapshot = Snapshot::default();
+        for (prefix, controller) in &self.controllers {
+            let inner = controller.get_snapshot()?;
+            snapshot.merge_prefixed(prefix, inner);
+        }
+
+        Ok(snapshot)
+    }
+}
@@ -1,15 +1,48 @@
-use crate::{data::Percentile, receiver::Receiver};
-use std::{fmt::Display, hash::Hash, marker::PhantomData, time::Duration};
+use crate::{
+    data::{snapshot::HistogramBuckets, Percentile, Snapshot},
+    receiver::Receiver,
+    transport::{Frame, Transport},
+};
+use std::{
+    fmt::Display,
+    hash::{BuildHasher, Hash},
+    marker::PhantomData,
+    sync::Arc,
+    time::Duration,
+};
+
+/// The smallest histogram granularity that can be configured, in milliseconds.
+const MIN_HISTOGRAM_GRANULARITY_MS: u64 = 1;
+
+/// The smallest snapshot callback interval that can be configured, in milliseconds.
+const MIN_SNAPSHOT_INTERVAL_MS: u64 = 1;
+
+/// A callback which is handed each periodically-generated snapshot.
+pub(crate) type SnapshotCallback = Arc<dyn Fn(&Snapshot) + Send + Sync>;
 
 /// A configuration builder for [`Receiver`].
 #[derive(Clone)]
 pub struct Configuration<T> {
     metric_type: PhantomData<T>,
     pub(crate) capacity: usize,
+    pub(crate) control_capacity: usize,
     pub(crate) batch_size: usize,
     pub(crate) histogram_window: Duration,
     pub(crate) histogram_granularity: Duration,
+    pub(crate) histogram_max_value: u64,
     pub(crate) percentiles: Vec<Percentile>,
+    pub(crate) histogram_buckets: Arc<HistogramBuckets>,
+    pub(crate) snapshot_callback: Option<(Duration, SnapshotCallback)>,
+    pub(crate) raw_histograms: bool,
+    pub(crate) sparse_threshold: u64,
+    pub(crate) interpolate_percentiles: bool,
+    pub(crate) align_windows: bool,
+    pub(crate) self_metrics: bool,
+    pub(crate) include_rates: bool,
+    pub(crate) include_windowed_counts: bool,
+    pub(crate) track_last_update: bool,
+    pub(crate) validate_facets: bool,
+    pub(crate) transport: Option<Arc<dyn Transport<Frame<T>>>>,
 }
 
 impl<T> Default for Configuration<T> {
@@ -17,15 +50,29 @@ impl<T> Default for Configuration<T> {
         Configuration {
             metric_type: PhantomData::<T>,
             capacity: 512,
+            control_capacity: 16,
             batch_size: 64,
             histogram_window: Duration::from_secs(10),
             histogram_granularity: Duration::from_secs(1),
+            histogram_max_value: u64::max_value(),
             percentiles: default_percentiles(),
+            histogram_buckets: Arc::new(HistogramBuckets::default()),
+            snapshot_callback: None,
+            raw_histograms: false,
+            sparse_threshold: 0,
+            interpolate_percentiles: false,
+            align_windows: false,
+            self_metrics: false,
+            include_rates: false,
+            include_windowed_counts: false,
+            track_last_update: false,
+            validate_facets: false,
+            transport: None,
         }
     }
 }
 
-impl<T: Send + Eq + Hash + Display + Clone> Configuration<T> {
+impl<T: Send + Eq + Hash + Display + Clone + 'static> Configuration<T> {
     /// Creates a new [`Configuration`] with default values.
     pub fn new() -> Configuration<T> { Default::default() }
 
@@ -48,6 +95,23 @@ impl<T: Send + Eq + Hash + Display + Clone> Configuration<T> {
         self
     }
 
+    /// Sets the control channel capacity.
+    ///
+    /// Defaults to 16.
+    ///
+    /// This controls the size of the channel used by controllers to send requests, such as for
+    /// snapshots, to the receiver.  This channel is shared amongst all controllers.  If this channel
+    /// is full when making a request, the request will be blocked until the channel has free space,
+    /// or fail, if it has a timeout.
+    ///
+    /// The receiver services requests between every batch of samples, so the default is plenty
+    /// unless there are many controllers making requests at the same time, such as when serving
+    /// many scrape endpoints or subscribers at once.  Values less than 1 are treated as 1.
+    pub fn control_capacity(mut self, capacity: usize) -> Self {
+        self.control_capacity = capacity.max(1);
+        self
+    }
+
     /// Sets the batch size.
     ///
     /// Defaults to 64.
@@ -57,9 +121,12 @@ impl<T: Send + Eq + Hash + Display + Clone> Configuration<T> {
     /// the ingest latency in the face of high metric ingest pressure at the cost of higher tail
     /// latencies.
     ///
+    /// The receiver allocates a buffer for a full batch up front and reuses it for every batch, so
+    /// this also sets how much memory that buffer holds onto.  Values less than 1 are treated as 1.
+    ///
     /// Long story short, you shouldn't need to change this, but it's here if you really do.
     pub fn batch_size(mut self, batch_size: usize) -> Self {
-        self.batch_size = batch_size;
+        self.batch_size = batch_size.max(1);
         self
     }
 
@@ -70,7 +137,8 @@ impl<T: Send + Eq + Hash + Display + Clone> Configuration<T> {
     /// This controls how long of a time frame the histogram will track, on a rolling window.
     /// We'll create enough underlying histogram buckets so that we have (window / granularity)
     /// buckets, and every interval that passes (granularity), we'll add a new bucket and drop the
-    /// oldest one, thereby providing a rolling window.
+    /// oldest one, thereby providing a rolling window.  To give the number of buckets instead of
+    /// their granularity, use [`histogram_buckets_count`](Configuration::histogram_buckets_count).
     ///
     /// Histograms, under the hood, are hard-coded to track three significant digits, and will take
     /// a theoretical maximum of around 60KB per bucket, so a single histogram metric with the
@@ -78,26 +146,290 @@ impl<T: Send + Eq + Hash + Display + Clone> Configuration<T> {
     ///
     /// In practice, this should be much smaller based on the maximum values pushed into the
     /// histogram, as the underlying histogram storage is automatically resized on the fly.
+    ///
+    /// The granularity is raised to at least one millisecond, and the window to at least the
+    /// granularity, as a histogram can't roll over in zero time, or cover no time at all.
     pub fn histogram(mut self, window: Duration, granularity: Duration) -> Self {
-        self.histogram_window = window;
+        let granularity = granularity.max(Duration::from_millis(MIN_HISTOGRAM_GRANULARITY_MS));
+        self.histogram_window = window.max(granularity);
         self.histogram_granularity = granularity;
         self
     }
 
+    /// Sets the histogram configuration by the number of buckets to divide the window into, rather
+    /// than by their granularity.
+    ///
+    /// This is equivalent to [`histogram`](Configuration::histogram) with a granularity of
+    /// `window / num_buckets`, so a 10 second window with 5 buckets rolls over every 2 seconds.  As
+    /// with `histogram`, one more bucket than this is kept, to collect values for the interval in
+    /// progress.
+    ///
+    /// The number of buckets is raised to at least one, and the granularity is raised to at least
+    /// one millisecond, which lowers the number of buckets for windows shorter than that many
+    /// milliseconds.
+    pub fn histogram_buckets_count(self, window: Duration, num_buckets: u32) -> Self {
+        self.histogram(window, window / num_buckets.max(1))
+    }
+
+    /// Sets the maximum value that histograms can track.
+    ///
+    /// Defaults to `u64::max_value()`.
+    ///
+    /// Histograms preallocate enough storage to track, with three significant digits, any value up
+    /// to this maximum, so the memory used by each histogram bucket is determined by it.  Lowering
+    /// it caps that memory, at the cost of clamping any value recorded above the maximum down to
+    /// the maximum itself.  For timing histograms, this is expressed in the unit of the histogram.
+    ///
+    /// The memory used by a given histogram is reported by
+    /// [`SummarizedHistogram::memory_usage`](crate::snapshot::SummarizedHistogram::memory_usage),
+    /// and the number of values it clamped by
+    /// [`SummarizedHistogram::clamped`](crate::snapshot::SummarizedHistogram::clamped).  The
+    /// maximum can be set for individual histograms via [`Facet::max_value`](crate::Facet::max_value).
+    ///
+    /// Values less than 2 are treated as 2, which is the smallest maximum possible.
+    pub fn histogram_max_value(mut self, max_value: u64) -> Self {
+        self.histogram_max_value = max_value.max(2);
+        self
+    }
+
     /// Sets the default percentiles for histograms.
     ///
     /// Defaults to min/p50/p95/p99/p999/max.
     ///
     /// This controls the percentiles we extract from histograms when taking a snapshot.
     /// Percentiles are represented in metrics as pXXX, where XXX is the percentile i.e. p99 is
-    /// 99.0, p999 is 99.9, etc.  min and max are 0.0 and 100.0, respectively.
+    /// 99.0, p999 is 99.9, etc.  min and max are 0.0 and 100.0, respectively.  Individual
+    /// histograms can use their own percentiles instead, via
+    /// [`Facet::percentiles`](crate::Facet::percentiles).
     pub fn percentiles(mut self, percentiles: &[f64]) -> Self {
         self.percentiles = percentiles.iter().cloned().map(Percentile::from).collect();
         self
     }
 
+    /// Sets the bucket boundaries for the histogram with the given name.
+    ///
+    /// Defaults to 5ms, 10ms, 25ms, 50ms, 100ms, 250ms, 500ms, 1s, 2.5s, 5s, and 10s for timing
+    /// histograms, and to no boundaries for value histograms.
+    ///
+    /// The name is the fully-qualified name, including any scope, as it would appear in a snapshot,
+    /// and the boundaries are in the unit of the histogram.  Snapshots carry the cumulative count
+    /// of values at or below each boundary, via
+    /// [`SummarizedHistogram::buckets`](crate::snapshot::SummarizedHistogram::buckets), for
+    /// exporters which report histograms as buckets rather than percentiles.
+    pub fn histogram_buckets(mut self, key: &str, bounds: &[u64]) -> Self {
+        Arc::make_mut(&mut self.histogram_buckets).set(key.to_owned(), bounds.to_vec());
+        self
+    }
+
+    /// Sets whether or not histogram windows are aligned to the wall clock.
+    ///
+    /// Defaults to `false`.
+    ///
+    /// Normally, histogram buckets roll over once `granularity` has passed since the last rollover,
+    /// so bucket boundaries depend on when the receiver started.  When aligned, buckets instead roll
+    /// over whenever the wall clock crosses a multiple of `granularity`, such as the top of every
+    /// second, so that multiple hosts with synchronized clocks roll their windows in step.  This
+    /// also applies to windowed counters.
+    ///
+    /// Rollovers are checked every 250 milliseconds, so a bucket may run over its wall-clock
+    /// boundary by up to that long.
+    pub fn align_windows(mut self, aligned: bool) -> Self {
+        self.align_windows = aligned;
+        self
+    }
+
+    /// Sets whether or not the receiver reports metrics about itself.
+    ///
+    /// Defaults to `false`.
+    ///
+    /// When enabled, snapshots include the `hotmic.channel.capacity` and `hotmic.channel.len`
+    /// gauges, which are the capacity of the data channel and the number of samples waiting in it.
+    /// A channel that is regularly close to full is a sign that the capacity should be raised, as
+    /// sinks will otherwise block when sending.  The channel length is sampled every 250
+    /// milliseconds.
+    pub fn self_metrics(mut self, enabled: bool) -> Self {
+        self.self_metrics = enabled;
+        self
+    }
+
+    /// Sets whether or not snapshots include the per-second rate of each counter.
+    ///
+    /// Defaults to `false`.
+    ///
+    /// When enabled, every counter in a snapshot is accompanied by a
+    /// [`TypedMeasurement::CounterRate`](crate::snapshot::TypedMeasurement::CounterRate), which is
+    /// the change in its value since the previous snapshot, divided by the time since then, so
+    /// that consumers don't need to diff snapshots themselves.  Counters which didn't exist at the
+    /// time of the previous snapshot are treated as having been zero then.  The first snapshot has
+    /// no previous snapshot to compare against, and so carries no rates.
+    ///
+    /// Rates are measured against the previous snapshot taken from the receiver by any means, so
+    /// consumers that each take their own snapshots, such as a controller and the
+    /// [`on_snapshot`](Configuration::on_snapshot) callback, will see rates over shorter intervals
+    /// than they expect.
+    pub fn include_rates(mut self, enabled: bool) -> Self {
+        self.include_rates = enabled;
+        self
+    }
+
+    /// Sets whether or not snapshots include a windowed count for every counter.
+    ///
+    /// Defaults to `false`.
+    ///
+    /// Counters are cumulative, while histograms only cover the trailing histogram window, as set
+    /// by [`histogram`](Configuration::histogram).  When enabled, every counter in a snapshot is
+    /// accompanied by a
+    /// [`TypedMeasurement::CounterWindowed`](crate::snapshot::TypedMeasurement::CounterWindowed),
+    /// under the same name, holding the sum of its updates over that same window, so that consumers
+    /// can choose between the all-time total and the recent view, and compare the recent view with
+    /// histograms directly.  Counters registered via
+    /// [`Facet::windowed_count`](crate::Facet::windowed_count) are only ever windowed, and so are
+    /// reported once, as before.
+    pub fn include_windowed_counts(mut self, enabled: bool) -> Self {
+        self.include_windowed_counts = enabled;
+        self
+    }
+
+    /// Sets whether or not the receiver tracks when each metric was last updated.
+    ///
+    /// Defaults to `false`.
+    ///
+    /// When enabled, the receiver records the time at which it processed the latest sample for each
+    /// metric, available via [`Controller::last_updates`](crate::Controller::last_updates).  A
+    /// metric that has stopped being updated is often a sign of a code path that has stopped
+    /// running.  This costs a clock read per sample, and so is disabled by default.
+    pub fn track_last_update(mut self, enabled: bool) -> Self {
+        self.track_last_update = enabled;
+        self
+    }
+
+    /// Sets whether or not the receiver checks samples against the facets registered for their
+    /// metric.
+    ///
+    /// Defaults to `false`.
+    ///
+    /// Metrics register themselves the first time they're sent, so without validation, sending a
+    /// value for a metric that was only ever meant to be a counter silently creates a value
+    /// histogram alongside it.  When enabled, samples for a metric with any facets registered at the
+    /// scope they were sent from are only accepted if one of those facets applies to them:
+    ///
+    /// - counts, from [`Sink::update_count`](crate::Sink::update_count) and friends, need a
+    ///   [`count`](crate::Facet::count), [`wide_count`](crate::Facet::wide_count), or
+    ///   [`windowed_count`](crate::Facet::windowed_count) facet
+    /// - gauges need a [`gauge`](crate::Facet::gauge), [`max_gauge`](crate::Facet::max_gauge), or
+    ///   [`min_gauge`](crate::Facet::min_gauge) facet
+    /// - timings need a [`timing_percentile`](crate::Facet::timing_percentile) or
+    ///   [`throughput`](crate::Facet::throughput) facet
+    /// - values, from [`Sink::update_value`](crate::Sink::update_value) and friends, need a
+    ///   [`value_percentile`](crate::Facet::value_percentile) facet
+    ///
+    /// Samples that don't match are dropped, and counted per metric in the
+    /// `hotmic.facets.mismatched{metric="..."}` counter, which is reported in snapshots once it is
+    /// non-zero.  Metrics with no facets registered are always accepted.
+    pub fn validate_facets(mut self, enabled: bool) -> Self {
+        self.validate_facets = enabled;
+        self
+    }
+
+    /// Sets whether or not snapshots include the raw values recorded in each histogram.
+    ///
+    /// Defaults to `false`.
+    ///
+    /// When enabled, every histogram in a snapshot carries the full set of recorded values and
+    /// their counts, available via
+    /// [`SummarizedHistogram::recorded`](crate::snapshot::SummarizedHistogram::recorded).  This is
+    /// useful for exporters that need more than the configured percentiles, but it makes every
+    /// snapshot allocate storage proportional to the number of distinct values recorded.
+    pub fn raw_histograms(mut self, enabled: bool) -> Self {
+        self.raw_histograms = enabled;
+        self
+    }
+
+    /// Sets the minimum number of values a histogram needs in its window for its percentiles to be
+    /// considered meaningful.
+    ///
+    /// Defaults to 0, which disables the check.
+    ///
+    /// Histograms in a snapshot with fewer values than this are flagged via
+    /// [`SummarizedHistogram::is_sparse`](crate::snapshot::SummarizedHistogram::is_sparse), so that
+    /// dashboards can avoid showing high percentiles computed from only a handful of values.  The
+    /// percentiles themselves are still reported as normal.
+    pub fn sparse_histogram_threshold(mut self, count: u64) -> Self {
+        self.sparse_threshold = count;
+        self
+    }
+
+    /// Sets whether or not histogram percentiles are interpolated between recorded values.
+    ///
+    /// Defaults to `false`.
+    ///
+    /// By default, a percentile is the smallest recorded value that at least that percentage of
+    /// values are less than or equal to, which is the behavior of HdrHistogram.  For histograms
+    /// with only a few values, percentiles then jump from one recorded value to the next as values
+    /// come and go, which makes for a steppy dashboard.  When enabled, percentiles are instead
+    /// linearly interpolated between the two recorded values on either side of them, so they move
+    /// smoothly, at the cost of reporting values which may never have been recorded.
+    ///
+    /// Histograms backed by sketches, via [`Facet::sketch`](crate::Facet::sketch), are always
+    /// interpolated, and aren't affected by this.
+    pub fn interpolate_percentiles(mut self, enabled: bool) -> Self {
+        self.interpolate_percentiles = enabled;
+        self
+    }
+
+    /// Sets a callback to be called with a snapshot on a regular interval.
+    ///
+    /// Defaults to no callback.
+    ///
+    /// Every `interval`, the receiver will take a snapshot and pass it to the given callback.  This
+    /// provides a simple extension point for exporting metrics without needing to poll for
+    /// snapshots via a [`Controller`](crate::Controller).
+    ///
+    /// The callback runs on the receiver thread, and so no samples or control frames are processed
+    /// while it is running.  It must be quick: any slow work, such as network I/O, should be handed
+    /// off to another thread.
+    ///
+    /// The interval is raised to at least one millisecond, as a zero interval would leave the
+    /// receiver doing nothing but taking snapshots.
+    pub fn on_snapshot<F>(mut self, interval: Duration, callback: F) -> Self
+    where
+        F: Fn(&Snapshot) + Send + Sync + 'static,
+    {
+        let interval = interval.max(Duration::from_millis(MIN_SNAPSHOT_INTERVAL_MS));
+        self.snapshot_callback = Some((interval, Arc::new(callback)));
+        self
+    }
+
+    /// Sets the transport that carries samples from sinks to the receiver.
+    ///
+    /// Defaults to [`BoundedTransport`](crate::transport::BoundedTransport), a bounded
+    /// crossbeam channel.
+    ///
+    /// The channel is created with the configured [`capacity`](Configuration::capacity).  Sinks
+    /// block while it's full, just as with the default transport, unless they use the asynchronous
+    /// send methods.
+    pub fn transport<X>(mut self, transport: X) -> Self
+    where
+        X: Transport<Frame<T>> + 'static,
+    {
+        self.transport = Some(Arc::new(transport));
+        self
+    }
+
     /// Create a [`Receiver`] based on this configuration.
     pub fn build(self) -> Receiver<T> { Receiver::from_config(self) }
+
+    /// Create a [`Receiver`] based on this configuration, using the given hasher for its metric
+    /// storage.
+    ///
+    /// By default, receivers use FNV, which is fast for short keys.  Depending on the length and
+    /// distribution of metric keys, a different hasher may be faster.
+    pub fn build_with_hasher<H>(self) -> Receiver<T, H>
+    where
+        H: BuildHasher + Default,
+    {
+        Receiver::from_config(self)
+    }
 }
 
 /// A default set of percentiles that should support most use cases.
@@ -1,16 +1,56 @@
-use super::data::snapshot::Snapshot;
-use crossbeam_channel::{bounded, Sender};
-use std::fmt;
+use super::{
+    data::{
+        snapshot::{MeasurementKind, Snapshot, TypedMeasurement},
+        view::MetricView,
+        Percentile,
+    },
+    helper::duration_between,
+};
+use crossbeam_channel::{bounded, RecvTimeoutError, SendTimeoutError, Sender};
+use futures::{Future, Poll};
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    fmt, mem,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 use tokio_sync::oneshot;
 
+/// The longest a controller will wait for a snapshot, in seconds.  Longer timeouts are capped to
+/// this, as a deadline too far in the future can't be represented.
+const MAX_SNAPSHOT_TIMEOUT_SECS: u64 = 365 * 24 * 60 * 60;
+
 /// Error conditions when retrieving a snapshot.
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum SnapshotError {
-    /// There was an internal error when trying to collect a snapshot.
-    InternalError,
-
     /// A snapshot was requested but the receiver is shutdown.
     ReceiverShutdown,
+
+    /// The receiver did not respond within the given timeout.
+    ///
+    /// The receiver may still be running, but is busy or stalled, and so the request can be retried.
+    Timeout,
+
+    /// The receiver accepted the request, but went away before responding to it.
+    ChannelClosed,
+}
+
+/// A snapshot that will be available once the receiver has processed the request for it.
+///
+/// Resolves to [`SnapshotError::ChannelClosed`] if the receiver goes away before responding.
+#[must_use = "futures do nothing unless polled"]
+pub struct SnapshotFuture {
+    rx: oneshot::Receiver<Snapshot>,
+}
+
+/// The last snapshot taken through a controller, shared by all of its clones.
+struct SnapshotCache {
+    ttl: Duration,
+    last: Mutex<Option<(Instant, Arc<Snapshot>)>>,
 }
 
 /// Various control actions performed by a controller.
@@ -20,6 +60,30 @@ pub(crate) enum ControlFrame {
 
     /// Takes a snapshot of the current metric state, but uses an asynchronous channel.
     SnapshotAsync(oneshot::Sender<Snapshot>),
+
+    /// Takes a snapshot of the current metric state, reusing the storage of the given snapshot.
+    SnapshotInto(Snapshot, Sender<Snapshot>),
+
+    /// Takes a snapshot of the largest metrics of the given kind.
+    GetTop(MeasurementKind, usize, Sender<Snapshot>),
+
+    /// Gets the current values of the metrics with the given names.
+    GetValues(Vec<String>, Sender<HashMap<String, TypedMeasurement>>),
+
+    /// Gets the times at which metrics were last updated.
+    GetLastUpdates(Sender<HashMap<String, Instant>>),
+
+    /// Clears all recorded values for the histogram(s) with the given metric name.
+    ResetHistogram(String, Sender<()>),
+
+    /// Merges the histograms whose names match the given pattern.
+    GetMergedHistogram(String, Sender<Snapshot>),
+
+    /// Replaces the default percentiles extracted from histograms.
+    SetPercentiles(Vec<Percentile>, Sender<()>),
+
+    /// Runs a closure against the current metric state.
+    WithState(Box<dyn FnOnce(&MetricView) + Send>),
 }
 
 /// Dedicated handle for performing operations on a running [`Receiver`](crate::receiver::Receiver).
@@ -29,39 +93,549 @@ pub(crate) enum ControlFrame {
 #[derive(Clone)]
 pub struct Controller {
     control_tx: Sender<ControlFrame>,
+    waker: Arc<dyn Fn() + Send + Sync>,
+    stopped: Arc<AtomicBool>,
+    running: Arc<AtomicBool>,
+    sinks: Arc<AtomicUsize>,
+    backlog: Arc<AtomicUsize>,
+    cache: Option<Arc<SnapshotCache>>,
 }
 
 impl Controller {
-    pub(crate) fn new(control_tx: Sender<ControlFrame>) -> Controller { Controller { control_tx } }
+    pub(crate) fn new(
+        control_tx: Sender<ControlFrame>, waker: Arc<dyn Fn() + Send + Sync>, stopped: Arc<AtomicBool>,
+        running: Arc<AtomicBool>, sinks: Arc<AtomicUsize>, backlog: Arc<AtomicUsize>,
+    ) -> Controller {
+        Controller {
+            control_tx,
+            waker,
+            stopped,
+            running,
+            sinks,
+            backlog,
+            cache: None,
+        }
+    }
+
+    /// Caches snapshots taken with [`get_cached_snapshot`](Controller::get_cached_snapshot) for
+    /// the given duration.
+    ///
+    /// Within `ttl` of a snapshot being taken, further requests are answered with that same
+    /// snapshot, rather than asking the receiver for a new one.  This protects the receiver from
+    /// bursts of requests, such as a scrape endpoint being hit by several scrapers at once.  The
+    /// cache is shared by this controller and any clones made of it afterwards, so concurrent
+    /// requests share a single snapshot: while one is being taken, other requests wait for it
+    /// rather than taking their own.
+    ///
+    /// A cached snapshot can be up to `ttl` older than when it's returned, so `ttl` should be kept
+    /// well below the interval at which the snapshot is expected to change meaningfully.
+    pub fn cache_snapshots(mut self, ttl: Duration) -> Self {
+        self.cache = Some(Arc::new(SnapshotCache {
+            ttl,
+            last: Mutex::new(None),
+        }));
+        self
+    }
+
+    /// Whether or not the receiver is still alive.
+    ///
+    /// The receiver is alive from when it's built until [`Receiver::run`](crate::Receiver::run)
+    /// returns, including by panicking, or the receiver is dropped.  Once it isn't, every request
+    /// fails immediately with [`SnapshotError::ReceiverShutdown`].  A receiver that is alive may
+    /// still be too busy to respond, which [`get_snapshot_timeout`](Controller::get_snapshot_timeout)
+    /// can detect.
+    pub fn is_receiver_alive(&self) -> bool { !self.stopped.load(Ordering::Acquire) }
+
+    /// Whether or not the receiver is currently running.
+    ///
+    /// The receiver is running from when [`Receiver::run`](crate::Receiver::run) is called until
+    /// it returns.  Unlike [`is_receiver_alive`](Controller::is_receiver_alive), this is `false`
+    /// for a receiver that hasn't been started yet, or that is driven by
+    /// [`Receiver::poll_ready`](crate::Receiver::poll_ready) instead.
+    pub fn is_running(&self) -> bool { self.running.load(Ordering::Acquire) }
+
+    /// Gets the number of sinks currently bound to the receiver.
+    ///
+    /// Every sink counts, including clones and scoped sinks, until it's dropped.  Sinks held by a
+    /// [`SinkFactory`](crate::SinkFactory) or [`GaugeBuffer`](crate::GaugeBuffer) count as well.  A
+    /// count that keeps growing points to sinks being created, such as per request, and leaked.
+    pub fn sink_count(&self) -> usize { self.sinks.load(Ordering::Relaxed) }
+
+    /// Gets an estimate of the number of messages waiting for the receiver to process.
+    ///
+    /// Messages are mostly samples, along with facet registrations and the like.  The receiver
+    /// records how many are waiting each time it picks up more to process, which it does
+    /// continually while busy, and at least every 250 milliseconds while idle, so this is the
+    /// backlog as of then rather than right now.  For a receiver driven by
+    /// [`Receiver::poll_ready`](crate::Receiver::poll_ready), it's recorded each time that's called.
+    /// Until the receiver first runs, it's zero.
+    ///
+    /// Reading this doesn't involve the receiver, so it's cheap enough for a health check to call
+    /// as often as it likes.  A backlog that keeps growing is the earliest sign that the receiver
+    /// can't keep up with its sinks, well before the channel fills up and sinks start to block.
+    pub fn channel_len(&self) -> usize { self.backlog.load(Ordering::Relaxed) }
+
+    /// Sends a control frame, and wakes the receiver up so that it sees it.
+    fn send(&self, msg: ControlFrame) -> Result<(), SnapshotError> {
+        if !self.is_receiver_alive() {
+            return Err(SnapshotError::ReceiverShutdown);
+        }
+
+        self.control_tx.send(msg).map_err(|_| SnapshotError::ReceiverShutdown)?;
+        (self.waker)();
+        Ok(())
+    }
 
     /// Retrieves a snapshot of the current metric state.
     pub fn get_snapshot(&self) -> Result<Snapshot, SnapshotError> {
         let (tx, rx) = bounded(0);
         let msg = ControlFrame::Snapshot(tx);
 
-        self.control_tx
-            .send(msg)
-            .map_err(|_| SnapshotError::ReceiverShutdown)
-            .and_then(move |_| rx.recv().map_err(|_| SnapshotError::InternalError))
+        self.send(msg)
+            .and_then(move |_| rx.recv().map_err(|_| SnapshotError::ChannelClosed))
+    }
+
+    /// Retrieves a snapshot of the current metric state, or the cached snapshot, if one was taken
+    /// recently enough.
+    ///
+    /// Snapshots are only cached if enabled via [`cache_snapshots`](Controller::cache_snapshots),
+    /// and otherwise this always takes a new snapshot.  Failed snapshots are never cached.
+    pub fn get_cached_snapshot(&self) -> Result<Arc<Snapshot>, SnapshotError> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return self.get_snapshot().map(Arc::new),
+        };
+
+        // The lock is held while the snapshot is taken, so that concurrent requests wait for it,
+        // rather than each asking the receiver for its own.
+        let mut last = cache.last.lock();
+        if let Some((taken_at, snapshot)) = last.as_ref() {
+            if taken_at.elapsed() < cache.ttl {
+                return Ok(snapshot.clone());
+            }
+        }
+
+        let snapshot = Arc::new(self.get_snapshot()?);
+        *last = Some((Instant::now(), snapshot.clone()));
+        Ok(snapshot)
+    }
+
+    /// Retrieves a snapshot of the current metric state, waiting at most `timeout` for it.
+    ///
+    /// Returns [`SnapshotError::Timeout`] if the receiver doesn't respond in time, which is useful
+    /// for health checks that need to tell a stalled receiver apart from one that has shut down.
+    /// Timeouts longer than a year are treated as a year.
+    pub fn get_snapshot_timeout(&self, timeout: Duration) -> Result<Snapshot, SnapshotError> {
+        if !self.is_receiver_alive() {
+            return Err(SnapshotError::ReceiverShutdown);
+        }
+
+        let timeout = timeout.min(Duration::from_secs(MAX_SNAPSHOT_TIMEOUT_SECS));
+        let deadline = Instant::now() + timeout;
+        let (tx, rx) = bounded(0);
+        let msg = ControlFrame::Snapshot(tx);
+
+        self.control_tx.send_timeout(msg, timeout).map_err(|e| {
+            match e {
+                SendTimeoutError::Timeout(_) => SnapshotError::Timeout,
+                SendTimeoutError::Disconnected(_) => SnapshotError::ReceiverShutdown,
+            }
+        })?;
+        (self.waker)();
+
+        let remaining = duration_between(Instant::now(), deadline);
+        rx.recv_timeout(remaining).map_err(|e| {
+            match e {
+                RecvTimeoutError::Timeout => SnapshotError::Timeout,
+                RecvTimeoutError::Disconnected => SnapshotError::ChannelClosed,
+            }
+        })
+    }
+
+    /// Retrieves a snapshot of the current metric state into an existing snapshot.
+    ///
+    /// Any measurements already in `snapshot` are replaced, and the storage that held them is
+    /// reused for the new ones, rather than allocating a new snapshot every time.  This reduces
+    /// allocator churn when snapshots are taken frequently, by reusing the same snapshot for each.
+    ///
+    /// If the snapshot can't be taken, `snapshot` is left empty.
+    pub fn snapshot_into(&self, snapshot: &mut Snapshot) -> Result<(), SnapshotError> {
+        let (tx, rx) = bounded(0);
+        let buffer = mem::replace(snapshot, Snapshot::default());
+        let msg = ControlFrame::SnapshotInto(buffer, tx);
+
+        self.send(msg)?;
+        *snapshot = rx.recv().map_err(|_| SnapshotError::ChannelClosed)?;
+        Ok(())
     }
 
     /// Retrieves a snapshot of the current metric state asynchronously.
-    pub fn get_snapshot_async(&self) -> Result<oneshot::Receiver<Snapshot>, SnapshotError> {
+    pub fn get_snapshot_async(&self) -> Result<SnapshotFuture, SnapshotError> {
         let (tx, rx) = oneshot::channel();
         let msg = ControlFrame::SnapshotAsync(tx);
 
-        self.control_tx
-            .send(msg)
-            .map_err(|_| SnapshotError::ReceiverShutdown)
-            .map(move |_| rx)
+        self.send(msg).map(move |_| SnapshotFuture { rx })
+    }
+
+    /// Retrieves the current values of the metrics with the given names.
+    ///
+    /// Names are fully-qualified, including any scope, as they would appear in a snapshot.  Only
+    /// the requested metrics are looked up and summarized, which is cheaper than taking a full
+    /// snapshot when only a handful of values are needed.  Names which don't match any metric are
+    /// left out of the result.
+    ///
+    /// A timing histogram shares its name with the counter that tracks how many timings were
+    /// recorded, and so if both exist, the histogram is returned.
+    pub fn get_values(&self, keys: &[&str]) -> Result<HashMap<String, TypedMeasurement>, SnapshotError> {
+        let (tx, rx) = bounded(0);
+        let keys = keys.iter().map(|key| (*key).to_owned()).collect();
+        let msg = ControlFrame::GetValues(keys, tx);
+
+        self.send(msg)
+            .and_then(move |_| rx.recv().map_err(|_| SnapshotError::ChannelClosed))
+    }
+
+    /// Retrieves the time at which each metric was last updated.
+    ///
+    /// Metrics are keyed by their fully-qualified name, including any scope, as they would appear
+    /// in a snapshot.  The time is when the receiver processed the latest sample for the metric,
+    /// which may be slightly after the sample was sent.  A metric with the same name across several
+    /// types, such as a timing histogram and its counter, has a single time.
+    ///
+    /// This is only tracked when enabled via
+    /// [`Configuration::track_last_update`](crate::Configuration::track_last_update), and is empty
+    /// otherwise.
+    pub fn last_updates(&self) -> Result<HashMap<String, Instant>, SnapshotError> {
+        let (tx, rx) = bounded(0);
+        let msg = ControlFrame::GetLastUpdates(tx);
+
+        self.send(msg)
+            .and_then(move |_| rx.recv().map_err(|_| SnapshotError::ChannelClosed))
+    }
+
+    /// Retrieves a snapshot of only the `n` largest metrics of the given kind.
+    ///
+    /// Counters and gauges are ranked by their value, and histograms by the number of values in
+    /// their window.  Only the selected metrics are summarized, which caps the cost of taking and
+    /// exporting a snapshot when there are many metrics, while still covering the busiest ones.
+    pub fn get_top(&self, kind: MeasurementKind, n: usize) -> Result<Snapshot, SnapshotError> {
+        let (tx, rx) = bounded(0);
+        let msg = ControlFrame::GetTop(kind, n, tx);
+
+        self.send(msg)
+            .and_then(move |_| rx.recv().map_err(|_| SnapshotError::ChannelClosed))
+    }
+
+    /// Retrieves a snapshot of the histograms whose names match the given pattern, merged together.
+    ///
+    /// This gives an aggregate view across scopes without recording every value twice, such as
+    /// `listener.*.latency` over `listener.a.latency`, `listener.b.latency`, and so on.  Names and
+    /// patterns are split into segments on dots, and each segment of the pattern must match the
+    /// segment of the name in the same position: `*` matches any one segment, and anything else
+    /// matches only itself.  Labels are ignored when matching, so histograms which differ only in
+    /// their labels are merged too.
+    ///
+    /// The matching timing histograms are merged into one, and the matching value histograms into
+    /// another, each named after the pattern, and summarized with the default percentiles.  A
+    /// histogram that can't be merged with the rest is left out: one backed by a sketch, via
+    /// [`Facet::sketch`](crate::Facet::sketch), when any of the others is backed by an
+    /// HdrHistogram, and a timing histogram in a coarser unit than the finest unit among those
+    /// matched, via [`Facet::unit`](crate::Facet::unit).  When nothing matches, the snapshot is
+    /// empty.
+    pub fn get_merged_histogram(&self, pattern: &str) -> Result<Snapshot, SnapshotError> {
+        let (tx, rx) = bounded(0);
+        let msg = ControlFrame::GetMergedHistogram(pattern.to_owned(), tx);
+
+        self.send(msg)
+            .and_then(move |_| rx.recv().map_err(|_| SnapshotError::ChannelClosed))
+    }
+
+    /// Clears all recorded values for the histogram with the given metric name.
+    ///
+    /// The metric name is the fully-qualified name, including any scope, as it would appear in a
+    /// snapshot.  Both timing and value histograms with a matching name are reset.  This empties
+    /// every bucket in the histogram's window, as if it had never been recorded to, but the
+    /// histogram itself remains registered.
+    ///
+    /// This call blocks until the receiver has processed the reset.
+    pub fn reset_histogram(&self, key: &str) -> Result<(), SnapshotError> {
+        let (tx, rx) = bounded(0);
+        let msg = ControlFrame::ResetHistogram(key.to_owned(), tx);
+
+        self.send(msg)
+            .and_then(move |_| rx.recv().map_err(|_| SnapshotError::ChannelClosed))
+    }
+
+    /// Replaces the percentiles extracted from histograms when taking a snapshot.
+    ///
+    /// This is the runtime counterpart of
+    /// [`Configuration::percentiles`](crate::Configuration::percentiles), and the percentiles are
+    /// clamped between 0.0 and 100.0 in the same way.  Snapshots taken after the change use the
+    /// new percentiles, such as to briefly look at p99.99 while chasing down a latency issue, and
+    /// then go back to the old ones.  Histograms with their own percentiles, set via
+    /// [`Facet::percentiles`](crate::Facet::percentiles), are unaffected.
+    ///
+    /// This call blocks until the receiver has processed the change.
+    pub fn set_percentiles(&self, percentiles: &[f64]) -> Result<(), SnapshotError> {
+        let (tx, rx) = bounded(0);
+        let percentiles = percentiles.iter().cloned().map(Percentile::from).collect();
+        let msg = ControlFrame::SetPercentiles(percentiles, tx);
+
+        self.send(msg)
+            .and_then(move |_| rx.recv().map_err(|_| SnapshotError::ChannelClosed))
+    }
+
+    /// Runs the given closure against the current metric state, and returns its result.
+    ///
+    /// The closure is given a [`MetricView`], which borrows the receiver's metrics in place, so
+    /// custom aggregations can be computed without allocating a full [`Snapshot`].
+    ///
+    /// The closure runs on the receiver thread, and so no samples or control frames are processed
+    /// while it is running.  It must be quick, and must not call back into a controller for the
+    /// same receiver, as that would deadlock.
+    ///
+    /// This call blocks until the receiver has run the closure.
+    pub fn with_state<F, R>(&self, f: F) -> Result<R, SnapshotError>
+    where
+        F: FnOnce(&MetricView) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (tx, rx) = bounded(0);
+        let msg = ControlFrame::WithState(Box::new(move |view| {
+            let _ = tx.send(f(view));
+        }));
+
+        self.send(msg)
+            .and_then(move |_| rx.recv().map_err(|_| SnapshotError::ChannelClosed))
     }
 }
 
 impl fmt::Display for SnapshotError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            SnapshotError::InternalError => write!(f, "internal error during snapshot generation"),
             SnapshotError::ReceiverShutdown => write!(f, "the receiver is not currently running"),
+            SnapshotError::Timeout => write!(f, "the receiver did not respond in time"),
+            SnapshotError::ChannelClosed => write!(f, "the receiver went away before responding"),
+        }
+    }
+}
+
+impl Future for SnapshotFuture {
+    type Error = SnapshotError;
+    type Item = Snapshot;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> { self.rx.poll().map_err(|_| SnapshotError::ChannelClosed) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SnapshotError;
+    use crate::{data::Snapshot, receiver::Receiver};
+    use futures::Future;
+    use std::{
+        sync::Arc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn test_snapshot_errors() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let controller = receiver.get_controller();
+
+        // The receiver exists, but isn't running, so nothing answers.
+        assert_eq!(
+            controller.get_snapshot_timeout(Duration::from_millis(50)).err(),
+            Some(SnapshotError::Timeout)
+        );
+
+        let future = controller.get_snapshot_async().expect("failed to request snapshot");
+        thread::spawn(move || receiver.run());
+        assert!(future.wait().is_ok());
+        assert!(controller.get_snapshot_timeout(Duration::from_secs(5)).is_ok());
+
+        // A timeout too long to compute a deadline for is capped rather than overflowing.
+        let forever = Duration::new(u64::max_value(), 0);
+        assert!(controller.get_snapshot_timeout(forever).is_ok());
+
+        let receiver = Receiver::<&'static str>::builder().build();
+        let controller = receiver.get_controller();
+        drop(receiver);
+        assert!(!controller.is_receiver_alive());
+        let mut snapshot = Snapshot::default();
+        assert_eq!(
+            controller.snapshot_into(&mut snapshot).err(),
+            Some(SnapshotError::ReceiverShutdown)
+        );
+        assert_eq!(controller.get_snapshot().err(), Some(SnapshotError::ReceiverShutdown));
+        assert_eq!(
+            controller.get_snapshot_async().err(),
+            Some(SnapshotError::ReceiverShutdown)
+        );
+    }
+
+    #[test]
+    fn test_receiver_panic() {
+        let mut receiver = Receiver::<&'static str>::builder()
+            .on_snapshot(Duration::from_millis(10), |_| panic!("exporter failed"))
+            .build();
+        let controller = receiver.get_controller();
+        assert!(controller.is_receiver_alive());
+
+        let handle = thread::spawn(move || receiver.run());
+        assert!(handle.join().is_err());
+
+        // The receiver thread is gone, so requests fail immediately rather than waiting on it.
+        assert!(!controller.is_receiver_alive());
+        let start = Instant::now();
+        assert_eq!(controller.get_snapshot().err(), Some(SnapshotError::ReceiverShutdown));
+        assert_eq!(
+            controller.get_snapshot_timeout(Duration::from_secs(5)).err(),
+            Some(SnapshotError::ReceiverShutdown)
+        );
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_sink_count() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let controller = receiver.get_controller();
+        assert_eq!(controller.sink_count(), 0);
+        assert!(!controller.is_running());
+
+        let sink = receiver.get_sink();
+        let scoped = sink.scoped("db").unwrap();
+        let cloned = scoped.clone();
+        assert_eq!(controller.sink_count(), 3);
+        drop(scoped);
+        drop(cloned);
+        assert_eq!(controller.sink_count(), 1);
+
+        thread::spawn(move || receiver.run());
+        sink.flush().expect("failed to flush");
+
+        drop(sink);
+        assert_eq!(controller.sink_count(), 0);
+        assert!(controller.is_running());
+    }
+
+    #[test]
+    fn test_channel_len() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let controller = receiver.get_controller();
+        let sink = receiver.get_sink();
+        assert_eq!(controller.channel_len(), 0);
+
+        for _ in 0..3 {
+            sink.increment("widgets");
+        }
+        assert_eq!(controller.channel_len(), 0);
+
+        // The backlog is recorded as the receiver picks up the waiting messages.
+        receiver.poll_ready();
+        assert_eq!(controller.channel_len(), 3);
+        receiver.poll_ready();
+        assert_eq!(controller.channel_len(), 0);
+    }
+
+    #[test]
+    fn test_wakeups_coalesced() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let controller = receiver.get_controller();
+        let sink = receiver.get_sink();
+
+        // Only one wakeup is queued, however many control frames are sent, and it isn't counted as
+        // part of the backlog.
+        sink.increment("widgets");
+        for _ in 0..3 {
+            (controller.waker)();
+        }
+        receiver.poll_ready();
+        assert_eq!(controller.channel_len(), 1);
+
+        // Once the receiver has picked it up, the next control frame queues another.
+        (controller.waker)();
+        (controller.waker)();
+        receiver.poll_ready();
+        assert_eq!(controller.channel_len(), 0);
+        receiver.poll_ready();
+        assert_eq!(controller.channel_len(), 0);
+    }
+
+    #[test]
+    fn test_cached_snapshot() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let sink = receiver.get_sink();
+        let controller = receiver.get_controller().cache_snapshots(Duration::from_secs(60));
+        let uncached = receiver.get_controller();
+        thread::spawn(move || receiver.run());
+
+        let first = controller.get_cached_snapshot().expect("failed to get snapshot");
+        assert!(first.measurements.is_empty());
+
+        // Requests within the TTL get the same snapshot, even through a clone, and even once the
+        // metrics have changed.
+        sink.update_count("widgets", 3);
+        sink.flush().expect("failed to flush");
+        assert_eq!(uncached.get_snapshot().unwrap().into_simple().count("widgets"), Some(3));
+        let second = controller
+            .clone()
+            .get_cached_snapshot()
+            .expect("failed to get snapshot");
+        assert!(Arc::ptr_eq(&first, &second));
+
+        // Without a cache, every request takes a new snapshot.
+        let first = uncached.get_cached_snapshot().expect("failed to get snapshot");
+        let second = uncached.get_cached_snapshot().expect("failed to get snapshot");
+        assert!(!Arc::ptr_eq(&first, &second));
+    }
+
+    #[test]
+    fn test_snapshot_into() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let sink = receiver.get_sink();
+        let controller = receiver.get_controller();
+        thread::spawn(move || receiver.run());
+
+        sink.update_count("widgets", 3);
+        sink.update_gauge("depth", 7);
+
+        sink.flush().expect("failed to flush");
+
+        let mut snapshot = Snapshot::default();
+        controller.snapshot_into(&mut snapshot).expect("failed to get snapshot");
+        assert_eq!(snapshot.measurements.len(), 2);
+
+        // Taking another snapshot replaces the measurements, in the same storage.
+        let storage = snapshot.measurements.as_ptr();
+        controller.snapshot_into(&mut snapshot).expect("failed to get snapshot");
+        assert_eq!(snapshot.measurements.as_ptr(), storage);
+
+        let snapshot = snapshot.into_simple();
+        assert_eq!(snapshot.count("widgets"), Some(3));
+        assert_eq!(snapshot.gauge("depth"), Some(7));
+    }
+
+    #[test]
+    fn test_set_percentiles() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let sink = receiver.get_sink();
+        let controller = receiver.get_controller();
+        thread::spawn(move || receiver.run());
+
+        for value in 1..=100 {
+            sink.update_value("payload", value);
         }
+        sink.flush().expect("failed to flush");
+        let snapshot = controller.get_snapshot().unwrap().into_simple();
+        assert!(snapshot.value_histogram("payload", 99.0).is_some());
+
+        controller
+            .set_percentiles(&[50.0, 99.99, 150.0])
+            .expect("failed to set percentiles");
+        let snapshot = controller.get_snapshot().unwrap().into_simple();
+        assert!(snapshot.value_histogram("payload", 99.99).is_some());
+        assert_eq!(snapshot.value_histogram("payload", 100.0), Some(100));
+        assert_eq!(snapshot.value_histogram("payload", 99.0), None);
     }
 }
@@ -1,33 +1,283 @@
+use crate::helper::{duration_as_nanos, intervals_between, periods_between, wall_clock_period};
 use fnv::FnvBuildHasher;
-use hashbrown::HashMap;
-use std::hash::Hash;
+use hashbrown::{hash_map::Iter, HashMap, HashSet};
+use parking_lot::Mutex;
+use std::{
+    hash::{BuildHasher, Hash},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
-pub(crate) struct Counter<T> {
-    data: HashMap<T, i64, FnvBuildHasher>,
+pub(crate) struct Counter<T, H = FnvBuildHasher> {
+    data: HashMap<T, i64, H>,
+    saturated: HashSet<T, H>,
 }
 
-impl<T: Clone + Eq + Hash> Counter<T> {
-    pub fn new() -> Counter<T> {
+impl<T: Clone + Eq + Hash, H: BuildHasher + Default> Counter<T, H> {
+    pub fn new() -> Counter<T, H> {
         Counter {
-            data: HashMap::<T, i64, FnvBuildHasher>::default(),
+            data: HashMap::default(),
+            saturated: HashSet::default(),
         }
     }
 
+    /// Adds `delta` to the counter, wrapping around if it overflows.
     pub fn update(&mut self, key: T, delta: i64) {
         let value = self.data.entry(key).or_insert(0);
-        *value += delta;
+        *value = value.wrapping_add(delta);
+    }
+
+    /// Adds `delta` to the counter, stopping at the largest or smallest possible value if it
+    /// overflows, and marking the counter as saturated.
+    pub fn update_saturating(&mut self, key: T, delta: i64) {
+        let value = self.data.get(&key).cloned().unwrap_or(0);
+        match value.checked_add(delta) {
+            Some(value) => {
+                let _ = self.data.insert(key, value);
+            },
+            None => {
+                let _ = self.data.insert(key.clone(), value.saturating_add(delta));
+                let _ = self.saturated.insert(key);
+            },
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_, T, i64> { self.data.iter() }
+
+    /// Iterates over the keys of counters which have saturated.
+    pub fn saturated(&self) -> impl Iterator<Item = &T> { self.saturated.iter() }
+
+    pub fn remove(&mut self, key: &T) -> bool {
+        let _ = self.saturated.remove(key);
+        self.data.remove(key).is_some()
     }
 
     pub fn values(&self) -> Vec<(T, i64)> { self.data.iter().map(|(k, v)| (k.clone(), *v)).collect() }
 }
 
+/// The value of a shared counter, which can be updated and read from any thread.
+///
+/// `AtomicI64` isn't available on every version of Rust that we support, so the value is held
+/// behind a lock instead, which is only ever held long enough to add to or read the value.
+#[derive(Debug, Default)]
+pub(crate) struct SharedValue(Mutex<i64>);
+
+impl SharedValue {
+    /// Adds `delta` to the value, wrapping around if it overflows.
+    pub fn update(&self, delta: i64) {
+        let mut value = self.0.lock();
+        *value = value.wrapping_add(delta);
+    }
+
+    pub fn value(&self) -> i64 { *self.0.lock() }
+}
+
+/// The counters which have been shared, so that every sink registering the same counter gets a
+/// handle to the same value.
+///
+/// This is only consulted when a counter is registered: the receiver is told about each counter as
+/// it's shared, and keeps track of them itself from then on.
+pub(crate) struct SharedCounters<T> {
+    data: Mutex<HashMap<T, Arc<SharedValue>, FnvBuildHasher>>,
+}
+
+impl<T: Clone + Eq + Hash> SharedCounters<T> {
+    pub fn new() -> SharedCounters<T> {
+        SharedCounters {
+            data: Mutex::new(HashMap::default()),
+        }
+    }
+
+    /// Gets the shared value of the counter for the given key, registering it if need be.
+    ///
+    /// Also returns whether or not this call registered the counter.
+    pub fn register(&self, key: T) -> (Arc<SharedValue>, bool) {
+        let mut data = self.data.lock();
+        if let Some(value) = data.get(&key) {
+            return (value.clone(), false);
+        }
+
+        let value = Arc::new(SharedValue::default());
+        let _ = data.insert(key, value.clone());
+        (value, true)
+    }
+}
+
+pub(crate) struct WideCounter<T, H = FnvBuildHasher> {
+    data: HashMap<T, u128, H>,
+}
+
+impl<T: Clone + Eq + Hash, H: BuildHasher + Default> WideCounter<T, H> {
+    pub fn new() -> WideCounter<T, H> {
+        WideCounter {
+            data: HashMap::default(),
+        }
+    }
+
+    pub fn update(&mut self, key: T, delta: i64) {
+        let value = self.data.entry(key).or_insert(0);
+        if delta >= 0 {
+            *value = value.saturating_add(delta as u128);
+        } else {
+            *value = value.saturating_sub(u128::from(delta.wrapping_neg() as u64));
+        }
+    }
+
+    pub fn remove(&mut self, key: &T) -> bool { self.data.remove(key).is_some() }
+
+    pub fn iter(&self) -> Iter<'_, T, u128> { self.data.iter() }
+
+    pub fn values(&self) -> Vec<(T, u128)> { self.data.iter().map(|(k, v)| (k.clone(), *v)).collect() }
+}
+
+/// Tracks how many units of work were done, and how long was spent doing them, for timings
+/// recorded with a count.
+pub(crate) struct Throughput<T, H = FnvBuildHasher> {
+    data: HashMap<T, (u64, u64), H>,
+}
+
+impl<T: Clone + Eq + Hash, H: BuildHasher + Default> Throughput<T, H> {
+    pub fn new() -> Throughput<T, H> {
+        Throughput {
+            data: HashMap::default(),
+        }
+    }
+
+    /// Adds `count` units of work, done over `nanos` nanoseconds.
+    pub fn update(&mut self, key: T, count: u64, nanos: u64) {
+        let (total_count, total_nanos) = self.data.entry(key).or_insert((0, 0));
+        *total_count = total_count.saturating_add(count);
+        *total_nanos = total_nanos.saturating_add(nanos);
+    }
+
+    pub fn remove(&mut self, key: &T) -> bool { self.data.remove(key).is_some() }
+
+    /// Iterates over the throughput of each key, in units per second.
+    ///
+    /// Keys which haven't yet spent any time doing work have no throughput, and are skipped.
+    pub fn iter(&self) -> impl Iterator<Item = (&T, f64)> {
+        self.data.iter().filter_map(|(k, (count, nanos))| {
+            if *nanos == 0 {
+                None
+            } else {
+                Some((k, *count as f64 * 1e9 / *nanos as f64))
+            }
+        })
+    }
+
+    pub fn values(&self) -> Vec<(T, f64)> { self.iter().map(|(k, v)| (k.clone(), v)).collect() }
+}
+
+pub(crate) struct WindowedCounter<T, H = FnvBuildHasher> {
+    window: Duration,
+    granularity: Duration,
+    aligned: bool,
+    data: HashMap<T, WindowedCount, H>,
+}
+
+impl<T: Clone + Eq + Hash, H: BuildHasher + Default> WindowedCounter<T, H> {
+    pub fn new(window: Duration, granularity: Duration, aligned: bool) -> WindowedCounter<T, H> {
+        WindowedCounter {
+            window,
+            granularity,
+            aligned,
+            data: HashMap::default(),
+        }
+    }
+
+    pub fn update(&mut self, key: T, delta: i64) {
+        let (window, granularity) = (self.window, self.granularity);
+        self.data
+            .entry(key)
+            .or_insert_with(|| WindowedCount::new(window, granularity))
+            .update(delta);
+    }
+
+    pub fn upkeep(&mut self, at: Instant, wall: SystemTime) {
+        for (_, counter) in self.data.iter_mut() {
+            if self.aligned {
+                counter.upkeep_aligned(wall);
+            } else {
+                counter.upkeep(at);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &T) -> bool { self.data.remove(key).is_some() }
+
+    pub fn iter(&self) -> Iter<'_, T, WindowedCount> { self.data.iter() }
+
+    pub fn values(&self) -> Vec<(T, i64)> { self.data.iter().map(|(k, v)| (k.clone(), v.value())).collect() }
+}
+
+/// A counter whose value is the sum of all deltas over a rolling window.
+///
+/// This uses the same bucketing as [`WindowedHistogram`](super::histogram::WindowedHistogram):
+/// (window / granularity) + 1 buckets, where every interval that passes (granularity), the oldest
+/// bucket is cleared and becomes the bucket that new deltas are added to.
+pub(crate) struct WindowedCount {
+    buckets: Vec<i64>,
+    bucket_index: usize,
+    last_upkeep: Instant,
+    last_period: u64,
+    granularity: Duration,
+}
+
+impl WindowedCount {
+    pub fn new(window: Duration, granularity: Duration) -> WindowedCount {
+        let num_buckets = ((duration_as_nanos(window) / duration_as_nanos(granularity)) as usize) + 1;
+
+        WindowedCount {
+            buckets: vec![0; num_buckets],
+            bucket_index: 0,
+            last_upkeep: Instant::now(),
+            last_period: wall_clock_period(SystemTime::now(), granularity),
+            granularity,
+        }
+    }
+
+    /// Rolls over one bucket for every interval of the granularity that has passed since the last
+    /// rollover, in the same way as [`WindowedHistogram::upkeep`](super::histogram::WindowedHistogram::upkeep).
+    pub fn upkeep(&mut self, at: Instant) {
+        let intervals = intervals_between(self.last_upkeep, at, self.granularity);
+        if intervals > 0 {
+            self.rotate(intervals);
+            self.last_upkeep += Duration::from_nanos(intervals * duration_as_nanos(self.granularity));
+        }
+    }
+
+    pub fn upkeep_aligned(&mut self, wall: SystemTime) {
+        let period = wall_clock_period(wall, self.granularity);
+        if period != self.last_period {
+            self.rotate(periods_between(self.last_period, period));
+            self.last_period = period;
+        }
+    }
+
+    fn rotate(&mut self, intervals: u64) {
+        for _ in 0..intervals.min(self.buckets.len() as u64) {
+            self.bucket_index += 1;
+            self.bucket_index %= self.buckets.len();
+            self.buckets[self.bucket_index] = 0;
+        }
+    }
+
+    pub fn update(&mut self, delta: i64) {
+        let bucket = &mut self.buckets[self.bucket_index];
+        *bucket = bucket.wrapping_add(delta);
+    }
+
+    pub fn value(&self) -> i64 { self.buckets.iter().fold(0i64, |acc, v| acc.wrapping_add(*v)) }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::Counter;
+    use super::{Counter, Throughput, WideCounter, WindowedCount, WindowedCounter};
+    use std::time::{Duration, Instant};
 
     #[test]
     fn test_counter_simple_update() {
-        let mut counter = Counter::new();
+        let mut counter: Counter<_> = Counter::new();
 
         let key = "foo";
         counter.update(key.clone(), 42);
@@ -36,4 +286,116 @@ mod tests {
         assert_eq!(values.len(), 1);
         assert_eq!(values[0].1, 42);
     }
+
+    #[test]
+    fn test_counter_overflow() {
+        let mut counter: Counter<_> = Counter::new();
+        counter.update("wrapping", i64::max_value());
+        counter.update("wrapping", 1);
+        counter.update_saturating("saturating", i64::max_value());
+        counter.update_saturating("saturating", 1);
+        counter.update_saturating("negative", i64::min_value());
+        counter.update_saturating("negative", -1);
+        counter.update_saturating("fine", 1);
+
+        let mut values = counter.values();
+        values.sort();
+        assert_eq!(
+            values,
+            vec![
+                ("fine", 1),
+                ("negative", i64::min_value()),
+                ("saturating", i64::max_value()),
+                ("wrapping", i64::min_value()),
+            ]
+        );
+
+        let mut saturated = counter.saturated().cloned().collect::<Vec<_>>();
+        saturated.sort();
+        assert_eq!(saturated, vec!["negative", "saturating"]);
+
+        // Saturated counters stay marked, even once they come back down, until they're removed.
+        counter.update_saturating("saturating", -1);
+        assert_eq!(counter.saturated().count(), 2);
+        assert!(counter.remove(&"saturating"));
+        assert_eq!(counter.saturated().count(), 1);
+    }
+
+    #[test]
+    fn test_wide_counter_update() {
+        let mut counter: WideCounter<_> = WideCounter::new();
+
+        let key = "foo";
+        counter.update(key, i64::max_value());
+        counter.update(key, i64::max_value());
+        counter.update(key, 2);
+
+        let values = counter.values();
+        assert_eq!(values.len(), 1);
+        assert_eq!(values[0].1, u128::from(u64::max_value()) + 1);
+
+        // Wide counters are unsigned, so they stop at zero.
+        counter.update(key, i64::min_value());
+        counter.update(key, i64::min_value());
+        counter.update(key, -2);
+        assert_eq!(counter.values()[0].1, 0);
+    }
+
+    #[test]
+    fn test_throughput_update() {
+        let mut throughput: Throughput<_> = Throughput::new();
+        throughput.update("idle", 0, 0);
+        assert!(throughput.values().is_empty());
+
+        // 30 rows over 1.5 seconds is 20 rows per second.
+        throughput.update("rows", 10, 500_000_000);
+        throughput.update("rows", 20, 1_000_000_000);
+        let values = throughput.values();
+        assert_eq!(values, vec![("rows", 20.0)]);
+
+        assert!(throughput.remove(&"rows"));
+        assert!(throughput.values().is_empty());
+    }
+
+    #[test]
+    fn test_windowed_counter_update() {
+        let mut counter: WindowedCounter<_> = WindowedCounter::new(Duration::new(5, 0), Duration::new(1, 0), false);
+
+        let key = "foo";
+        counter.update(key, 42);
+        counter.update(key, -2);
+
+        let values = counter.values();
+        assert_eq!(values.len(), 1);
+        assert_eq!(values[0].1, 40);
+    }
+
+    #[test]
+    fn test_windowed_count_rollover() {
+        let mut wc = WindowedCount::new(Duration::new(2, 0), Duration::new(1, 0));
+        let now = Instant::now();
+
+        wc.update(1);
+        assert_eq!(wc.value(), 1);
+
+        let now = now + Duration::new(1, 0);
+        wc.upkeep(now);
+        wc.update(2);
+        assert_eq!(wc.value(), 3);
+
+        let now = now + Duration::new(1, 0);
+        wc.upkeep(now);
+        wc.update(3);
+        assert_eq!(wc.value(), 6);
+
+        // The first delta has now aged out of the window.
+        let now = now + Duration::new(1, 0);
+        wc.upkeep(now);
+        assert_eq!(wc.value(), 5);
+
+        // Upkeep running late catches up on every bucket it missed.
+        let now = now + Duration::new(2, 0);
+        wc.upkeep(now);
+        assert_eq!(wc.value(), 0);
+    }
 }
@@ -0,0 +1,654 @@
+use super::{Percentile, ScopedKey, Storage};
+use fnv::FnvBuildHasher;
+use hashbrown::HashMap;
+use std::{
+    fmt::{self, Display},
+    hash::{BuildHasher, Hash},
+    time::Duration,
+};
+
+/// The unit that a timing histogram stores its values in.
+///
+/// Timings are always measured in nanoseconds, but are converted to the configured unit before
+/// being recorded.  Coarser units keep the recorded values small, which is useful for long-running
+/// operations whose nanosecond timings would otherwise be needlessly large.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TimeUnit {
+    /// Nanoseconds.  This is the default.
+    Nanoseconds,
+
+    /// Microseconds.
+    Microseconds,
+
+    /// Milliseconds.
+    Milliseconds,
+}
+
+impl TimeUnit {
+    /// Converts a value in nanoseconds to this unit, truncating any remainder.
+    pub fn from_nanos(self, nanos: u64) -> u64 {
+        match self {
+            TimeUnit::Nanoseconds => nanos,
+            TimeUnit::Microseconds => nanos / 1_000,
+            TimeUnit::Milliseconds => nanos / 1_000_000,
+        }
+    }
+
+    /// Converts a value in this unit to nanoseconds, saturating at `u64::max_value()`.
+    pub fn to_nanos(self, value: u64) -> u64 {
+        match self {
+            TimeUnit::Nanoseconds => value,
+            TimeUnit::Microseconds => value.saturating_mul(1_000),
+            TimeUnit::Milliseconds => value.saturating_mul(1_000_000),
+        }
+    }
+
+    /// Converts a value in this unit to a [`Duration`].
+    pub fn to_duration(self, value: u64) -> Duration {
+        match self {
+            TimeUnit::Nanoseconds => Duration::from_nanos(value),
+            TimeUnit::Microseconds => Duration::from_micros(value),
+            TimeUnit::Milliseconds => Duration::from_millis(value),
+        }
+    }
+
+    /// Gets the abbreviated label for this unit: `ns`, `us`, or `ms`.
+    pub fn label(self) -> &'static str {
+        match self {
+            TimeUnit::Nanoseconds => "ns",
+            TimeUnit::Microseconds => "us",
+            TimeUnit::Milliseconds => "ms",
+        }
+    }
+}
+
+impl Default for TimeUnit {
+    fn default() -> TimeUnit { TimeUnit::Nanoseconds }
+}
+
+/// A timing, in nanoseconds.
+///
+/// Timings display themselves in whichever of nanoseconds, microseconds, milliseconds, or seconds
+/// keeps them readable, so `1_500_000` nanoseconds is displayed as `1.50ms`.  Values below a
+/// microsecond are displayed exactly, and everything else to two decimal places, unless a
+/// precision is given, as in `{:.0}`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timing(u64);
+
+impl Timing {
+    /// Creates a timing from a number of nanoseconds.
+    pub fn from_nanos(nanos: u64) -> Timing { Timing(nanos) }
+
+    /// Gets the number of nanoseconds in this timing.
+    pub fn as_nanos(self) -> u64 { self.0 }
+
+    /// Gets the number of seconds in this timing, including fractional seconds.
+    pub fn as_secs_f64(self) -> f64 { self.0 as f64 / 1_000_000_000.0 }
+}
+
+impl From<Timing> for Duration {
+    fn from(timing: Timing) -> Duration { Duration::from_nanos(timing.0) }
+}
+
+impl fmt::Display for Timing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (divisor, label) = match self.0 {
+            0..=999 => return write!(f, "{}ns", self.0),
+            1_000..=999_999 => (1_000.0, TimeUnit::Microseconds.label()),
+            1_000_000..=999_999_999 => (1_000_000.0, TimeUnit::Milliseconds.label()),
+            _ => (1_000_000_000.0, "s"),
+        };
+        let precision = f.precision().unwrap_or(2);
+        write!(f, "{:.*}{}", precision, self.0 as f64 / divisor, label)
+    }
+}
+
+/// The type of metric that a facet refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FacetKind {
+    /// A counter.
+    Count,
+
+    /// A wide, unsigned counter.
+    WideCount,
+
+    /// A counter over a rolling window.
+    WindowedCount,
+
+    /// A gauge.
+    Gauge,
+
+    /// A gauge which keeps the largest value it has been set to.
+    MaxGauge,
+
+    /// A gauge which keeps the smallest value it has been set to.
+    MinGauge,
+
+    /// A timing histogram.
+    TimingPercentile,
+
+    /// A value histogram.
+    ValuePercentile,
+
+    /// The throughput of a timing histogram.
+    Throughput,
+}
+
+/// A facet: an explicit registration of interest in a metric.
+///
+/// Metrics register themselves automatically the first time they're sent, using the default
+/// behavior for their type.  Facets allow callers to register a metric ahead of time, with
+/// customized behavior for how the metric is tracked.
+///
+/// Facets are registered at the scope of the [`Sink`](crate::Sink) they were added through, just
+/// like the metrics themselves.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Facet<T> {
+    pub(crate) kind: FacetKind,
+    pub(crate) key: T,
+    pub(crate) unit: TimeUnit,
+    pub(crate) reject_above: Option<u64>,
+    pub(crate) allowed_labels: Option<Vec<(String, Option<Vec<String>>)>>,
+    pub(crate) sketch: bool,
+    pub(crate) max_value: Option<u64>,
+    pub(crate) saturating: bool,
+    pub(crate) output_name: Option<String>,
+    pub(crate) expose_count: bool,
+    pub(crate) percentiles: Option<Vec<Percentile>>,
+}
+
+impl<T> Facet<T> {
+    fn new(kind: FacetKind, key: T) -> Facet<T> {
+        Facet {
+            kind,
+            key,
+            unit: TimeUnit::default(),
+            reject_above: None,
+            allowed_labels: None,
+            sketch: false,
+            max_value: None,
+            saturating: false,
+            output_name: None,
+            expose_count: false,
+            percentiles: None,
+        }
+    }
+
+    /// Creates a facet for a counter.
+    pub fn count(key: T) -> Facet<T> { Facet::new(FacetKind::Count, key) }
+
+    /// Creates a facet for a wide counter.
+    ///
+    /// Wide counters are backed by a `u128` rather than an `i64`, for counters which could
+    /// otherwise overflow over the lifetime of a process, such as a running total of bytes
+    /// processed.  They are updated with the same calls as regular counters, but are unsigned:
+    /// updates that would take them below zero stop at zero.  They are reported in snapshots as
+    /// [`TypedMeasurement::CounterWide`](crate::snapshot::TypedMeasurement::CounterWide), instead
+    /// of as regular counters.
+    pub fn wide_count(key: T) -> Facet<T> { Facet::new(FacetKind::WideCount, key) }
+
+    /// Creates a facet for a windowed counter.
+    ///
+    /// Rather than accumulating for the lifetime of the receiver, a windowed counter reports the sum
+    /// of all updates over the trailing histogram window, as set by
+    /// [`Configuration::histogram`](crate::Configuration::histogram).  This gives a rolling count,
+    /// such as requests per minute, without needing to diff a cumulative counter between snapshots.
+    /// They are updated with the same calls as regular counters, and are reported in snapshots as
+    /// [`TypedMeasurement::CounterWindowed`](crate::snapshot::TypedMeasurement::CounterWindowed).
+    pub fn windowed_count(key: T) -> Facet<T> { Facet::new(FacetKind::WindowedCount, key) }
+
+    /// Creates a facet for a gauge.
+    pub fn gauge(key: T) -> Facet<T> { Facet::new(FacetKind::Gauge, key) }
+
+    /// Creates a facet for a max gauge.
+    ///
+    /// Rather than taking the last value it was set to, a max gauge reports the largest value it
+    /// was set to over the trailing histogram window, as set by
+    /// [`Configuration::histogram`](crate::Configuration::histogram), such as peak memory usage.
+    /// A gauge holds its value until it's set again, so once the peak has rolled out of the window,
+    /// the value it was last set to counts towards the peak too.  They are updated with the same
+    /// calls as regular gauges, and are reported in snapshots as regular gauges.
+    pub fn max_gauge(key: T) -> Facet<T> { Facet::new(FacetKind::MaxGauge, key) }
+
+    /// Creates a facet for a min gauge.
+    ///
+    /// The counterpart to [`max_gauge`](Facet::max_gauge): a min gauge only changes when it is set
+    /// to a value smaller than its current one, and so reports the lowest value it was set to over
+    /// the window.
+    pub fn min_gauge(key: T) -> Facet<T> { Facet::new(FacetKind::MinGauge, key) }
+
+    /// Creates a facet for a timing histogram.
+    pub fn timing_percentile(key: T) -> Facet<T> { Facet::new(FacetKind::TimingPercentile, key) }
+
+    /// Creates a facet for a value histogram.
+    pub fn value_percentile(key: T) -> Facet<T> { Facet::new(FacetKind::ValuePercentile, key) }
+
+    /// Creates a facet for the throughput of a timing histogram.
+    ///
+    /// Timings recorded with a count, such as with
+    /// [`Sink::update_timing_with_count`](crate::Sink::update_timing_with_count), are split into a
+    /// counter and a timing histogram, which loses the relationship between the two, such as the
+    /// number of rows returned by a database query and how long the query took.  A throughput
+    /// tracks both, and reports the total count divided by the total time spent, in units per
+    /// second, as [`TypedMeasurement::Throughput`](crate::snapshot::TypedMeasurement::Throughput)
+    /// under the same name as the timing histogram.
+    ///
+    /// Throughput is tracked for as long as the facet is registered, and timings rejected by
+    /// [`reject_above`](Facet::reject_above) don't count towards it.  The counter and timing
+    /// histogram are still reported as usual.
+    pub fn throughput(key: T) -> Facet<T> { Facet::new(FacetKind::Throughput, key) }
+
+    /// Sets the unit that timings are stored in.
+    ///
+    /// Defaults to nanoseconds.
+    ///
+    /// Only applies to timing histograms.  Timings are converted to this unit before being
+    /// recorded, and the resulting summarized histogram is labeled with the unit.  Changing the unit
+    /// of a histogram that already has recorded values will mix units until they age out of the
+    /// histogram window.
+    pub fn unit(mut self, unit: TimeUnit) -> Self {
+        self.unit = unit;
+        self
+    }
+
+    /// Sets a threshold above which values are rejected.
+    ///
+    /// Defaults to no threshold.
+    ///
+    /// Only applies to histograms.  Values above the threshold are dropped, rather than recorded,
+    /// and counted instead.  The number of rejected values is reported by
+    /// [`SummarizedHistogram::rejected`](crate::snapshot::SummarizedHistogram::rejected).  This
+    /// guards percentiles against the occasional absurd value, such as a timing from a clock glitch,
+    /// which would otherwise skew the maximum for the entire histogram window.
+    ///
+    /// For timing histograms, the threshold is expressed in the unit of the histogram.
+    pub fn reject_above(mut self, threshold: u64) -> Self {
+        self.reject_above = Some(threshold);
+        self
+    }
+
+    /// Backs the histogram with a quantile sketch, rather than an HdrHistogram.
+    ///
+    /// Defaults to an HdrHistogram.
+    ///
+    /// Only applies to histograms.  The sketch is a t-digest, which estimates percentiles to within
+    /// a small relative error no matter how wide the range of recorded values is, and has no
+    /// maximum trackable value, so values above
+    /// [`Configuration::histogram_max_value`](crate::Configuration::histogram_max_value) are kept
+    /// as-is.  This suits distributions that span many orders of magnitude, such as payload sizes.
+    /// HdrHistogram remains the better choice for latencies, where its bounded error is exact.
+    ///
+    /// Values are still recorded as integers, as with every other histogram, and sketch-backed
+    /// histograms are summarized in snapshots just like any other histogram.  Changing whether a
+    /// histogram is backed by a sketch drops any values it already recorded.
+    pub fn sketch(mut self) -> Self {
+        self.sketch = true;
+        self
+    }
+
+    /// Reports the number of values recorded by the histogram as its own counter.
+    ///
+    /// Defaults to not reporting it.
+    ///
+    /// Only applies to histograms.  Snapshots then include a counter named after the histogram,
+    /// with `.total` appended, such as `db.query.total`, holding the total number of values the
+    /// histogram has recorded.  This is named so as not to collide with the `.count` that exporters
+    /// report for the window of each histogram.  Unlike
+    /// [`SummarizedHistogram::count`](crate::snapshot::SummarizedHistogram::count), which only
+    /// covers the histogram window, this only ever goes up, so a rate can be derived from it, like
+    /// the `_count` series of a Prometheus histogram.  Timings recorded with a count, via
+    /// [`Sink::update_timing_with_count`](crate::Sink::update_timing_with_count), still only count
+    /// once each.
+    pub fn expose_count(mut self) -> Self {
+        self.expose_count = true;
+        self
+    }
+
+    /// Sets the percentiles reported for the histogram.
+    ///
+    /// Defaults to the percentiles set by
+    /// [`Configuration::percentiles`](crate::Configuration::percentiles).
+    ///
+    /// Only applies to histograms.  Different metrics call for different percentiles: a cache hit
+    /// histogram might only need p50 and p90, while a latency with an SLO needs p99, p999, and
+    /// beyond.  Snapshots summarize this histogram with exactly the given percentiles, in place of
+    /// the defaults, so no work is spent on percentiles that nobody looks at.
+    pub fn percentiles(mut self, percentiles: &[f64]) -> Self {
+        self.percentiles = Some(percentiles.iter().cloned().map(Percentile::from).collect());
+        self
+    }
+
+    /// Makes the counter saturate, rather than wrap around, when it overflows.
+    ///
+    /// Defaults to wrapping around.
+    ///
+    /// Only applies to regular counters.  A saturating counter that would go above `i64::MAX`, or
+    /// below `i64::MIN`, stops there instead, and is flagged in snapshots by the
+    /// `hotmic.counters.saturated{metric="..."}` gauge, set to 1, where `metric` is the name of the
+    /// counter.  The flag stays set for as long as the counter exists, even if the counter comes
+    /// back down.  Wide counters always saturate, as they're unsigned.
+    ///
+    /// A wrapped counter jumps from one end of its range to the other, which looks like a huge
+    /// change in [counter rates](crate::Configuration::include_rates), while a saturated counter
+    /// stops changing, and so has a rate of zero until it comes back down.
+    pub fn saturating(mut self) -> Self {
+        self.saturating = true;
+        self
+    }
+
+    /// Sets the maximum value that the histogram can track.
+    ///
+    /// Defaults to [`Configuration::histogram_max_value`](crate::Configuration::histogram_max_value).
+    ///
+    /// Only applies to histograms backed by an HdrHistogram.  Histogram storage is allocated up
+    /// front, with enough room for any value up to the maximum, and is never resized while the
+    /// receiver is recording to it, so recording never allocates on the receiver thread.  Values
+    /// above the maximum are clamped to it instead, and counted, as reported by
+    /// [`SummarizedHistogram::clamped`](crate::snapshot::SummarizedHistogram::clamped).  A
+    /// histogram that regularly clamps values needs a higher maximum.
+    ///
+    /// For timing histograms, the maximum is expressed in the unit of the histogram.  Values less
+    /// than 2 are treated as 2, which is the smallest maximum possible.  Changing the maximum of a
+    /// histogram drops any values it already recorded.
+    pub fn max_value(mut self, max_value: u64) -> Self {
+        self.max_value = Some(max_value.max(2));
+        self
+    }
+
+    /// Sets the name the metric is reported under, in place of its key.
+    ///
+    /// Defaults to the key itself, as displayed by its `Display` implementation.
+    ///
+    /// This decouples the keys used to send metrics from the names they're exported with, such as
+    /// an enum variant whose `Display` is terse, like `Ok`, being exported as `requests_ok_total`.
+    /// The output name replaces only the key: the scope and labels of the metric are still added
+    /// to it.  It applies to every type of metric with the key, at the scope of the sink the facet
+    /// was added through, for as long as the facet is registered.
+    pub fn output_name(mut self, name: &str) -> Self {
+        self.output_name = Some(name.to_owned());
+        self
+    }
+
+    /// Allows a label, with any value, on the metric.
+    ///
+    /// Defaults to allowing all labels.
+    ///
+    /// Once any label is allowed, via this or [`allow_label_values`](Facet::allow_label_values),
+    /// only the allowed labels are: samples for the metric sent by a sink whose labels include any
+    /// other label are rejected.  Rejected samples are dropped, and counted in the
+    /// `hotmic.labels.rejected{metric="..."}` counter, where `metric` is the name of the metric
+    /// without any labels.  Samples sent without labels are always allowed.  This bounds the
+    /// cardinality of a metric whose labels come from user input.
+    ///
+    /// Allowed labels apply to the metric at the scope of the sink the facet was added through,
+    /// whatever labels that sink has, and to every type of sample sent for the metric.
+    pub fn allow_label(self, key: &str) -> Self { self.allow(key, None) }
+
+    /// Allows a label on the metric, but only with one of the given values.
+    ///
+    /// Samples whose value for the label is not one of the given values are rejected, as with
+    /// labels that aren't allowed at all.  See [`allow_label`](Facet::allow_label) for details.
+    pub fn allow_label_values(self, key: &str, values: &[&str]) -> Self {
+        let values = values.iter().map(|value| (*value).to_owned()).collect();
+        self.allow(key, Some(values))
+    }
+
+    fn allow(mut self, key: &str, values: Option<Vec<String>>) -> Self {
+        let allowed = self.allowed_labels.get_or_insert_with(Vec::new);
+        match allowed.iter_mut().find(|(k, _)| k == key) {
+            Some(existing) => existing.1 = values,
+            None => allowed.push((key.to_owned(), values)),
+        }
+        self
+    }
+
+    /// Gets how the histogram for the metric should be stored, if not in the default way.
+    pub(crate) fn storage(&self) -> Option<Storage> {
+        if self.sketch {
+            Some(Storage::Sketch)
+        } else {
+            self.max_value.map(Storage::Bounded)
+        }
+    }
+
+    /// Whether or not the given labels are allowed on the metric.
+    pub(crate) fn allows_labels(&self, labels: &[(String, String)]) -> bool {
+        let allowed = match &self.allowed_labels {
+            Some(allowed) => allowed,
+            None => return true,
+        };
+
+        labels.iter().all(|(key, value)| {
+            allowed
+                .iter()
+                .any(|(k, values)| k == key && values.as_ref().map(|values| values.contains(value)).unwrap_or(true))
+        })
+    }
+
+    /// Gets the metric key of this facet.
+    pub fn key(&self) -> &T { &self.key }
+
+    /// Gets the type of metric this facet refers to.
+    pub fn kind(&self) -> FacetKind { self.kind }
+}
+
+impl<T: Clone + Eq + Hash + Display> Facet<T> {
+    pub(crate) fn into_scoped(self, scope_id: u64) -> Facet<ScopedKey<T>> {
+        Facet {
+            kind: self.kind,
+            key: ScopedKey(scope_id, self.key),
+            unit: self.unit,
+            reject_above: self.reject_above,
+            allowed_labels: self.allowed_labels,
+            sketch: self.sketch,
+            max_value: self.max_value,
+            saturating: self.saturating,
+            output_name: self.output_name,
+            expose_count: self.expose_count,
+            percentiles: self.percentiles,
+        }
+    }
+}
+
+impl<T: Clone + Eq + Hash + Display> Facet<ScopedKey<T>> {
+    pub(crate) fn into_unscoped(self) -> Facet<T> {
+        Facet {
+            kind: self.kind,
+            key: self.key.1,
+            unit: self.unit,
+            reject_above: self.reject_above,
+            allowed_labels: self.allowed_labels,
+            sketch: self.sketch,
+            max_value: self.max_value,
+            saturating: self.saturating,
+            output_name: self.output_name,
+            expose_count: self.expose_count,
+            percentiles: self.percentiles,
+        }
+    }
+}
+
+/// Registry of facets, indexed by key and type.
+pub(crate) struct Facets<T, H = FnvBuildHasher> {
+    data: HashMap<T, Vec<Facet<T>>, H>,
+
+    // The number of facets with an output name.
+    output_names: usize,
+
+    // The number of facets which restrict labels.
+    label_rules: usize,
+}
+
+impl<T: Clone + Eq + Hash, H: BuildHasher + Default> Facets<T, H> {
+    pub fn new() -> Facets<T, H> {
+        Facets {
+            data: HashMap::default(),
+            output_names: 0,
+            label_rules: 0,
+        }
+    }
+
+    pub fn add(&mut self, facet: Facet<T>) {
+        if facet.allowed_labels.is_some() {
+            self.label_rules += 1;
+        }
+        if facet.output_name.is_some() {
+            self.output_names += 1;
+        }
+
+        let facets = self.data.entry(facet.key.clone()).or_insert_with(Vec::new);
+        match facets.iter_mut().find(|f| f.kind == facet.kind) {
+            Some(existing) => {
+                if existing.allowed_labels.is_some() {
+                    self.label_rules -= 1;
+                }
+                if existing.output_name.is_some() {
+                    self.output_names -= 1;
+                }
+                *existing = facet
+            },
+            None => facets.push(facet),
+        }
+    }
+
+    pub fn remove(&mut self, kind: FacetKind, key: &T) -> Option<Facet<T>> {
+        let (removed, now_empty) = match self.data.get_mut(key) {
+            Some(facets) => {
+                let removed = facets
+                    .iter()
+                    .position(|f| f.kind == kind)
+                    .map(|idx| facets.swap_remove(idx));
+                (removed, facets.is_empty())
+            },
+            None => return None,
+        };
+
+        if now_empty {
+            let _ = self.data.remove(key);
+        }
+
+        if removed.as_ref().map(|f| f.allowed_labels.is_some()).unwrap_or(false) {
+            self.label_rules -= 1;
+        }
+        if removed.as_ref().map(|f| f.output_name.is_some()).unwrap_or(false) {
+            self.output_names -= 1;
+        }
+
+        removed
+    }
+
+    /// Whether or not there are no registered facets at all.
+    pub fn is_empty(&self) -> bool { self.data.is_empty() }
+
+    /// Whether or not any registered facet restricts labels.
+    pub fn has_label_rules(&self) -> bool { self.label_rules > 0 }
+
+    /// Whether or not any registered facet has an output name.
+    pub fn has_output_names(&self) -> bool { self.output_names > 0 }
+
+    /// Gets the output name of the metric with the given key, from any of its facets.
+    pub fn output_name(&self, key: &T) -> Option<&str> {
+        self.get_all(key)
+            .iter()
+            .find_map(|f| f.output_name.as_ref().map(String::as_str))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Facet<T>> { self.data.values().flatten() }
+
+    pub fn get_all(&self, key: &T) -> &[Facet<T>] { self.data.get(key).map(Vec::as_slice).unwrap_or(&[]) }
+
+    pub fn get(&self, kind: FacetKind, key: &T) -> Option<&Facet<T>> {
+        self.data
+            .get(key)
+            .and_then(|facets| facets.iter().find(|f| f.kind == kind))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Facet, FacetKind, Facets, TimeUnit, Timing};
+    use std::time::Duration;
+
+    #[test]
+    fn test_facets_add_get_remove() {
+        let mut facets: Facets<_> = Facets::new();
+        facets.add(Facet::count("foo"));
+        facets.add(Facet::timing_percentile("foo").unit(TimeUnit::Microseconds));
+
+        assert!(facets.get(FacetKind::Count, &"foo").is_some());
+        assert!(facets.get(FacetKind::Gauge, &"foo").is_none());
+        assert_eq!(
+            facets.get(FacetKind::TimingPercentile, &"foo").map(|f| f.unit),
+            Some(TimeUnit::Microseconds)
+        );
+
+        // Registering the same facet again replaces the existing one.
+        facets.add(Facet::timing_percentile("foo").unit(TimeUnit::Milliseconds));
+        assert_eq!(
+            facets.get(FacetKind::TimingPercentile, &"foo").map(|f| f.unit),
+            Some(TimeUnit::Milliseconds)
+        );
+
+        assert!(facets.remove(FacetKind::Count, &"foo").is_some());
+        assert!(facets.remove(FacetKind::Count, &"foo").is_none());
+        assert!(facets.get(FacetKind::Count, &"foo").is_none());
+        assert!(facets.get(FacetKind::TimingPercentile, &"foo").is_some());
+    }
+
+    #[test]
+    fn test_facet_allowed_labels() {
+        let labels = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| ((*k).to_owned(), (*v).to_owned()))
+                .collect::<Vec<_>>()
+        };
+
+        let facet = Facet::count("requests");
+        assert!(facet.allows_labels(&labels(&[("anything", "goes")])));
+
+        let facet = facet.allow_label("service").allow_label_values("env", &["prod", "dev"]);
+        assert!(facet.allows_labels(&[]));
+        assert!(facet.allows_labels(&labels(&[("service", "api"), ("env", "prod")])));
+        assert!(!facet.allows_labels(&labels(&[("env", "staging")])));
+        assert!(!facet.allows_labels(&labels(&[("user_id", "42")])));
+
+        let mut facets: Facets<_> = Facets::new();
+        assert!(!facets.has_label_rules());
+        facets.add(facet);
+        assert!(facets.has_label_rules());
+        facets.add(Facet::count("requests"));
+        assert!(!facets.has_label_rules());
+        facets.add(Facet::gauge("requests").allow_label("env"));
+        assert!(facets.has_label_rules());
+        assert!(facets.remove(FacetKind::Gauge, &"requests").is_some());
+        assert!(!facets.has_label_rules());
+    }
+
+    #[test]
+    fn test_time_unit_conversion() {
+        assert_eq!(TimeUnit::Nanoseconds.from_nanos(1_500_000), 1_500_000);
+        assert_eq!(TimeUnit::Microseconds.from_nanos(1_500_000), 1_500);
+        assert_eq!(TimeUnit::Milliseconds.from_nanos(1_500_000), 1);
+
+        assert_eq!(TimeUnit::Nanoseconds.to_duration(1_500), Duration::from_nanos(1_500));
+        assert_eq!(TimeUnit::Microseconds.to_duration(1_500), Duration::from_micros(1_500));
+        assert_eq!(TimeUnit::Milliseconds.to_duration(1_500), Duration::from_millis(1_500));
+    }
+
+    #[test]
+    fn test_timing_display() {
+        assert_eq!(Timing::from_nanos(0).to_string(), "0ns");
+        assert_eq!(Timing::from_nanos(999).to_string(), "999ns");
+        assert_eq!(Timing::from_nanos(1_000).to_string(), "1.00us");
+        assert_eq!(Timing::from_nanos(1_500_000).to_string(), "1.50ms");
+        assert_eq!(Timing::from_nanos(2_250_000_000).to_string(), "2.25s");
+        assert_eq!(format!("{:.0}", Timing::from_nanos(12_345)), "12us");
+        assert_eq!(format!("{:.3}", Timing::from_nanos(90_000_000_000)), "90.000s");
+
+        assert_eq!(Timing::from_nanos(1_500_000_000).as_secs_f64(), 1.5);
+        assert_eq!(Duration::from(Timing::from_nanos(1_500)), Duration::from_nanos(1_500));
+        assert_eq!(TimeUnit::Microseconds.to_nanos(1_500), 1_500_000);
+        assert_eq!(TimeUnit::Milliseconds.to_nanos(u64::max_value()), u64::max_value());
+    }
+}
@@ -1,15 +1,45 @@
+use crate::helper::{duration_as_nanos, intervals_between, periods_between, wall_clock_period};
 use fnv::FnvBuildHasher;
-use hashbrown::HashMap;
-use std::hash::Hash;
+use hashbrown::{hash_map::Iter, HashMap};
+use std::{
+    hash::{BuildHasher, Hash},
+    time::{Duration, Instant, SystemTime},
+};
 
-pub(crate) struct Gauge<T> {
-    data: HashMap<T, u64, FnvBuildHasher>,
+pub(crate) struct Gauge<T, H = FnvBuildHasher> {
+    data: HashMap<T, u64, H>,
+
+    // Max and min gauges, which track their peak or trough over the window, and write it to `data`.
+    extremes: HashMap<T, WindowedExtreme, H>,
+    window: Duration,
+    granularity: Duration,
+    aligned: bool,
+}
+
+/// The largest or smallest value a gauge has been set to over a rolling window.
+///
+/// This uses the same bucketing as [`WindowedCount`](super::counter::WindowedCount): (window /
+/// granularity) + 1 buckets, where every interval that passes (granularity), the oldest bucket
+/// becomes the bucket that new values are compared against.  A gauge holds its value until it's set
+/// again, so each bucket starts out holding the latest value, rather than being cleared.
+struct WindowedExtreme {
+    max: bool,
+    latest: u64,
+    buckets: Vec<u64>,
+    bucket_index: usize,
+    last_upkeep: Instant,
+    last_period: u64,
+    granularity: Duration,
 }
 
-impl<T: Clone + Eq + Hash> Gauge<T> {
-    pub fn new() -> Gauge<T> {
+impl<T: Clone + Eq + Hash, H: BuildHasher + Default> Gauge<T, H> {
+    pub fn new(window: Duration, granularity: Duration, aligned: bool) -> Gauge<T, H> {
         Gauge {
-            data: HashMap::<T, u64, FnvBuildHasher>::default(),
+            data: HashMap::default(),
+            extremes: HashMap::default(),
+            window,
+            granularity,
+            aligned,
         }
     }
 
@@ -18,16 +48,116 @@ impl<T: Clone + Eq + Hash> Gauge<T> {
         *ivalue = value;
     }
 
+    /// Updates the gauge only if `value` is greater than the largest value it was set to over the
+    /// window.
+    pub fn update_max(&mut self, key: T, value: u64) { self.update_extreme(key, value, true) }
+
+    /// Updates the gauge only if `value` is less than the smallest value it was set to over the
+    /// window.
+    pub fn update_min(&mut self, key: T, value: u64) { self.update_extreme(key, value, false) }
+
+    fn update_extreme(&mut self, key: T, value: u64, max: bool) {
+        let (window, granularity) = (self.window, self.granularity);
+        let extreme = self
+            .extremes
+            .entry(key.clone())
+            .or_insert_with(|| WindowedExtreme::new(window, granularity, max, value));
+        extreme.update(value);
+        let _ = self.data.insert(key, extreme.value());
+    }
+
+    /// Rolls over the windows of max and min gauges, so that values older than the window no
+    /// longer count towards their peak or trough.
+    pub fn upkeep(&mut self, at: Instant, wall: SystemTime) {
+        for (key, extreme) in self.extremes.iter_mut() {
+            if self.aligned {
+                extreme.upkeep_aligned(wall);
+            } else {
+                extreme.upkeep(at);
+            }
+
+            if let Some(value) = self.data.get_mut(key) {
+                *value = extreme.value();
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &T) -> bool {
+        let _ = self.extremes.remove(key);
+        self.data.remove(key).is_some()
+    }
+
+    pub fn iter(&self) -> Iter<'_, T, u64> { self.data.iter() }
+
     pub fn values(&self) -> Vec<(T, u64)> { self.data.iter().map(|(k, v)| (k.clone(), *v)).collect() }
 }
 
+impl WindowedExtreme {
+    fn new(window: Duration, granularity: Duration, max: bool, value: u64) -> WindowedExtreme {
+        let num_buckets = ((duration_as_nanos(window) / duration_as_nanos(granularity)) as usize) + 1;
+
+        WindowedExtreme {
+            max,
+            latest: value,
+            buckets: vec![value; num_buckets],
+            bucket_index: 0,
+            last_upkeep: Instant::now(),
+            last_period: wall_clock_period(SystemTime::now(), granularity),
+            granularity,
+        }
+    }
+
+    fn update(&mut self, value: u64) {
+        self.latest = value;
+        let bucket = &mut self.buckets[self.bucket_index];
+        *bucket = if self.max {
+            (*bucket).max(value)
+        } else {
+            (*bucket).min(value)
+        };
+    }
+
+    fn value(&self) -> u64 {
+        let values = self.buckets.iter().cloned();
+        let value = if self.max { values.max() } else { values.min() };
+        value.unwrap_or(self.latest)
+    }
+
+    fn upkeep(&mut self, at: Instant) {
+        let intervals = intervals_between(self.last_upkeep, at, self.granularity);
+        if intervals > 0 {
+            self.rotate(intervals);
+            self.last_upkeep += Duration::from_nanos(intervals * duration_as_nanos(self.granularity));
+        }
+    }
+
+    fn upkeep_aligned(&mut self, wall: SystemTime) {
+        let period = wall_clock_period(wall, self.granularity);
+        if period != self.last_period {
+            self.rotate(periods_between(self.last_period, period));
+            self.last_period = period;
+        }
+    }
+
+    fn rotate(&mut self, intervals: u64) {
+        for _ in 0..intervals.min(self.buckets.len() as u64) {
+            self.bucket_index += 1;
+            self.bucket_index %= self.buckets.len();
+            self.buckets[self.bucket_index] = self.latest;
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::Gauge;
+    use std::time::{Duration, Instant, SystemTime};
+
+    fn gauge() -> Gauge<&'static str> { Gauge::new(Duration::new(5, 0), Duration::new(1, 0), false) }
 
     #[test]
     fn test_gauge_simple_update() {
-        let mut gauge = Gauge::new();
+        let mut gauge = gauge();
 
         let key = "foo";
         gauge.update(key, 42);
@@ -36,4 +166,45 @@ mod tests {
         assert_eq!(values.len(), 1);
         assert_eq!(values[0].1, 42);
     }
+
+    #[test]
+    fn test_gauge_max_min_update() {
+        let mut gauge = gauge();
+
+        for value in &[7, 42, 3, 12] {
+            gauge.update_max("peak", *value);
+            gauge.update_min("trough", *value);
+        }
+
+        let mut values = gauge.values();
+        values.sort();
+        assert_eq!(values, vec![("peak", 42), ("trough", 3)]);
+    }
+
+    #[test]
+    fn test_gauge_max_min_window() {
+        let mut gauge = gauge();
+        gauge.update_max("peak", 42);
+        gauge.update_max("peak", 7);
+        gauge.update_min("trough", 3);
+        gauge.update_min("trough", 12);
+        let start = Instant::now();
+
+        // The peak and trough are kept until they roll out of the window, after which the gauges
+        // report the value they were last set to.
+        gauge.upkeep(start + Duration::new(5, 0), SystemTime::now());
+        let mut values = gauge.values();
+        values.sort();
+        assert_eq!(values, vec![("peak", 42), ("trough", 3)]);
+
+        gauge.upkeep(start + Duration::new(6, 0), SystemTime::now());
+        let mut values = gauge.values();
+        values.sort();
+        assert_eq!(values, vec![("peak", 7), ("trough", 12)]);
+
+        // Values set since then count towards the new window as usual.
+        gauge.update_max("peak", 9);
+        gauge.update_max("peak", 8);
+        assert_eq!(gauge.iter().find(|(k, _)| **k == "peak").map(|(_, v)| *v), Some(9));
+    }
 }
@@ -1,24 +1,64 @@
-use crate::helper::duration_as_nanos;
+use super::{sketch::QuantileSketch, snapshot::Exemplar};
+use crate::helper::{duration_as_nanos, duration_between, intervals_between, periods_between, wall_clock_period};
 use fnv::FnvBuildHasher;
-use hashbrown::HashMap;
+use hashbrown::{hash_map::Iter, HashMap};
 use hdrhistogram::Histogram as HdrHistogram;
 use std::{
-    hash::Hash,
-    time::{Duration, Instant},
+    hash::{BuildHasher, Hash},
+    mem,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-pub(crate) struct Histogram<T> {
+/// How a key's histogram is stored, when a facet asks for something other than the default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Storage {
+    /// Backed by [`QuantileSketch`]es.
+    Sketch,
+
+    /// Backed by HdrHistograms with the given maximum trackable value.
+    Bounded(u64),
+}
+
+pub(crate) struct Histogram<T, H = FnvBuildHasher> {
     window: Duration,
     granularity: Duration,
-    data: HashMap<T, WindowedHistogram, FnvBuildHasher>,
+    max_value: u64,
+    aligned: bool,
+    data: HashMap<T, WindowedHistogram, H>,
+    storage: HashMap<T, Storage, H>,
 }
 
-impl<T: Clone + Eq + Hash> Histogram<T> {
-    pub fn new(window: Duration, granularity: Duration) -> Histogram<T> {
+impl<T: Clone + Eq + Hash, H: BuildHasher + Default> Histogram<T, H> {
+    pub fn new(window: Duration, granularity: Duration, max_value: u64, aligned: bool) -> Histogram<T, H> {
         Histogram {
             window,
             granularity,
-            data: HashMap::<T, WindowedHistogram, FnvBuildHasher>::default(),
+            max_value,
+            aligned,
+            data: HashMap::default(),
+            storage: HashMap::default(),
+        }
+    }
+
+    /// Sets how the given key is stored, or resets it to the default storage if `None`.
+    ///
+    /// If this changes how the key is stored, any values already recorded for it are dropped.
+    pub fn set_storage(&mut self, key: T, storage: Option<Storage>) {
+        let previous = match storage {
+            Some(storage) => self.storage.insert(key.clone(), storage),
+            None => self.storage.remove(&key),
+        };
+
+        if previous != storage {
+            let _ = self.data.remove(&key);
+        }
+    }
+
+    fn new_windowed(&self, key: &T) -> WindowedHistogram {
+        match self.storage.get(key) {
+            Some(Storage::Sketch) => WindowedHistogram::new_sketch(self.window, self.granularity),
+            Some(Storage::Bounded(max_value)) => WindowedHistogram::new(self.window, self.granularity, *max_value),
+            None => WindowedHistogram::new(self.window, self.granularity, self.max_value),
         }
     }
 
@@ -26,105 +66,582 @@ impl<T: Clone + Eq + Hash> Histogram<T> {
         if let Some(wh) = self.data.get_mut(&key) {
             wh.update(value);
         } else {
-            let mut wh = WindowedHistogram::new(self.window, self.granularity);
+            let mut wh = self.new_windowed(&key);
             wh.update(value);
             let _ = self.data.insert(key, wh);
         }
     }
 
-    pub fn upkeep(&mut self, at: Instant) {
+    /// Updates the histogram for the given key with a value that was measured at `when`, rather
+    /// than now, so that it lands in the bucket covering that time.
+    ///
+    /// `now` and `wall` are the current time, used to place `when` against the buckets when they
+    /// are aligned to the wall clock.  Returns `false` if `when` is too old to fall in the window,
+    /// in which case the value is dropped.
+    pub fn update_at(&mut self, key: T, value: u64, when: Instant, now: Instant, wall: SystemTime) -> bool {
+        let aligned = self.aligned;
+        let wh = match self.data.get_mut(&key) {
+            Some(wh) => wh,
+            None => {
+                let wh = self.new_windowed(&key);
+                self.data.entry(key).or_insert(wh)
+            },
+        };
+
+        if aligned {
+            // Values recorded before the Unix epoch can't be placed in a wall-clock period.
+            let elapsed = duration_between(when, now);
+            match wall.duration_since(UNIX_EPOCH) {
+                Ok(since_epoch) if since_epoch >= elapsed => wh.update_at_aligned(value, wall - elapsed),
+                _ => false,
+            }
+        } else {
+            wh.update_at(value, when)
+        }
+    }
+
+    /// Updates the histogram for the given key, attaching an exemplar to the value.
+    pub fn update_with_exemplar(&mut self, key: T, value: u64, exemplar: String) {
+        let wh = match self.data.get_mut(&key) {
+            Some(wh) => wh,
+            None => {
+                let wh = self.new_windowed(&key);
+                self.data.entry(key).or_insert(wh)
+            },
+        };
+        wh.update_with_exemplar(value, exemplar);
+    }
+
+    pub fn reject(&mut self, key: T) {
+        if let Some(wh) = self.data.get_mut(&key) {
+            wh.reject();
+        } else {
+            let mut wh = self.new_windowed(&key);
+            wh.reject();
+            let _ = self.data.insert(key, wh);
+        }
+    }
+
+    pub fn clear(&mut self, key: &T) -> bool {
+        match self.data.get_mut(key) {
+            Some(wh) => {
+                wh.clear();
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn remove(&mut self, key: &T) -> bool {
+        let _ = self.storage.remove(key);
+        self.data.remove(key).is_some()
+    }
+
+    pub fn keys(&self) -> Vec<T> { self.data.keys().cloned().collect() }
+
+    pub fn upkeep(&mut self, at: Instant, wall: SystemTime) {
         for (_, histogram) in self.data.iter_mut() {
-            histogram.upkeep(at);
+            if self.aligned {
+                histogram.upkeep_aligned(at, wall);
+            } else {
+                histogram.upkeep(at);
+            }
         }
     }
 
+    pub fn iter(&self) -> Iter<'_, T, WindowedHistogram> { self.data.iter() }
+
     pub fn values(&self) -> Vec<(T, HistogramSnapshot)> {
         self.data.iter().map(|(k, v)| (k.clone(), v.snapshot())).collect()
     }
 }
 
+/// The storage for each bucket of a windowed histogram.
+enum Buckets {
+    Hdr(Vec<HdrHistogram<u64>>),
+    Sketch(Vec<QuantileSketch>),
+}
+
 pub(crate) struct WindowedHistogram {
-    buckets: Vec<HdrHistogram<u64>>,
+    buckets: Buckets,
     num_buckets: usize,
     bucket_index: usize,
     sum: u64,
+    total: u64,
+    rejected: u64,
+    clamped: u64,
     last_upkeep: Instant,
+    last_period: u64,
     granularity: Duration,
+
+    // When each bucket started collecting values.
+    bucket_starts: Vec<Instant>,
+
+    // The most recent exemplar for values of each bit length, along with when it was recorded.
+    // This is only allocated once an exemplar is recorded.
+    exemplars: Vec<Option<(Exemplar, Instant)>>,
 }
 
 impl WindowedHistogram {
-    pub fn new(window: Duration, granularity: Duration) -> WindowedHistogram {
+    pub fn new(window: Duration, granularity: Duration, max_value: u64) -> WindowedHistogram {
         let num_buckets = ((duration_as_nanos(window) / duration_as_nanos(granularity)) as usize) + 1;
         let mut buckets = Vec::with_capacity(num_buckets);
 
         for _ in 0..num_buckets {
-            let histogram = HdrHistogram::new_with_bounds(1, u64::max_value(), 3).unwrap();
+            let histogram = HdrHistogram::new_with_bounds(1, max_value, 3).unwrap();
             buckets.push(histogram);
         }
 
+        WindowedHistogram::with_buckets(Buckets::Hdr(buckets), num_buckets, granularity)
+    }
+
+    /// Creates a windowed histogram whose buckets are [`QuantileSketch`]es.
+    pub fn new_sketch(window: Duration, granularity: Duration) -> WindowedHistogram {
+        let num_buckets = ((duration_as_nanos(window) / duration_as_nanos(granularity)) as usize) + 1;
+        let buckets = (0..num_buckets).map(|_| QuantileSketch::new()).collect();
+
+        WindowedHistogram::with_buckets(Buckets::Sketch(buckets), num_buckets, granularity)
+    }
+
+    fn with_buckets(buckets: Buckets, num_buckets: usize, granularity: Duration) -> WindowedHistogram {
+        let now = Instant::now();
         WindowedHistogram {
             buckets,
             num_buckets,
             bucket_index: 0,
             sum: 0,
-            last_upkeep: Instant::now(),
+            total: 0,
+            rejected: 0,
+            clamped: 0,
+            last_upkeep: now,
+            last_period: wall_clock_period(SystemTime::now(), granularity),
             granularity,
+            bucket_starts: vec![now; num_buckets],
+            exemplars: Vec::new(),
         }
     }
 
+    /// Rolls over one bucket for every interval of the granularity that has passed since the last
+    /// rollover.
+    ///
+    /// When upkeep runs late, several intervals may have passed, and rolling over just one bucket
+    /// would leave the window lagging behind: values would pile into one bucket, and then drop out
+    /// of the window all at once.  Instead, every bucket that should have rolled over does, so the
+    /// window stays in step with the time that has actually passed.
     pub fn upkeep(&mut self, at: Instant) {
-        if at >= self.last_upkeep + self.granularity {
-            self.bucket_index += 1;
-            self.bucket_index %= self.num_buckets;
-            self.buckets[self.bucket_index].clear();
-            self.last_upkeep = at;
+        let intervals = intervals_between(self.last_upkeep, at, self.granularity);
+        if intervals > 0 {
+            // Buckets keep to the schedule set by the first one, rather than drifting by however
+            // late each upkeep runs.
+            let latest = self.last_upkeep + Duration::from_nanos(intervals * duration_as_nanos(self.granularity));
+            self.rotate(intervals, latest);
+            self.last_upkeep = latest;
+        }
+    }
+
+    /// Rolls over to the next bucket whenever `wall` crosses into a new wall-clock period, so that
+    /// buckets start and end on wall-clock boundaries rather than relative to when they were created.
+    ///
+    /// As with [`upkeep`](WindowedHistogram::upkeep), one bucket is rolled over for every period
+    /// that has passed.  The buckets rolled over to are marked as starting at `at`, which should be
+    /// the monotonic time corresponding to `wall`.
+    pub fn upkeep_aligned(&mut self, at: Instant, wall: SystemTime) {
+        let period = wall_clock_period(wall, self.granularity);
+        if period != self.last_period {
+            self.rotate(periods_between(self.last_period, period), at);
+            self.last_period = period;
+        }
+    }
+
+    /// Rolls over the given number of buckets, the last of which started at `latest`, with each one
+    /// before it starting one granularity earlier.  Rolling over the whole window, or more, clears
+    /// every bucket.
+    fn rotate(&mut self, intervals: u64, latest: Instant) {
+        // No bucket rolled over to can have started before the current one, which also keeps the
+        // subtraction from reaching back past the start of the monotonic clock.
+        let max_offset = duration_between(self.bucket_starts[self.bucket_index], latest);
+        let granularity = duration_as_nanos(self.granularity);
+        for i in (0..intervals.min(self.num_buckets as u64)).rev() {
+            let offset = Duration::from_nanos(granularity * i).min(max_offset);
+            self.advance(latest - offset);
+        }
+    }
+
+    fn advance(&mut self, at: Instant) {
+        self.bucket_index += 1;
+        self.bucket_index %= self.num_buckets;
+        self.bucket_starts[self.bucket_index] = at;
+        match &mut self.buckets {
+            Buckets::Hdr(buckets) => buckets[self.bucket_index].clear(),
+            Buckets::Sketch(buckets) => buckets[self.bucket_index].clear(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        let now = Instant::now();
+        self.bucket_starts.iter_mut().for_each(|start| *start = now);
+        self.exemplars.clear();
+        match &mut self.buckets {
+            Buckets::Hdr(buckets) => buckets.iter_mut().for_each(HdrHistogram::clear),
+            Buckets::Sketch(buckets) => buckets.iter_mut().for_each(QuantileSketch::clear),
+        }
+        self.sum = 0;
+        self.total = 0;
+        self.rejected = 0;
+        self.clamped = 0;
+    }
+
+    pub fn reject(&mut self) { self.rejected = self.rejected.wrapping_add(1); }
+
+    pub fn update(&mut self, value: u64) { self.record(self.bucket_index, value) }
+
+    /// Records a value, keeping the given exemplar as the most recent one for values of about the
+    /// same magnitude.
+    pub fn update_with_exemplar(&mut self, value: u64, label: String) {
+        self.update(value);
+
+        if self.exemplars.is_empty() {
+            self.exemplars.resize(65, None);
+        }
+        let exemplar = Exemplar {
+            label,
+            value,
+            timestamp: SystemTime::now(),
+        };
+        self.exemplars[exemplar_slot(value)] = Some((exemplar, Instant::now()));
+    }
+
+    /// Records a value into the bucket covering `when`, returning `false`, and dropping the value,
+    /// if `when` is too old to fall in the window.
+    ///
+    /// Values from the future land in the current bucket.
+    pub fn update_at(&mut self, value: u64, when: Instant) -> bool {
+        // The current bucket covers everything since the last upkeep, and each bucket before it
+        // covers one granularity's worth of time before that.
+        let buckets_ago = if when >= self.last_upkeep {
+            0
+        } else {
+            duration_as_nanos(self.last_upkeep - when) / duration_as_nanos(self.granularity) + 1
+        };
+        self.update_ago(value, buckets_ago)
+    }
+
+    /// Records a value into the bucket covering the wall-clock time `wall`, returning `false`, and
+    /// dropping the value, if `wall` is too old to fall in the window.
+    ///
+    /// This is the counterpart of [`update_at`](WindowedHistogram::update_at) for buckets aligned
+    /// with [`upkeep_aligned`](WindowedHistogram::upkeep_aligned).
+    pub fn update_at_aligned(&mut self, value: u64, wall: SystemTime) -> bool {
+        let period = wall_clock_period(wall, self.granularity);
+        self.update_ago(value, self.last_period.saturating_sub(period))
+    }
+
+    fn update_ago(&mut self, value: u64, buckets_ago: u64) -> bool {
+        // The oldest bucket is the next one to be cleared, and so isn't written into, as anything
+        // recorded there would be gone at the next upkeep.
+        if buckets_ago >= self.num_buckets as u64 - 1 {
+            return false;
         }
+
+        let index = (self.bucket_index + self.num_buckets - buckets_ago as usize) % self.num_buckets;
+        self.record(index, value);
+        true
     }
 
-    pub fn update(&mut self, value: u64) {
-        self.buckets[self.bucket_index].saturating_record(value);
+    fn record(&mut self, index: usize, value: u64) {
+        match &mut self.buckets {
+            Buckets::Hdr(buckets) => {
+                let histogram = &mut buckets[index];
+                if value > histogram.high() {
+                    self.clamped = self.clamped.wrapping_add(1);
+                }
+                histogram.saturating_record(value)
+            },
+            Buckets::Sketch(buckets) => buckets[index].record(value),
+        }
         self.sum = self.sum.wrapping_add(value);
+        self.total = self.total.wrapping_add(1);
     }
 
-    pub fn snapshot(&self) -> HistogramSnapshot {
-        let mut base = HdrHistogram::new_from(&self.buckets[self.bucket_index]);
-        for histogram in &self.buckets {
-            base.add(histogram).unwrap()
+    /// Number of values recorded across all buckets in this histogram.
+    pub fn count(&self) -> u64 {
+        match &self.buckets {
+            Buckets::Hdr(buckets) => buckets.iter().map(|h| h.len()).sum(),
+            Buckets::Sketch(buckets) => buckets.iter().map(|s| s.count()).sum(),
         }
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let mut snapshot = match &self.buckets {
+            Buckets::Hdr(buckets) => {
+                let mut base = HdrHistogram::new_from(&buckets[self.bucket_index]);
+                for histogram in buckets {
+                    // Buckets all share the same bounds, so this can't fail today, but a bucket
+                    // that can't be added is better left out of the snapshot than allowed to take
+                    // down the receiver.
+                    if let Err(e) = base.add(histogram) {
+                        eprintln!("error adding histogram bucket to snapshot: {:?}", e);
+                    }
+                }
+                HistogramSnapshot::new(base, self.sum)
+            },
+            Buckets::Sketch(buckets) => {
+                let mut base = QuantileSketch::new();
+                for sketch in buckets {
+                    base.add(sketch);
+                }
+                base.compress();
+                HistogramSnapshot::from_sketch(base, self.sum)
+            },
+        };
+        snapshot.memory_usage = self.memory_usage();
+        snapshot.covered = self.covered(Instant::now());
+        snapshot.exemplars = self.exemplars();
+        snapshot.total = self.total;
+        snapshot.rejected = self.rejected;
+        snapshot.clamped = self.clamped;
+        snapshot
+    }
+
+    /// How much time the values in this histogram cover, as of `now`.
+    ///
+    /// This is the time since the oldest bucket started collecting values.  Buckets only roll over
+    /// during upkeep, so this can differ from the configured window: it's shorter for a histogram
+    /// that hasn't existed for a full window yet, and can be longer when upkeep runs late.
+    pub fn covered(&self, now: Instant) -> Duration {
+        let oldest = (self.bucket_index + 1) % self.num_buckets;
+        duration_between(self.bucket_starts[oldest], now)
+    }
 
-        HistogramSnapshot::new(base, self.sum)
+    /// Gets the exemplars for values still in the window, in ascending order of value.
+    fn exemplars(&self) -> Vec<Exemplar> {
+        let oldest = self.bucket_starts[(self.bucket_index + 1) % self.num_buckets];
+        self.exemplars
+            .iter()
+            .filter_map(|slot| slot.as_ref())
+            .filter(|(_, at)| *at >= oldest)
+            .map(|(exemplar, _)| exemplar.clone())
+            .collect()
+    }
+
+    /// Approximate number of bytes used by the counts of all buckets in this histogram.
+    pub fn memory_usage(&self) -> usize {
+        match &self.buckets {
+            Buckets::Hdr(buckets) => {
+                buckets
+                    .iter()
+                    .map(|h| h.distinct_values() * mem::size_of::<u64>())
+                    .sum()
+            },
+            Buckets::Sketch(buckets) => buckets.iter().map(QuantileSketch::memory_usage).sum(),
+        }
     }
 }
 
+/// The merged contents of a windowed histogram.
+#[derive(Debug)]
+enum Distribution {
+    Hdr(HdrHistogram<u64>),
+    Sketch(QuantileSketch),
+}
+
 #[derive(Debug)]
 pub struct HistogramSnapshot {
-    histogram: HdrHistogram<u64>,
+    distribution: Distribution,
     sum: u64,
     count: u64,
+    total: u64,
+    covered: Duration,
+    exemplars: Vec<Exemplar>,
+    memory_usage: usize,
+    rejected: u64,
+    clamped: u64,
 }
 
 impl HistogramSnapshot {
     pub fn new(histogram: HdrHistogram<u64>, sum: u64) -> Self {
         let count = histogram.len();
+        HistogramSnapshot::with_distribution(Distribution::Hdr(histogram), sum, count)
+    }
+
+    pub(crate) fn from_sketch(sketch: QuantileSketch, sum: u64) -> Self {
+        let count = sketch.count();
+        HistogramSnapshot::with_distribution(Distribution::Sketch(sketch), sum, count)
+    }
+
+    fn with_distribution(distribution: Distribution, sum: u64, count: u64) -> Self {
+        HistogramSnapshot {
+            distribution,
+            sum,
+            count,
+            total: 0,
+            covered: Duration::new(0, 0),
+            exemplars: Vec::new(),
+            memory_usage: 0,
+            rejected: 0,
+            clamped: 0,
+        }
+    }
+
+    /// Gets the value at the given percentile, between 0.0 and 100.0.
+    pub fn value_at_percentile(&self, percentile: f64) -> u64 {
+        match &self.distribution {
+            Distribution::Hdr(histogram) => histogram.value_at_percentile(percentile),
+            Distribution::Sketch(sketch) => sketch.value_at_quantile(percentile / 100.0),
+        }
+    }
+
+    /// Gets the value at the given percentile, between 0.0 and 100.0, interpolated linearly between
+    /// the recorded values on either side of it.
+    ///
+    /// Sketches are always interpolated, so for them this is the same as
+    /// [`value_at_percentile`](HistogramSnapshot::value_at_percentile).
+    pub fn interpolated_value_at_percentile(&self, percentile: f64) -> u64 {
+        let histogram = match &self.distribution {
+            Distribution::Hdr(histogram) => histogram,
+            Distribution::Sketch(sketch) => return sketch.value_at_quantile(percentile / 100.0),
+        };
+
+        let count = histogram.len();
+        if count == 0 {
+            return 0;
+        }
 
-        HistogramSnapshot { histogram, sum, count }
+        // The percentile falls at a fractional rank between two values, counting from zero, which
+        // are found by walking the recorded values until their cumulative count passes each rank.
+        let rank = (percentile.max(0.0).min(100.0) / 100.0) * (count - 1) as f64;
+        let (lower_rank, upper_rank) = (rank.floor() as u64, rank.ceil() as u64);
+        let (mut lower, mut upper) = (None, 0);
+        let mut seen = 0;
+        for v in histogram.iter_recorded() {
+            seen += v.count_at_value();
+            if lower.is_none() && seen > lower_rank {
+                lower = Some(v.value_iterated_to());
+            }
+            if seen > upper_rank {
+                upper = v.value_iterated_to();
+                break;
+            }
+        }
+
+        let lower = lower.unwrap_or(upper) as f64;
+        (lower + (upper as f64 - lower) * rank.fract()).round() as u64
+    }
+
+    /// Gets the number of values recorded that are less than or equal to `value`.
+    pub fn count_at_or_below(&self, value: u64) -> u64 {
+        match &self.distribution {
+            Distribution::Hdr(histogram) => histogram.count_between(0, value),
+            Distribution::Sketch(sketch) => sketch.count_at_or_below(value),
+        }
     }
 
-    pub fn histogram(&self) -> &HdrHistogram<u64> { &self.histogram }
+    /// Gets the recorded values along with their counts.
+    ///
+    /// For an HdrHistogram, these are the distinct values recorded, as the highest value equivalent
+    /// to their bucket.  For a sketch, these are its centroids.
+    pub fn recorded(&self) -> Vec<(u64, u64)> {
+        match &self.distribution {
+            Distribution::Hdr(histogram) => {
+                histogram
+                    .iter_recorded()
+                    .map(|v| (v.value_iterated_to(), v.count_at_value()))
+                    .collect()
+            },
+            Distribution::Sketch(sketch) => sketch.centroids(),
+        }
+    }
 
     pub fn sum(&self) -> u64 { self.sum }
 
     pub fn count(&self) -> u64 { self.count }
+
+    /// Number of values recorded over the lifetime of the histogram, rather than in its window.
+    pub fn total(&self) -> u64 { self.total }
+
+    /// How much time the values in this histogram cover.
+    pub fn covered(&self) -> Duration { self.covered }
+
+    /// Exemplars attached to values in this histogram, in ascending order of value.
+    pub fn exemplars(&self) -> &[Exemplar] { &self.exemplars }
+
+    pub fn memory_usage(&self) -> usize { self.memory_usage }
+
+    pub fn rejected(&self) -> u64 { self.rejected }
+
+    /// Whether or not this snapshot is of a histogram backed by a sketch.
+    pub(crate) fn is_sketch(&self) -> bool {
+        match self.distribution {
+            Distribution::Sketch(_) => true,
+            Distribution::Hdr(_) => false,
+        }
+    }
+
+    /// Adds the values of another snapshot into this one, as if they had been recorded by the same
+    /// histogram.
+    ///
+    /// Returns `false`, leaving this snapshot as it was, if the two can't be merged, as with an
+    /// HdrHistogram and a sketch.  HdrHistograms with different maximums are merged with the
+    /// larger of the two, so no values are lost.  Exemplars are kept as in the histogram itself: only
+    /// the most recent for values of roughly the same magnitude.
+    pub(crate) fn merge(&mut self, other: &HistogramSnapshot) -> bool {
+        match (&mut self.distribution, &other.distribution) {
+            (Distribution::Hdr(histogram), Distribution::Hdr(addend)) => {
+                let widest = if addend.high() > histogram.high() {
+                    addend
+                } else {
+                    &*histogram
+                };
+                let mut merged = HdrHistogram::new_from(widest);
+                if merged.add(&*histogram).is_err() || merged.add(addend).is_err() {
+                    return false;
+                }
+                *histogram = merged;
+            },
+            (Distribution::Sketch(sketch), Distribution::Sketch(addend)) => {
+                sketch.add(addend);
+                sketch.compress();
+            },
+            _ => return false,
+        }
+
+        self.sum = self.sum.wrapping_add(other.sum);
+        self.count = self.count.wrapping_add(other.count);
+        self.total = self.total.wrapping_add(other.total);
+        self.rejected = self.rejected.wrapping_add(other.rejected);
+        self.clamped = self.clamped.wrapping_add(other.clamped);
+        self.covered = self.covered.max(other.covered);
+        self.memory_usage += other.memory_usage;
+
+        let mut slots = vec![None; 65];
+        for exemplar in self.exemplars.drain(..).chain(other.exemplars.iter().cloned()) {
+            let slot: &mut Option<Exemplar> = &mut slots[exemplar_slot(exemplar.value)];
+            if slot.as_ref().map_or(true, |e| exemplar.timestamp >= e.timestamp) {
+                *slot = Some(exemplar);
+            }
+        }
+        self.exemplars = slots.into_iter().flatten().collect();
+        true
+    }
+
+    pub fn clamped(&self) -> u64 { self.clamped }
 }
 
+/// Gets the slot an exemplar for the given value is kept in: one per bit length, so that values
+/// within a factor of two of each other share a slot.
+fn exemplar_slot(value: u64) -> usize { 64 - value.leading_zeros() as usize }
+
 #[cfg(test)]
 mod tests {
-    use super::{Histogram, WindowedHistogram};
-    use std::time::{Duration, Instant};
+    use super::{Buckets, Histogram, HistogramSnapshot, Storage, WindowedHistogram};
+    use crate::data::snapshot::Exemplar;
+    use hdrhistogram::Histogram as HdrHistogram;
+    use std::time::{Duration, Instant, UNIX_EPOCH};
 
     #[test]
     fn test_histogram_simple_update() {
-        let mut histogram = Histogram::new(Duration::new(5, 0), Duration::new(1, 0));
+        let mut histogram: Histogram<_> =
+            Histogram::new(Duration::new(5, 0), Duration::new(1, 0), u64::max_value(), false);
 
         let key = "foo";
         histogram.update(key, 1245);
@@ -133,14 +650,15 @@ mod tests {
         assert_eq!(values.len(), 1);
 
         let hdr = &values[0].1;
-        assert_eq!(hdr.histogram().len(), 1);
-        assert_eq!(hdr.histogram().max(), 1245);
+        assert_eq!(hdr.count(), 1);
+        assert_eq!(hdr.value_at_percentile(100.0), 1245);
         assert_eq!(hdr.sum(), 1245);
     }
 
     #[test]
     fn test_histogram_complex_update() {
-        let mut histogram = Histogram::new(Duration::new(5, 0), Duration::new(1, 0));
+        let mut histogram: Histogram<_> =
+            Histogram::new(Duration::new(5, 0), Duration::new(1, 0), u64::max_value(), false);
 
         let key = "foo";
         histogram.update(key, 1245);
@@ -152,14 +670,166 @@ mod tests {
         assert_eq!(values.len(), 1);
 
         let hdr = &values[0].1;
-        assert_eq!(hdr.histogram().len(), 4);
-        assert_eq!(hdr.histogram().max(), 1248);
+        assert_eq!(hdr.count(), 4);
+        assert_eq!(hdr.value_at_percentile(100.0), 1248);
         assert_eq!(hdr.sum(), 3728);
     }
 
+    #[test]
+    fn test_histogram_reject() {
+        let mut histogram: Histogram<_> =
+            Histogram::new(Duration::new(5, 0), Duration::new(1, 0), u64::max_value(), false);
+
+        histogram.reject("foo");
+        histogram.update("foo", 1245);
+        histogram.reject("foo");
+
+        let values = histogram.values();
+        assert_eq!(values.len(), 1);
+        assert_eq!(values[0].1.count(), 1);
+        assert_eq!(values[0].1.rejected(), 2);
+
+        assert!(histogram.clear(&"foo"));
+        assert_eq!(histogram.values()[0].1.rejected(), 0);
+    }
+
+    #[test]
+    fn test_histogram_clear() {
+        let mut histogram: Histogram<_> =
+            Histogram::new(Duration::new(5, 0), Duration::new(1, 0), u64::max_value(), false);
+
+        histogram.update("foo", 1245);
+        histogram.update("bar", 1022);
+        assert!(histogram.clear(&"foo"));
+        assert!(!histogram.clear(&"baz"));
+
+        let mut values = histogram.values();
+        values.sort_by_key(|(k, _)| *k);
+        assert_eq!(values.len(), 2);
+        assert_eq!(values[0].0, "bar");
+        assert_eq!(values[0].1.count(), 1);
+        assert_eq!(values[1].0, "foo");
+        assert_eq!(values[1].1.count(), 0);
+        assert_eq!(values[1].1.sum(), 0);
+    }
+
+    #[test]
+    fn test_windowed_histogram_max_value() {
+        let mut wh = WindowedHistogram::new(Duration::new(5, 0), Duration::new(1, 0), u64::max_value());
+        let mut capped = WindowedHistogram::new(Duration::new(5, 0), Duration::new(1, 0), 1_000_000);
+
+        // A lower maximum trackable value needs fewer counts per bucket.
+        assert!(capped.memory_usage() > 0);
+        assert!(capped.memory_usage() < wh.memory_usage());
+
+        // Values above the maximum are clamped, rather than growing the histogram.
+        let usage = capped.memory_usage();
+        wh.update(5_000_000);
+        capped.update(5_000_000);
+        assert_eq!(capped.memory_usage(), usage);
+
+        let snapshot = capped.snapshot();
+        assert_eq!(snapshot.memory_usage(), usage);
+        assert!(snapshot.value_at_percentile(100.0) < 5_000_000);
+        assert!(wh.snapshot().value_at_percentile(100.0) >= 5_000_000);
+    }
+
+    #[test]
+    fn test_windowed_histogram_zero() {
+        // The lowest discernible value of the buckets is 1, but that only bounds their precision:
+        // zero is still recorded as zero, rather than being clamped up to 1.
+        let mut hdr = WindowedHistogram::new(Duration::new(5, 0), Duration::new(1, 0), u64::max_value());
+        let mut sketch = WindowedHistogram::new_sketch(Duration::new(5, 0), Duration::new(1, 0));
+        for wh in [&mut hdr, &mut sketch].iter_mut() {
+            wh.update(0);
+            wh.update(0);
+            wh.update(500);
+
+            let snapshot = wh.snapshot();
+            assert_eq!(snapshot.value_at_percentile(50.0), 0);
+            assert_eq!(snapshot.count_at_or_below(0), 2);
+            assert_eq!(snapshot.clamped(), 0);
+        }
+    }
+
+    #[test]
+    fn test_histogram_snapshot_merge_exemplars() {
+        let exemplar = |label: &str, value, secs| {
+            Exemplar {
+                label: label.to_owned(),
+                value,
+                timestamp: UNIX_EPOCH + Duration::from_secs(secs),
+            }
+        };
+        let snapshot = |exemplars| {
+            let mut histogram = HdrHistogram::<u64>::new_with_bounds(1, u64::max_value(), 3).unwrap();
+            histogram.saturating_record(1);
+            let mut snapshot = HistogramSnapshot::new(histogram, 1);
+            snapshot.exemplars = exemplars;
+            snapshot
+        };
+
+        let mut merged = snapshot(vec![exemplar("a", 5, 10), exemplar("b", 100, 10)]);
+        let other = snapshot(vec![
+            exemplar("c", 6, 20),
+            exemplar("d", 120, 5),
+            exemplar("e", 1_000, 5),
+        ]);
+        assert!(merged.merge(&other));
+
+        // Values within a factor of two of each other share a slot, and the most recent exemplar
+        // in each slot is kept.
+        let labels = merged.exemplars().iter().map(|e| e.label()).collect::<Vec<_>>();
+        assert_eq!(labels, vec!["c", "b", "e"]);
+        assert_eq!(merged.count(), 2);
+    }
+
+    #[test]
+    fn test_histogram_storage() {
+        let mut histogram: Histogram<_> =
+            Histogram::new(Duration::new(5, 0), Duration::new(1, 0), u64::max_value(), false);
+
+        histogram.update("foo", 10);
+        histogram.update("foo", 5_000);
+        assert_eq!(histogram.values()[0].1.clamped(), 0);
+
+        // Switching to a lower maximum drops what was recorded, and clamps values above it.
+        histogram.set_storage("foo", Some(Storage::Bounded(1_000)));
+        assert!(histogram.values().is_empty());
+        histogram.update("foo", 10);
+        histogram.update("foo", 5_000);
+
+        let values = histogram.values();
+        let snapshot = &values[0].1;
+        assert_eq!(snapshot.count(), 2);
+        assert_eq!(snapshot.clamped(), 1);
+        assert!(snapshot.value_at_percentile(100.0) < 5_000);
+
+        // Setting the same storage again keeps what was recorded.
+        histogram.set_storage("foo", Some(Storage::Bounded(1_000)));
+        assert_eq!(histogram.values()[0].1.count(), 2);
+        histogram.set_storage("foo", None);
+        assert!(histogram.values().is_empty());
+    }
+
+    #[test]
+    fn test_windowed_histogram_mismatched_buckets() {
+        let mut small = HdrHistogram::new_with_bounds(1, 1_000, 3).unwrap();
+        small.saturating_record(10);
+        let mut large = HdrHistogram::new_with_bounds(1, u64::max_value(), 3).unwrap();
+        large.saturating_record(5_000_000);
+
+        // The bucket that doesn't fit within the bounds of the current bucket is skipped, rather
+        // than panicking.
+        let wh = WindowedHistogram::with_buckets(Buckets::Hdr(vec![small, large]), 2, Duration::new(1, 0));
+        let snapshot = wh.snapshot();
+        assert_eq!(snapshot.count(), 1);
+        assert_eq!(snapshot.value_at_percentile(100.0), 10);
+    }
+
     #[test]
     fn test_windowed_histogram_rollover() {
-        let mut wh = WindowedHistogram::new(Duration::new(5, 0), Duration::new(1, 0));
+        let mut wh = WindowedHistogram::new(Duration::new(5, 0), Duration::new(1, 0), u64::max_value());
         let now = Instant::now();
 
         let snapshot = wh.snapshot();
@@ -215,4 +885,150 @@ mod tests {
         let snapshot = wh.snapshot();
         assert_eq!(snapshot.count(), 3);
     }
+
+    #[test]
+    fn test_windowed_histogram_upkeep_lag() {
+        let mut wh = WindowedHistogram::new(Duration::new(5, 0), Duration::new(1, 0), u64::max_value());
+        let start = wh.last_upkeep;
+        wh.update(1);
+
+        // Upkeep running three and a half seconds late rolls over three buckets, not just one, and
+        // stays on the schedule of whole seconds.
+        let index = wh.bucket_index;
+        wh.upkeep(start + Duration::from_millis(3_500));
+        assert_eq!(wh.bucket_index, (index + 3) % wh.num_buckets);
+        assert_eq!(wh.last_upkeep, start + Duration::new(3, 0));
+        assert_eq!(wh.snapshot().count(), 1);
+
+        // Another three seconds later, the value has rolled out of the window.
+        wh.update(2);
+        wh.upkeep(start + Duration::new(6, 0));
+        assert_eq!(wh.snapshot().count(), 1);
+        assert_eq!(wh.covered(start + Duration::new(6, 0)), Duration::new(5, 0));
+
+        // Lagging by more than the whole window clears it.
+        wh.upkeep(start + Duration::new(100, 0));
+        assert_eq!(wh.snapshot().count(), 0);
+        assert_eq!(wh.last_upkeep, start + Duration::new(100, 0));
+    }
+
+    #[test]
+    fn test_windowed_histogram_aligned_catch_up() {
+        let mut wh = WindowedHistogram::new(Duration::new(5, 0), Duration::new(1, 0), u64::max_value());
+        let start = wh.last_upkeep;
+        let wall = UNIX_EPOCH + Duration::from_millis(1_000_500);
+        wh.upkeep_aligned(start, wall);
+
+        // Three periods pass by the wall clock but only a millisecond by the monotonic clock, so the
+        // buckets can't start three seconds back: none of them start before the bucket they follow.
+        wh.upkeep_aligned(start + Duration::from_millis(1), wall + Duration::new(3, 0));
+        assert!(wh.bucket_starts.iter().all(|at| *at >= start));
+        assert_eq!(wh.bucket_starts[wh.bucket_index], start + Duration::from_millis(1));
+    }
+
+    #[test]
+    fn test_windowed_histogram_covered() {
+        let mut wh = WindowedHistogram::new(Duration::new(5, 0), Duration::new(1, 0), u64::max_value());
+        let start = wh.last_upkeep;
+
+        // Until the window fills up, the histogram covers everything since it was created.
+        assert_eq!(
+            wh.covered(start + Duration::from_millis(500)),
+            Duration::from_millis(500)
+        );
+        for i in 1..=5 {
+            wh.upkeep(start + Duration::new(i, 0));
+        }
+        assert_eq!(wh.covered(start + Duration::new(5, 0)), Duration::new(5, 0));
+
+        // Once full, it covers a full window, plus however late upkeep runs.
+        wh.upkeep(start + Duration::from_millis(6_250));
+        assert_eq!(
+            wh.covered(start + Duration::from_millis(6_500)),
+            Duration::from_millis(5_500)
+        );
+    }
+
+    #[test]
+    fn test_windowed_histogram_update_at() {
+        let mut wh = WindowedHistogram::new(Duration::new(5, 0), Duration::new(1, 0), u64::max_value());
+
+        // Move ten seconds past creation, so that earlier times can be built forward from the
+        // start, rather than by subtracting from an `Instant`, which can underflow.
+        let start = wh.last_upkeep;
+        let now = start + Duration::new(10, 0);
+        wh.upkeep(now);
+
+        // Two values now, one from two seconds ago, and one from well before the window.
+        wh.update(1);
+        assert!(wh.update_at(2, now + Duration::from_millis(500)));
+        assert!(wh.update_at(3, start + Duration::from_millis(8_500)));
+        assert!(!wh.update_at(4, start));
+        assert_eq!(wh.snapshot().count(), 3);
+
+        // The value from two seconds ago is the first to roll out of the window.
+        for i in 1..=3 {
+            wh.upkeep(now + Duration::new(i, 0));
+            assert_eq!(wh.snapshot().count(), 3);
+        }
+        wh.upkeep(now + Duration::new(4, 0));
+        assert_eq!(wh.snapshot().count(), 2);
+        wh.upkeep(now + Duration::new(5, 0));
+        assert_eq!(wh.snapshot().count(), 2);
+        wh.upkeep(now + Duration::new(6, 0));
+        assert_eq!(wh.snapshot().count(), 0);
+    }
+
+    #[test]
+    fn test_windowed_histogram_update_at_aligned() {
+        let mut wh = WindowedHistogram::new(Duration::new(5, 0), Duration::new(1, 0), u64::max_value());
+        let start = UNIX_EPOCH + Duration::from_millis(1_000_500);
+        wh.upkeep_aligned(Instant::now(), start);
+
+        assert!(wh.update_at_aligned(1, start - Duration::from_millis(600)));
+        assert!(!wh.update_at_aligned(2, start - Duration::new(5, 0)));
+        assert_eq!(wh.snapshot().count(), 1);
+
+        // The value was recorded in the previous second, so it rolls out one bucket early.
+        for i in 1..=4 {
+            wh.upkeep_aligned(Instant::now(), start + Duration::new(i, 0));
+            assert_eq!(wh.snapshot().count(), 1);
+        }
+        wh.upkeep_aligned(Instant::now(), start + Duration::new(5, 0));
+        assert_eq!(wh.snapshot().count(), 0);
+    }
+
+    #[test]
+    fn test_histogram_update_at_before_epoch() {
+        let mut histogram: Histogram<_> =
+            Histogram::new(Duration::new(5, 0), Duration::new(1, 0), u64::max_value(), true);
+
+        // Recorded 600ms before a wall-clock time 100ms after the epoch, so it has no period.
+        let when = Instant::now();
+        let now = when + Duration::from_millis(600);
+        let wall = UNIX_EPOCH + Duration::from_millis(100);
+        assert!(!histogram.update_at("foo", 1, when, now, wall));
+    }
+
+    #[test]
+    fn test_windowed_histogram_aligned_rollover() {
+        let mut wh = WindowedHistogram::new(Duration::new(5, 0), Duration::new(1, 0), u64::max_value());
+        let start = UNIX_EPOCH + Duration::from_millis(1_000_500);
+
+        wh.upkeep_aligned(Instant::now(), start);
+        let index = wh.bucket_index;
+        wh.update(1);
+
+        // Still within the same wall-clock second, so no rollover.
+        wh.upkeep_aligned(Instant::now(), start + Duration::from_millis(400));
+        assert_eq!(wh.bucket_index, index);
+
+        // Less than a granularity has passed since the last upkeep, but we've crossed into the
+        // next second, so we roll over, with the new bucket starting at the time we were given.
+        let at = Instant::now() + Duration::from_millis(500);
+        wh.upkeep_aligned(at, start + Duration::from_millis(500));
+        assert_eq!(wh.bucket_index, (index + 1) % wh.num_buckets);
+        assert_eq!(wh.bucket_starts[wh.bucket_index], at);
+        assert_eq!(wh.snapshot().count(), 1);
+    }
 }
@@ -1,14 +1,26 @@
 use std::{
     fmt::{self, Display},
     hash::Hash,
+    str::FromStr,
+    time::Instant,
 };
 
 pub mod counter;
+pub mod facet;
 pub mod gauge;
 pub mod histogram;
+pub mod sketch;
 pub mod snapshot;
+pub mod view;
 
-pub(crate) use self::{counter::Counter, gauge::Gauge, histogram::Histogram, snapshot::Snapshot};
+pub(crate) use self::{
+    counter::{Counter, SharedCounters, SharedValue, Throughput, WideCounter, WindowedCounter},
+    facet::{Facet, FacetKind, Facets, TimeUnit},
+    gauge::Gauge,
+    histogram::{Histogram, Storage},
+    snapshot::{MeasurementKey, Snapshot},
+};
+use crate::scopes::render_labels;
 
 /// A measurement.
 ///
@@ -31,11 +43,29 @@ pub(crate) enum Sample<T> {
 
     /// A timed sample.
     ///
-    /// Includes the start and end times, as well as a count field.
+    /// Includes the start and end times, and counts once.  This is the most common sample of all,
+    /// so it's kept as small as possible.
+    TimingHistogram(T, u64, u64),
+
+    /// A timed sample, with a count.
     ///
-    /// The count field can represent amounts integral to the event, such as the number of bytes
-    /// processed in the given time delta.
-    TimingHistogram(T, u64, u64, u64),
+    /// Like `TimingHistogram`, but the count can represent amounts integral to the event, such as
+    /// the number of bytes processed in the given time delta.  The start and end times, and the
+    /// count, are boxed, so that this doesn't make every sample larger.
+    TimingHistogramCount(T, Box<(u64, u64, u64)>),
+
+    /// A timed sample, measured elsewhere.
+    ///
+    /// Unlike `TimingHistogram`, this carries an already-computed duration, in nanoseconds, rather
+    /// than start and end times, and so the clock is never involved.
+    TimingLatency(T, u64),
+
+    /// A timed sample, measured elsewhere, at a given time.
+    ///
+    /// Like `TimingLatency`, but recorded into the histogram bucket covering the time the sample
+    /// was taken, rather than the current bucket.  The duration and the time are boxed, so that
+    /// this doesn't make every sample larger.
+    TimingLatencyAt(T, Box<(u64, Instant)>),
 
     /// A single value measured over time.
     ///
@@ -44,28 +74,134 @@ pub(crate) enum Sample<T> {
     /// histograms, since the end result is just a single number, but we don't spice it up with
     /// special unit labels or anything.
     ValueHistogram(T, u64),
+
+    /// A timed sample, with an exemplar.
+    ///
+    /// Like `TimingHistogram`, with a count of one, but also carries an exemplar, such as a trace
+    /// ID, to attach to the recorded value.  The start and end times, and the exemplar, are boxed,
+    /// so that this doesn't make every sample larger.
+    TimingExemplar(T, Box<(u64, u64, String)>),
+
+    /// A single value measured over time, with an exemplar.
+    ///
+    /// Like `ValueHistogram`, but also carries an exemplar to attach to the recorded value.
+    ValueExemplar(T, Box<(u64, String)>),
 }
 
 /// An integer scoped metric key.
 #[derive(Clone, Hash, PartialEq, Eq, Debug)]
-pub(crate) struct ScopedKey<T: Clone + Eq + Hash + Display>(u64, T);
+pub(crate) struct ScopedKey<T>(u64, T);
 
 impl<T: Clone + Eq + Hash + Display> ScopedKey<T> {
+    pub(crate) fn new(scope_id: u64, key: T) -> ScopedKey<T> { ScopedKey(scope_id, key) }
+
     pub(crate) fn id(&self) -> u64 { self.0 }
 
-    pub(crate) fn into_string_scoped(self, scope: String) -> StringScopedKey<T> { StringScopedKey(scope, self.1) }
+    pub(crate) fn with_scope_id(&self, scope_id: u64) -> ScopedKey<T> { ScopedKey(scope_id, self.1.clone()) }
+
+    /// Converts this key into its string-scoped form, displayed as `name` rather than the key
+    /// itself if given.
+    pub(crate) fn into_string_scoped(
+        self, scope: String, labels: Vec<(String, String)>, name: Option<String>,
+    ) -> StringScopedKey<T> {
+        StringScopedKey(scope, self.1, labels, name)
+    }
 }
 
 /// A string scoped metric key.
+///
+/// Any labels are kept alongside the metric name, sorted.  If the metric has an output name, that's
+/// used as the metric name instead of the key.
 #[derive(Clone, Hash, PartialEq, Eq, Debug)]
-pub(crate) struct StringScopedKey<T: Clone + Eq + Hash + Display>(String, T);
+pub(crate) struct StringScopedKey<T: Clone + Eq + Hash + Display>(String, T, Vec<(String, String)>, Option<String>);
 
 impl<T: Clone + Hash + Eq + Display> Display for StringScopedKey<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if self.0.is_empty() {
-            write!(f, "{}", self.1)
+        if !self.0.is_empty() {
+            write!(f, "{}.", self.0)?;
+        }
+        match &self.3 {
+            Some(name) => write!(f, "{}{}", name, render_labels(&self.2)),
+            None => write!(f, "{}{}", self.1, render_labels(&self.2)),
+        }
+    }
+}
+
+impl<T: Clone + Hash + Eq + Display> From<StringScopedKey<T>> for MeasurementKey {
+    fn from(key: StringScopedKey<T>) -> MeasurementKey {
+        let StringScopedKey(scope, key, labels, name) = key;
+        let name = name.unwrap_or_else(|| key.to_string());
+        MeasurementKey::new(name).with_scope(&scope).with_labels(labels)
+    }
+}
+
+impl<T> Sample<T> {
+    /// Creates a timed sample, only boxing the count if it's more than one.
+    pub(crate) fn timing(key: T, start: u64, end: u64, count: u64) -> Sample<T> {
+        if count == 1 {
+            Sample::TimingHistogram(key, start, end)
         } else {
-            write!(f, "{}.{}", self.0, self.1)
+            Sample::TimingHistogramCount(key, Box::new((start, end, count)))
+        }
+    }
+
+    pub(crate) fn key(&self) -> &T {
+        match self {
+            Sample::Count(key, _) => key,
+            Sample::Gauge(key, _) => key,
+            Sample::TimingHistogram(key, _, _) => key,
+            Sample::TimingHistogramCount(key, _) => key,
+            Sample::TimingLatency(key, _) => key,
+            Sample::TimingLatencyAt(key, _) => key,
+            Sample::ValueHistogram(key, _) => key,
+            Sample::TimingExemplar(key, _) => key,
+            Sample::ValueExemplar(key, _) => key,
+        }
+    }
+}
+
+impl<T> Sample<T> {
+    /// Whether or not a facet of the given kind applies to this sample.
+    pub(crate) fn is_tracked_by(&self, kind: FacetKind) -> bool {
+        match self {
+            Sample::Count(..) => {
+                match kind {
+                    FacetKind::Count | FacetKind::WideCount | FacetKind::WindowedCount => true,
+                    _ => false,
+                }
+            },
+            Sample::Gauge(..) => {
+                match kind {
+                    FacetKind::Gauge | FacetKind::MaxGauge | FacetKind::MinGauge => true,
+                    _ => false,
+                }
+            },
+            Sample::TimingHistogram(..)
+            | Sample::TimingHistogramCount(..)
+            | Sample::TimingLatency(..)
+            | Sample::TimingLatencyAt(..)
+            | Sample::TimingExemplar(..) => {
+                match kind {
+                    FacetKind::TimingPercentile | FacetKind::Throughput => true,
+                    _ => false,
+                }
+            },
+            Sample::ValueHistogram(..) | Sample::ValueExemplar(..) => kind == FacetKind::ValuePercentile,
+        }
+    }
+
+    /// Replaces the key of this sample.
+    pub(crate) fn with_key<U>(self, key: U) -> Sample<U> {
+        match self {
+            Sample::Count(_, value) => Sample::Count(key, value),
+            Sample::Gauge(_, value) => Sample::Gauge(key, value),
+            Sample::TimingHistogram(_, start, end) => Sample::TimingHistogram(key, start, end),
+            Sample::TimingHistogramCount(_, timing) => Sample::TimingHistogramCount(key, timing),
+            Sample::TimingLatency(_, nanos) => Sample::TimingLatency(key, nanos),
+            Sample::TimingLatencyAt(_, latency) => Sample::TimingLatencyAt(key, latency),
+            Sample::ValueHistogram(_, value) => Sample::ValueHistogram(key, value),
+            Sample::TimingExemplar(_, timing) => Sample::TimingExemplar(key, timing),
+            Sample::ValueExemplar(_, value) => Sample::ValueExemplar(key, value),
         }
     }
 }
@@ -75,14 +211,30 @@ impl<T: Clone + Eq + Hash + Display> Sample<T> {
         match self {
             Sample::Count(key, value) => Sample::Count(ScopedKey(scope_id, key), value),
             Sample::Gauge(key, value) => Sample::Gauge(ScopedKey(scope_id, key), value),
-            Sample::TimingHistogram(key, start, end, count) => {
-                Sample::TimingHistogram(ScopedKey(scope_id, key), start, end, count)
-            },
+            Sample::TimingHistogram(key, start, end) => Sample::TimingHistogram(ScopedKey(scope_id, key), start, end),
+            Sample::TimingHistogramCount(key, timing) => Sample::TimingHistogramCount(ScopedKey(scope_id, key), timing),
+            Sample::TimingLatency(key, nanos) => Sample::TimingLatency(ScopedKey(scope_id, key), nanos),
+            Sample::TimingLatencyAt(key, latency) => Sample::TimingLatencyAt(ScopedKey(scope_id, key), latency),
             Sample::ValueHistogram(key, count) => Sample::ValueHistogram(ScopedKey(scope_id, key), count),
+            Sample::TimingExemplar(key, timing) => Sample::TimingExemplar(ScopedKey(scope_id, key), timing),
+            Sample::ValueExemplar(key, value) => Sample::ValueExemplar(ScopedKey(scope_id, key), value),
         }
     }
 }
 
+/// Error conditions when parsing a percentile from a string.
+#[derive(Debug, PartialEq)]
+pub enum PercentileParseError {
+    /// The input was empty.
+    Empty,
+
+    /// The input was not a recognized label, or a valid number.
+    InvalidFormat,
+
+    /// The input was a valid number, but was not between 0 and 100.
+    OutOfRange,
+}
+
 /// A labeled percentile.
 ///
 /// This represents a floating-point value from 0 to 100, with a string label to be used for
@@ -132,3 +284,69 @@ impl From<f64> for Percentile {
         Percentile { label, value: clamped }
     }
 }
+
+impl FromStr for Percentile {
+    type Err = PercentileParseError;
+
+    /// Parses a percentile from a string.
+    ///
+    /// This is the inverse of the label derivation: `min` and `max` are accepted, as well as the
+    /// `pXXX` form, where the first two digits are the whole percentage and any remaining digits are
+    /// the fractional part i.e. `p99` is 99.0, `p999` is 99.9, and `p05` is 0.5.  Plain numbers, such
+    /// as `99.9`, are also accepted.
+    ///
+    /// Since labels don't carry a decimal point, single-digit percentiles with a fractional part are
+    /// ambiguous: `p55` is always parsed as 55.0, never 5.5.  Use a plain number for those.
+    ///
+    /// Unlike [`From<f64>`], values are not clamped: a number outside of 0 to 100 is an error.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(PercentileParseError::Empty);
+        }
+
+        let value = match s {
+            "min" => 0.0,
+            "max" => 100.0,
+            _ if s.starts_with('p') => {
+                let digits = &s[1..];
+                if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+                    return Err(PercentileParseError::InvalidFormat);
+                }
+
+                // A leading zero means there is no whole percentage, and `p100` would otherwise be
+                // read as 10.0, so both get special treatment.
+                let (whole, fraction) = if digits.len() > 1 && digits.starts_with('0') {
+                    digits.split_at(1)
+                } else if digits.len() <= 2 || digits == "100" {
+                    (digits, "")
+                } else {
+                    digits.split_at(2)
+                };
+
+                format!("{}.{}0", whole, fraction)
+                    .parse::<f64>()
+                    .map_err(|_| PercentileParseError::InvalidFormat)?
+            },
+            _ => s.parse::<f64>().map_err(|_| PercentileParseError::InvalidFormat)?,
+        };
+
+        // NaN fails both comparisons, and so is out of range too.
+        let in_range = value >= 0.0 && value <= 100.0;
+        if !in_range {
+            return Err(PercentileParseError::OutOfRange);
+        }
+
+        Ok(Percentile::from(value))
+    }
+}
+
+impl fmt::Display for PercentileParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PercentileParseError::Empty => write!(f, "cannot parse percentile from empty string"),
+            PercentileParseError::InvalidFormat => write!(f, "invalid percentile format"),
+            PercentileParseError::OutOfRange => write!(f, "percentile must be between 0 and 100"),
+        }
+    }
+}
@@ -0,0 +1,320 @@
+use std::{cmp::Ordering, mem};
+
+/// The compression of a sketch, which bounds the number of centroids it keeps.
+///
+/// Higher compression means more centroids, and so more accurate quantiles at the cost of more
+/// memory.  At 100, a sketch holds a few hundred centroids at most.
+const DEFAULT_COMPRESSION: f64 = 100.0;
+
+/// How many values are buffered, as a multiple of the compression, before being merged into the
+/// centroids.
+const BUFFER_FACTOR: usize = 5;
+
+/// A cluster of nearby values, represented by their mean.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Centroid {
+    mean: f64,
+    weight: f64,
+}
+
+/// A t-digest: a compact sketch of a distribution for estimating quantiles.
+///
+/// Values are clustered into centroids, which are kept small near the tails of the distribution
+/// and allowed to grow towards the median.  This keeps extreme quantiles, such as p99.9, accurate,
+/// while using the same amount of memory no matter how wide the range of recorded values is.
+/// Unlike an HdrHistogram, there is no maximum trackable value, and relative accuracy doesn't
+/// depend on the magnitude of values.
+#[derive(Debug, Clone)]
+pub(crate) struct QuantileSketch {
+    compression: f64,
+    centroids: Vec<Centroid>,
+    unmerged: Vec<Centroid>,
+    count: u64,
+    min: f64,
+    max: f64,
+}
+
+impl QuantileSketch {
+    pub fn new() -> QuantileSketch {
+        QuantileSketch {
+            compression: DEFAULT_COMPRESSION,
+            centroids: Vec::new(),
+            unmerged: Vec::new(),
+            count: 0,
+            min: 0.0,
+            max: 0.0,
+        }
+    }
+
+    /// Records a value.
+    pub fn record(&mut self, value: u64) {
+        let value = value as f64;
+        self.push(
+            Centroid {
+                mean: value,
+                weight: 1.0,
+            },
+            value,
+            value,
+        );
+    }
+
+    /// Adds all of the values recorded by another sketch into this one.
+    pub fn add(&mut self, other: &QuantileSketch) {
+        if other.count == 0 {
+            return;
+        }
+
+        for centroid in other.centroids.iter().chain(other.unmerged.iter()) {
+            self.push(*centroid, other.min, other.max);
+        }
+    }
+
+    fn push(&mut self, centroid: Centroid, min: f64, max: f64) {
+        if self.count == 0 {
+            self.min = min;
+            self.max = max;
+        } else {
+            self.min = self.min.min(min);
+            self.max = self.max.max(max);
+        }
+
+        self.count += centroid.weight as u64;
+        self.unmerged.push(centroid);
+        if self.unmerged.len() >= BUFFER_FACTOR * self.compression as usize {
+            self.compress();
+        }
+    }
+
+    /// Merges any buffered values into the centroids.
+    ///
+    /// Quantiles are only estimated from the centroids, so this must be called after recording
+    /// values, and before querying the sketch.
+    pub fn compress(&mut self) {
+        if self.unmerged.is_empty() {
+            return;
+        }
+
+        let mut items = mem::replace(&mut self.unmerged, Vec::new());
+        items.extend_from_slice(&self.centroids);
+        items.sort_by(|a, b| a.mean.partial_cmp(&b.mean).unwrap_or(Ordering::Equal));
+
+        let total = self.count as f64;
+        let mut merged = Vec::with_capacity(self.centroids.len() + 1);
+        let mut items = items.into_iter();
+        let mut current = match items.next() {
+            Some(centroid) => centroid,
+            None => return,
+        };
+        let mut seen = 0.0;
+
+        for item in items {
+            // A centroid may hold at most 4nq(1-q)/δ values, where q is the quantile at either of
+            // its edges, so centroids at the tails stay small.
+            let proposed = current.weight + item.weight;
+            let q0 = seen / total;
+            let q2 = (seen + proposed) / total;
+            let limit = 4.0 * total * (q0 * (1.0 - q0)).min(q2 * (1.0 - q2)) / self.compression;
+
+            if proposed <= limit {
+                current.mean += (item.mean - current.mean) * item.weight / proposed;
+                current.weight = proposed;
+            } else {
+                seen += current.weight;
+                merged.push(current);
+                current = item;
+            }
+        }
+        merged.push(current);
+
+        self.centroids = merged;
+    }
+
+    /// Clears all recorded values.
+    pub fn clear(&mut self) {
+        self.centroids.clear();
+        self.unmerged.clear();
+        self.count = 0;
+        self.min = 0.0;
+        self.max = 0.0;
+    }
+
+    /// Number of values recorded in this sketch.
+    pub fn count(&self) -> u64 { self.count }
+
+    /// Gets the estimated value at the given quantile, between 0.0 and 1.0.
+    ///
+    /// The minimum and maximum are tracked exactly.  Other quantiles are interpolated between the
+    /// centroids on either side of them.
+    pub fn value_at_quantile(&self, quantile: f64) -> u64 {
+        debug_assert!(
+            self.unmerged.is_empty(),
+            "sketch must be compressed before being queried"
+        );
+
+        let centroids = &self.centroids;
+        if centroids.is_empty() {
+            return 0;
+        }
+        if quantile <= 0.0 {
+            return self.min as u64;
+        }
+        if quantile >= 1.0 {
+            return self.max as u64;
+        }
+
+        // Each centroid is treated as if its values were centered around its mean, and so quantiles
+        // between two centroids are interpolated between their means.
+        let target = quantile * self.count as f64;
+        let first = centroids[0];
+        if target < first.weight / 2.0 {
+            let t = target / (first.weight / 2.0);
+            return to_value(self.min + (first.mean - self.min) * t);
+        }
+
+        let mut cumulative = 0.0;
+        for pair in centroids.windows(2) {
+            let (left, right) = (pair[0], pair[1]);
+            let left_center = cumulative + left.weight / 2.0;
+            let right_center = cumulative + left.weight + right.weight / 2.0;
+            if target < right_center {
+                let t = (target - left_center) / (right_center - left_center);
+                return to_value(left.mean + (right.mean - left.mean) * t);
+            }
+            cumulative += left.weight;
+        }
+
+        let last = centroids[centroids.len() - 1];
+        let t = ((target - cumulative - last.weight / 2.0) / (last.weight / 2.0)).min(1.0);
+        to_value(last.mean + (self.max - last.mean) * t)
+    }
+
+    /// Gets the estimated number of recorded values that are less than or equal to `value`.
+    pub fn count_at_or_below(&self, value: u64) -> u64 {
+        debug_assert!(
+            self.unmerged.is_empty(),
+            "sketch must be compressed before being queried"
+        );
+
+        let centroids = &self.centroids;
+        let value = value as f64;
+        if centroids.is_empty() || value < self.min {
+            return 0;
+        }
+        if value >= self.max {
+            return self.count;
+        }
+
+        let first = centroids[0];
+        if value < first.mean {
+            let t = (value - self.min) / (first.mean - self.min);
+            return to_value(first.weight / 2.0 * t);
+        }
+
+        let mut cumulative = 0.0;
+        for pair in centroids.windows(2) {
+            let (left, right) = (pair[0], pair[1]);
+            if value < right.mean {
+                let t = (value - left.mean) / (right.mean - left.mean);
+                return to_value(cumulative + left.weight / 2.0 + (left.weight + right.weight) / 2.0 * t);
+            }
+            cumulative += left.weight;
+        }
+
+        let last = centroids[centroids.len() - 1];
+        let t = (value - last.mean) / (self.max - last.mean);
+        to_value(cumulative + last.weight / 2.0 + last.weight / 2.0 * t)
+    }
+
+    /// Gets the centroids of this sketch, as their rounded means along with their weights.
+    pub fn centroids(&self) -> Vec<(u64, u64)> {
+        self.centroids
+            .iter()
+            .map(|c| (to_value(c.mean), c.weight as u64))
+            .collect()
+    }
+
+    /// Approximate number of bytes used by this sketch.
+    pub fn memory_usage(&self) -> usize {
+        (self.centroids.capacity() + self.unmerged.capacity()) * mem::size_of::<Centroid>()
+    }
+}
+
+fn to_value(value: f64) -> u64 { value.round().max(0.0) as u64 }
+
+#[cfg(test)]
+mod tests {
+    use super::QuantileSketch;
+
+    fn sketch_of(values: impl Iterator<Item = u64>) -> QuantileSketch {
+        let mut sketch = QuantileSketch::new();
+        for value in values {
+            sketch.record(value);
+        }
+        sketch.compress();
+        sketch
+    }
+
+    #[test]
+    fn test_sketch_quantiles() {
+        let sketch = sketch_of(1..=100_000);
+        assert_eq!(sketch.count(), 100_000);
+        assert!(sketch.centroids().len() < 1_000);
+
+        assert_eq!(sketch.value_at_quantile(0.0), 1);
+        assert_eq!(sketch.value_at_quantile(1.0), 100_000);
+        for (quantile, expected) in &[(0.5, 50_000.0), (0.9, 90_000.0), (0.99, 99_000.0), (0.999, 99_900.0)] {
+            let value = sketch.value_at_quantile(*quantile) as f64;
+            assert!(
+                (value - expected).abs() / expected < 0.01,
+                "q{} was {}",
+                quantile,
+                value
+            );
+        }
+
+        let below = sketch.count_at_or_below(25_000) as f64;
+        assert!((below - 25_000.0).abs() < 250.0);
+        assert_eq!(sketch.count_at_or_below(0), 0);
+        assert_eq!(sketch.count_at_or_below(100_000), 100_000);
+    }
+
+    #[test]
+    fn test_sketch_small() {
+        let sketch = sketch_of([10, 20, 30].iter().cloned());
+        assert_eq!(sketch.centroids(), vec![(10, 1), (20, 1), (30, 1)]);
+        assert_eq!(sketch.value_at_quantile(0.5), 20);
+        assert_eq!(sketch.count_at_or_below(20), 2);
+
+        let empty = sketch_of(0..0);
+        assert_eq!(empty.value_at_quantile(0.5), 0);
+        assert_eq!(empty.count_at_or_below(20), 0);
+    }
+
+    #[test]
+    fn test_sketch_wide_range() {
+        // Values spanning many orders of magnitude keep their relative accuracy.
+        let sketch = sketch_of((0..60).map(|i| 1u64 << i));
+        assert_eq!(sketch.value_at_quantile(0.0), 1);
+        assert_eq!(sketch.value_at_quantile(1.0), 1 << 59);
+        assert_eq!(sketch.value_at_quantile(0.5), 3 << 28);
+    }
+
+    #[test]
+    fn test_sketch_add() {
+        let mut sketch = sketch_of(1..=50_000);
+        let other = sketch_of(50_001..=100_000);
+        sketch.add(&other);
+        sketch.compress();
+
+        assert_eq!(sketch.count(), 100_000);
+        assert_eq!(sketch.value_at_quantile(0.0), 1);
+        assert_eq!(sketch.value_at_quantile(1.0), 100_000);
+        let median = sketch.value_at_quantile(0.5) as f64;
+        assert!((median - 50_000.0).abs() < 500.0);
+
+        sketch.clear();
+        assert_eq!(sketch.count(), 0);
+        assert_eq!(sketch.value_at_quantile(0.5), 0);
+    }
+}
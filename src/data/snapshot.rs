@@ -1,65 +1,499 @@
-use super::{histogram::HistogramSnapshot, Percentile};
-use std::{collections::HashMap, fmt::Display};
+use super::{facet::Timing, histogram::HistogramSnapshot, Percentile, TimeUnit};
+use crate::scopes::render_labels;
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fmt,
+    hash::{Hash, Hasher},
+    sync::Arc,
+    time::{Duration, SystemTime},
+    vec,
+};
+
+/// The default bucket boundaries for timing histograms, in nanoseconds: 5ms up to 10s.
+const DEFAULT_TIMING_BUCKETS: [u64; 11] = [
+    5_000_000,
+    10_000_000,
+    25_000_000,
+    50_000_000,
+    100_000_000,
+    250_000_000,
+    500_000_000,
+    1_000_000_000,
+    2_500_000_000,
+    5_000_000_000,
+    10_000_000_000,
+];
+
+/// The key of a measurement: the fully-qualified name of its metric, and the labels it was sent
+/// with.
+///
+/// The name includes the scope the metric was sent from, joined with `.` as by
+/// [`Sink::scoped`](crate::Sink::scoped), and the key keeps track of where the scope ends, so that
+/// a key like `"pool.size"` sent from the root scope isn't mistaken for `size` in a `pool` scope.
+/// Labels are kept apart from the name, so that exporters can render them in whatever way their
+/// format tags metrics, and are sorted by key.  Displaying a key renders it in its canonical form,
+/// as `name{key="value",...}`, with the labels as rendered by
+/// [`Sink::with_labels`](crate::Sink::with_labels).
+///
+/// Keys compare equal when their fully-qualified names and labels are equal, wherever their scopes
+/// end.  The name and labels are shared between clones, so keys are cheap to clone.
+#[derive(Debug, Clone)]
+pub struct MeasurementKey {
+    name: Arc<str>,
+    scope_len: usize,
+    labels: Arc<[(String, String)]>,
+}
+
+impl MeasurementKey {
+    /// Creates a key for the given metric name, without a scope or any labels.
+    pub fn new<N: Into<String>>(name: N) -> Self {
+        MeasurementKey {
+            name: Arc::from(name.into()),
+            scope_len: 0,
+            labels: Arc::from(Vec::new()),
+        }
+    }
+
+    /// Places the metric under the given scope, ahead of any scope it already has.
+    ///
+    /// An empty scope leaves the key as it is.
+    pub fn with_scope(mut self, scope: &str) -> Self {
+        if scope.is_empty() {
+            return self;
+        }
+
+        self.scope_len = if self.scope_len == 0 {
+            scope.len()
+        } else {
+            scope.len() + 1 + self.scope_len
+        };
+        self.name = Arc::from(format!("{}.{}", scope, self.name));
+        self
+    }
+
+    /// Sets the labels of this key, replacing any it already has.
+    pub fn with_labels<I, K, V>(mut self, labels: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let mut labels = labels
+            .into_iter()
+            .map(|(k, v)| (k.into(), v.into()))
+            .collect::<Vec<_>>();
+        labels.sort();
+        self.labels = Arc::from(labels);
+        self
+    }
+
+    /// Appends a suffix to the name of the metric, ahead of any labels.
+    pub(crate) fn with_suffix(mut self, suffix: &str) -> Self {
+        self.name = Arc::from(format!("{}{}", self.name, suffix));
+        self
+    }
+
+    /// Gets the fully-qualified name of the metric, including its scope but not its labels.
+    pub fn name(&self) -> &str { &self.name }
+
+    /// Gets the scope the metric was sent from, which is empty for the root scope.
+    pub fn scope(&self) -> &str { &self.name[..self.scope_len] }
+
+    /// Gets the name of the metric within its scope.
+    pub fn local_name(&self) -> &str {
+        if self.scope_len == 0 {
+            &self.name
+        } else {
+            &self.name[self.scope_len + 1..]
+        }
+    }
+
+    /// Gets the labels of the metric, sorted by key.
+    pub fn labels(&self) -> &[(String, String)] { &self.labels }
+}
+
+impl PartialEq for MeasurementKey {
+    fn eq(&self, other: &MeasurementKey) -> bool { self.name == other.name && self.labels == other.labels }
+}
+
+impl Eq for MeasurementKey {}
+
+impl Hash for MeasurementKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+        self.labels.hash(state);
+    }
+}
+
+impl PartialOrd for MeasurementKey {
+    fn partial_cmp(&self, other: &MeasurementKey) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+
+impl Ord for MeasurementKey {
+    fn cmp(&self, other: &MeasurementKey) -> Ordering { (&self.name, &self.labels).cmp(&(&other.name, &other.labels)) }
+}
+
+/// Compares a key with a name in its canonical form, as the key is displayed.
+impl PartialEq<str> for MeasurementKey {
+    fn eq(&self, other: &str) -> bool {
+        if self.labels.is_empty() {
+            return &*self.name == other;
+        }
+
+        other.starts_with(&*self.name) && other[self.name.len()..] == *render_labels(&self.labels)
+    }
+}
+
+impl<'a> From<&'a str> for MeasurementKey {
+    fn from(name: &'a str) -> Self { MeasurementKey::new(name) }
+}
+
+impl From<String> for MeasurementKey {
+    fn from(name: String) -> Self { MeasurementKey::new(name) }
+}
+
+impl fmt::Display for MeasurementKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "{}{}", self.name, render_labels(&self.labels)) }
+}
 
 /// A typed metric measurement, used in snapshots.
 ///
 /// This type provides a way to wrap the value of a metric, for use in a snapshot, while also
 /// providing the overall type of the metric, so that downstream consumers who how to properly
 /// format the data.
+///
+/// Measurements cover different spans of time, depending on their type:
+///
+/// - counters, wide counters, and throughputs are cumulative, covering every update since the metric was first updated,
+///   for as long as the receiver exists or until its facet is removed
+/// - windowed counters and histograms cover the trailing histogram window, as set by
+///   [`Configuration::histogram`](crate::Configuration::histogram)
+/// - counter rates cover the time since the previous snapshot
+/// - gauges are the value as of their last update
+///
+/// A counter can be reported both cumulatively and over the window, via
+/// [`Configuration::include_windowed_counts`](crate::Configuration::include_windowed_counts).
 #[derive(Debug, PartialEq, Eq)]
 pub enum TypedMeasurement {
-    Counter(String, i64),
-    Gauge(String, u64),
-    TimingHistogram(String, SummarizedHistogram),
-    ValueHistogram(String, SummarizedHistogram),
+    /// A counter, holding the sum of every update since the counter was first updated.
+    Counter(MeasurementKey, i64),
+    /// A wide counter, registered via [`Facet::wide_count`](crate::Facet::wide_count).
+    ///
+    /// Exporters which cannot represent a `u128` should saturate the value to the largest value
+    /// they can represent, such as `u64::max_value()`, rather than truncating it.
+    CounterWide(MeasurementKey, u128),
+    /// A windowed counter, registered via [`Facet::windowed_count`](crate::Facet::windowed_count).
+    ///
+    /// Unlike a regular counter, the value is the sum of all updates over the trailing window,
+    /// rather than over the lifetime of the receiver.  With
+    /// [`Configuration::include_windowed_counts`](crate::Configuration::include_windowed_counts),
+    /// every regular counter is also reported as a windowed counter, under the same name.
+    CounterWindowed(MeasurementKey, i64),
+    /// The per-second rate of a counter, enabled via
+    /// [`Configuration::include_rates`](crate::Configuration::include_rates).
+    ///
+    /// This is the change in the value of the counter since the previous snapshot, divided by the
+    /// time since that snapshot, and is reported alongside the counter itself, under the same name.
+    CounterRate(MeasurementKey, Rate),
+    Gauge(MeasurementKey, u64),
+    /// The throughput of a timing histogram, in units per second, registered via
+    /// [`Facet::throughput`](crate::Facet::throughput).
+    ///
+    /// This is the total count of the timings recorded for the metric divided by their total
+    /// duration, and is reported alongside the timing histogram, under the same name.
+    Throughput(MeasurementKey, Rate),
+    TimingHistogram(MeasurementKey, SummarizedHistogram),
+    ValueHistogram(MeasurementKey, SummarizedHistogram),
+}
+
+/// A rate, in units per second, as reported for counter rates and throughput.
+///
+/// Rates compare equal only when their values are bitwise identical, which makes equality an
+/// equivalence relation, so that measurements holding them can implement `Eq`.
+#[derive(Debug, Clone, Copy)]
+pub struct Rate(f64);
+
+impl Rate {
+    /// Creates a rate from a number of units per second.
+    pub fn new(per_second: f64) -> Rate { Rate(per_second) }
+
+    /// Gets the number of units per second.
+    pub fn per_second(self) -> f64 { self.0 }
+}
+
+impl PartialEq for Rate {
+    fn eq(&self, other: &Rate) -> bool { self.0.to_bits() == other.0.to_bits() }
+}
+
+impl Eq for Rate {}
+
+/// The type of a [`TypedMeasurement`], without its name or value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MeasurementKind {
+    Counter,
+    CounterWide,
+    CounterWindowed,
+    CounterRate,
+    Gauge,
+    Throughput,
+    TimingHistogram,
+    ValueHistogram,
+}
+
+impl TypedMeasurement {
+    /// Gets the key of this measurement, holding its name and labels.
+    pub fn key(&self) -> &MeasurementKey {
+        match self {
+            TypedMeasurement::Counter(key, _) => key,
+            TypedMeasurement::CounterWide(key, _) => key,
+            TypedMeasurement::CounterWindowed(key, _) => key,
+            TypedMeasurement::CounterRate(key, _) => key,
+            TypedMeasurement::Gauge(key, _) => key,
+            TypedMeasurement::Throughput(key, _) => key,
+            TypedMeasurement::TimingHistogram(key, _) => key,
+            TypedMeasurement::ValueHistogram(key, _) => key,
+        }
+    }
+
+    /// Gets the name of this measurement, without its labels.
+    pub fn name(&self) -> &str { self.key().name() }
+
+    /// Gets the labels of this measurement, sorted by key.
+    pub fn labels(&self) -> &[(String, String)] { self.key().labels() }
+
+    /// Gets the kind of this measurement.
+    pub fn kind(&self) -> MeasurementKind {
+        match self {
+            TypedMeasurement::Counter(..) => MeasurementKind::Counter,
+            TypedMeasurement::CounterWide(..) => MeasurementKind::CounterWide,
+            TypedMeasurement::CounterWindowed(..) => MeasurementKind::CounterWindowed,
+            TypedMeasurement::CounterRate(..) => MeasurementKind::CounterRate,
+            TypedMeasurement::Gauge(..) => MeasurementKind::Gauge,
+            TypedMeasurement::Throughput(..) => MeasurementKind::Throughput,
+            TypedMeasurement::TimingHistogram(..) => MeasurementKind::TimingHistogram,
+            TypedMeasurement::ValueHistogram(..) => MeasurementKind::ValueHistogram,
+        }
+    }
+
+    /// Gets the value of this measurement if it is a counter.
+    ///
+    /// Windowed counters are included, but wide counters are not, as their value may not fit.
+    pub fn as_counter(&self) -> Option<i64> {
+        match self {
+            TypedMeasurement::Counter(_, value) | TypedMeasurement::CounterWindowed(_, value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Gets the value of this measurement if it is a counter rate.
+    pub fn as_counter_rate(&self) -> Option<f64> {
+        match self {
+            TypedMeasurement::CounterRate(_, value) => Some(value.per_second()),
+            _ => None,
+        }
+    }
+
+    /// Gets the value of this measurement if it is a gauge.
+    pub fn as_gauge(&self) -> Option<u64> {
+        match self {
+            TypedMeasurement::Gauge(_, value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Gets the value of this measurement if it is a throughput.
+    pub fn as_throughput(&self) -> Option<f64> {
+        match self {
+            TypedMeasurement::Throughput(_, value) => Some(value.per_second()),
+            _ => None,
+        }
+    }
+
+    /// Gets the value of this measurement if it is a timing or value histogram.
+    pub fn as_histogram(&self) -> Option<&SummarizedHistogram> {
+        match self {
+            TypedMeasurement::TimingHistogram(_, value) | TypedMeasurement::ValueHistogram(_, value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Prepends the given prefix to the name of this measurement.
+    pub(crate) fn with_prefix(self, prefix: &str) -> Self {
+        if prefix.is_empty() {
+            return self;
+        }
+
+        let prefixed = |key: MeasurementKey| key.with_scope(prefix);
+        match self {
+            TypedMeasurement::Counter(name, value) => TypedMeasurement::Counter(prefixed(name), value),
+            TypedMeasurement::CounterWide(name, value) => TypedMeasurement::CounterWide(prefixed(name), value),
+            TypedMeasurement::CounterWindowed(name, value) => TypedMeasurement::CounterWindowed(prefixed(name), value),
+            TypedMeasurement::CounterRate(name, value) => TypedMeasurement::CounterRate(prefixed(name), value),
+            TypedMeasurement::Gauge(name, value) => TypedMeasurement::Gauge(prefixed(name), value),
+            TypedMeasurement::Throughput(name, value) => TypedMeasurement::Throughput(prefixed(name), value),
+            TypedMeasurement::TimingHistogram(name, value) => TypedMeasurement::TimingHistogram(prefixed(name), value),
+            TypedMeasurement::ValueHistogram(name, value) => TypedMeasurement::ValueHistogram(prefixed(name), value),
+        }
+    }
 }
 
 /// A point-in-time view of metric data.
 #[derive(Default, Debug)]
 pub struct Snapshot {
-    measurements: Vec<TypedMeasurement>,
+    pub(crate) measurements: Vec<TypedMeasurement>,
+    pub(crate) taken_at: Option<(u64, SystemTime)>,
 }
 
 impl Snapshot {
     /// Stores a counter value for the given metric key.
-    pub(crate) fn set_count<T>(&mut self, key: T, value: i64)
+    pub(crate) fn set_count<K>(&mut self, key: K, value: i64)
+    where
+        K: Into<MeasurementKey>,
+    {
+        self.measurements.push(TypedMeasurement::Counter(key.into(), value));
+    }
+
+    /// Stores a wide counter value for the given metric key.
+    pub(crate) fn set_wide_count<K>(&mut self, key: K, value: u128)
+    where
+        K: Into<MeasurementKey>,
+    {
+        self.measurements.push(TypedMeasurement::CounterWide(key.into(), value));
+    }
+
+    /// Stores a windowed counter value for the given metric key.
+    pub(crate) fn set_windowed_count<K>(&mut self, key: K, value: i64)
     where
-        T: Display,
+        K: Into<MeasurementKey>,
     {
         self.measurements
-            .push(TypedMeasurement::Counter(key.to_string(), value));
+            .push(TypedMeasurement::CounterWindowed(key.into(), value));
+    }
+
+    /// Stores a counter rate, in units per second, for the given metric key.
+    pub(crate) fn set_counter_rate<K>(&mut self, key: K, value: f64)
+    where
+        K: Into<MeasurementKey>,
+    {
+        self.measurements
+            .push(TypedMeasurement::CounterRate(key.into(), Rate::new(value)));
     }
 
     /// Stores a gauge value for the given metric key.
-    pub(crate) fn set_gauge<T>(&mut self, key: T, value: u64)
+    pub(crate) fn set_gauge<K>(&mut self, key: K, value: u64)
     where
-        T: Display,
+        K: Into<MeasurementKey>,
     {
-        self.measurements.push(TypedMeasurement::Gauge(key.to_string(), value));
+        self.measurements.push(TypedMeasurement::Gauge(key.into(), value));
     }
 
-    /// Sets timing percentiles for the given metric key.
-    ///
-    /// From the given `HdrHistogram`, all the specific `percentiles` will be extracted and stored.
-    pub(crate) fn set_timing_histogram<T>(&mut self, key: T, h: HistogramSnapshot, percentiles: &[Percentile])
+    /// Stores a throughput, in units per second, for the given metric key.
+    pub(crate) fn set_throughput<K>(&mut self, key: K, value: f64)
     where
-        T: Display,
+        K: Into<MeasurementKey>,
     {
-        let summarized = SummarizedHistogram::from_histogram(h, percentiles);
         self.measurements
-            .push(TypedMeasurement::TimingHistogram(key.to_string(), summarized));
+            .push(TypedMeasurement::Throughput(key.into(), Rate::new(value)));
     }
 
-    /// Sets value percentiles for the given metric key.
-    ///
-    /// From the given `HdrHistogram`, all the specific `percentiles` will be extracted and stored.
-    pub(crate) fn set_value_histogram<T>(&mut self, key: T, h: HistogramSnapshot, percentiles: &[Percentile])
+    /// Stores a summarized timing histogram for the given metric key.
+    pub(crate) fn set_timing_histogram<K>(&mut self, key: K, summarized: SummarizedHistogram)
     where
-        T: Display,
+        K: Into<MeasurementKey>,
     {
-        let summarized = SummarizedHistogram::from_histogram(h, percentiles);
         self.measurements
-            .push(TypedMeasurement::ValueHistogram(key.to_string(), summarized));
+            .push(TypedMeasurement::TimingHistogram(key.into(), summarized));
+    }
+
+    /// Stores a summarized value histogram for the given metric key.
+    pub(crate) fn set_value_histogram<K>(&mut self, key: K, summarized: SummarizedHistogram)
+    where
+        K: Into<MeasurementKey>,
+    {
+        self.measurements
+            .push(TypedMeasurement::ValueHistogram(key.into(), summarized));
+    }
+
+    /// Merges the measurements of another [`Snapshot`] into this one.
+    ///
+    /// If `prefix` is not empty, it is prepended to the name of every merged measurement, using `.`
+    /// as the separator, in the same way that sink scopes are joined, and becomes part of its
+    /// [`scope`](MeasurementKey::scope).  Measurements are otherwise merged as-is, so merging two
+    /// snapshots that share metric names, without distinct prefixes, will result in duplicate
+    /// names.
+    pub fn merge_prefixed(&mut self, prefix: &str, other: Snapshot) {
+        self.measurements
+            .extend(other.measurements.into_iter().map(|m| m.with_prefix(prefix)));
+    }
+
+    /// Keeps only the measurements of the given kind, dropping the rest.
+    ///
+    /// This is useful for outputs that only handle one kind of metric, such as histograms for
+    /// latency objectives, as the measurements they don't handle are dropped without being rendered
+    /// or converted.  It composes with [`merge_prefixed`](Snapshot::merge_prefixed), to export a
+    /// single kind of metric from several snapshots.
+    pub fn filter_kind(self, kind: MeasurementKind) -> Snapshot { self.filter_kinds(&[kind]) }
+
+    /// Keeps only the measurements of the given kinds, dropping the rest.
+    ///
+    /// See [`filter_kind`](Snapshot::filter_kind).
+    pub fn filter_kinds(mut self, kinds: &[MeasurementKind]) -> Snapshot {
+        self.measurements.retain(|m| kinds.contains(&m.kind()));
+        self
+    }
+
+    /// Gets when this snapshot was taken, according to the receiver's clock, in nanoseconds.
+    ///
+    /// This is on the same timescale as [`Clock::now`](quanta::Clock::now) for the clock of any
+    /// sink bound to the receiver, via [`Sink::clock`](crate::Sink::clock): the calibrated
+    /// reference time, which counts nanoseconds from an arbitrary point, such as when the machine
+    /// booted, and is unrelated to the wall clock.  Raw values from `start` and `end` are not on
+    /// this timescale.  Returns `None` for a snapshot that wasn't taken by a receiver.
+    pub fn clock_time(&self) -> Option<u64> { self.taken_at.map(|(clock, _)| clock) }
+
+    /// Gets when this snapshot was taken, according to the wall clock.
+    ///
+    /// This is read at the same time as [`clock_time`](Snapshot::clock_time), so the two together
+    /// line up the receiver's clock with the wall clock.  Returns `None` for a snapshot that wasn't
+    /// taken by a receiver.
+    pub fn wall_time(&self) -> Option<SystemTime> { self.taken_at.map(|(_, wall)| wall) }
+
+    /// Converts a reading of the receiver's clock, in nanoseconds, into the wall-clock time it
+    /// corresponds to.
+    ///
+    /// This is useful for lining up events timed with the receiver's clock, such as the start of a
+    /// slow request, with logs and other systems that use the wall clock.  The receiver's clock is
+    /// monotonic, while the wall clock can be stepped or slewed, such as by NTP, so the conversion
+    /// is exact as of when the snapshot was taken, and drifts the further the reading is from then.
+    /// A recent snapshot gives the best results.  Returns `None` for a snapshot that wasn't taken by
+    /// a receiver.
+    pub fn clock_to_wall(&self, clock_time: u64) -> Option<SystemTime> {
+        self.taken_at.map(|(clock, wall)| {
+            if clock_time >= clock {
+                wall + Duration::from_nanos(clock_time - clock)
+            } else {
+                wall - Duration::from_nanos(clock - clock_time)
+            }
+        })
+    }
+
+    /// Gets an iterator over the measurements of the given kind, without consuming the snapshot.
+    pub fn iter_kind(&self, kind: MeasurementKind) -> impl Iterator<Item = &TypedMeasurement> {
+        self.measurements.iter().filter(move |m| m.kind() == kind)
+    }
+
+    /// Sorts the measurements in this snapshot by name, and then by kind.
+    ///
+    /// Measurements are otherwise in no particular order, which can vary from one snapshot to the
+    /// next.  Sorting them gives a deterministic order, for output that's compared or diffed.
+    pub fn sort(&mut self) { self.measurements.sort_by(|a, b| sort_key(a).cmp(&sort_key(b))); }
+
+    /// Gets references to the measurements in this snapshot, in the order of [`sort`](Snapshot::sort).
+    fn sorted(&self) -> Vec<&TypedMeasurement> {
+        let mut measurements = self.measurements.iter().collect::<Vec<_>>();
+        measurements.sort_by(|a, b| sort_key(a).cmp(&sort_key(b)));
+        measurements
     }
 
     /// Converts this [`Snapshot`] into [`SimpleSnapshot`].
@@ -72,16 +506,69 @@ impl Snapshot {
     pub fn into_vec(self) -> Vec<TypedMeasurement> { self.measurements }
 }
 
+impl IntoIterator for Snapshot {
+    type IntoIter = vec::IntoIter<TypedMeasurement>;
+    type Item = TypedMeasurement;
+
+    fn into_iter(self) -> Self::IntoIter { self.measurements.into_iter() }
+}
+
+/// Compares the measurements of two snapshots, regardless of their order.
+///
+/// Only the measurements themselves are compared, not how the snapshots were configured to
+/// summarize histograms.
+impl PartialEq for Snapshot {
+    fn eq(&self, other: &Snapshot) -> bool {
+        if self.measurements.len() != other.measurements.len() {
+            return false;
+        }
+
+        let (ours, theirs) = (self.sorted(), other.sorted());
+
+        // Measurements with the same name and kind, such as those merged from different snapshots
+        // without a prefix, can be in either order, so each run of them is matched up as a whole.
+        let mut start = 0;
+        while start < ours.len() {
+            let key = sort_key(ours[start]);
+            let end = start + ours[start..].iter().take_while(|m| sort_key(m) == key).count();
+            if theirs[start..end].iter().any(|m| sort_key(m) != key) {
+                return false;
+            }
+
+            let mut unmatched = theirs[start..end].to_vec();
+            for measurement in &ours[start..end] {
+                match unmatched.iter().position(|m| m == measurement) {
+                    Some(i) => {
+                        let _ = unmatched.swap_remove(i);
+                    },
+                    None => return false,
+                }
+            }
+            start = end;
+        }
+
+        true
+    }
+}
+
+fn sort_key(measurement: &TypedMeasurement) -> (&MeasurementKey, MeasurementKind) {
+    (measurement.key(), measurement.kind())
+}
+
 /// A user-friendly metric snapshot that allows easy retrieval of values.
 ///
 /// This is good for programmatic exploration of values, whereas [`Snapshot`] is designed around
 /// being consumed by output adapters that send metrics to external collection systems.
-#[derive(Default)]
+#[derive(Default, Debug, PartialEq)]
 pub struct SimpleSnapshot {
-    pub(crate) counters: HashMap<String, i64>,
-    pub(crate) gauges: HashMap<String, u64>,
-    pub(crate) timings: HashMap<String, SummarizedHistogram>,
-    pub(crate) values: HashMap<String, SummarizedHistogram>,
+    pub(crate) counters: HashMap<MeasurementKey, i64>,
+    pub(crate) wide_counters: HashMap<MeasurementKey, u128>,
+    pub(crate) windowed_counters: HashMap<MeasurementKey, i64>,
+    pub(crate) counter_rates: HashMap<MeasurementKey, f64>,
+    pub(crate) gauges: HashMap<MeasurementKey, u64>,
+    pub(crate) throughputs: HashMap<MeasurementKey, f64>,
+    pub(crate) timings: HashMap<MeasurementKey, SummarizedHistogram>,
+    pub(crate) values: HashMap<MeasurementKey, SummarizedHistogram>,
 }
 
 impl SimpleSnapshot {
@@ -92,9 +579,21 @@ impl SimpleSnapshot {
                 TypedMeasurement::Counter(key, value) => {
                     ss.counters.insert(key, value);
                 },
+                TypedMeasurement::CounterWide(key, value) => {
+                    ss.wide_counters.insert(key, value);
+                },
+                TypedMeasurement::CounterWindowed(key, value) => {
+                    ss.windowed_counters.insert(key, value);
+                },
+                TypedMeasurement::CounterRate(key, value) => {
+                    ss.counter_rates.insert(key, value.per_second());
+                },
                 TypedMeasurement::Gauge(key, value) => {
                     ss.gauges.insert(key, value);
                 },
+                TypedMeasurement::Throughput(key, value) => {
+                    ss.throughputs.insert(key, value.per_second());
+                },
                 TypedMeasurement::TimingHistogram(key, value) => {
                     ss.timings.insert(key, value);
                 },
@@ -106,33 +605,201 @@ impl SimpleSnapshot {
         ss
     }
 
+    /// Converts this [`SimpleSnapshot`] back into a [`Snapshot`].
+    ///
+    /// This is the inverse of [`Snapshot::into_simple`], so that a snapshot can be inspected as a
+    /// [`SimpleSnapshot`] and then handed on to anything that consumes a [`Snapshot`], such as an
+    /// exporter.  The measurements are sorted, as with [`Snapshot::sort`], so the order is
+    /// deterministic.
+    pub fn into_snapshot(self) -> Snapshot {
+        let mut measurements = Vec::new();
+        measurements.extend(self.counters.into_iter().map(|(k, v)| TypedMeasurement::Counter(k, v)));
+        measurements.extend(
+            self.wide_counters
+                .into_iter()
+                .map(|(k, v)| TypedMeasurement::CounterWide(k, v)),
+        );
+        measurements.extend(
+            self.windowed_counters
+                .into_iter()
+                .map(|(k, v)| TypedMeasurement::CounterWindowed(k, v)),
+        );
+        measurements.extend(
+            self.counter_rates
+                .into_iter()
+                .map(|(k, v)| TypedMeasurement::CounterRate(k, Rate::new(v))),
+        );
+        measurements.extend(self.gauges.into_iter().map(|(k, v)| TypedMeasurement::Gauge(k, v)));
+        measurements.extend(
+            self.throughputs
+                .into_iter()
+                .map(|(k, v)| TypedMeasurement::Throughput(k, Rate::new(v))),
+        );
+        measurements.extend(
+            self.timings
+                .into_iter()
+                .map(|(k, v)| TypedMeasurement::TimingHistogram(k, v)),
+        );
+        measurements.extend(
+            self.values
+                .into_iter()
+                .map(|(k, v)| TypedMeasurement::ValueHistogram(k, v)),
+        );
+
+        let mut snapshot = Snapshot {
+            measurements,
+            ..Snapshot::default()
+        };
+        snapshot.sort();
+        snapshot
+    }
+
     /// Gets the counter value for the given metric key.
     ///
     /// Returns `None` if the metric key has no counter value in this snapshot.
-    pub fn count(&self, key: &str) -> Option<i64> { self.counters.get(key).cloned() }
+    pub fn count(&self, key: &str) -> Option<i64> { self.counters.get(&MeasurementKey::new(key)).cloned() }
+
+    /// Gets the wide counter value for the given metric key.
+    ///
+    /// Returns `None` if the metric key has no wide counter value in this snapshot.
+    pub fn wide_count(&self, key: &str) -> Option<u128> { self.wide_counters.get(&MeasurementKey::new(key)).cloned() }
+
+    /// Gets the windowed counter value for the given metric key.
+    ///
+    /// Returns `None` if the metric key has no windowed counter value in this snapshot.
+    pub fn windowed_count(&self, key: &str) -> Option<i64> {
+        self.windowed_counters.get(&MeasurementKey::new(key)).cloned()
+    }
+
+    /// Gets the per-second rate of the counter with the given metric key.
+    ///
+    /// Returns `None` if the metric key has no counter rate in this snapshot.
+    pub fn counter_rate(&self, key: &str) -> Option<f64> { self.counter_rates.get(&MeasurementKey::new(key)).cloned() }
 
     /// Gets the gauge value for the given metric key.
     ///
     /// Returns `None` if the metric key has no gauge value in this snapshot.
-    pub fn gauge(&self, key: &str) -> Option<u64> { self.gauges.get(key).cloned() }
+    pub fn gauge(&self, key: &str) -> Option<u64> { self.gauges.get(&MeasurementKey::new(key)).cloned() }
+
+    /// Gets the throughput, in units per second, for the given metric key.
+    ///
+    /// Returns `None` if the metric key has no throughput in this snapshot.
+    pub fn throughput(&self, key: &str) -> Option<f64> { self.throughputs.get(&MeasurementKey::new(key)).cloned() }
 
     /// Gets the given timing percentile for given metric key.
     ///
+    /// The value is converted from the unit that the timing histogram stores its values in, and
+    /// displays itself in a readable unit.  Use
+    /// [`timing_histogram_raw`](SimpleSnapshot::timing_histogram_raw) to get the value as stored.
+    ///
     /// Returns `None` if the metric key has no value at the given percentile in this snapshot.
-    pub fn timing_histogram(&self, key: &str, percentile: f64) -> Option<u64> {
+    pub fn timing_histogram(&self, key: &str, percentile: f64) -> Option<Timing> {
+        timing_percentile(self.timings.get(&MeasurementKey::new(key)), percentile)
+    }
+
+    /// Gets the given timing percentile for the given metric key, as stored.
+    ///
+    /// The value is in the unit of the timing histogram, as set via
+    /// [`Facet::unit`](crate::Facet::unit), which is nanoseconds by default.
+    ///
+    /// Returns `None` if the metric key has no value at the given percentile in this snapshot.
+    pub fn timing_histogram_raw(&self, key: &str, percentile: f64) -> Option<u64> {
         let p = Percentile::from(percentile);
-        self.timings.get(key).and_then(|s| s.measurements().get(&p)).cloned()
+        self.timings
+            .get(&MeasurementKey::new(key))
+            .and_then(|s| s.measurements().get(&p))
+            .cloned()
+    }
+
+    /// Gets the given timing percentile for the given metric key, as a [`Duration`].
+    ///
+    /// The value is converted from the unit that the timing histogram stores its values in, so
+    /// this is correct whatever unit was set via [`Facet::unit`](crate::Facet::unit).
+    ///
+    /// Returns `None` if the metric key has no value at the given percentile in this snapshot.
+    pub fn timing_histogram_duration(&self, key: &str, percentile: f64) -> Option<Duration> {
+        let p = Percentile::from(percentile);
+        self.timings.get(&MeasurementKey::new(key)).and_then(|s| {
+            let unit = s.unit().unwrap_or_default();
+            s.measurements().get(&p).map(|value| unit.to_duration(*value))
+        })
+    }
+
+    /// Gets the number of observations in the timing histogram for the given metric key.
+    ///
+    /// Returns `None` if the metric key has no timing histogram in this snapshot.
+    pub fn timing_count(&self, key: &str) -> Option<u64> {
+        self.timings.get(&MeasurementKey::new(key)).map(|s| s.count())
     }
 
     /// Gets the given value percentile for the given metric key.
     ///
     /// Returns `None` if the metric key has no value at the given percentile in this snapshot.
     pub fn value_histogram(&self, key: &str, percentile: f64) -> Option<u64> {
-        let p = Percentile::from(percentile);
-        self.values.get(key).and_then(|s| s.measurements().get(&p)).cloned()
+        value_percentile(self.values.get(&MeasurementKey::new(key)), percentile)
+    }
+
+    /// Gets the number of observations in the value histogram for the given metric key.
+    ///
+    /// Returns `None` if the metric key has no value histogram in this snapshot.
+    pub fn value_count(&self, key: &str) -> Option<u64> {
+        self.values.get(&MeasurementKey::new(key)).map(|s| s.count())
+    }
+
+    /// Gets the counter value for the given metric key with the given labels.
+    ///
+    /// The labels must match the labels the metric was sent with, as set via
+    /// [`Sink::with_labels`](crate::Sink::with_labels), exactly, although in any order.
+    ///
+    /// Returns `None` if the metric key has no counter value with those labels in this snapshot.
+    pub fn count_labeled(&self, key: &str, labels: &[(&str, &str)]) -> Option<i64> {
+        self.counters.get(&labeled_key(key, labels)).cloned()
+    }
+
+    /// Gets the gauge value for the given metric key with the given labels.
+    ///
+    /// Labels are matched as for [`count_labeled`](SimpleSnapshot::count_labeled).
+    pub fn gauge_labeled(&self, key: &str, labels: &[(&str, &str)]) -> Option<u64> {
+        self.gauges.get(&labeled_key(key, labels)).cloned()
+    }
+
+    /// Gets the given timing percentile for the given metric key with the given labels.
+    ///
+    /// Labels are matched as for [`count_labeled`](SimpleSnapshot::count_labeled).
+    pub fn timing_histogram_labeled(&self, key: &str, labels: &[(&str, &str)], percentile: f64) -> Option<Timing> {
+        timing_percentile(self.timings.get(&labeled_key(key, labels)), percentile)
+    }
+
+    /// Gets the given value percentile for the given metric key with the given labels.
+    ///
+    /// Labels are matched as for [`count_labeled`](SimpleSnapshot::count_labeled).
+    pub fn value_histogram_labeled(&self, key: &str, labels: &[(&str, &str)], percentile: f64) -> Option<u64> {
+        value_percentile(self.values.get(&labeled_key(key, labels)), percentile)
     }
 }
 
+/// Builds the key of a metric with the given labels.
+fn labeled_key(key: &str, labels: &[(&str, &str)]) -> MeasurementKey {
+    MeasurementKey::new(key).with_labels(labels.iter().cloned())
+}
+
+/// Gets the given percentile of a timing histogram, converted from the unit of the histogram.
+fn timing_percentile(summary: Option<&SummarizedHistogram>, percentile: f64) -> Option<Timing> {
+    let p = Percentile::from(percentile);
+    summary.and_then(|s| {
+        let unit = s.unit().unwrap_or_default();
+        s.measurements()
+            .get(&p)
+            .map(|value| Timing::from_nanos(unit.to_nanos(*value)))
+    })
+}
+
+/// Gets the given percentile of a value histogram.
+fn value_percentile(summary: Option<&SummarizedHistogram>, percentile: f64) -> Option<u64> {
+    let p = Percentile::from(percentile);
+    summary.and_then(|s| s.measurements().get(&p)).cloned()
+}
+
 /// A pre-summarized histogram.
 ///
 /// Based on the configuration of the [`Receiver`], this histogram will represent only the
@@ -142,41 +809,358 @@ impl SimpleSnapshot {
 pub struct SummarizedHistogram {
     count: u64,
     sum: u64,
+    rejected: u64,
+    clamped: u64,
+    covered: Duration,
+    memory_usage: usize,
+    unit: Option<TimeUnit>,
     measurements: HashMap<Percentile, u64>,
+    exemplars: HashMap<Percentile, Exemplar>,
+    recorded: Option<Vec<(u64, u64)>>,
+    sparse: bool,
+    buckets: Vec<(u64, u64)>,
 }
 
 impl SummarizedHistogram {
-    pub(crate) fn from_histogram(histogram: HistogramSnapshot, percentiles: &[Percentile]) -> Self {
+    /// Extracts the given percentiles from a histogram, interpolating them between recorded values
+    /// if `interpolate` is set, and keeping its recorded values if `raw` is set.
+    pub(crate) fn from_histogram(
+        histogram: &HistogramSnapshot, percentiles: &[Percentile], raw: bool, interpolate: bool,
+    ) -> Self {
         let mut measurements = HashMap::default();
         let count = histogram.count();
         let sum = histogram.sum();
+        let rejected = histogram.rejected();
+        let clamped = histogram.clamped();
+        let covered = histogram.covered();
+        let memory_usage = histogram.memory_usage();
 
         for percentile in percentiles {
-            let value = histogram.histogram().value_at_percentile(percentile.value);
+            let value = if interpolate {
+                histogram.interpolated_value_at_percentile(percentile.value)
+            } else {
+                histogram.value_at_percentile(percentile.value)
+            };
             measurements.insert(percentile.clone(), value);
         }
 
+        let exemplars = percentile_exemplars(&measurements, histogram.exemplars());
+        let recorded = if raw { Some(histogram.recorded()) } else { None };
+
         SummarizedHistogram {
             count,
             sum,
+            rejected,
+            clamped,
+            covered,
+            memory_usage,
+            unit: None,
             measurements,
+            exemplars,
+            recorded,
+            sparse: false,
+            buckets: Vec::new(),
         }
     }
 
+    /// Labels the histogram with the unit its timings were recorded in.
+    pub(crate) fn with_unit(mut self, unit: TimeUnit) -> Self {
+        self.unit = Some(unit);
+        self
+    }
+
+    /// Counts the values in `histogram` at or below each of the given bucket boundaries.
+    pub(crate) fn with_buckets(mut self, histogram: &HistogramSnapshot, bounds: &[u64]) -> Self {
+        self.buckets = bounds
+            .iter()
+            .map(|bound| (*bound, histogram.count_at_or_below(*bound)))
+            .collect();
+        self
+    }
+
+    /// Marks the histogram as sparse if it has fewer than `threshold` values.
+    pub(crate) fn with_sparse_threshold(mut self, threshold: u64) -> Self {
+        self.sparse = self.count < threshold;
+        self
+    }
+
     /// Gets the total count of measurements present in the underlying histogram.
     pub fn count(&self) -> u64 { self.count }
 
+    /// Whether or not the histogram had too few values in its window for its percentiles to be
+    /// meaningful.
+    ///
+    /// A percentile like p99.9 says little about a handful of values, so a histogram is sparse when
+    /// its [`count`](SummarizedHistogram::count) is below the threshold set via
+    /// [`Configuration::sparse_histogram_threshold`](crate::Configuration::sparse_histogram_threshold).
+    /// Without a threshold, histograms are never sparse.
+    pub fn is_sparse(&self) -> bool { self.sparse }
+
     /// Gets the total sum of the measurements recorded in the underlying histogram.
     pub fn sum(&self) -> u64 { self.sum }
 
+    /// Gets the number of values rejected by the underlying histogram.
+    ///
+    /// Values are only rejected when a threshold has been set via
+    /// [`Facet::reject_above`](crate::Facet::reject_above).  Rejected values are not part of the
+    /// count, sum, or percentiles.  Unlike those, this count is not windowed: it accumulates for as
+    /// long as the histogram exists.
+    pub fn rejected(&self) -> u64 { self.rejected }
+
+    /// Gets the number of values which were above the maximum the underlying histogram can track,
+    /// and so were recorded as the maximum instead.
+    ///
+    /// The maximum is set via
+    /// [`Configuration::histogram_max_value`](crate::Configuration::histogram_max_value), or per
+    /// histogram via [`Facet::max_value`](crate::Facet::max_value).  Clamped values are part of the
+    /// count and percentiles, but their sum is exact.  Like
+    /// [`rejected`](SummarizedHistogram::rejected), this count is not windowed.
+    ///
+    /// Values are only ever clamped down: there's no lower bound, and zero is recorded as zero.
+    /// Timings which round to zero show up as such in the percentiles, rather than as the smallest
+    /// value the histogram can otherwise distinguish.
+    pub fn clamped(&self) -> u64 { self.clamped }
+
+    /// Gets how much time the values in the underlying histogram cover.
+    ///
+    /// This is the time from when the oldest bucket in the histogram window started collecting
+    /// values until the snapshot was taken, which is the period the count and percentiles actually
+    /// represent.  Buckets roll over during the receiver's periodic upkeep, rather than at exact
+    /// intervals, so this usually runs a little over the configured window, by up to the upkeep
+    /// interval.  It's shorter than the window for a histogram that hasn't been around for a full
+    /// window yet, or that was reset.
+    pub fn covered(&self) -> Duration { self.covered }
+
+    /// Gets the approximate number of bytes used by the underlying histogram.
+    ///
+    /// This covers the storage for every bucket in the histogram window, and is useful for tracking
+    /// down histograms which are using more memory than expected.
+    pub fn memory_usage(&self) -> usize { self.memory_usage }
+
+    /// Gets the unit of the values in this histogram.
+    ///
+    /// Returns `Some(unit)` for timing histograms, and `None` for value histograms, whose values
+    /// have no inherent unit.
+    pub fn unit(&self) -> Option<TimeUnit> { self.unit }
+
     /// Gets the map of percentile/value pairs extracted from the underlying histogram.
     pub fn measurements(&self) -> &HashMap<Percentile, u64> { &self.measurements }
+
+    /// Gets the exemplars attached to the extracted percentiles.
+    ///
+    /// Exemplars are attached to values with
+    /// [`Sink::update_timing_with_exemplar`](crate::Sink::update_timing_with_exemplar) or
+    /// [`Sink::update_value_with_exemplar`](crate::Sink::update_value_with_exemplar).  Each
+    /// percentile covers the values above the next lowest percentile, up to its own value, and is
+    /// paired with the most recent exemplar attached to a value in that range, if there is one.
+    /// Only the most recent exemplar is kept for values of roughly the same magnitude, within a
+    /// factor of two of each other, and exemplars age out of the histogram window with their values.
+    pub fn exemplars(&self) -> &HashMap<Percentile, Exemplar> { &self.exemplars }
+
+    /// Gets the extracted percentiles, each paired with the total count of the histogram.
+    ///
+    /// Each entry is a `(label, value, count)` tuple, sorted by percentile, where `count` is the
+    /// same as [`count`](SummarizedHistogram::count).  Keeping the count alongside each value makes
+    /// it easy to weight percentiles by sample count when merging histograms from multiple sources.
+    pub fn measurements_with_count(&self) -> Vec<(&str, u64, u64)> {
+        let mut measurements = self.measurements.iter().collect::<Vec<_>>();
+        measurements.sort_by(|a, b| a.0.value.partial_cmp(&b.0.value).unwrap_or(Ordering::Equal));
+        measurements
+            .into_iter()
+            .map(|(percentile, value)| (percentile.label(), *value, self.count))
+            .collect()
+    }
+
+    /// Gets the cumulative count of values at or below each bucket boundary.
+    ///
+    /// Each entry is a `(bound, count)` pair, in ascending order of boundary, where `count` is the
+    /// number of values in the histogram less than or equal to `bound`, as in a Prometheus
+    /// histogram's `le` buckets.  The final, unbounded bucket is
+    /// [`count`](SummarizedHistogram::count).  Counts are subject to the same three significant
+    /// digits of precision as the histogram itself.
+    ///
+    /// Boundaries are set per metric via
+    /// [`Configuration::histogram_buckets`](crate::Configuration::histogram_buckets).  Timing
+    /// histograms without configured boundaries use a default ladder from 5 milliseconds up to 10
+    /// seconds, in the unit of the histogram, while value histograms without configured boundaries
+    /// have no buckets.
+    pub fn buckets(&self) -> &[(u64, u64)] { &self.buckets }
+
+    /// Gets the raw values recorded in the underlying histogram, along with their counts.
+    ///
+    /// Each entry is a `(value, count)` pair, in ascending order of value, where the value is the
+    /// highest value equivalent to the bucket it was recorded in.  This allows building custom
+    /// aggregations, such as heatmaps, from the actual data instead of the extracted percentiles.
+    /// For histograms backed by a sketch, as set by [`Facet::sketch`](crate::Facet::sketch), each
+    /// entry is instead a centroid of the sketch: its mean, rounded, and the number of values in it.
+    ///
+    /// Returns `None` unless raw histograms were enabled via
+    /// [`Configuration::raw_histograms`](crate::Configuration::raw_histograms).
+    pub fn recorded(&self) -> Option<&[(u64, u64)]> { self.recorded.as_ref().map(Vec::as_slice) }
+}
+
+/// An exemplar attached to a value recorded in a histogram, such as the ID of the trace it was
+/// measured in.
+///
+/// Exemplars link a metric to an individual event that contributed to it, so that a latency spike
+/// in a percentile can be traced back to a request that saw it, as with OpenMetrics exemplars.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exemplar {
+    pub(crate) label: String,
+    pub(crate) value: u64,
+    pub(crate) timestamp: SystemTime,
+}
+
+impl Exemplar {
+    /// Gets the exemplar itself, as given when the value was recorded.
+    pub fn label(&self) -> &str { &self.label }
+
+    /// Gets the value the exemplar was attached to.
+    ///
+    /// For timing histograms, this is in the unit of the histogram.
+    pub fn value(&self) -> u64 { self.value }
+
+    /// Gets the time at which the value was recorded.
+    pub fn timestamp(&self) -> SystemTime { self.timestamp }
+}
+
+/// Pairs each percentile with the most recent exemplar between its value and the value of the next
+/// lowest percentile.
+///
+/// `exemplars` must be in ascending order of value.
+fn percentile_exemplars(
+    measurements: &HashMap<Percentile, u64>, exemplars: &[Exemplar],
+) -> HashMap<Percentile, Exemplar> {
+    let mut paired = HashMap::new();
+    if exemplars.is_empty() {
+        return paired;
+    }
+
+    let mut percentiles = measurements.iter().collect::<Vec<_>>();
+    percentiles.sort_by(|a, b| a.0.value.partial_cmp(&b.0.value).unwrap_or(Ordering::Equal));
+
+    let mut start = 0;
+    for (percentile, value) in percentiles {
+        let end = start + exemplars[start..].iter().take_while(|e| e.value <= *value).count();
+        if let Some(exemplar) = exemplars[start..end].iter().max_by_key(|e| e.timestamp) {
+            let _ = paired.insert(percentile.clone(), exemplar.clone());
+        }
+        start = end;
+    }
+    paired
+}
+
+/// Bucket boundaries for histograms, by metric name.
+///
+/// The default boundaries for timing histograms are converted to each unit up front, so that
+/// looking them up doesn't allocate.
+#[derive(Debug, Clone)]
+pub(crate) struct HistogramBuckets {
+    bounds: HashMap<String, Vec<u64>>,
+    nanoseconds: Vec<u64>,
+    microseconds: Vec<u64>,
+    milliseconds: Vec<u64>,
+}
+
+impl Default for HistogramBuckets {
+    fn default() -> Self {
+        HistogramBuckets {
+            bounds: HashMap::new(),
+            nanoseconds: default_timing_buckets(TimeUnit::Nanoseconds),
+            microseconds: default_timing_buckets(TimeUnit::Microseconds),
+            milliseconds: default_timing_buckets(TimeUnit::Milliseconds),
+        }
+    }
+}
+
+impl HistogramBuckets {
+    pub fn set(&mut self, name: String, mut bounds: Vec<u64>) {
+        bounds.sort();
+        bounds.dedup();
+        let _ = self.bounds.insert(name, bounds);
+    }
+
+    /// Gets the bucket boundaries for the given histogram, falling back to the default boundaries
+    /// for timing histograms.
+    pub fn bounds(&self, name: &str, unit: Option<TimeUnit>) -> &[u64] {
+        if let Some(bounds) = self.bounds.get(name) {
+            return bounds;
+        }
+
+        match unit {
+            Some(TimeUnit::Nanoseconds) => &self.nanoseconds,
+            Some(TimeUnit::Microseconds) => &self.microseconds,
+            Some(TimeUnit::Milliseconds) => &self.milliseconds,
+            None => &[],
+        }
+    }
+}
+
+fn default_timing_buckets(unit: TimeUnit) -> Vec<u64> {
+    let mut bounds = DEFAULT_TIMING_BUCKETS
+        .iter()
+        .map(|bound| unit.from_nanos(*bound))
+        .collect::<Vec<_>>();
+    bounds.dedup();
+    bounds
+}
+
+impl fmt::Display for TypedMeasurement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TypedMeasurement::Counter(name, value) => write!(f, "counter {}: {}", name, value),
+            TypedMeasurement::CounterWide(name, value) => write!(f, "wide counter {}: {}", name, value),
+            TypedMeasurement::CounterWindowed(name, value) => write!(f, "windowed counter {}: {}", name, value),
+            TypedMeasurement::CounterRate(name, value) => write!(f, "counter rate {}: {}/s", name, value.per_second()),
+            TypedMeasurement::Gauge(name, value) => write!(f, "gauge {}: {}", name, value),
+            TypedMeasurement::Throughput(name, value) => write!(f, "throughput {}: {}/s", name, value.per_second()),
+            TypedMeasurement::TimingHistogram(name, value) => write!(f, "timing histogram {}: {}", name, value),
+            TypedMeasurement::ValueHistogram(name, value) => write!(f, "value histogram {}: {}", name, value),
+        }
+    }
+}
+
+impl fmt::Display for SummarizedHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "count={} sum={}", self.count, self.sum)?;
+        if let Some(unit) = self.unit {
+            write!(f, " unit={}", unit.label())?;
+        }
+
+        for (label, value, _) in self.measurements_with_count() {
+            write!(f, " {}={}", label, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Displays one measurement per line, sorted, so that the output is deterministic.
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut lines = self.measurements.iter().map(|m| m.to_string()).collect::<Vec<_>>();
+        lines.sort();
+        for line in lines {
+            writeln!(f, "{}", line)?;
+        }
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{HistogramSnapshot, Percentile, Snapshot, TypedMeasurement};
+    use super::{
+        HistogramBuckets, HistogramSnapshot, MeasurementKey, MeasurementKind, Percentile, Rate, Snapshot,
+        SummarizedHistogram, TimeUnit, Timing, TypedMeasurement,
+    };
+    use crate::data::PercentileParseError;
     use hdrhistogram::Histogram;
+    use std::time::Duration;
+
+    fn summarize(histogram: Histogram<u64>, sum: u64, percentiles: &[Percentile]) -> SummarizedHistogram {
+        SummarizedHistogram::from_histogram(&HistogramSnapshot::new(histogram, sum), percentiles, false, false)
+    }
 
     #[test]
     fn test_snapshot_simple_set_and_get() {
@@ -187,8 +1171,447 @@ mod tests {
 
         let values = snapshot.into_vec();
 
-        assert_eq!(values[0], TypedMeasurement::Counter("ok".to_owned(), 1));
-        assert_eq!(values[1], TypedMeasurement::Gauge("ok".to_owned(), 42));
+        assert_eq!(values[0], TypedMeasurement::Counter("ok".into(), 1));
+        assert_eq!(values[1], TypedMeasurement::Gauge("ok".into(), 42));
+    }
+
+    #[test]
+    fn test_simple_snapshot_histogram_counts() {
+        let mut snapshot = Snapshot::default();
+        let mut h1 = Histogram::<u64>::new_with_bounds(1, u64::max_value(), 3).unwrap();
+        h1.saturating_record(500_000);
+        h1.saturating_record(750_000);
+        let mut h2 = Histogram::<u64>::new_with_bounds(1, u64::max_value(), 3).unwrap();
+        h2.saturating_record(4096);
+
+        let percentiles = [Percentile::from(50.0)];
+        snapshot.set_timing_histogram(
+            "ok",
+            summarize(h1, 1_250_000, &percentiles).with_unit(TimeUnit::Nanoseconds),
+        );
+        snapshot.set_value_histogram("buf_size", summarize(h2, 4096, &percentiles));
+
+        let simple = snapshot.into_simple();
+        assert_eq!(simple.timing_count("ok"), Some(2));
+        assert_eq!(simple.value_count("buf_size"), Some(1));
+        assert_eq!(simple.timing_count("buf_size"), None);
+        assert_eq!(simple.value_count("ok"), None);
+    }
+
+    #[test]
+    fn test_simple_snapshot_into_snapshot() {
+        let build = || {
+            let mut h1 = Histogram::<u64>::new_with_bounds(1, u64::max_value(), 3).unwrap();
+            h1.saturating_record(42);
+
+            let percentiles = [Percentile::from(50.0)];
+            let mut snapshot = Snapshot::default();
+            snapshot.set_gauge("pool.size", 8);
+            snapshot.set_count("requests", 3);
+            snapshot.set_counter_rate("requests", 1.5);
+            snapshot.set_wide_count("bytes", u128::from(u64::max_value()) * 2);
+            snapshot.set_windowed_count("recent", 2);
+            snapshot.set_throughput("query", 10.0);
+            snapshot.set_timing_histogram(
+                "query",
+                summarize(h1.clone(), 42, &percentiles).with_unit(TimeUnit::Nanoseconds),
+            );
+            snapshot.set_value_histogram("payload", summarize(h1, 42, &percentiles));
+            snapshot
+        };
+
+        let roundtripped = build().into_simple().into_snapshot();
+        assert_eq!(roundtripped, build());
+
+        // Measurements come back sorted.
+        let names = roundtripped
+            .measurements
+            .iter()
+            .map(|m| (m.name(), m.kind()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                ("bytes", MeasurementKind::CounterWide),
+                ("payload", MeasurementKind::ValueHistogram),
+                ("pool.size", MeasurementKind::Gauge),
+                ("query", MeasurementKind::Throughput),
+                ("query", MeasurementKind::TimingHistogram),
+                ("recent", MeasurementKind::CounterWindowed),
+                ("requests", MeasurementKind::Counter),
+                ("requests", MeasurementKind::CounterRate),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_simple_snapshot_timing_duration() {
+        let mut snapshot = Snapshot::default();
+        let mut h1 = Histogram::<u64>::new_with_bounds(1, u64::max_value(), 3).unwrap();
+        h1.saturating_record(250);
+        let mut h2 = Histogram::<u64>::new_with_bounds(1, u64::max_value(), 3).unwrap();
+        h2.saturating_record(750);
+
+        let percentiles = [Percentile::from(100.0)];
+        snapshot.set_timing_histogram("ns", summarize(h1, 250, &percentiles).with_unit(TimeUnit::Nanoseconds));
+        snapshot.set_timing_histogram("ms", summarize(h2, 750, &percentiles).with_unit(TimeUnit::Milliseconds));
+
+        let simple = snapshot.into_simple();
+        assert_eq!(
+            simple.timing_histogram_duration("ns", 100.0),
+            Some(Duration::from_nanos(250))
+        );
+        assert_eq!(
+            simple.timing_histogram_duration("ms", 100.0),
+            Some(Duration::from_millis(750))
+        );
+        assert_eq!(simple.timing_histogram_duration("ms", 50.0), None);
+        assert_eq!(simple.timing_histogram_duration("missing", 100.0), None);
+
+        assert_eq!(
+            simple.timing_histogram("ms", 100.0),
+            Some(Timing::from_nanos(750_000_000))
+        );
+        assert_eq!(simple.timing_histogram_raw("ms", 100.0), Some(750));
+        let ns = simple.timing_histogram("ns", 100.0).expect("missing timing");
+        assert_eq!(ns.to_string(), "250ns");
+        assert_eq!(simple.timing_histogram_raw("missing", 100.0), None);
+    }
+
+    #[test]
+    fn test_snapshot_wide_count() {
+        let mut snapshot = Snapshot::default();
+        snapshot.set_wide_count("bytes", u128::from(u64::max_value()) * 4);
+        snapshot.set_count("bytes", 1);
+
+        let simple = snapshot.into_simple();
+        assert_eq!(simple.wide_count("bytes"), Some(u128::from(u64::max_value()) * 4));
+        assert_eq!(simple.count("bytes"), Some(1));
+        assert_eq!(simple.wide_count("missing"), None);
+    }
+
+    #[test]
+    fn test_snapshot_labeled_lookups() {
+        let mut h1 = Histogram::<u64>::new_with_bounds(1, u64::max_value(), 3).unwrap();
+        h1.saturating_record(42);
+
+        let percentiles = [Percentile::from(50.0)];
+        let mut snapshot = Snapshot::default();
+        let login = |name: &str| MeasurementKey::new(name).with_labels(vec![("endpoint", "/login")]);
+        snapshot.set_timing_histogram(
+            login("http.latency").with_labels(vec![("method", "POST"), ("endpoint", "/login")]),
+            summarize(h1.clone(), 42, &percentiles).with_unit(TimeUnit::Nanoseconds),
+        );
+        snapshot.set_value_histogram(login("http.size"), summarize(h1, 42, &percentiles));
+        snapshot.set_count(login("http.requests"), 3);
+        snapshot.set_gauge("http.inflight", 2);
+
+        let simple = snapshot.into_simple();
+        let labels = [("method", "POST"), ("endpoint", "/login")];
+        assert_eq!(
+            simple.timing_histogram_labeled("http.latency", &labels, 50.0),
+            Some(Timing::from_nanos(42))
+        );
+        assert_eq!(
+            simple.timing_histogram_labeled("http.latency", &labels[1..], 50.0),
+            None
+        );
+        assert_eq!(
+            simple.value_histogram_labeled("http.size", &labels[1..], 50.0),
+            Some(42)
+        );
+        assert_eq!(simple.count_labeled("http.requests", &labels[1..]), Some(3));
+        assert_eq!(simple.gauge_labeled("http.inflight", &[]), Some(2));
+    }
+
+    #[test]
+    fn test_snapshot_counter_rate() {
+        let mut snapshot = Snapshot::default();
+        snapshot.set_count("ok", 30);
+        snapshot.set_counter_rate("ok", 2.5);
+        assert_eq!(snapshot.to_string(), "counter ok: 30\ncounter rate ok: 2.5/s\n");
+
+        let measurements = snapshot.into_vec();
+        assert_eq!(measurements[1].kind(), MeasurementKind::CounterRate);
+        assert_eq!(measurements[1].as_counter_rate(), Some(2.5));
+        assert_eq!(measurements[1].as_counter(), None);
+        assert_eq!(
+            measurements[1],
+            TypedMeasurement::CounterRate("ok".into(), Rate::new(2.5))
+        );
+        assert_ne!(
+            measurements[1],
+            TypedMeasurement::CounterRate("ok".into(), Rate::new(2.0))
+        );
+        assert_eq!(Rate::new(std::f64::NAN), Rate::new(std::f64::NAN));
+
+        let mut snapshot = Snapshot::default();
+        snapshot.set_count("ok", 30);
+        snapshot.set_counter_rate("ok", 2.5);
+        let simple = snapshot.into_simple();
+        assert_eq!(simple.count("ok"), Some(30));
+        assert_eq!(simple.counter_rate("ok"), Some(2.5));
+        assert_eq!(simple.counter_rate("missing"), None);
+    }
+
+    #[test]
+    fn test_snapshot_display() {
+        let mut snapshot = Snapshot::default();
+        snapshot.set_gauge("total", 42);
+        snapshot.set_count("ok", 1);
+
+        let mut h1 = Histogram::<u64>::new_with_bounds(1, u64::max_value(), 3).unwrap();
+        h1.saturating_record(500);
+        let percentiles = [Percentile::from(100.0), Percentile::from(0.0), Percentile::from(99.0)];
+        snapshot.set_timing_histogram("ok", summarize(h1, 500, &percentiles).with_unit(TimeUnit::Nanoseconds));
+
+        assert_eq!(
+            snapshot.to_string(),
+            "counter ok: 1\ngauge total: 42\ntiming histogram ok: count=1 sum=500 unit=ns min=500 p99=500 max=500\n"
+        );
+    }
+
+    #[test]
+    fn test_measurements_with_count() {
+        let mut h1 = Histogram::<u64>::new_with_bounds(1, u64::max_value(), 3).unwrap();
+        h1.saturating_record(100);
+        h1.saturating_record(500);
+
+        let percentiles = [Percentile::from(100.0), Percentile::from(0.0)];
+        let summary = SummarizedHistogram::from_histogram(&HistogramSnapshot::new(h1, 600), &percentiles, false, false);
+
+        assert_eq!(
+            summary.measurements_with_count(),
+            vec![("min", 100, 2), ("max", 500, 2)]
+        );
+    }
+
+    #[test]
+    fn test_snapshot_raw_histograms() {
+        let mut h1 = Histogram::<u64>::new_with_bounds(1, u64::max_value(), 3).unwrap();
+        h1.saturating_record(10);
+        h1.saturating_record(10);
+        h1.saturating_record(42);
+
+        let percentiles = [Percentile::from(50.0)];
+
+        let histogram = HistogramSnapshot::new(h1, 62);
+        let a = SummarizedHistogram::from_histogram(&histogram, &percentiles, false, false);
+        let b = SummarizedHistogram::from_histogram(&histogram, &percentiles, true, false);
+        assert_eq!(a.recorded(), None);
+        assert_eq!(b.recorded(), Some(&[(10, 2), (42, 1)][..]));
+    }
+
+    #[test]
+    fn test_snapshot_interpolated_percentiles() {
+        let mut h1 = Histogram::<u64>::new_with_bounds(1, u64::max_value(), 3).unwrap();
+        h1.saturating_record(100);
+        h1.saturating_record(200);
+
+        let percentiles = [Percentile::from(0.0), Percentile::from(50.0), Percentile::from(75.0)];
+
+        let histogram = HistogramSnapshot::new(h1, 300);
+        let mut snapshot = Snapshot::default();
+        snapshot.set_value_histogram(
+            "a",
+            SummarizedHistogram::from_histogram(&histogram, &percentiles, false, false),
+        );
+        snapshot.set_value_histogram(
+            "b",
+            SummarizedHistogram::from_histogram(&histogram, &percentiles, false, true),
+        );
+
+        let simple = snapshot.into_simple();
+        assert_eq!(simple.value_histogram("a", 50.0), Some(100));
+        assert_eq!(simple.value_histogram("a", 75.0), Some(200));
+        assert_eq!(simple.value_histogram("b", 0.0), Some(100));
+        assert_eq!(simple.value_histogram("b", 50.0), Some(150));
+        assert_eq!(simple.value_histogram("b", 75.0), Some(175));
+    }
+
+    #[test]
+    fn test_snapshot_sparse_histograms() {
+        let mut h1 = Histogram::<u64>::new_with_bounds(1, u64::max_value(), 3).unwrap();
+        h1.saturating_record(10);
+        h1.saturating_record(42);
+
+        let percentiles = [Percentile::from(99.9)];
+
+        let histogram = HistogramSnapshot::new(h1, 52);
+        let sparse = [0, 3, 2]
+            .iter()
+            .map(|threshold| {
+                SummarizedHistogram::from_histogram(&histogram, &percentiles, false, false)
+                    .with_sparse_threshold(*threshold)
+                    .is_sparse()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(sparse, vec![false, true, false]);
+    }
+
+    #[test]
+    fn test_snapshot_histogram_buckets() {
+        let mut h1 = Histogram::<u64>::new_with_bounds(1, u64::max_value(), 3).unwrap();
+        for value in &[3, 8, 8, 20, 200] {
+            h1.saturating_record(*value);
+        }
+
+        let mut buckets = HistogramBuckets::default();
+        buckets.set("configured".to_owned(), vec![100, 10, 5]);
+
+        let values = HistogramSnapshot::new(h1, 239);
+        let summarize = |histogram: &HistogramSnapshot, name: &str, unit: Option<TimeUnit>| {
+            SummarizedHistogram::from_histogram(histogram, &[], false, false)
+                .with_buckets(histogram, buckets.bounds(name, unit))
+        };
+
+        let configured = summarize(&values, "configured", None);
+        assert_eq!(configured.buckets(), &[(5, 1), (10, 3), (100, 4)][..]);
+        assert!(summarize(&values, "unconfigured", None).buckets().is_empty());
+
+        let mut h2 = Histogram::<u64>::new_with_bounds(1, u64::max_value(), 3).unwrap();
+        h2.saturating_record(7);
+        h2.saturating_record(30_000);
+        let timings = HistogramSnapshot::new(h2, 30_007);
+        let timing = summarize(&timings, "timing", Some(TimeUnit::Milliseconds));
+        assert_eq!(timing.buckets().len(), 11);
+        assert_eq!(timing.buckets()[0], (5, 0));
+        assert_eq!(timing.buckets()[1], (10, 1));
+        assert_eq!(timing.buckets()[10], (10_000, 1));
+    }
+
+    #[test]
+    fn test_typed_measurement_helpers() {
+        let mut snapshot = Snapshot::default();
+        snapshot.set_count("widgets", 7);
+        snapshot.set_gauge("red_balloons", 99);
+
+        let mut h1 = Histogram::<u64>::new_with_bounds(1, u64::max_value(), 3).unwrap();
+        h1.saturating_record(500);
+        snapshot.set_value_histogram("buf_size", summarize(h1, 500, &[Percentile::from(50.0)]));
+
+        let measurements = snapshot.into_iter().collect::<Vec<_>>();
+        assert_eq!(measurements.len(), 3);
+
+        assert_eq!(measurements[0].name(), "widgets");
+        assert_eq!(measurements[0].kind(), MeasurementKind::Counter);
+        assert_eq!(measurements[0].as_counter(), Some(7));
+        assert_eq!(measurements[0].as_gauge(), None);
+
+        assert_eq!(measurements[1].name(), "red_balloons");
+        assert_eq!(measurements[1].kind(), MeasurementKind::Gauge);
+        assert_eq!(measurements[1].as_gauge(), Some(99));
+        assert_eq!(measurements[1].as_counter(), None);
+
+        assert_eq!(measurements[2].name(), "buf_size");
+        assert_eq!(measurements[2].kind(), MeasurementKind::ValueHistogram);
+        assert_eq!(measurements[2].as_histogram().map(|h| h.sum()), Some(500));
+    }
+
+    #[test]
+    fn test_snapshot_merge_prefixed() {
+        let mut first = Snapshot::default();
+        first.set_count("ok", 1);
+
+        let mut second = Snapshot::default();
+        second.set_count("ok", 2);
+        second.set_gauge("total", 42);
+
+        let mut merged = Snapshot::default();
+        merged.merge_prefixed("", first);
+        merged.merge_prefixed("plugin", second);
+
+        let values = merged.into_vec();
+        assert_eq!(values.len(), 3);
+        assert_eq!(values[0], TypedMeasurement::Counter("ok".into(), 1));
+        assert_eq!(values[1], TypedMeasurement::Counter("plugin.ok".into(), 2));
+        assert_eq!(values[2], TypedMeasurement::Gauge("plugin.total".into(), 42));
+        assert_eq!(values[1].key().scope(), "plugin");
+        assert_eq!(values[1].key().local_name(), "ok");
+    }
+
+    #[test]
+    fn test_measurement_key() {
+        let key = MeasurementKey::new("pool.size");
+        assert_eq!((key.scope(), key.local_name()), ("", "pool.size"));
+
+        let key = key.with_scope("db").with_scope("app");
+        assert_eq!(key.name(), "app.db.pool.size");
+        assert_eq!((key.scope(), key.local_name()), ("app.db", "pool.size"));
+        assert_eq!(key, MeasurementKey::new("app.db.pool.size"));
+
+        let key = key.with_labels(vec![("role", "primary"), ("env", "prod")]);
+        assert_eq!(
+            key.labels(),
+            &[
+                ("env".to_owned(), "prod".to_owned()),
+                ("role".to_owned(), "primary".to_owned())
+            ][..]
+        );
+        assert_eq!(key.to_string(), r#"app.db.pool.size{env="prod",role="primary"}"#);
+        assert_eq!(key, *r#"app.db.pool.size{env="prod",role="primary"}"#);
+        assert_ne!(key, MeasurementKey::new("app.db.pool.size"));
+    }
+
+    #[test]
+    fn test_snapshot_filter_kind() {
+        let mut snapshot = Snapshot::default();
+        snapshot.set_count("ok", 1);
+        snapshot.set_gauge("total", 42);
+        snapshot.set_count("errors", 2);
+        snapshot.set_counter_rate("ok", 0.5);
+
+        let counters = snapshot.iter_kind(MeasurementKind::Counter).map(TypedMeasurement::name);
+        assert_eq!(counters.collect::<Vec<_>>(), vec!["ok", "errors"]);
+        assert_eq!(snapshot.iter_kind(MeasurementKind::TimingHistogram).count(), 0);
+
+        let gauges = snapshot.filter_kinds(&[MeasurementKind::Gauge, MeasurementKind::CounterRate]);
+        assert_eq!(gauges.into_vec().len(), 2);
+
+        let mut snapshot = Snapshot::default();
+        snapshot.set_count("ok", 1);
+        snapshot.set_gauge("total", 42);
+        let values = snapshot.filter_kind(MeasurementKind::Gauge).into_vec();
+        assert_eq!(values, vec![TypedMeasurement::Gauge("total".into(), 42)]);
+    }
+
+    #[test]
+    fn test_snapshot_eq() {
+        let mut first = Snapshot::default();
+        first.set_count("ok", 1);
+        first.set_gauge("total", 42);
+        first.set_count("ok", 2);
+
+        let mut second = Snapshot::default();
+        second.set_count("ok", 2);
+        second.set_gauge("total", 42);
+        second.set_count("ok", 1);
+        assert_eq!(first, second);
+
+        second.sort();
+        let values = second.into_vec();
+        assert_eq!(values[0].name(), "ok");
+        assert_eq!(values[2], TypedMeasurement::Gauge("total".into(), 42));
+
+        let mut third = Snapshot::default();
+        third.set_count("ok", 1);
+        third.set_gauge("total", 42);
+        third.set_count("ok", 1);
+        assert_ne!(first, third);
+
+        let mut fourth = Snapshot::default();
+        fourth.set_count("ok", 1);
+        fourth.set_gauge("ok", 42);
+        fourth.set_count("ok", 2);
+        assert_ne!(first, fourth);
+
+        let mut first_simple = Snapshot::default();
+        first_simple.set_count("ok", 1);
+        first_simple.set_gauge("total", 42);
+        let mut second_simple = Snapshot::default();
+        second_simple.set_gauge("total", 42);
+        second_simple.set_count("ok", 1);
+        assert_eq!(first_simple.into_simple(), second_simple.into_simple());
     }
 
     #[test]
@@ -214,7 +1637,10 @@ mod tests {
             tpercentiles.push(Percentile::from(100.0));
             let fake = Percentile::from(63.0);
 
-            snapshot.set_timing_histogram(tkey.clone(), HistogramSnapshot::new(h1, sum), &tpercentiles);
+            snapshot.set_timing_histogram(
+                tkey.clone(),
+                summarize(h1, sum, &tpercentiles).with_unit(TimeUnit::Nanoseconds),
+            );
 
             let values = snapshot.into_vec();
             match values.get(0) {
@@ -222,6 +1648,7 @@ mod tests {
                     assert_eq!(key, "ok");
                     assert_eq!(summary.count(), 4);
                     assert_eq!(summary.sum(), 3_500_000);
+                    assert_eq!(summary.unit(), Some(TimeUnit::Nanoseconds));
 
                     let min_tpercentile = summary.measurements().get(&tpercentiles[0]);
                     let p50_tpercentile = summary.measurements().get(&tpercentiles[1]);
@@ -260,7 +1687,7 @@ mod tests {
             tpercentiles.push(Percentile::from(100.0));
             let fake = Percentile::from(63.0);
 
-            snapshot.set_value_histogram(tkey.clone(), HistogramSnapshot::new(h1, sum), &tpercentiles);
+            snapshot.set_value_histogram(tkey.clone(), summarize(h1, sum, &tpercentiles));
 
             let values = snapshot.into_vec();
             match values.get(0) {
@@ -268,6 +1695,7 @@ mod tests {
                     assert_eq!(key, "ok");
                     assert_eq!(summary.count(), 4);
                     assert_eq!(summary.sum(), 3_500_000);
+                    assert_eq!(summary.unit(), None);
 
                     let min_tpercentile = summary.measurements().get(&tpercentiles[0]);
                     let p50_tpercentile = summary.measurements().get(&tpercentiles[1]);
@@ -311,4 +1739,24 @@ mod tests {
         let p9999_p = Percentile::from(99.99);
         assert_eq!(p9999_p.label(), "p9999");
     }
+
+    #[test]
+    fn test_percentiles_from_str() {
+        let labels = ["min", "p50", "p95", "p99", "p999", "p9999", "p05", "max"];
+        for label in labels.iter() {
+            let p = label.parse::<Percentile>().unwrap();
+            assert_eq!(p.label(), *label);
+        }
+
+        assert_eq!("p999".parse::<Percentile>().unwrap().percentile(), 99.9);
+        assert_eq!("p100".parse::<Percentile>().unwrap().percentile(), 100.0);
+        assert_eq!("99.9".parse::<Percentile>().unwrap().label(), "p999");
+
+        assert_eq!("".parse::<Percentile>(), Err(PercentileParseError::Empty));
+        assert_eq!("p".parse::<Percentile>(), Err(PercentileParseError::InvalidFormat));
+        assert_eq!("p9x".parse::<Percentile>(), Err(PercentileParseError::InvalidFormat));
+        assert_eq!("median".parse::<Percentile>(), Err(PercentileParseError::InvalidFormat));
+        assert_eq!("101".parse::<Percentile>(), Err(PercentileParseError::OutOfRange));
+        assert_eq!("NaN".parse::<Percentile>(), Err(PercentileParseError::OutOfRange));
+    }
 }
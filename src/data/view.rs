@@ -0,0 +1,81 @@
+use super::histogram::HistogramSnapshot;
+
+/// Access to the live metric state of a receiver, independent of its key and hasher types.
+pub(crate) trait MetricState {
+    fn visit_counters(&self, f: &mut dyn FnMut(&str, i64));
+
+    fn visit_gauges(&self, f: &mut dyn FnMut(&str, u64));
+
+    fn visit_timing_histograms(&self, f: &mut dyn FnMut(&str, &HistogramView));
+
+    fn visit_value_histograms(&self, f: &mut dyn FnMut(&str, &HistogramView));
+}
+
+/// A read-only view over the live metric state of a [`Receiver`](crate::Receiver).
+///
+/// Metrics are visited in place, by their fully-qualified name, without building a
+/// [`Snapshot`](crate::snapshot::Snapshot).  Views are only handed out by
+/// [`Controller::with_state`](crate::Controller::with_state).
+pub struct MetricView<'a> {
+    state: &'a dyn MetricState,
+}
+
+impl<'a> MetricView<'a> {
+    pub(crate) fn new(state: &'a dyn MetricState) -> MetricView<'a> { MetricView { state } }
+
+    /// Calls `f` with the name and value of every counter.
+    pub fn counters<F>(&self, mut f: F)
+    where
+        F: FnMut(&str, i64),
+    {
+        self.state.visit_counters(&mut f)
+    }
+
+    /// Calls `f` with the name and value of every gauge.
+    pub fn gauges<F>(&self, mut f: F)
+    where
+        F: FnMut(&str, u64),
+    {
+        self.state.visit_gauges(&mut f)
+    }
+
+    /// Calls `f` with the name and contents of every timing histogram.
+    ///
+    /// Each histogram is merged across its window before being visited, which is more expensive
+    /// than visiting counters or gauges.
+    pub fn timing_histograms<F>(&self, mut f: F)
+    where
+        F: FnMut(&str, &HistogramView),
+    {
+        self.state.visit_timing_histograms(&mut f)
+    }
+
+    /// Calls `f` with the name and contents of every value histogram.
+    ///
+    /// Each histogram is merged across its window before being visited, which is more expensive
+    /// than visiting counters or gauges.
+    pub fn value_histograms<F>(&self, mut f: F)
+    where
+        F: FnMut(&str, &HistogramView),
+    {
+        self.state.visit_value_histograms(&mut f)
+    }
+}
+
+/// A read-only view over a histogram in a [`MetricView`].
+pub struct HistogramView<'a> {
+    histogram: &'a HistogramSnapshot,
+}
+
+impl<'a> HistogramView<'a> {
+    pub(crate) fn new(histogram: &'a HistogramSnapshot) -> HistogramView<'a> { HistogramView { histogram } }
+
+    /// Gets the number of values recorded in the histogram window.
+    pub fn count(&self) -> u64 { self.histogram.count() }
+
+    /// Gets the sum of all values recorded in the histogram.
+    pub fn sum(&self) -> u64 { self.histogram.sum() }
+
+    /// Gets the value at the given percentile, between 0.0 and 100.0.
+    pub fn value_at_percentile(&self, percentile: f64) -> u64 { self.histogram.value_at_percentile(percentile) }
+}
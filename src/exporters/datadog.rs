@@ -0,0 +1,300 @@
+use super::count_delta;
+use crate::{
+    control::{Controller, SnapshotError},
+    snapshot::{MeasurementKey, Snapshot, SummarizedHistogram, TypedMeasurement},
+};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    fmt, thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const DEFAULT_ENDPOINT: &str = "https://api.datadoghq.com/api/v1/series";
+
+/// Error conditions when exporting to Datadog.
+#[derive(Debug)]
+pub enum DatadogError {
+    /// A snapshot could not be taken.
+    Snapshot(SnapshotError),
+
+    /// The series could not be submitted to the Datadog API.
+    Http(String),
+}
+
+/// Exports snapshots directly to the Datadog metrics API.
+///
+/// Every interval, a snapshot is taken and converted into Datadog's v1 series format, and all of
+/// the resulting series are submitted in a single request.
+///
+/// Metrics are submitted under their name within their scope, and the scope they were sent from, if
+/// any, becomes a `scope:<scope>` tag.  Labels become tags as well, as `<key>:<value>`.  Counters
+/// are submitted as counts of how much they changed since the last flush, while windowed counters
+/// and gauges are submitted as gauges.  Histograms are submitted as one gauge per configured
+/// percentile, named with the percentile label, such as `.p99` or `.max`, along with `.count` and
+/// `.sum` gauges, of the number and sum of the values in the histogram window, and an `.avg`
+/// gauge.  The sum over the window gives throughput, such as bytes per second from a histogram of
+/// payload sizes.
+pub struct DatadogExporter {
+    controller: Controller,
+    api_key: String,
+    interval: Duration,
+    endpoint: String,
+    tags: Vec<String>,
+    last_counts: HashMap<MeasurementKey, f64>,
+}
+
+impl DatadogExporter {
+    /// Creates a new [`DatadogExporter`] that submits metrics from the given controller, using the
+    /// given API key, every `interval`.
+    pub fn new(controller: Controller, api_key: &str, interval: Duration) -> Self {
+        DatadogExporter {
+            controller,
+            api_key: api_key.to_owned(),
+            interval,
+            endpoint: DEFAULT_ENDPOINT.to_owned(),
+            tags: Vec::new(),
+            last_counts: HashMap::new(),
+        }
+    }
+
+    /// Sets the URL that series are submitted to.
+    ///
+    /// Defaults to `https://api.datadoghq.com/api/v1/series`.  This must be changed for accounts
+    /// on a different Datadog site, such as the EU site.
+    pub fn endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = endpoint.to_owned();
+        self
+    }
+
+    /// Adds a tag that is attached to every series, such as `env:production`.
+    pub fn tag(mut self, tag: &str) -> Self {
+        self.tags.push(tag.to_owned());
+        self
+    }
+
+    /// Runs the exporter, flushing every interval.
+    ///
+    /// This blocks forever, and so should be run on a dedicated thread.  Failed flushes are
+    /// skipped, and the exporter tries again at the next interval.
+    pub fn run(&mut self) {
+        loop {
+            thread::sleep(self.interval);
+            let _ = self.flush();
+        }
+    }
+
+    /// Takes a snapshot and submits it to Datadog.
+    pub fn flush(&mut self) -> Result<(), DatadogError> {
+        let snapshot = self.controller.get_snapshot().map_err(DatadogError::Snapshot)?;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let body = self.to_series(snapshot, timestamp);
+
+        let response = ureq::post(&self.endpoint)
+            .set("DD-API-KEY", &self.api_key)
+            .send_json(body);
+        if let Some(err) = response.synthetic_error() {
+            return Err(DatadogError::Http(err.to_string()));
+        }
+        if !response.ok() {
+            return Err(DatadogError::Http(format!(
+                "unexpected status code {}",
+                response.status()
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Converts a snapshot into the request body for the series API.
+    fn to_series(&mut self, snapshot: Snapshot, timestamp: u64) -> Value {
+        let mut series = Vec::new();
+
+        for measurement in snapshot.into_vec() {
+            match measurement {
+                TypedMeasurement::Counter(key, value) => {
+                    let delta = count_delta(&mut self.last_counts, key.clone(), value as f64);
+                    series.push(self.series(&key, "", delta, "count", timestamp));
+                },
+                TypedMeasurement::CounterWide(key, value) => {
+                    let delta = count_delta(&mut self.last_counts, key.clone(), value as f64);
+                    series.push(self.series(&key, "", delta, "count", timestamp));
+                },
+                TypedMeasurement::CounterWindowed(key, value) => {
+                    series.push(self.series(&key, "", value as f64, "gauge", timestamp));
+                },
+                TypedMeasurement::CounterRate(key, value) => {
+                    series.push(self.series(&key, ".rate", value.per_second(), "gauge", timestamp));
+                },
+                TypedMeasurement::Gauge(key, value) => {
+                    series.push(self.series(&key, "", value as f64, "gauge", timestamp));
+                },
+                TypedMeasurement::Throughput(key, value) => {
+                    series.push(self.series(&key, ".throughput", value.per_second(), "gauge", timestamp));
+                },
+                TypedMeasurement::TimingHistogram(key, summary) | TypedMeasurement::ValueHistogram(key, summary) => {
+                    self.histogram_series(&key, &summary, timestamp, &mut series);
+                },
+            }
+        }
+
+        json!({ "series": series })
+    }
+
+    fn histogram_series(
+        &self, key: &MeasurementKey, summary: &SummarizedHistogram, timestamp: u64, series: &mut Vec<Value>,
+    ) {
+        for (percentile, value) in summary.measurements() {
+            let suffix = format!(".{}", percentile.label());
+            series.push(self.series(key, &suffix, *value as f64, "gauge", timestamp));
+        }
+
+        series.push(self.series(key, ".count", summary.count() as f64, "gauge", timestamp));
+        series.push(self.series(key, ".sum", summary.sum() as f64, "gauge", timestamp));
+
+        if summary.count() > 0 {
+            let avg = summary.sum() as f64 / summary.count() as f64;
+            series.push(self.series(key, ".avg", avg, "gauge", timestamp));
+        }
+    }
+
+    fn series(&self, key: &MeasurementKey, suffix: &str, value: f64, kind: &str, timestamp: u64) -> Value {
+        let mut tags = self.tags.clone();
+        if !key.scope().is_empty() {
+            tags.push(format!("scope:{}", key.scope()));
+        }
+        tags.extend(key.labels().iter().map(|(k, v)| format!("{}:{}", k, v)));
+
+        json!({
+            "metric": format!("{}{}", key.local_name(), suffix),
+            "points": [[timestamp, value]],
+            "type": kind,
+            "tags": tags,
+        })
+    }
+}
+
+impl fmt::Display for DatadogError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DatadogError::Snapshot(e) => write!(f, "failed to take snapshot: {}", e),
+            DatadogError::Http(reason) => write!(f, "failed to submit series: {}", reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DatadogExporter;
+    use crate::{
+        data::{histogram::HistogramSnapshot, snapshot::SummarizedHistogram, MeasurementKey, Percentile, Snapshot},
+        Receiver,
+    };
+    use hdrhistogram::Histogram;
+    use serde_json::json;
+    use std::time::Duration;
+
+    #[test]
+    fn test_to_series() {
+        let receiver = Receiver::<&'static str>::builder().build();
+        let mut exporter =
+            DatadogExporter::new(receiver.get_controller(), "key", Duration::from_secs(10)).tag("env:test");
+
+        let queries = || MeasurementKey::new("queries").with_scope("db");
+        let mut snapshot = Snapshot::default();
+        snapshot.set_count(queries(), 10);
+        snapshot.set_gauge(
+            MeasurementKey::new("connections").with_labels(vec![("pool", "main")]),
+            4,
+        );
+        let body = exporter.to_series(snapshot, 1234);
+
+        assert_eq!(
+            body,
+            json!({
+                "series": [
+                    {
+                        "metric": "queries",
+                        "points": [[1234, 10.0]],
+                        "type": "count",
+                        "tags": ["env:test", "scope:db"],
+                    },
+                    {
+                        "metric": "connections",
+                        "points": [[1234, 4.0]],
+                        "type": "gauge",
+                        "tags": ["env:test", "pool:main"],
+                    },
+                ]
+            })
+        );
+
+        // Counters are submitted as the change since the last flush.
+        let mut snapshot = Snapshot::default();
+        snapshot.set_count(queries(), 15);
+        let body = exporter.to_series(snapshot, 1244);
+        assert_eq!(body["series"][0]["points"], json!([[1244, 5.0]]));
+
+        // Dots in a key, or in a label, aren't taken for the end of a scope.
+        let mut snapshot = Snapshot::default();
+        snapshot.set_gauge(
+            MeasurementKey::new("pool.size")
+                .with_scope("db.primary")
+                .with_labels(vec![("host", "db1.local")]),
+            8,
+        );
+        snapshot.set_gauge("cache.hits", 3);
+        let body = exporter.to_series(snapshot, 1254);
+        let series = body["series"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|s| (s["metric"].clone(), s["tags"].clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            series,
+            vec![
+                (
+                    json!("pool.size"),
+                    json!(["env:test", "scope:db.primary", "host:db1.local"])
+                ),
+                (json!("cache.hits"), json!(["env:test"])),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_histogram_series() {
+        let receiver = Receiver::<&'static str>::builder().build();
+        let mut exporter = DatadogExporter::new(receiver.get_controller(), "key", Duration::from_secs(10));
+
+        let mut histogram = Histogram::<u64>::new_with_bounds(1, u64::max_value(), 3).unwrap();
+        histogram.saturating_record(100);
+        histogram.saturating_record(300);
+        let mut snapshot = Snapshot::default();
+        let histogram = HistogramSnapshot::new(histogram, 400);
+        snapshot.set_value_histogram(
+            "payload",
+            SummarizedHistogram::from_histogram(&histogram, &[Percentile::from(100.0)], false, false),
+        );
+        let body = exporter.to_series(snapshot, 1234);
+
+        let series = body["series"].as_array().unwrap();
+        let points = series
+            .iter()
+            .map(|s| (s["metric"].as_str().unwrap(), s["points"][0][1].as_f64().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            points,
+            vec![
+                ("payload.max", 300.0),
+                ("payload.count", 2.0),
+                ("payload.sum", 400.0),
+                ("payload.avg", 200.0),
+            ]
+        );
+    }
+}
@@ -0,0 +1,28 @@
+//! Exporters for sending snapshots to external systems.
+//!
+//! Each exporter is gated behind a feature of the same name, so that their dependencies are only
+//! pulled in when needed.
+#[cfg(feature = "datadog")]
+mod datadog;
+#[cfg(feature = "write")]
+mod write;
+
+#[cfg(feature = "datadog")]
+pub use self::datadog::{DatadogError, DatadogExporter};
+#[cfg(feature = "write")]
+pub use self::write::{Format, WriteError, WriteExporter};
+
+#[cfg(any(feature = "datadog", feature = "write"))]
+use std::{collections::HashMap, hash::Hash};
+
+/// Gets how much a counter has changed since the last flush, and records its current value.
+#[cfg(any(feature = "datadog", feature = "write"))]
+fn count_delta<K: Eq + Hash>(last_counts: &mut HashMap<K, f64>, key: K, value: f64) -> f64 {
+    let last = last_counts.insert(key, value).unwrap_or(0.0);
+    if value >= last {
+        value - last
+    } else {
+        // The counter went backwards, so count everything since it was reset.
+        value
+    }
+}
@@ -0,0 +1,337 @@
+use super::count_delta;
+use crate::{
+    control::{Controller, SnapshotError},
+    helper::duration_as_nanos,
+    snapshot::{Snapshot, SummarizedHistogram, TypedMeasurement},
+};
+use serde_json::{json, Map, Value};
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Write},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Error conditions when exporting to a writer.
+#[derive(Debug)]
+pub enum WriteError {
+    /// A snapshot could not be taken.
+    Snapshot(SnapshotError),
+
+    /// The rendered snapshot could not be written.
+    Io(io::Error),
+}
+
+/// The line format that a [`WriteExporter`] renders snapshots in.
+///
+/// Every format renders one metric per line.  Labels are rendered in each format's own way of
+/// tagging metrics, rather than as part of the metric name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// The StatsD line format, as `name:value|type`, with labels as DogStatsD tags.
+    ///
+    /// Counters are written as counts of how much they changed since the last flush, as StatsD
+    /// servers sum counts themselves, and everything else is written as a gauge.
+    Statsd,
+
+    /// The Graphite plaintext format, as `name value timestamp`, with labels as Graphite tags.
+    Graphite,
+
+    /// The InfluxDB line protocol, as `name,tags value=value timestamp`, with a timestamp in
+    /// nanoseconds.
+    Influx,
+
+    /// One JSON object per line, holding the `name`, `labels`, `value`, and `timestamp`, in
+    /// seconds, of the metric.
+    Json,
+}
+
+/// Exports snapshots to any [`Write`], such as a file, a socket, or a pipe, in a line format.
+///
+/// Every interval, a snapshot is taken and rendered in the configured [`Format`], and the whole
+/// snapshot is written at once, followed by a flush of the writer.
+///
+/// Counters and gauges are written as a single metric each.  Rates and throughputs are written
+/// with a `.rate` and `.throughput` suffix, respectively.  Histograms are written as one metric per
+/// configured percentile, named with the percentile label, such as `.p99` or `.max`, along with
+/// `.count` and `.sum` metrics, of the number and sum of the values in the histogram window, and an
+/// `.avg` metric.
+pub struct WriteExporter<W: Write> {
+    controller: Controller,
+    writer: W,
+    format: Format,
+    interval: Duration,
+    last_counts: HashMap<String, f64>,
+}
+
+/// A single metric, ready to be rendered.
+struct Point<'a> {
+    name: String,
+    labels: &'a [(String, String)],
+    value: f64,
+    counter: bool,
+}
+
+impl<W: Write> WriteExporter<W> {
+    /// Creates a new [`WriteExporter`] that writes metrics from the given controller to `writer`,
+    /// in the given format, every `interval`.
+    pub fn new(controller: Controller, writer: W, format: Format, interval: Duration) -> Self {
+        WriteExporter {
+            controller,
+            writer,
+            format,
+            interval,
+            last_counts: HashMap::new(),
+        }
+    }
+
+    /// Gets a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W { &self.writer }
+
+    /// Consumes the exporter, returning the underlying writer.
+    pub fn into_inner(self) -> W { self.writer }
+
+    /// Runs the exporter, flushing every interval.
+    ///
+    /// This blocks forever, and so should be run on a dedicated thread.  Failed flushes are
+    /// skipped, and the exporter tries again at the next interval.
+    pub fn run(&mut self) {
+        loop {
+            thread::sleep(self.interval);
+            let _ = self.flush();
+        }
+    }
+
+    /// Takes a snapshot and writes it.
+    pub fn flush(&mut self) -> Result<(), WriteError> {
+        let snapshot = self.controller.get_snapshot().map_err(WriteError::Snapshot)?;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_else(|_| Duration::new(0, 0));
+        let rendered = self.render(snapshot, timestamp);
+
+        self.writer.write_all(rendered.as_bytes()).map_err(WriteError::Io)?;
+        self.writer.flush().map_err(WriteError::Io)
+    }
+
+    /// Renders a snapshot in the configured format.
+    fn render(&mut self, snapshot: Snapshot, timestamp: Duration) -> String {
+        let mut rendered = String::new();
+
+        for measurement in snapshot.into_vec() {
+            let (name, labels) = (measurement.name(), measurement.labels());
+            let mut points = Vec::new();
+            let mut point = |suffix: &str, value: f64, counter: bool| {
+                points.push(Point {
+                    name: format!("{}{}", name, suffix),
+                    labels,
+                    value,
+                    counter,
+                })
+            };
+
+            match &measurement {
+                TypedMeasurement::Counter(_, value) => point("", *value as f64, true),
+                TypedMeasurement::CounterWide(_, value) => point("", *value as f64, true),
+                TypedMeasurement::CounterWindowed(_, value) => point("", *value as f64, false),
+                TypedMeasurement::CounterRate(_, value) => point(".rate", value.per_second(), false),
+                TypedMeasurement::Gauge(_, value) => point("", *value as f64, false),
+                TypedMeasurement::Throughput(_, value) => point(".throughput", value.per_second(), false),
+                TypedMeasurement::TimingHistogram(_, summary) | TypedMeasurement::ValueHistogram(_, summary) => {
+                    histogram_points(summary, &mut point)
+                },
+            }
+
+            for point in points {
+                self.render_point(&point, timestamp, &mut rendered);
+            }
+        }
+
+        rendered
+    }
+
+    fn render_point(&mut self, point: &Point, timestamp: Duration, rendered: &mut String) {
+        let line = match self.format {
+            Format::Statsd => {
+                let (value, kind) = if point.counter {
+                    (self.count_delta(point), "c")
+                } else {
+                    (point.value, "g")
+                };
+                let mut line = format!("{}:{}|{}", sanitize(&point.name, ":|@#,"), value, kind);
+                for (i, (key, value)) in point.labels.iter().enumerate() {
+                    line.push_str(if i == 0 { "|#" } else { "," });
+                    line.push_str(&format!("{}:{}", sanitize(key, ":|@#,"), sanitize(value, "|@#,")));
+                }
+                line
+            },
+            Format::Graphite => {
+                let mut name = sanitize(&point.name, " ;");
+                for (key, value) in point.labels {
+                    name.push_str(&format!(";{}={}", sanitize(key, " ;=~"), sanitize(value, " ;~")));
+                }
+                format!("{} {} {}", name, point.value, timestamp.as_secs())
+            },
+            Format::Influx => {
+                let mut name = escape_influx(&point.name, ", ");
+                for (key, value) in point.labels {
+                    name.push_str(&format!(
+                        ",{}={}",
+                        escape_influx(key, ",= "),
+                        escape_influx(value, ",= ")
+                    ));
+                }
+                format!("{} value={} {}", name, point.value, duration_as_nanos(timestamp))
+            },
+            Format::Json => {
+                let labels = point
+                    .labels
+                    .iter()
+                    .map(|(key, value)| (key.clone(), Value::String(value.clone())))
+                    .collect::<Map<_, _>>();
+                json!({
+                    "name": point.name,
+                    "labels": labels,
+                    "value": point.value,
+                    "timestamp": timestamp.as_secs(),
+                })
+                .to_string()
+            },
+        };
+
+        rendered.push_str(&line);
+        rendered.push('\n');
+    }
+
+    /// Gets how much a counter point has changed since the last flush, keyed by its name and labels.
+    fn count_delta(&mut self, point: &Point) -> f64 {
+        let key = format!("{}{}", point.name, crate::scopes::render_labels(point.labels));
+        count_delta(&mut self.last_counts, key, point.value)
+    }
+}
+
+fn histogram_points<F: FnMut(&str, f64, bool)>(summary: &SummarizedHistogram, point: &mut F) {
+    for (percentile, value) in summary.measurements() {
+        point(&format!(".{}", percentile.label()), *value as f64, false);
+    }
+
+    point(".count", summary.count() as f64, false);
+    point(".sum", summary.sum() as f64, false);
+
+    if summary.count() > 0 {
+        point(".avg", summary.sum() as f64 / summary.count() as f64, false);
+    }
+}
+
+/// Replaces characters that have special meaning in a format with underscores.
+fn sanitize(s: &str, special: &str) -> String {
+    s.chars()
+        .map(|c| if special.contains(c) || c == '\n' { '_' } else { c })
+        .collect()
+}
+
+/// Escapes characters that have special meaning in the InfluxDB line protocol with backslashes.
+fn escape_influx(s: &str, special: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == '\n' {
+            escaped.push_str("\\n");
+            continue;
+        }
+        if special.contains(c) || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WriteError::Snapshot(e) => write!(f, "failed to take snapshot: {}", e),
+            WriteError::Io(e) => write!(f, "failed to write snapshot: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Format, WriteExporter};
+    use crate::{
+        data::{histogram::HistogramSnapshot, snapshot::SummarizedHistogram, MeasurementKey, Percentile, Snapshot},
+        Receiver,
+    };
+    use hdrhistogram::Histogram;
+    use std::time::Duration;
+
+    fn render(format: Format, snapshots: Vec<Snapshot>) -> Vec<String> {
+        let receiver = Receiver::<&'static str>::builder().build();
+        let mut exporter = WriteExporter::new(receiver.get_controller(), Vec::new(), format, Duration::from_secs(10));
+        snapshots
+            .into_iter()
+            .map(|snapshot| exporter.render(snapshot, Duration::new(1234, 500)))
+            .collect()
+    }
+
+    fn queries() -> MeasurementKey {
+        MeasurementKey::new("db.queries").with_labels(vec![("host", "a b"), ("env", "prod")])
+    }
+
+    fn snapshot() -> Snapshot {
+        let mut snapshot = Snapshot::default();
+        snapshot.set_count(queries(), 10);
+        snapshot.set_gauge("connections", 4);
+        snapshot
+    }
+
+    #[test]
+    fn test_formats() {
+        let mut next = Snapshot::default();
+        next.set_count(queries(), 15);
+        assert_eq!(
+            render(Format::Statsd, vec![snapshot(), next]),
+            vec![
+                "db.queries:10|c|#env:prod,host:a b\nconnections:4|g\n",
+                "db.queries:5|c|#env:prod,host:a b\n",
+            ]
+        );
+
+        assert_eq!(
+            render(Format::Graphite, vec![snapshot()]),
+            vec!["db.queries;env=prod;host=a_b 10 1234\nconnections 4 1234\n"]
+        );
+        assert_eq!(
+            render(Format::Influx, vec![snapshot()]),
+            vec!["db.queries,env=prod,host=a\\ b value=10 1234000000500\nconnections value=4 1234000000500\n"]
+        );
+        assert_eq!(
+            render(Format::Json, vec![snapshot()]),
+            vec![concat!(
+                r#"{"labels":{"env":"prod","host":"a b"},"name":"db.queries","timestamp":1234,"value":10.0}"#,
+                "\n",
+                r#"{"labels":{},"name":"connections","timestamp":1234,"value":4.0}"#,
+                "\n",
+            )]
+        );
+    }
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::<u64>::new_with_bounds(1, u64::max_value(), 3).unwrap();
+        histogram.saturating_record(100);
+        histogram.saturating_record(300);
+        let mut snapshot = Snapshot::default();
+        let histogram = HistogramSnapshot::new(histogram, 400);
+        snapshot.set_value_histogram(
+            "payload",
+            SummarizedHistogram::from_histogram(&histogram, &[Percentile::from(100.0)], false, false),
+        );
+
+        assert_eq!(
+            render(Format::Graphite, vec![snapshot]),
+            vec!["payload.max 300 1234\npayload.count 2 1234\npayload.sum 400 1234\npayload.avg 200 1234\n"]
+        );
+    }
+}
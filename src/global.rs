@@ -0,0 +1,87 @@
+use crate::{receiver::Receiver, sink::Sink};
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use std::{any::Any, fmt, fmt::Display, hash::Hash, thread};
+
+lazy_static! {
+    static ref GLOBAL_SINK: RwLock<Option<Box<dyn Any + Send + Sync>>> = RwLock::new(None);
+}
+
+/// Error conditions when setting the global receiver.
+#[derive(Debug, PartialEq)]
+pub enum GlobalError {
+    /// A global receiver has already been set.
+    AlreadySet,
+}
+
+/// Sets the global receiver.
+///
+/// The receiver is spawned on a dedicated thread, and sinks bound to it can then be retrieved from
+/// anywhere with [`global_sink`].  This avoids having to pass a [`Sink`] through every part of a
+/// small program.  As the receiver is consumed, any [`Controller`](crate::Controller) needed to
+/// take snapshots must be created before calling this.
+///
+/// The global receiver can only be set once.  If it has already been set, the given receiver is
+/// dropped without being run, and [`GlobalError::AlreadySet`] is returned.
+pub fn set_global_receiver<T>(mut receiver: Receiver<T>) -> Result<(), GlobalError>
+where
+    T: Clone + Eq + Hash + Display + Send + Sync + 'static,
+{
+    {
+        let mut global = GLOBAL_SINK.write();
+        if global.is_some() {
+            return Err(GlobalError::AlreadySet);
+        }
+        *global = Some(Box::new(receiver.get_sink()));
+    }
+
+    thread::spawn(move || receiver.run());
+    Ok(())
+}
+
+/// Gets a [`Sink`] bound to the global receiver.
+///
+/// As there is only one global receiver, it has a single metric key type: the type of the receiver
+/// given to [`set_global_receiver`].  Returns `None` if the global receiver has not been set, or if
+/// `T` is not its metric key type.
+pub fn global_sink<T>() -> Option<Sink<T>>
+where
+    T: Clone + Eq + Hash + Display + Send + Sync + 'static,
+{
+    GLOBAL_SINK
+        .read()
+        .as_ref()
+        .and_then(|sink| sink.downcast_ref::<Sink<T>>())
+        .cloned()
+}
+
+impl fmt::Display for GlobalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GlobalError::AlreadySet => write!(f, "the global receiver has already been set"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{global_sink, set_global_receiver, GlobalError};
+    use crate::receiver::Receiver;
+
+    #[test]
+    fn test_global_receiver() {
+        assert!(global_sink::<&'static str>().is_none());
+
+        let receiver = Receiver::<&'static str>::builder().build();
+        let controller = receiver.get_controller();
+        assert_eq!(set_global_receiver(receiver), Ok(()));
+
+        let sink = global_sink::<&'static str>().expect("global sink should be set");
+        sink.update_gauge("total", 42);
+        assert!(global_sink::<String>().is_none());
+
+        let other = Receiver::<&'static str>::builder().build();
+        assert_eq!(set_global_receiver(other), Err(GlobalError::AlreadySet));
+        assert!(controller.get_snapshot().is_ok());
+    }
+}
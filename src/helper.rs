@@ -1,18 +1,130 @@
 use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
     io::{Error, ErrorKind},
-    time::Duration,
+    sync::atomic::{AtomicUsize, Ordering as AtomicOrdering},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 /// Helpers to create an I/O error from a string.
 pub fn io_error(reason: &str) -> Error { Error::new(ErrorKind::Other, reason) }
 
+/// Gets a new, process-unique identifier for a clock.
+pub fn next_clock_id() -> usize {
+    static NEXT_CLOCK_ID: AtomicUsize = AtomicUsize::new(1);
+    NEXT_CLOCK_ID.fetch_add(1, AtomicOrdering::Relaxed)
+}
+
 /// Converts a duration to nanoseconds.
 pub fn duration_as_nanos(d: Duration) -> u64 { (d.as_secs() * 1_000_000_000) + u64::from(d.subsec_nanos()) }
 
+/// Gets the time from `earlier` until `later`, or zero if `later` isn't after `earlier`.
+pub fn duration_between(earlier: Instant, later: Instant) -> Duration {
+    if later > earlier {
+        later - earlier
+    } else {
+        Duration::new(0, 0)
+    }
+}
+
+/// Gets the index of the wall-clock period, of length `granularity`, that `wall` falls into.
+///
+/// Periods are counted from the Unix epoch, so two hosts with synchronized clocks will agree on
+/// where each period starts and ends.
+pub fn wall_clock_period(wall: SystemTime, granularity: Duration) -> u64 {
+    let since_epoch = wall.duration_since(UNIX_EPOCH).map(duration_as_nanos).unwrap_or(0);
+    since_epoch / duration_as_nanos(granularity)
+}
+
+/// Gets how many whole intervals, of length `granularity`, have passed from `last` to `at`.
+pub fn intervals_between(last: Instant, at: Instant, granularity: Duration) -> u64 {
+    if at <= last {
+        return 0;
+    }
+    duration_as_nanos(at - last) / duration_as_nanos(granularity)
+}
+
+/// Gets how many wall-clock periods have passed from period `last` to period `period`.
+///
+/// A wall clock that went backwards still counts as one period having passed, as there's no telling
+/// how much time actually passed.
+pub fn periods_between(last: u64, period: u64) -> u64 {
+    if period >= last {
+        period - last
+    } else {
+        1
+    }
+}
+
+/// Whether or not a metric name matches a pattern.
+///
+/// Names and patterns are split into segments on dots, and each segment of the pattern must match
+/// the segment of the name in the same position: `*` matches any one segment, and anything else
+/// matches only itself.  Names don't include labels, so `listener.*.latency` matches
+/// `listener.a.latency` and `listener.b.latency`, whatever their labels, but not `listener.latency`
+/// or `listener.a.b.latency`.
+pub fn name_matches(pattern: &str, name: &str) -> bool {
+    let mut segments = name.split('.');
+    pattern
+        .split('.')
+        .all(|p| segments.next().map_or(false, |s| p == "*" || p == s))
+        && segments.next().is_none()
+}
+
+/// Selects the `n` items with the highest rank, in descending order of rank.
+///
+/// Only `n` items are held at a time, so this is cheaper than sorting every item when `n` is small.
+pub fn top_n<I, F, R>(items: I, n: usize, rank: F) -> Vec<I::Item>
+where
+    I: Iterator,
+    F: Fn(&I::Item) -> R,
+    R: Ord,
+{
+    if n == 0 {
+        return Vec::new();
+    }
+
+    // Keep a min-heap of the best items so far, so the worst of them is always on top.
+    let mut heap = BinaryHeap::with_capacity(n + 1);
+    for item in items {
+        heap.push(Reverse(Ranked {
+            rank: rank(&item),
+            item,
+        }));
+        if heap.len() > n {
+            let _ = heap.pop();
+        }
+    }
+
+    let mut selected = heap.into_vec();
+    selected.sort();
+    selected.into_iter().map(|Reverse(ranked)| ranked.item).collect()
+}
+
+/// An item, ordered only by its rank.
+struct Ranked<R, T> {
+    rank: R,
+    item: T,
+}
+
+impl<R: Ord, T> Ord for Ranked<R, T> {
+    fn cmp(&self, other: &Self) -> Ordering { self.rank.cmp(&other.rank) }
+}
+
+impl<R: Ord, T> PartialOrd for Ranked<R, T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+
+impl<R: Ord, T> PartialEq for Ranked<R, T> {
+    fn eq(&self, other: &Self) -> bool { self.rank == other.rank }
+}
+
+impl<R: Ord, T> Eq for Ranked<R, T> {}
+
 #[cfg(test)]
 mod tests {
-    use super::duration_as_nanos;
-    use std::time::Duration;
+    use super::{duration_as_nanos, duration_between, name_matches, top_n, wall_clock_period};
+    use std::time::{Duration, Instant, UNIX_EPOCH};
 
     #[test]
     fn test_simple_duration_as_nanos() {
@@ -22,4 +134,56 @@ mod tests {
         assert_eq!(duration_as_nanos(d1), 3_000_000_000);
         assert_eq!(duration_as_nanos(d2), 500_000_000);
     }
+
+    #[test]
+    fn test_duration_between() {
+        let now = Instant::now();
+        let later = now + Duration::from_millis(5);
+
+        assert_eq!(duration_between(now, later), Duration::from_millis(5));
+        assert_eq!(duration_between(later, now), Duration::new(0, 0));
+        assert_eq!(duration_between(now, now), Duration::new(0, 0));
+    }
+
+    #[test]
+    fn test_name_matches() {
+        assert!(name_matches("listener.a.latency", "listener.a.latency"));
+        assert!(name_matches("listener.*.latency", "listener.a.latency"));
+        assert!(name_matches("*.*.latency", "listener.a.latency"));
+        assert!(!name_matches("listener.*.latency", "listener.latency"));
+        assert!(!name_matches("listener.*.latency", "listener.a.b.latency"));
+        assert!(!name_matches("listener.*", "listener.a.latency"));
+        assert!(!name_matches("listener.*.latency", "listener.a.errors"));
+    }
+
+    #[test]
+    fn test_top_n() {
+        let items = [("a", 3), ("b", 9), ("c", 1), ("d", 7), ("e", 5)];
+
+        assert_eq!(
+            top_n(items.iter(), 3, |(_, v)| *v),
+            vec![&("b", 9), &("d", 7), &("e", 5)]
+        );
+        assert_eq!(top_n(items.iter(), 10, |(_, v)| *v).len(), 5);
+        assert!(top_n(items.iter(), 0, |(_, v)| *v).is_empty());
+    }
+
+    #[test]
+    fn test_wall_clock_period() {
+        let granularity = Duration::from_secs(1);
+
+        assert_eq!(wall_clock_period(UNIX_EPOCH, granularity), 0);
+        assert_eq!(
+            wall_clock_period(UNIX_EPOCH + Duration::from_millis(999), granularity),
+            0
+        );
+        assert_eq!(
+            wall_clock_period(UNIX_EPOCH + Duration::from_millis(1000), granularity),
+            1
+        );
+        assert_eq!(
+            wall_clock_period(UNIX_EPOCH + Duration::from_millis(2500), Duration::from_millis(500)),
+            5
+        );
+    }
 }
@@ -47,7 +47,9 @@
 //! # extern crate hotmic;
 //! use hotmic::Receiver;
 //! use std::{thread, time::Duration};
-//! let receiver = Receiver::builder().build();
+//! // Metric keys are `String`s here, but any type that converts into the key type can be sent, so
+//! // string literals work just as well.
+//! let receiver = Receiver::<String>::builder().build();
 //! let sink = receiver.get_sink();
 //!
 //! // We can update a counter.  Counters are signed, and can be updated either with a delta, or
@@ -86,6 +88,22 @@
 //! sink.update_value("buf_size", buf_size);
 //! ```
 //!
+//! # Facets
+//!
+//! Metrics register themselves the first time they're sent, but callers can also register a
+//! metric ahead of time by adding a [`Facet`] through a [`Sink`].  Facets customize how the
+//! receiver tracks a given metric, such as the unit that a timing histogram stores its values in:
+//!
+//! ```
+//! # extern crate hotmic;
+//! use hotmic::{Facet, Receiver, TimeUnit};
+//! let receiver = Receiver::builder().build();
+//! let sink = receiver.get_sink();
+//!
+//! // Timings for this metric will be stored, and reported, in microseconds.
+//! sink.add_facet(Facet::timing_percentile("db.slow_query").unit(TimeUnit::Microseconds));
+//! ```
+//!
 //! # Scopes
 //!
 //! Metrics can be scoped, not unlike loggers, at the [`Sink`] level.  This allows sinks to easily
@@ -104,7 +122,7 @@
 //! ```
 //! # extern crate hotmic;
 //! use hotmic::Receiver;
-//! let receiver = Receiver::builder().build();
+//! let receiver = Receiver::<&'static str>::builder().build();
 //!
 //! // This sink has no scope aka the root scope.  The metric will just end up as "widgets".
 //! let root_sink = receiver.get_sink();
@@ -112,12 +130,12 @@
 //!
 //! // This sink is under the "secret" scope.  Since we derived ourselves from the root scope,
 //! // we're not nested under anything, but our metric name will end up being "secret.widgets".
-//! let scoped_sink = root_sink.scoped("secret");
+//! let scoped_sink = root_sink.scoped("secret").unwrap();
 //! scoped_sink.update_count("widgets", 42);
 //!
 //! // This sink is under the "supersecret" scope, but we're also nested!  The metric name for this
 //! // sample will end up being "secret.supersecret.widget".
-//! let scoped_sink_two = scoped_sink.scoped("supersecret");
+//! let scoped_sink_two = scoped_sink.scoped("supersecret").unwrap();
 //! scoped_sink_two.update_count("widgets", 42);
 //!
 //! // Sinks retain their scope even when cloned, so the metric name will be the same as above.
@@ -129,28 +147,93 @@
 //! // nesting N levels deep.
 //! //
 //! // This metric name will end up being "super.secret.ultra.special.widgets".
-//! let scoped_sink_three = scoped_sink.scoped(&["super", "secret", "ultra", "special"]);
+//! let scoped_sink_three = scoped_sink.scoped(&["super", "secret", "ultra", "special"]).unwrap();
 //! scoped_sink_two.update_count("widgets", 42);
 //! ```
 #[macro_use]
 extern crate derivative;
 
+mod collector;
 mod configuration;
 mod control;
 mod data;
+pub mod exporters;
+mod global;
 mod helper;
 mod receiver;
 mod scopes;
 mod sink;
+mod testing;
+pub mod transport;
+mod upkeep;
 
 pub use self::{
+    collector::SnapshotCollector,
     configuration::Configuration,
-    control::{Controller, SnapshotError},
-    data::Percentile,
+    control::{Controller, SnapshotError, SnapshotFuture},
+    data::{
+        facet::{Facet, FacetKind, TimeUnit, Timing},
+        view::{HistogramView, MetricView},
+        Percentile, PercentileParseError,
+    },
+    global::{global_sink, set_global_receiver, GlobalError},
     receiver::Receiver,
-    sink::{Sink, SinkError},
+    sink::{GaugeBuffer, Measurement, ScopedTimer, SendFuture, SharedCounter, Sink, SinkError, SinkFactory, Timer},
+    testing::TestReceiver,
 };
 
+/// Derives `Display` for an enum of metric keys.
+///
+/// Each variant is displayed as its name in snake case, or as the name given by a
+/// `#[metric(rename = "...")]` attribute, which keeps metric names consistent without writing a
+/// `Display` implementation by hand.  Only enums whose variants have no fields are supported.
+///
+/// Requires the `derive` feature.
+///
+/// ```
+/// # #[cfg(feature = "derive")]
+/// # fn main() {
+/// use hotmic::{MetricName, Receiver};
+///
+/// #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, MetricName)]
+/// enum Metric {
+///     QueriesExecuted,
+///     #[metric(rename = "pool.size")]
+///     PoolSize,
+/// }
+///
+/// let receiver = Receiver::<Metric>::builder().build();
+/// let sink = receiver.get_sink();
+/// sink.increment(Metric::QueriesExecuted);
+/// sink.update_gauge(Metric::PoolSize, 8);
+/// assert_eq!(Metric::QueriesExecuted.to_string(), "queries_executed");
+/// # }
+/// # #[cfg(not(feature = "derive"))]
+/// # fn main() {}
+/// ```
+#[cfg(feature = "derive")]
+pub use hotmic_derive::MetricName;
+
 pub mod snapshot {
-    pub use super::data::snapshot::{SimpleSnapshot, Snapshot, SummarizedHistogram, TypedMeasurement};
+    pub use super::data::snapshot::{
+        Exemplar, MeasurementKey, MeasurementKind, Rate, SimpleSnapshot, Snapshot, SummarizedHistogram,
+        TypedMeasurement,
+    };
+}
+
+/// The most commonly used types, for glob importing.
+///
+/// ```
+/// use hotmic::prelude::*;
+///
+/// let mut receiver = Receiver::builder().percentiles(&[50.0, 99.0]).build();
+/// let sink: Sink<&'static str> = receiver.get_sink();
+/// sink.add_facet(Facet::timing_percentile("latency").unit(TimeUnit::Microseconds));
+/// let controller: Controller = receiver.get_controller();
+/// ```
+pub mod prelude {
+    pub use super::{
+        snapshot::{SimpleSnapshot, Snapshot, TypedMeasurement},
+        Configuration, Controller, Facet, Percentile, Receiver, Sink, TimeUnit,
+    };
 }
@@ -1,131 +1,429 @@
 use crate::{
-    configuration::Configuration,
+    configuration::{Configuration, SnapshotCallback},
     control::{ControlFrame, Controller},
-    data::{Counter, Gauge, Histogram, Sample, ScopedKey, Snapshot, StringScopedKey},
+    data::{
+        histogram::HistogramSnapshot,
+        snapshot::{MeasurementKind, SummarizedHistogram, TypedMeasurement},
+        view::{HistogramView, MetricState, MetricView},
+        Counter, Facet, FacetKind, Facets, Gauge, Histogram, MeasurementKey, Percentile, Sample, ScopedKey,
+        SharedCounters, SharedValue, Snapshot, StringScopedKey, Throughput, TimeUnit, WideCounter, WindowedCounter,
+    },
+    helper::{duration_between, name_matches, next_clock_id, top_n},
     scopes::Scopes,
-    sink::Sink,
+    sink::{SendWaiters, Sink, SinkFactory},
+    transport::{
+        BoundedTransport, Frame, RecvTimeoutError, Transport, TransportReceiver, TransportSender, TryRecvError,
+    },
+    upkeep::{Tick, Upkeep, UpkeepTasks},
 };
-use crossbeam_channel::{self, bounded, tick, Select, TryRecvError};
+use crossbeam_channel::{self, bounded};
+use fnv::FnvBuildHasher;
 use quanta::Clock;
 use std::{
-    fmt::Display,
-    hash::Hash,
-    sync::Arc,
-    time::{Duration, Instant},
+    collections::{HashMap, HashSet},
+    fmt::{Display, Write},
+    hash::{BuildHasher, Hash},
+    mem,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
+/// How often histogram and windowed counter upkeep is performed, in milliseconds.
+const UPKEEP_INTERVAL_MS: u64 = 250;
+
+/// The name of the counter of timings whose end was not after their start.
+const CLOCK_ANOMALIES: &str = "hotmic.clock_anomalies";
+
 /// Wrapper for all messages that flow over the data channel between sink/receiver.
 pub(crate) enum MessageFrame<T> {
     /// A normal data message holding a metric sample.
     Data(Sample<T>),
+
+    /// Several data messages, sent together by [`Sink::record`].
+    DataMulti(Vec<Sample<T>>),
+
+    /// Registers a facet.
+    ///
+    /// Facets are boxed, as they're registered rarely but are larger than most samples, and every
+    /// message sent over the channel is sized to fit the largest variant.
+    AddFacet(Box<Facet<T>>),
+
+    /// Deregisters a facet.
+    RemoveFacet(FacetKind, T),
+
+    /// Gets the facets which apply to metrics sent from the given scope and base scope.
+    GetFacets(u64, u64, crossbeam_channel::Sender<Vec<Facet<T>>>),
+
+    /// Responds once every message sent before it has been processed.
+    Flush(crossbeam_channel::Sender<()>),
+
+    /// Shares a counter, so that updates to it are also applied to the given value.
+    ShareCounter(T, Arc<SharedValue>),
+
+    /// Wakes up the receiver, so that it notices a waiting control frame.
+    ///
+    /// Wakeups are coalesced, so at most one of these is in the channel at a time, and it isn't
+    /// counted as part of the backlog.
+    Wake,
 }
 
 /// Metrics receiver which aggregates and processes samples.
-pub struct Receiver<T: Clone + Eq + Hash + Display + Send> {
+///
+/// Metrics are stored in hash maps keyed by metric key, using the hasher `H`.  This defaults to
+/// FNV, which is fast for short keys, but a different hasher may perform better for long keys and
+/// can be used by building the receiver with [`Configuration::build_with_hasher`].
+pub struct Receiver<T: Clone + Eq + Hash + Display + Send, H: BuildHasher = FnvBuildHasher> {
     config: Configuration<T>,
 
     // Sample aggregation machinery.
-    msg_tx: crossbeam_channel::Sender<MessageFrame<ScopedKey<T>>>,
-    msg_rx: Option<crossbeam_channel::Receiver<MessageFrame<ScopedKey<T>>>>,
+    msg_tx: Arc<dyn TransportSender<Frame<T>>>,
+    msg_rx: Option<Box<dyn TransportReceiver<Frame<T>>>>,
     control_tx: crossbeam_channel::Sender<ControlFrame>,
     control_rx: Option<crossbeam_channel::Receiver<ControlFrame>>,
 
     // Metric machinery.
-    counter: Counter<ScopedKey<T>>,
-    gauge: Gauge<ScopedKey<T>>,
-    thistogram: Histogram<ScopedKey<T>>,
-    vhistogram: Histogram<ScopedKey<T>>,
+    facets: Facets<ScopedKey<T>, H>,
+    counter: Counter<ScopedKey<T>, H>,
+    wide_counter: WideCounter<ScopedKey<T>, H>,
+    windowed_counter: WindowedCounter<ScopedKey<T>, H>,
+    throughput: Throughput<ScopedKey<T>, H>,
+    gauge: Gauge<ScopedKey<T>, H>,
+    thistogram: Histogram<ScopedKey<T>, H>,
+    vhistogram: Histogram<ScopedKey<T>, H>,
+    label_rejections: Counter<ScopedKey<T>, H>,
+    facet_mismatches: Counter<ScopedKey<T>, H>,
+    clock_anomalies: i64,
+
+    // Counters shared with sinks, along with their values as of when updates made through their
+    // handles were last brought up to date.
+    shared_counters: Arc<SharedCounters<ScopedKey<T>>>,
+    shared: HashMap<ScopedKey<T>, (Arc<SharedValue>, i64), H>,
+
+    // Tasks waiting for room in the channel, which are woken up whenever messages are taken out.
+    send_waiters: Arc<SendWaiters>,
+
+    // Counter values as of the last snapshot, for computing rates.
+    last_counts: HashMap<ScopedKey<T>, i64, H>,
+    last_counts_at: Option<Instant>,
+
+    // When each metric was last updated, if tracked.
+    last_updates: HashMap<ScopedKey<T>, Instant, H>,
+
+    // The keys of metrics as they appear in snapshots, so that their names are only rendered once.
+    // Facets can rename metrics, so these are dropped whenever facets change.
+    measurement_keys: HashMap<ScopedKey<T>, MeasurementKey, H>,
+
+    // Self metrics.
+    channel_len: usize,
+
+    // Periodic work, run between batches of samples.
+    upkeep: UpkeepTasks<T, H>,
 
     clock: Clock,
+    clock_id: usize,
     scopes: Arc<Scopes>,
+
+    // Set once the receiver stops, so that controllers can fail fast.
+    stopped: Arc<AtomicBool>,
+
+    // Set while the receiver is running, the number of live sinks, and the number of messages that
+    // were waiting when the receiver last picked up more, for controllers to report.
+    running: Arc<AtomicBool>,
+    sinks: Arc<AtomicUsize>,
+    backlog: Arc<AtomicUsize>,
+
+    // Set while a wakeup is in the channel, so that controllers don't queue up more than one.
+    wake_pending: Arc<AtomicBool>,
+}
+
+/// Marks a receiver as running, and then as stopped when dropped, including when unwinding from a
+/// panic.
+struct StopGuard {
+    stopped: Arc<AtomicBool>,
+    running: Arc<AtomicBool>,
+}
+
+impl StopGuard {
+    fn new(stopped: Arc<AtomicBool>, running: Arc<AtomicBool>) -> StopGuard {
+        running.store(true, Ordering::Release);
+        StopGuard { stopped, running }
+    }
+}
+
+impl Drop for StopGuard {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        self.stopped.store(true, Ordering::Release);
+    }
 }
 
 impl<T: Clone + Eq + Hash + Display + Send> Receiver<T> {
-    pub(crate) fn from_config(config: Configuration<T>) -> Receiver<T> {
+    /// Gets a builder to configure a `Receiver` instance with.
+    pub fn builder() -> Configuration<T> { Configuration::default() }
+}
+
+impl<T: Clone + Eq + Hash + Display + Send + 'static, H: BuildHasher + Default> Receiver<T, H> {
+    pub(crate) fn from_config(config: Configuration<T>) -> Receiver<T, H> {
         // Create our data, control, and buffer channels.
-        let (msg_tx, msg_rx) = bounded(config.capacity);
-        let (control_tx, control_rx) = bounded(16);
+        let (msg_tx, msg_rx) = match &config.transport {
+            Some(transport) => transport.channel(config.capacity),
+            None => BoundedTransport.channel(config.capacity),
+        };
+        let (control_tx, control_rx) = bounded(config.control_capacity);
 
         let histogram_window = config.histogram_window;
         let histogram_granularity = config.histogram_granularity;
+        let align_windows = config.align_windows;
+        let histogram_max_value = config.histogram_max_value;
+
+        let mut upkeep = UpkeepTasks::new();
+        upkeep.add(WindowRollover);
+        if config.self_metrics {
+            upkeep.add(ChannelLength);
+        }
+        if let Some((interval, callback)) = config.snapshot_callback.clone() {
+            upkeep.add(SnapshotCallbackTask { interval, callback });
+        }
 
         Receiver {
             config,
-            msg_tx,
+            msg_tx: Arc::from(msg_tx),
             msg_rx: Some(msg_rx),
             control_tx,
             control_rx: Some(control_rx),
+            facets: Facets::new(),
             counter: Counter::new(),
-            gauge: Gauge::new(),
-            thistogram: Histogram::new(histogram_window, histogram_granularity),
-            vhistogram: Histogram::new(histogram_window, histogram_granularity),
+            wide_counter: WideCounter::new(),
+            windowed_counter: WindowedCounter::new(histogram_window, histogram_granularity, align_windows),
+            throughput: Throughput::new(),
+            gauge: Gauge::new(histogram_window, histogram_granularity, align_windows),
+            thistogram: Histogram::new(
+                histogram_window,
+                histogram_granularity,
+                histogram_max_value,
+                align_windows,
+            ),
+            vhistogram: Histogram::new(
+                histogram_window,
+                histogram_granularity,
+                histogram_max_value,
+                align_windows,
+            ),
+            label_rejections: Counter::new(),
+            facet_mismatches: Counter::new(),
+            clock_anomalies: 0,
+            shared_counters: Arc::new(SharedCounters::new()),
+            shared: HashMap::default(),
+            send_waiters: Arc::new(SendWaiters::default()),
+            last_counts: HashMap::default(),
+            last_counts_at: None,
+            last_updates: HashMap::default(),
+            measurement_keys: HashMap::default(),
+            channel_len: 0,
+            upkeep,
             clock: Clock::new(),
+            clock_id: next_clock_id(),
             scopes: Arc::new(Scopes::new()),
+            stopped: Arc::new(AtomicBool::new(false)),
+            running: Arc::new(AtomicBool::new(false)),
+            sinks: Arc::new(AtomicUsize::new(0)),
+            backlog: Arc::new(AtomicUsize::new(0)),
+            wake_pending: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    /// Gets a builder to configure a `Receiver` instance with.
-    pub fn builder() -> Configuration<T> { Configuration::default() }
-
     /// Creates a `Sink` bound to this receiver.
     pub fn get_sink(&self) -> Sink<T> {
-        Sink::new_with_scope_id(
+        Sink::root(
             self.msg_tx.clone(),
             self.clock.clone(),
+            self.clock_id,
             self.scopes.clone(),
-            "".to_owned(),
-            0,
+            self.sinks.clone(),
+            self.shared_counters.clone(),
+            self.send_waiters.clone(),
         )
     }
 
+    /// Creates a [`SinkFactory`] which creates sinks bound to this receiver.
+    pub fn get_sink_factory(&self) -> SinkFactory<T> { SinkFactory::new(self.get_sink()) }
+
     /// Creates a `Controller` bound to this receiver.
-    pub fn get_controller(&self) -> Controller { Controller::new(self.control_tx.clone()) }
+    pub fn get_controller(&self) -> Controller {
+        // The receiver only ever blocks on the data channel, so controllers nudge it through there
+        // after sending a control frame.  Only one wakeup is ever in the channel at a time: any
+        // more would just be noise for the receiver to wade through.  If the channel is full, the
+        // wakeup isn't needed, as the receiver won't block before it next checks for control
+        // frames, so it's dropped, and the next control frame sends another.
+        let msg_tx = self.msg_tx.clone();
+        let wake_pending = self.wake_pending.clone();
+        let waker = Arc::new(move || {
+            if !wake_pending.swap(true, Ordering::AcqRel) && msg_tx.try_send(Frame(MessageFrame::Wake)).is_err() {
+                wake_pending.store(false, Ordering::Release);
+            }
+        });
+
+        Controller::new(
+            self.control_tx.clone(),
+            waker,
+            self.stopped.clone(),
+            self.running.clone(),
+            self.sinks.clone(),
+            self.backlog.clone(),
+        )
+    }
 
     /// Run the receiver.
+    ///
+    /// Samples are processed in batches of up to the configured
+    /// [`batch_size`](Configuration::batch_size).  The batch buffer is allocated once, with room for
+    /// a full batch, and reused every time around the loop, so processing samples doesn't allocate.
     pub fn run(&mut self) {
         let batch_size = self.config.batch_size;
         let mut batch = Vec::with_capacity(batch_size);
-        let upkeep_rx = tick(Duration::from_millis(250));
         let control_rx = self.control_rx.take().expect("failed to take control rx");
         let msg_rx = self.msg_rx.take().expect("failed to take msg rx");
-
-        let mut selector = Select::new();
-        let _ = selector.recv(&upkeep_rx);
-        let _ = selector.recv(&control_rx);
-        let _ = selector.recv(&msg_rx);
+        let _guard = StopGuard::new(self.stopped.clone(), self.running.clone());
 
         loop {
-            // Block on having something to do.
-            let _ = selector.ready();
-
-            if upkeep_rx.try_recv().is_ok() {
-                let now = Instant::now();
-                self.thistogram.upkeep(now);
-                self.vhistogram.upkeep(now);
+            // Block on having something to do, or on periodic work coming due.
+            let timeout = duration_between(Instant::now(), self.next_periodic());
+            match msg_rx.recv_timeout(timeout) {
+                Ok(Frame(MessageFrame::Wake)) => self.wake_pending.store(false, Ordering::Release),
+                Ok(mframe) => batch.push(mframe),
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(e) => eprintln!("error receiving message frame: {}", e),
             }
 
+            let channel_len = self.pending_len(&*msg_rx);
+            self.backlog.store(batch.len() + channel_len, Ordering::Relaxed);
+            self.run_periodic(channel_len);
+
             while let Ok(cframe) = control_rx.try_recv() {
                 self.process_control_frame(cframe);
             }
 
-            loop {
-                match msg_rx.try_recv() {
-                    Ok(mframe) => batch.push(mframe),
-                    Err(TryRecvError::Empty) => break,
-                    Err(e) => eprintln!("error receiving message frame: {}", e),
-                }
-
-                if batch.len() == batch_size {
-                    break;
-                }
-            }
+            Self::fill_batch(&mut batch, batch_size, &*msg_rx, &control_rx);
+            self.send_waiters.notify();
 
             if !batch.is_empty() {
                 for mframe in batch.drain(0..) {
                     self.process_msg_frame(mframe);
                 }
             }
+
+            // Service any control frames that arrived while we were processing the batch, rather
+            // than waiting until the next time around the loop.
+            while let Ok(cframe) = control_rx.try_recv() {
+                self.process_control_frame(cframe);
+            }
+        }
+    }
+
+    /// Gets the number of messages waiting in the channel, not counting a pending wakeup.
+    fn pending_len(&self, msg_rx: &dyn TransportReceiver<Frame<T>>) -> usize {
+        let wake = if self.wake_pending.load(Ordering::Acquire) {
+            1
+        } else {
+            0
+        };
+        msg_rx.len().saturating_sub(wake)
+    }
+
+    /// Fills the batch with waiting messages, until it holds `batch_size` of them.
+    ///
+    /// The batch is never filled past `batch_size`, so a batch allocated with that capacity is
+    /// never reallocated.
+    fn fill_batch(
+        batch: &mut Vec<Frame<T>>, batch_size: usize, msg_rx: &dyn TransportReceiver<Frame<T>>,
+        control_rx: &crossbeam_channel::Receiver<ControlFrame>,
+    ) {
+        while batch.len() < batch_size {
+            match msg_rx.try_recv() {
+                Ok(mframe) => batch.push(mframe),
+                Err(TryRecvError::Empty) => break,
+                Err(e) => eprintln!("error receiving message frame: {}", e),
+            }
+
+            // Stop filling the batch early if a control frame is waiting.  Under sustained ingest
+            // pressure, the data channel is never empty, so without this check, control frames
+            // could sit behind a full batch's worth of samples every time around.
+            if !control_rx.is_empty() {
+                break;
+            }
+        }
+    }
+
+    /// Processes any samples and control frames that are currently waiting, and then returns.
+    ///
+    /// This is an alternative to [`run`](Receiver::run) for embedding the receiver in an existing
+    /// event loop, rather than giving it a dedicated thread.  It never blocks: only the samples
+    /// already in the channel when it's called are processed, so busy sinks can't hold it up, and
+    /// histogram upkeep and the snapshot callback are run if they've come due since the last call.
+    ///
+    /// Nothing wakes the event loop when samples arrive, so this should be called periodically,
+    /// such as from a repeating timer.  Sinks block when the channel is full, so it should be called
+    /// often enough to keep up with them, and at least as often as the histogram granularity, so
+    /// that windows roll over on time.  Controllers block until their request is processed, and so
+    /// must not be used from the thread that calls this.
+    pub fn poll_ready(&mut self) {
+        let control_rx = self.control_rx.take().expect("failed to take control rx");
+        let msg_rx = self.msg_rx.take().expect("failed to take msg rx");
+
+        let channel_len = self.pending_len(&*msg_rx);
+        self.backlog.store(channel_len, Ordering::Relaxed);
+        self.run_periodic(channel_len);
+
+        while let Ok(cframe) = control_rx.try_recv() {
+            self.process_control_frame(cframe);
+        }
+
+        for _ in 0..msg_rx.len() {
+            match msg_rx.try_recv() {
+                Ok(mframe) => self.process_msg_frame(mframe),
+                Err(_) => break,
+            }
+        }
+        self.send_waiters.notify();
+
+        while let Ok(cframe) = control_rx.try_recv() {
+            self.process_control_frame(cframe);
         }
+
+        self.control_rx = Some(control_rx);
+        self.msg_rx = Some(msg_rx);
+    }
+
+    /// Gets when the next upkeep task is due.
+    fn next_periodic(&self) -> Instant {
+        self.upkeep
+            .next_due()
+            .unwrap_or_else(|| Instant::now() + Duration::from_millis(UPKEEP_INTERVAL_MS))
+    }
+
+    /// Runs every upkeep task that has come due since it last ran.
+    fn run_periodic(&mut self, channel_len: usize) {
+        let tick = Tick {
+            now: Instant::now(),
+            wall: SystemTime::now(),
+            channel_len,
+        };
+
+        // Tasks are given the receiver, so they're moved out of it while they run.
+        let mut upkeep = mem::replace(&mut self.upkeep, UpkeepTasks::new());
+        upkeep.run_due(self, &tick);
+        self.upkeep = upkeep;
+    }
+
+    /// Rolls over histogram, windowed counter, and max and min gauge windows.
+    fn roll_windows(&mut self, now: Instant, wall: SystemTime) {
+        self.thistogram.upkeep(now, wall);
+        self.vhistogram.upkeep(now, wall);
+        self.windowed_counter.upkeep(now, wall);
+        self.gauge.upkeep(now, wall);
     }
 
     /// Gets the string representation of an integer scope.
@@ -133,84 +431,2089 @@ impl<T: Clone + Eq + Hash + Display + Send> Receiver<T> {
     /// Returns `Some(scope)` if found, `None` otherwise.  Scope ID `0` is reserved for the root
     /// scope.
     fn get_string_scope(&self, key: ScopedKey<T>) -> Option<StringScopedKey<T>> {
+        let name = if self.facets.has_output_names() {
+            self.facets.output_name(&key).map(str::to_owned)
+        } else {
+            None
+        };
+
         let scope_id = key.id();
         if scope_id == 0 {
-            return Some(key.into_string_scoped("".to_owned()));
+            return Some(key.into_string_scoped("".to_owned(), Vec::new(), name));
+        }
+
+        self.scopes
+            .get(scope_id)
+            .map(|(scope, labels)| key.into_string_scoped(scope, labels, name))
+    }
+
+    /// Gets the key of the given metric as it appears in a snapshot, with its name and labels.
+    ///
+    /// Returns `None` if the key's scope is not registered.
+    fn measurement_key(&self, key: &ScopedKey<T>) -> Option<MeasurementKey> {
+        match self.measurement_keys.get(key) {
+            Some(actual_key) => Some(actual_key.clone()),
+            None => self.get_string_scope(key.clone()).map(MeasurementKey::from),
+        }
+    }
+
+    /// Gets the key of the given metric as in [`measurement_key`](Receiver::measurement_key),
+    /// keeping it around for the next snapshot.
+    fn cached_measurement_key(&mut self, key: &ScopedKey<T>) -> Option<MeasurementKey> {
+        if let Some(actual_key) = self.measurement_keys.get(key) {
+            return Some(actual_key.clone());
         }
 
-        self.scopes.get(scope_id).map(|scope| key.into_string_scoped(scope))
+        let actual_key = self.measurement_key(key)?;
+        let _ = self.measurement_keys.insert(key.clone(), actual_key.clone());
+        Some(actual_key)
+    }
+
+    /// Writes the fully-qualified name of the given key into `buf`, replacing its contents.
+    ///
+    /// Returns `false` if the key's scope is not registered.
+    fn write_name(&self, key: &ScopedKey<T>, buf: &mut String) -> bool {
+        buf.clear();
+        match self.get_string_scope(key.clone()) {
+            Some(actual_key) => write!(buf, "{}", actual_key).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Creates an empty snapshot, taken now.
+    fn new_snapshot(&self) -> Snapshot {
+        let mut snapshot = Snapshot::default();
+        self.reset_snapshot(&mut snapshot);
+        snapshot
+    }
+
+    /// Empties a snapshot, keeping its storage, and marks it as taken now.
+    fn reset_snapshot(&self, snapshot: &mut Snapshot) {
+        snapshot.measurements.clear();
+        snapshot.taken_at = Some((self.clock.now(), SystemTime::now()));
+    }
+
+    /// Processes every sample waiting in the channel, without running upkeep or handling control
+    /// frames.
+    pub(crate) fn process_pending(&mut self) {
+        if let Some(msg_rx) = self.msg_rx.take() {
+            while let Ok(mframe) = msg_rx.try_recv() {
+                self.process_msg_frame(mframe);
+            }
+            self.send_waiters.notify();
+            self.msg_rx = Some(msg_rx);
+        }
     }
 
     /// Gets a snapshot of the current metrics/facets.
-    fn get_snapshot(&self) -> Snapshot {
+    pub(crate) fn get_snapshot(&mut self) -> Snapshot {
+        self.sync_shared_counters();
         let mut snapshot = Snapshot::default();
+        self.fill_snapshot(&mut snapshot);
+        snapshot
+    }
+
+    /// Replaces the contents of a snapshot with the current metrics/facets.
+    fn fill_snapshot(&mut self, snapshot: &mut Snapshot) {
+        let now = Instant::now();
+        self.reset_snapshot(snapshot);
         let cvalues = self.counter.values();
+        let wvalues = self.wide_counter.values();
+        let wcvalues = self.windowed_counter.values();
         let gvalues = self.gauge.values();
+        let tpvalues = self.throughput.values();
         let tvalues = self.thistogram.values();
         let vvalues = self.vhistogram.values();
 
         for (key, value) in cvalues {
-            if let Some(actual_key) = self.get_string_scope(key) {
+            let rate = self.counter_rate(&key, value, now);
+            if let Some(actual_key) = self.cached_measurement_key(&key) {
+                if let Some(rate) = rate {
+                    snapshot.set_counter_rate(actual_key.clone(), rate);
+                }
                 snapshot.set_count(actual_key, value);
             }
         }
 
+        if self.config.include_rates {
+            self.last_counts = self.counter.iter().map(|(k, v)| (k.clone(), *v)).collect();
+            self.last_counts_at = Some(now);
+        }
+
+        for (key, value) in wvalues {
+            if let Some(actual_key) = self.cached_measurement_key(&key) {
+                snapshot.set_wide_count(actual_key, value);
+            }
+        }
+
+        for (key, value) in wcvalues {
+            if let Some(actual_key) = self.cached_measurement_key(&key) {
+                snapshot.set_windowed_count(actual_key, value);
+            }
+        }
+
         for (key, value) in gvalues {
-            if let Some(actual_key) = self.get_string_scope(key) {
+            if let Some(actual_key) = self.cached_measurement_key(&key) {
                 snapshot.set_gauge(actual_key, value);
             }
         }
 
+        for (key, value) in tpvalues {
+            if let Some(actual_key) = self.cached_measurement_key(&key) {
+                snapshot.set_throughput(actual_key, value);
+            }
+        }
+
         for (key, value) in tvalues {
-            if let Some(actual_key) = self.get_string_scope(key) {
-                snapshot.set_timing_histogram(actual_key, value, &self.config.percentiles);
+            if let Some(actual_key) = self.cached_measurement_key(&key) {
+                let unit = self.timing_unit(&key);
+                let percentiles = self.percentiles(FacetKind::TimingPercentile, &key);
+                if self.exposes_count(FacetKind::TimingPercentile, &key) {
+                    snapshot.set_count(actual_key.clone().with_suffix(".total"), value.total() as i64);
+                }
+                let summarized = self.summarize(&actual_key, &value, percentiles, Some(unit));
+                snapshot.set_timing_histogram(actual_key, summarized);
             }
         }
 
         for (key, value) in vvalues {
-            if let Some(actual_key) = self.get_string_scope(key) {
-                snapshot.set_value_histogram(actual_key, value, &self.config.percentiles);
+            if let Some(actual_key) = self.cached_measurement_key(&key) {
+                let percentiles = self.percentiles(FacetKind::ValuePercentile, &key);
+                if self.exposes_count(FacetKind::ValuePercentile, &key) {
+                    snapshot.set_count(actual_key.clone().with_suffix(".total"), value.total() as i64);
+                }
+                let summarized = self.summarize(&actual_key, &value, percentiles, None);
+                snapshot.set_value_histogram(actual_key, summarized);
             }
         }
 
-        snapshot
+        let mut name = String::new();
+        for (key, value) in self.label_rejections.iter() {
+            if self.write_name(key, &mut name) {
+                snapshot.set_count(label_rejections_key(&name), *value);
+            }
+        }
+
+        for (key, value) in self.facet_mismatches.iter() {
+            if self.write_name(key, &mut name) {
+                snapshot.set_count(facet_mismatches_key(&name), *value);
+            }
+        }
+
+        for key in self.counter.saturated() {
+            if self.write_name(key, &mut name) {
+                snapshot.set_gauge(saturated_key(&name), 1);
+            }
+        }
+
+        if self.clock_anomalies > 0 {
+            snapshot.set_count(CLOCK_ANOMALIES, self.clock_anomalies);
+        }
+
+        if self.config.self_metrics {
+            snapshot.set_gauge("hotmic.channel.capacity", self.config.capacity as u64);
+            snapshot.set_gauge("hotmic.channel.len", self.channel_len as u64);
+        }
     }
 
-    /// Processes a control frame.
-    fn process_control_frame(&self, msg: ControlFrame) {
-        match msg {
-            ControlFrame::Snapshot(tx) => {
-                let snapshot = self.get_snapshot();
-                let _ = tx.send(snapshot);
+    /// Gets the per-second rate of a counter since the last snapshot, if rates are enabled.
+    fn counter_rate(&self, key: &ScopedKey<T>, value: i64, now: Instant) -> Option<f64> {
+        let elapsed = now.duration_since(self.last_counts_at?);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1_000_000_000.0;
+        if elapsed <= 0.0 {
+            return None;
+        }
+
+        // Counters wrap around, so the change since the last snapshot does too.
+        let last = self.last_counts.get(key).cloned().unwrap_or(0);
+        Some(value.wrapping_sub(last) as f64 / elapsed)
+    }
+
+    /// Gets a snapshot of the `n` largest metrics of the given kind.
+    ///
+    /// Counter rates are ranked by their rate since the last snapshot, without this counting as a
+    /// snapshot itself, and are only available when rates are enabled.
+    fn get_top(&self, kind: MeasurementKind, n: usize) -> Snapshot {
+        let now = Instant::now();
+        let mut snapshot = self.new_snapshot();
+
+        match kind {
+            MeasurementKind::Counter => {
+                for (key, value) in top_n(self.counter.iter(), n, |(_, v)| **v) {
+                    if let Some(actual_key) = self.get_string_scope(key.clone()) {
+                        snapshot.set_count(actual_key, *value);
+                    }
+                }
             },
-            ControlFrame::SnapshotAsync(tx) => {
-                let snapshot = self.get_snapshot();
-                let _ = tx.send(snapshot);
+            MeasurementKind::CounterWide => {
+                for (key, value) in top_n(self.wide_counter.iter(), n, |(_, v)| **v) {
+                    if let Some(actual_key) = self.get_string_scope(key.clone()) {
+                        snapshot.set_wide_count(actual_key, *value);
+                    }
+                }
+            },
+            MeasurementKind::CounterWindowed => {
+                let values = self.windowed_counter.iter().map(|(k, v)| (k, v.value()));
+                for (key, value) in top_n(values, n, |(_, v)| *v) {
+                    if let Some(actual_key) = self.get_string_scope(key.clone()) {
+                        snapshot.set_windowed_count(actual_key, value);
+                    }
+                }
+            },
+            MeasurementKind::CounterRate => {
+                // Every counter is measured over the same interval, so ranking them by their change
+                // in value is the same as ranking them by their rate.  Counters wrap around, so the
+                // change in them does too.
+                let last = |k: &ScopedKey<T>| self.last_counts.get(k).cloned().unwrap_or(0);
+                for (key, value) in top_n(self.counter.iter(), n, |(k, v)| v.wrapping_sub(last(k))) {
+                    if let Some(rate) = self.counter_rate(key, *value, now) {
+                        if let Some(actual_key) = self.get_string_scope(key.clone()) {
+                            snapshot.set_counter_rate(actual_key, rate);
+                        }
+                    }
+                }
+            },
+            MeasurementKind::Gauge => {
+                for (key, value) in top_n(self.gauge.iter(), n, |(_, v)| **v) {
+                    if let Some(actual_key) = self.get_string_scope(key.clone()) {
+                        snapshot.set_gauge(actual_key, *value);
+                    }
+                }
+            },
+            MeasurementKind::Throughput => {
+                // Throughputs are never negative, and the bits of non-negative floats sort in the
+                // same order as the floats themselves.
+                for (key, value) in top_n(self.throughput.iter(), n, |(_, v)| v.to_bits()) {
+                    if let Some(actual_key) = self.get_string_scope(key.clone()) {
+                        snapshot.set_throughput(actual_key, value);
+                    }
+                }
+            },
+            MeasurementKind::TimingHistogram => {
+                for (key, histogram) in top_n(self.thistogram.iter(), n, |(_, h)| h.count()) {
+                    let unit = self.timing_unit(key);
+                    let percentiles = self.percentiles(FacetKind::TimingPercentile, key);
+                    if let Some(actual_key) = self.measurement_key(key) {
+                        let summarized = self.summarize(&actual_key, &histogram.snapshot(), percentiles, Some(unit));
+                        snapshot.set_timing_histogram(actual_key, summarized);
+                    }
+                }
+            },
+            MeasurementKind::ValueHistogram => {
+                for (key, histogram) in top_n(self.vhistogram.iter(), n, |(_, h)| h.count()) {
+                    let percentiles = self.percentiles(FacetKind::ValuePercentile, key);
+                    if let Some(actual_key) = self.measurement_key(key) {
+                        let summarized = self.summarize(&actual_key, &histogram.snapshot(), percentiles, None);
+                        snapshot.set_value_histogram(actual_key, summarized);
+                    }
+                }
             },
         }
+
+        snapshot
     }
 
-    /// Processes a message frame.
-    fn process_msg_frame(&mut self, msg: MessageFrame<ScopedKey<T>>) {
-        match msg {
-            MessageFrame::Data(sample) => {
-                match sample {
-                    Sample::Count(key, count) => {
-                        self.counter.update(key, count);
-                    },
-                    Sample::Gauge(key, value) => {
-                        self.gauge.update(key, value);
-                    },
-                    Sample::TimingHistogram(key, start, end, count) => {
-                        let delta = self.clock.delta(start, end);
-                        self.counter.update(key.clone(), count as i64);
-                        self.thistogram.update(key, delta);
-                    },
-                    Sample::ValueHistogram(key, value) => {
-                        self.vhistogram.update(key, value);
-                    },
+    /// Gets the current values of the metrics whose fully-qualified names are in `names`.
+    ///
+    /// Measurements are keyed by name, so when a timing histogram and its counter share a name, the
+    /// histogram, being stored last, is the one returned.
+    fn get_values(&self, names: &[String]) -> HashMap<String, TypedMeasurement> {
+        let wanted = names.iter().map(String::as_str).collect::<HashSet<_>>();
+        let is_wanted = |key: &MeasurementKey| wanted.contains(key.to_string().as_str());
+        let wanted_key = |key: &ScopedKey<T>| self.measurement_key(key).filter(|key| is_wanted(key));
+        let mut snapshot = self.new_snapshot();
+        let mut name = String::new();
+
+        for (key, value) in self.counter.iter() {
+            if let Some(key) = wanted_key(key) {
+                snapshot.set_count(key, *value);
+            }
+        }
+
+        for (key, value) in self.wide_counter.iter() {
+            if let Some(key) = wanted_key(key) {
+                snapshot.set_wide_count(key, *value);
+            }
+        }
+
+        for (key, value) in self.windowed_counter.iter() {
+            if let Some(key) = wanted_key(key) {
+                snapshot.set_windowed_count(key, value.value());
+            }
+        }
+
+        for (key, value) in self.gauge.iter() {
+            if let Some(key) = wanted_key(key) {
+                snapshot.set_gauge(key, *value);
+            }
+        }
+
+        for (key, value) in self.throughput.iter() {
+            if let Some(key) = wanted_key(key) {
+                snapshot.set_throughput(key, value);
+            }
+        }
+
+        for (key, histogram) in self.thistogram.iter() {
+            if let Some(actual_key) = wanted_key(key) {
+                let unit = self.timing_unit(key);
+                let percentiles = self.percentiles(FacetKind::TimingPercentile, key);
+                let summarized = self.summarize(&actual_key, &histogram.snapshot(), percentiles, Some(unit));
+                snapshot.set_timing_histogram(actual_key, summarized);
+            }
+        }
+
+        for (key, histogram) in self.vhistogram.iter() {
+            if let Some(actual_key) = wanted_key(key) {
+                let percentiles = self.percentiles(FacetKind::ValuePercentile, key);
+                let summarized = self.summarize(&actual_key, &histogram.snapshot(), percentiles, None);
+                snapshot.set_value_histogram(actual_key, summarized);
+            }
+        }
+
+        for (key, value) in self.label_rejections.iter() {
+            if self.write_name(key, &mut name) {
+                let rejections_key = label_rejections_key(&name);
+                if is_wanted(&rejections_key) {
+                    snapshot.set_count(rejections_key, *value);
+                }
+            }
+        }
+
+        for (key, value) in self.facet_mismatches.iter() {
+            if self.write_name(key, &mut name) {
+                let mismatches_key = facet_mismatches_key(&name);
+                if is_wanted(&mismatches_key) {
+                    snapshot.set_count(mismatches_key, *value);
+                }
+            }
+        }
+
+        for key in self.counter.saturated() {
+            if self.write_name(key, &mut name) {
+                let saturated_key = saturated_key(&name);
+                if is_wanted(&saturated_key) {
+                    snapshot.set_gauge(saturated_key, 1);
+                }
+            }
+        }
+
+        if self.clock_anomalies > 0 && wanted.contains(CLOCK_ANOMALIES) {
+            snapshot.set_count(CLOCK_ANOMALIES, self.clock_anomalies);
+        }
+
+        if self.config.self_metrics {
+            if wanted.contains("hotmic.channel.capacity") {
+                snapshot.set_gauge("hotmic.channel.capacity", self.config.capacity as u64);
+            }
+            if wanted.contains("hotmic.channel.len") {
+                snapshot.set_gauge("hotmic.channel.len", self.channel_len as u64);
+            }
+        }
+
+        snapshot
+            .into_iter()
+            .map(|measurement| (measurement.key().to_string(), measurement))
+            .collect()
+    }
+
+    /// Updates the counter for the given key, which may be a wide or windowed counter if one was
+    /// registered.
+    fn update_count(&mut self, key: ScopedKey<T>, delta: i64, rules: FacetRules) {
+        let kind = rules.count;
+        if self.config.include_windowed_counts && kind != Some(FacetKind::WindowedCount) {
+            self.windowed_counter.update(key.clone(), delta);
+        }
+
+        match kind {
+            Some(FacetKind::WideCount) => self.wide_counter.update(key, delta),
+            Some(FacetKind::WindowedCount) => self.windowed_counter.update(key, delta),
+            _ if rules.saturating => self.counter.update_saturating(key, delta),
+            _ => self.counter.update(key, delta),
+        }
+    }
+
+    /// Applies any updates made directly to shared counters since they were last brought up to date.
+    fn sync_shared_counters(&mut self) {
+        let deltas = self
+            .shared
+            .iter_mut()
+            .filter_map(|(key, (value, seen))| {
+                let current = value.value();
+                let delta = current.wrapping_sub(*seen);
+                *seen = current;
+                if delta != 0 {
+                    Some((key.clone(), delta))
+                } else {
+                    None
                 }
+            })
+            .collect::<Vec<_>>();
+
+        for (key, delta) in deltas {
+            let rules = self.facet_rules(&key);
+            self.update_count(key, delta, rules);
+        }
+    }
+
+    /// Updates the gauge for the given key, which may keep its maximum or minimum value if a max or
+    /// min gauge was registered.
+    fn update_gauge(&mut self, key: ScopedKey<T>, value: u64, rules: FacetRules) {
+        match rules.gauge {
+            Some(FacetKind::MaxGauge) => self.gauge.update_max(key, value),
+            Some(FacetKind::MinGauge) => self.gauge.update_min(key, value),
+            _ => self.gauge.update(key, value),
+        }
+    }
+
+    /// Updates the timing histogram, and its counter, for the given key.
+    ///
+    /// If `when` is given, the timing is recorded into the histogram bucket covering that time.
+    fn update_timing(
+        &mut self, key: ScopedKey<T>, nanos: u64, count: u64, when: Option<Instant>, exemplar: Option<String>,
+        rules: FacetRules,
+    ) {
+        let value = rules.unit.from_nanos(nanos);
+        let rejected = is_rejected(rules.timing_reject_above, value);
+
+        self.update_count(key.clone(), count as i64, rules);
+        if !rejected && rules.throughput {
+            self.throughput.update(key.clone(), count, nanos);
+        }
+
+        if rejected {
+            self.thistogram.reject(key);
+        } else if let Some(when) = when {
+            let _ = self
+                .thistogram
+                .update_at(key, value, when, Instant::now(), SystemTime::now());
+        } else if let Some(exemplar) = exemplar {
+            self.thistogram.update_with_exemplar(key, value, exemplar);
+        } else {
+            self.thistogram.update(key, value);
+        }
+    }
+
+    /// Records that the metric with the given key was just updated.
+    fn touch(&mut self, key: &ScopedKey<T>) {
+        let now = Instant::now();
+        match self.last_updates.get_mut(key) {
+            Some(at) => *at = now,
+            None => {
+                let _ = self.last_updates.insert(key.clone(), now);
             },
         }
     }
+
+    /// Whether or not the histogram for the given key reports its count as a counter.
+    fn exposes_count(&self, kind: FacetKind, key: &ScopedKey<T>) -> bool {
+        self.facets.get(kind, key).map(|f| f.expose_count).unwrap_or(false)
+    }
+
+    /// Gets the times at which metrics were last updated, by their fully-qualified names.
+    fn get_last_updates(&self) -> HashMap<String, Instant> {
+        let mut name = String::new();
+        self.last_updates
+            .iter()
+            .filter_map(|(key, at)| {
+                if self.write_name(key, &mut name) {
+                    Some((name.clone(), *at))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Registers a facet, switching the histogram for its metric to the storage it asks for.
+    fn add_facet(&mut self, facet: Facet<ScopedKey<T>>) {
+        // Registering a facet that's already registered changes nothing, so that facets can be
+        // registered wherever a metric is used without any risk of resetting it.
+        if self.facets.get(facet.kind, &facet.key) == Some(&facet) {
+            return;
+        }
+
+        match facet.kind {
+            FacetKind::TimingPercentile => self.thistogram.set_storage(facet.key.clone(), facet.storage()),
+            FacetKind::ValuePercentile => self.vhistogram.set_storage(facet.key.clone(), facet.storage()),
+            _ => {},
+        }
+
+        self.facets.add(facet);
+        self.measurement_keys.clear();
+    }
+
+    /// Gets the facets which apply to metrics sent from the given scope.
+    ///
+    /// Facets which restrict labels are registered at the base scope, without labels, and so those
+    /// are included as well.
+    fn registered_facets(&self, scope_id: u64, base_scope_id: u64) -> Vec<Facet<ScopedKey<T>>> {
+        self.facets
+            .iter()
+            .filter(|f| f.key.id() == scope_id || (f.key.id() == base_scope_id && f.allowed_labels.is_some()))
+            .cloned()
+            .collect()
+    }
+
+    /// Deregisters a facet, and drops any data recorded for the metric it applied to.
+    fn remove_facet(&mut self, kind: FacetKind, key: ScopedKey<T>) {
+        let _ = self.facets.remove(kind, &key);
+        let _ = self.last_updates.remove(&key);
+        self.measurement_keys.clear();
+
+        let _ = match kind {
+            FacetKind::Count => self.counter.remove(&key),
+            FacetKind::WideCount => self.wide_counter.remove(&key),
+            FacetKind::WindowedCount => self.windowed_counter.remove(&key),
+            FacetKind::Gauge | FacetKind::MaxGauge | FacetKind::MinGauge => self.gauge.remove(&key),
+            FacetKind::TimingPercentile => self.thistogram.remove(&key),
+            FacetKind::ValuePercentile => self.vhistogram.remove(&key),
+            FacetKind::Throughput => self.throughput.remove(&key),
+        };
+
+        // Windowed counts kept alongside counters go along with them.
+        if self.config.include_windowed_counts && (kind == FacetKind::Count || kind == FacetKind::WideCount) {
+            let _ = self.windowed_counter.remove(&key);
+        }
+    }
+
+    /// Whether or not the labels of the given key are allowed by the facets for its metric.
+    ///
+    /// Samples with labels that aren't allowed are counted as rejected against the metric.  Labels
+    /// are only looked up if some facet restricts them, as otherwise every label is allowed.
+    fn check_labels(&mut self, key: &ScopedKey<T>) -> bool {
+        if !self.facets.has_label_rules() {
+            return true;
+        }
+
+        let facets = &self.facets;
+        let rejected = self
+            .scopes
+            .with_labels(key.id(), |base_id, labels| {
+                let base = key.with_scope_id(base_id);
+                if facets.get_all(&base).iter().all(|f| f.allows_labels(labels)) {
+                    None
+                } else {
+                    Some(base)
+                }
+            })
+            .and_then(|base| base);
+
+        match rejected {
+            Some(base) => {
+                self.label_rejections.update(base, 1);
+                false
+            },
+            None => true,
+        }
+    }
+
+    /// Gets how the facets registered for the given key affect recording its samples.
+    ///
+    /// No facets are registered at all in the common case, and then the lookup is skipped.
+    fn facet_rules(&self, key: &ScopedKey<T>) -> FacetRules {
+        if self.facets.is_empty() {
+            FacetRules::default()
+        } else {
+            FacetRules::new(self.facets.get_all(key))
+        }
+    }
+
+    /// Gets how the facets registered for the metric of a sample affect recording it, and whether
+    /// or not the sample is tracked by one of them, if there are any.
+    ///
+    /// The facets are looked up once, for both, and not at all if no facets are registered.
+    /// Samples that aren't tracked are counted as mismatched against the metric.
+    fn check_facets(&mut self, sample: &Sample<ScopedKey<T>>) -> Option<FacetRules> {
+        if self.facets.is_empty() {
+            return Some(FacetRules::default());
+        }
+
+        let facets = self.facets.get_all(sample.key());
+        if !self.config.validate_facets || facets.is_empty() || facets.iter().any(|f| sample.is_tracked_by(f.kind)) {
+            return Some(FacetRules::new(facets));
+        }
+
+        self.facet_mismatches.update(sample.key().clone(), 1);
+        None
+    }
+
+    /// Gets the unit that timings for the given key are stored in.
+    fn timing_unit(&self, key: &ScopedKey<T>) -> TimeUnit {
+        self.facets
+            .get(FacetKind::TimingPercentile, key)
+            .map(|facet| facet.unit)
+            .unwrap_or_default()
+    }
+
+    /// Gets the percentiles to summarize the given histogram with.
+    fn percentiles(&self, kind: FacetKind, key: &ScopedKey<T>) -> &[Percentile] {
+        self.facets
+            .get(kind, key)
+            .and_then(|facet| facet.percentiles.as_ref())
+            .map(Vec::as_slice)
+            .unwrap_or(&self.config.percentiles)
+    }
+
+    /// Summarizes a histogram for a snapshot, as this receiver is configured.
+    ///
+    /// `unit` is the unit of a timing histogram, and `None` for a value histogram.
+    fn summarize(
+        &self, key: &MeasurementKey, histogram: &HistogramSnapshot, percentiles: &[Percentile], unit: Option<TimeUnit>,
+    ) -> SummarizedHistogram {
+        let config = &self.config;
+        let summarized = SummarizedHistogram::from_histogram(
+            histogram,
+            percentiles,
+            config.raw_histograms,
+            config.interpolate_percentiles,
+        )
+        .with_buckets(histogram, config.histogram_buckets.bounds(key.name(), unit))
+        .with_sparse_threshold(config.sparse_threshold);
+
+        match unit {
+            Some(unit) => summarized.with_unit(unit),
+            None => summarized,
+        }
+    }
+
+    /// Whether or not the fully-qualified name of the given key matches `name`.
+    fn key_matches(&self, key: &ScopedKey<T>, name: &str) -> bool {
+        self.get_string_scope(key.clone())
+            .map(|actual_key| actual_key.to_string() == name)
+            .unwrap_or(false)
+    }
+
+    /// Merges the histograms whose fully-qualified name matches the given pattern, timing and value
+    /// histograms separately, into a snapshot of the merged histograms named after the pattern.
+    fn get_merged_histogram(&self, pattern: &str) -> Snapshot {
+        let mut snapshot = self.new_snapshot();
+
+        // Timing histograms hold values in their own unit, so only those in the same unit can be
+        // merged, and the finest unit loses the least precision.
+        let timings = self
+            .thistogram
+            .iter()
+            .filter_map(|(key, histogram)| {
+                self.measurement_key(key)
+                    .filter(|actual_key| name_matches(pattern, actual_key.name()))
+                    .map(|actual_key| (actual_key, self.timing_unit(key), histogram.snapshot()))
+            })
+            .collect::<Vec<_>>();
+        if let Some(unit) = timings
+            .iter()
+            .map(|(_, unit, _)| *unit)
+            .min_by_key(|unit| unit.to_nanos(1))
+        {
+            let histograms = timings
+                .into_iter()
+                .filter(|(_, u, _)| *u == unit)
+                .map(|(key, _, histogram)| (key, histogram))
+                .collect();
+            if let Some(merged) = merge_histograms(histograms) {
+                let key = MeasurementKey::new(pattern);
+                let summarized = self.summarize(&key, &merged, &self.config.percentiles, Some(unit));
+                snapshot.set_timing_histogram(key, summarized);
+            }
+        }
+
+        let values = self
+            .vhistogram
+            .iter()
+            .filter_map(|(key, histogram)| {
+                self.measurement_key(key)
+                    .filter(|actual_key| name_matches(pattern, actual_key.name()))
+                    .map(|actual_key| (actual_key, histogram.snapshot()))
+            })
+            .collect();
+        if let Some(merged) = merge_histograms(values) {
+            let key = MeasurementKey::new(pattern);
+            let summarized = self.summarize(&key, &merged, &self.config.percentiles, None);
+            snapshot.set_value_histogram(key, summarized);
+        }
+
+        snapshot
+    }
+
+    /// Clears the histograms whose fully-qualified name matches the given name.
+    fn reset_histogram(&mut self, name: &str) {
+        for key in self.thistogram.keys() {
+            if self.key_matches(&key, name) {
+                let _ = self.thistogram.clear(&key);
+            }
+        }
+
+        for key in self.vhistogram.keys() {
+            if self.key_matches(&key, name) {
+                let _ = self.vhistogram.clear(&key);
+            }
+        }
+    }
+
+    /// Processes a control frame.
+    fn process_control_frame(&mut self, msg: ControlFrame) {
+        self.sync_shared_counters();
+        match msg {
+            ControlFrame::Snapshot(tx) => {
+                let snapshot = self.get_snapshot();
+                let _ = tx.send(snapshot);
+            },
+            ControlFrame::SnapshotAsync(tx) => {
+                let snapshot = self.get_snapshot();
+                let _ = tx.send(snapshot);
+            },
+            ControlFrame::SnapshotInto(mut snapshot, tx) => {
+                self.fill_snapshot(&mut snapshot);
+                let _ = tx.send(snapshot);
+            },
+            ControlFrame::GetTop(kind, n, tx) => {
+                let snapshot = self.get_top(kind, n);
+                let _ = tx.send(snapshot);
+            },
+            ControlFrame::GetValues(names, tx) => {
+                let values = self.get_values(&names);
+                let _ = tx.send(values);
+            },
+            ControlFrame::GetLastUpdates(tx) => {
+                let _ = tx.send(self.get_last_updates());
+            },
+            ControlFrame::ResetHistogram(name, tx) => {
+                self.reset_histogram(&name);
+                let _ = tx.send(());
+            },
+            ControlFrame::GetMergedHistogram(pattern, tx) => {
+                let _ = tx.send(self.get_merged_histogram(&pattern));
+            },
+            ControlFrame::SetPercentiles(percentiles, tx) => {
+                self.config.percentiles = percentiles;
+                let _ = tx.send(());
+            },
+            ControlFrame::WithState(f) => {
+                let view = MetricView::new(self);
+                f(&view);
+            },
+        }
+    }
+
+    /// Processes a single metric sample.
+    fn process_sample(&mut self, sample: Sample<ScopedKey<T>>) {
+        if !self.check_labels(sample.key()) {
+            return;
+        }
+
+        let rules = match self.check_facets(&sample) {
+            Some(rules) => rules,
+            None => return,
+        };
+
+        if self.config.track_last_update {
+            self.touch(sample.key());
+        }
+
+        match sample {
+            Sample::Count(key, count) => {
+                // Updates to a shared counter are applied to its shared value too, and marked as
+                // seen, so that they aren't counted again when syncing updates made through handles.
+                if let Some((value, seen)) = self.shared.get_mut(&key) {
+                    value.update(count);
+                    *seen = seen.wrapping_add(count);
+                }
+                self.update_count(key, count, rules);
+            },
+            Sample::Gauge(key, value) => {
+                self.update_gauge(key, value, rules);
+            },
+            Sample::TimingHistogram(key, start, end) => {
+                let delta = self.timing_delta(start, end);
+                self.update_timing(key, delta, 1, None, None, rules);
+            },
+            Sample::TimingHistogramCount(key, timing) => {
+                let (start, end, count) = *timing;
+                let delta = self.timing_delta(start, end);
+                self.update_timing(key, delta, count, None, None, rules);
+            },
+            Sample::TimingLatency(key, nanos) => {
+                self.update_timing(key, nanos, 1, None, None, rules);
+            },
+            Sample::TimingLatencyAt(key, latency) => {
+                let (nanos, when) = *latency;
+                self.update_timing(key, nanos, 1, Some(when), None, rules);
+            },
+            Sample::ValueHistogram(key, value) => {
+                self.update_value(key, value, None, rules);
+            },
+            Sample::TimingExemplar(key, timing) => {
+                let (start, end, exemplar) = *timing;
+                let delta = self.timing_delta(start, end);
+                self.update_timing(key, delta, 1, None, Some(exemplar), rules);
+            },
+            Sample::ValueExemplar(key, value) => {
+                let (value, exemplar) = *value;
+                self.update_value(key, value, Some(exemplar), rules);
+            },
+        }
+    }
+
+    /// Converts raw start and end times from the clock into a duration, in nanoseconds.
+    fn timing_delta(&mut self, start: u64, end: u64) -> u64 {
+        // The timing is still recorded, as zero, but a clock that goes backwards, or doesn't move at
+        // all, is worth knowing about.
+        if end > start {
+            self.clock.delta(start, end)
+        } else {
+            self.clock_anomalies += 1;
+            0
+        }
+    }
+
+    fn update_value(&mut self, key: ScopedKey<T>, value: u64, exemplar: Option<String>, rules: FacetRules) {
+        if is_rejected(rules.value_reject_above, value) {
+            self.vhistogram.reject(key);
+        } else if let Some(exemplar) = exemplar {
+            self.vhistogram.update_with_exemplar(key, value, exemplar);
+        } else {
+            self.vhistogram.update(key, value);
+        }
+    }
+
+    /// Processes a message frame.
+    fn process_msg_frame(&mut self, msg: Frame<T>) {
+        match msg.0 {
+            MessageFrame::Data(sample) => self.process_sample(sample),
+            MessageFrame::DataMulti(samples) => {
+                for sample in samples {
+                    self.process_sample(sample);
+                }
+            },
+            MessageFrame::AddFacet(facet) => self.add_facet(*facet),
+            MessageFrame::RemoveFacet(kind, key) => self.remove_facet(kind, key),
+            MessageFrame::GetFacets(scope_id, base_scope_id, tx) => {
+                let _ = tx.send(self.registered_facets(scope_id, base_scope_id));
+            },
+            MessageFrame::Flush(tx) => {
+                let _ = tx.send(());
+            },
+            MessageFrame::ShareCounter(key, value) => {
+                let _ = self.shared.entry(key).or_insert((value, 0));
+            },
+            MessageFrame::Wake => self.wake_pending.store(false, Ordering::Release),
+        }
+    }
+}
+
+impl<T: Clone + Eq + Hash + Display + Send, H: BuildHasher> Drop for Receiver<T, H> {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Release);
+        self.send_waiters.close();
+    }
+}
+
+impl<T: Clone + Eq + Hash + Display + Send + 'static, H: BuildHasher + Default> MetricState for Receiver<T, H> {
+    fn visit_counters(&self, f: &mut dyn FnMut(&str, i64)) {
+        let mut name = String::new();
+        for (key, value) in self.counter.iter() {
+            if self.write_name(key, &mut name) {
+                f(&name, *value);
+            }
+        }
+    }
+
+    fn visit_gauges(&self, f: &mut dyn FnMut(&str, u64)) {
+        let mut name = String::new();
+        for (key, value) in self.gauge.iter() {
+            if self.write_name(key, &mut name) {
+                f(&name, *value);
+            }
+        }
+    }
+
+    fn visit_timing_histograms(&self, f: &mut dyn FnMut(&str, &HistogramView)) {
+        let mut name = String::new();
+        for (key, histogram) in self.thistogram.iter() {
+            if self.write_name(key, &mut name) {
+                let snapshot = histogram.snapshot();
+                f(&name, &HistogramView::new(&snapshot));
+            }
+        }
+    }
+
+    fn visit_value_histograms(&self, f: &mut dyn FnMut(&str, &HistogramView)) {
+        let mut name = String::new();
+        for (key, histogram) in self.vhistogram.iter() {
+            if self.write_name(key, &mut name) {
+                let snapshot = histogram.snapshot();
+                f(&name, &HistogramView::new(&snapshot));
+            }
+        }
+    }
+}
+
+/// Gets the key of the counter of samples rejected for having labels that aren't allowed.
+fn label_rejections_key(metric: &str) -> MeasurementKey {
+    MeasurementKey::new("hotmic.labels.rejected").with_labels(vec![("metric", metric)])
+}
+
+/// Rolls over histogram and windowed counter windows.
+struct WindowRollover;
+
+impl<T: Clone + Eq + Hash + Display + Send + 'static, H: BuildHasher + Default> Upkeep<T, H> for WindowRollover {
+    fn interval(&self) -> Duration { Duration::from_millis(UPKEEP_INTERVAL_MS) }
+
+    fn run(&mut self, receiver: &mut Receiver<T, H>, tick: &Tick) { receiver.roll_windows(tick.now, tick.wall); }
+}
+
+/// Records the length of the data channel, for self metrics.
+struct ChannelLength;
+
+impl<T: Clone + Eq + Hash + Display + Send, H: BuildHasher> Upkeep<T, H> for ChannelLength {
+    fn interval(&self) -> Duration { Duration::from_millis(UPKEEP_INTERVAL_MS) }
+
+    fn run(&mut self, receiver: &mut Receiver<T, H>, tick: &Tick) { receiver.channel_len = tick.channel_len; }
+}
+
+/// Calls the snapshot callback with a snapshot of the current metrics.
+struct SnapshotCallbackTask {
+    interval: Duration,
+    callback: SnapshotCallback,
+}
+
+impl<T: Clone + Eq + Hash + Display + Send + 'static, H: BuildHasher + Default> Upkeep<T, H> for SnapshotCallbackTask {
+    fn interval(&self) -> Duration { self.interval }
+
+    fn run(&mut self, receiver: &mut Receiver<T, H>, _: &Tick) { (self.callback)(&receiver.get_snapshot()); }
+}
+
+/// Gets the key of the counter of samples rejected for not matching the facets of their metric.
+fn facet_mismatches_key(metric: &str) -> MeasurementKey {
+    MeasurementKey::new("hotmic.facets.mismatched").with_labels(vec![("metric", metric)])
+}
+
+/// Gets the key of the gauge which flags a counter as saturated.
+fn saturated_key(metric: &str) -> MeasurementKey {
+    MeasurementKey::new("hotmic.counters.saturated").with_labels(vec![("metric", metric)])
+}
+
+/// Whether or not a histogram value should be rejected, based on the facet for the histogram.
+fn is_rejected(reject_above: Option<u64>, value: u64) -> bool {
+    match reject_above {
+        Some(threshold) => value > threshold,
+        None => false,
+    }
+}
+
+/// How the facets registered for a metric affect recording its samples.
+///
+/// Built from a single lookup of the facets for a sample, and then handed to each step of
+/// recording it, rather than each step looking up the facets it cares about again.
+#[derive(Clone, Copy, Default)]
+struct FacetRules {
+    /// The wide or windowed counter registered for the metric, if any.
+    count: Option<FacetKind>,
+
+    /// Whether or not the counter saturates rather than wrapping around.
+    saturating: bool,
+
+    /// The max or min gauge registered for the metric, if any.
+    gauge: Option<FacetKind>,
+
+    /// The unit timings are stored in.
+    unit: TimeUnit,
+
+    /// The threshold above which timings are rejected.
+    timing_reject_above: Option<u64>,
+
+    /// The threshold above which values are rejected.
+    value_reject_above: Option<u64>,
+
+    /// Whether or not throughput is tracked for timings.
+    throughput: bool,
+}
+
+impl FacetRules {
+    fn new<T>(facets: &[Facet<T>]) -> FacetRules {
+        let mut rules = FacetRules::default();
+        for facet in facets {
+            match facet.kind {
+                FacetKind::Count => rules.saturating = facet.saturating,
+                FacetKind::WideCount | FacetKind::WindowedCount => rules.count = rules.count.or(Some(facet.kind)),
+                FacetKind::MaxGauge | FacetKind::MinGauge => rules.gauge = rules.gauge.or(Some(facet.kind)),
+                FacetKind::TimingPercentile => {
+                    rules.unit = facet.unit;
+                    rules.timing_reject_above = facet.reject_above;
+                },
+                FacetKind::ValuePercentile => rules.value_reject_above = facet.reject_above,
+                FacetKind::Throughput => rules.throughput = true,
+                FacetKind::Gauge => {},
+            }
+        }
+        rules
+    }
+}
+
+/// Merges histograms together, in order of their keys, so that the result doesn't depend on the
+/// order they're stored in.
+///
+/// HdrHistograms and sketches can't be merged with each other, so if there are any HdrHistograms,
+/// only those are merged, and otherwise the sketches are.
+fn merge_histograms(mut histograms: Vec<(MeasurementKey, HistogramSnapshot)>) -> Option<HistogramSnapshot> {
+    if histograms.iter().any(|(_, histogram)| !histogram.is_sketch()) {
+        histograms.retain(|(_, histogram)| !histogram.is_sketch());
+    }
+    histograms.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut histograms = histograms.into_iter().map(|(_, histogram)| histogram);
+    let mut merged = histograms.next()?;
+    for histogram in histograms {
+        let _ = merged.merge(&histogram);
+    }
+    Some(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ControlFrame, MessageFrame, Receiver, SinkFactory};
+    use crate::{
+        data::{
+            snapshot::{MeasurementKind, TypedMeasurement},
+            Facet, Sample, ScopedKey, Snapshot,
+        },
+        sink::Measurement,
+    };
+    use crossbeam_channel::bounded;
+    use std::{
+        mem, thread,
+        time::{Duration, Instant, SystemTime},
+    };
+
+    #[test]
+    fn test_batch_not_reallocated() {
+        let mut receiver = Receiver::<&'static str>::builder().batch_size(8).build();
+        let sink = receiver.get_sink();
+        for _ in 0..100 {
+            sink.increment("ok");
+        }
+
+        let msg_rx = receiver.msg_rx.take().unwrap();
+        let control_rx = receiver.control_rx.take().unwrap();
+        let mut batch = Vec::with_capacity(8);
+        let capacity = batch.capacity();
+        let mut batches = 0;
+        loop {
+            Receiver::<&'static str>::fill_batch(&mut batch, 8, &*msg_rx, &control_rx);
+            if batch.is_empty() {
+                break;
+            }
+            assert!(batch.len() <= 8);
+            assert_eq!(batch.capacity(), capacity);
+
+            batches += 1;
+            for mframe in batch.drain(..) {
+                receiver.process_msg_frame(mframe);
+            }
+        }
+
+        assert_eq!(batches, 13);
+        assert_eq!(receiver.get_snapshot().into_simple().count("ok"), Some(100));
+    }
+
+    #[test]
+    fn test_snapshot_not_starved_under_load() {
+        let mut receiver = Receiver::<&'static str>::builder().batch_size(1024).build();
+        let sink = receiver.get_sink();
+        for _ in 0..100 {
+            sink.increment("ok");
+        }
+
+        // A snapshot request arrives while the data channel is still full of samples.
+        let (tx, rx) = bounded(1);
+        receiver.control_tx.send(ControlFrame::Snapshot(tx)).unwrap();
+
+        // Filling the batch stops as soon as the control frame is seen, rather than taking all of
+        // the waiting samples first.
+        let msg_rx = receiver.msg_rx.take().unwrap();
+        let control_rx = receiver.control_rx.take().unwrap();
+        let mut batch = Vec::with_capacity(1024);
+        Receiver::<&'static str>::fill_batch(&mut batch, 1024, &*msg_rx, &control_rx);
+        assert_eq!(batch.len(), 1);
+        assert_eq!(msg_rx.len(), 99);
+
+        for mframe in batch.drain(..) {
+            receiver.process_msg_frame(mframe);
+        }
+        receiver.process_control_frame(control_rx.try_recv().unwrap());
+        assert_eq!(rx.try_recv().unwrap().into_simple().count("ok"), Some(1));
+    }
+
+    #[test]
+    fn test_message_frame_size() {
+        // Every message sent over the data channel is sized to fit the largest message frame, so
+        // anything other than samples should never make a frame larger than the samples it carries.
+        assert_eq!(
+            mem::size_of::<MessageFrame<ScopedKey<&'static str>>>(),
+            mem::size_of::<Sample<ScopedKey<&'static str>>>()
+        );
+        assert_eq!(
+            mem::size_of::<MessageFrame<ScopedKey<String>>>(),
+            mem::size_of::<Sample<ScopedKey<String>>>()
+        );
+
+        // Samples themselves are no larger than a key, a start and end time, and a tag, with
+        // anything bigger boxed.
+        let words = 3 * mem::size_of::<u64>();
+        assert!(mem::size_of::<Sample<ScopedKey<&'static str>>>() <= mem::size_of::<ScopedKey<&'static str>>() + words);
+        assert!(mem::size_of::<Sample<ScopedKey<String>>>() <= mem::size_of::<ScopedKey<String>>() + words);
+    }
+
+    #[test]
+    fn test_control_capacity() {
+        let receiver = Receiver::<&'static str>::builder().build();
+        assert_eq!(receiver.control_tx.capacity(), Some(16));
+
+        // Requests queue up until the receiver gets to them, up to the capacity of the channel.
+        let receiver = Receiver::<&'static str>::builder().control_capacity(64).build();
+        let controller = receiver.get_controller();
+        let pending = (0..64)
+            .map(|_| controller.get_snapshot_async().expect("failed to request snapshot"))
+            .collect::<Vec<_>>();
+        assert_eq!(pending.len(), 64);
+        assert_eq!(receiver.control_tx.len(), 64);
+
+        let receiver = Receiver::<&'static str>::builder().control_capacity(0).build();
+        assert_eq!(receiver.control_tx.capacity(), Some(1));
+    }
+
+    #[test]
+    fn test_exemplars() {
+        let mut receiver = Receiver::<&'static str>::builder().percentiles(&[50.0, 100.0]).build();
+        let sink = receiver.get_sink();
+        sink.update_value_with_exemplar("payload", 10, "trace-a");
+        sink.update_value_with_exemplar("payload", 1_000, "trace-b");
+        sink.update_value_with_exemplar("payload", 12, "trace-c");
+        let start = sink.clock().start();
+        sink.update_timing_with_exemplar("query", start, sink.clock().end(), "trace-d");
+        receiver.process_pending();
+
+        let mut exemplars = receiver
+            .get_snapshot()
+            .into_vec()
+            .into_iter()
+            .flat_map(|measurement| {
+                match measurement {
+                    TypedMeasurement::ValueHistogram(name, summary)
+                    | TypedMeasurement::TimingHistogram(name, summary) => {
+                        summary
+                            .exemplars()
+                            .iter()
+                            .map(|(percentile, exemplar)| {
+                                (
+                                    name.to_string(),
+                                    percentile.label().to_owned(),
+                                    exemplar.label().to_owned(),
+                                )
+                            })
+                            .collect()
+                    },
+                    _ => Vec::new(),
+                }
+            })
+            .collect::<Vec<_>>();
+        exemplars.sort();
+
+        // Values of about the same magnitude share an exemplar, so the most recent one wins.
+        let exemplar =
+            |name: &str, percentile: &str, label: &str| (name.to_owned(), percentile.to_owned(), label.to_owned());
+        assert_eq!(
+            exemplars[..2],
+            [
+                exemplar("payload", "max", "trace-b"),
+                exemplar("payload", "p50", "trace-c")
+            ]
+        );
+        assert_eq!(exemplars.len(), 3);
+        assert_eq!((exemplars[2].0.as_str(), exemplars[2].2.as_str()), ("query", "trace-d"));
+    }
+
+    #[test]
+    fn test_duplicate_facets() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let sink = receiver.get_sink();
+        let facets = [
+            Facet::count("requests"),
+            Facet::windowed_count("errors"),
+            Facet::max_gauge("peak"),
+            Facet::value_percentile("payload").sketch(),
+        ];
+
+        sink.add_facets(&facets);
+        sink.update_count("requests", 5);
+        sink.update_count("errors", 2);
+        sink.update_gauge("peak", 9);
+        sink.update_value("payload", 100);
+
+        // Registering the same facets again is a no-op, so everything recorded so far survives.
+        sink.add_facets(&facets);
+        sink.update_count("requests", 3);
+        sink.update_gauge("peak", 4);
+        sink.update_value("payload", 300);
+        receiver.process_pending();
+
+        let snapshot = receiver.get_snapshot().into_simple();
+        assert_eq!(snapshot.count("requests"), Some(8));
+        assert_eq!(snapshot.windowed_count("errors"), Some(2));
+        assert_eq!(snapshot.gauge("peak"), Some(9));
+        assert_eq!(snapshot.value_count("payload"), Some(2));
+        assert_eq!(receiver.facets.iter().count(), 4);
+    }
+
+    #[test]
+    fn test_poll_ready() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let sink = receiver.get_sink();
+        sink.update_count("requests", 2);
+        sink.update_gauge("connections", 5);
+
+        // Nothing is processed until we poll.
+        assert!(receiver.get_snapshot().into_simple().count("requests").is_none());
+
+        receiver.poll_ready();
+        let snapshot = receiver.get_snapshot().into_simple();
+        assert_eq!(snapshot.count("requests"), Some(2));
+        assert_eq!(snapshot.gauge("connections"), Some(5));
+
+        // Polling with nothing waiting returns immediately, and can be repeated.
+        receiver.poll_ready();
+        sink.update_count("requests", 1);
+        receiver.poll_ready();
+        assert_eq!(receiver.get_snapshot().into_simple().count("requests"), Some(3));
+    }
+
+    #[test]
+    fn test_get_top() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let msg_rx = receiver.msg_rx.take().unwrap();
+        let sink = receiver.get_sink();
+        sink.update_count("a", 3);
+        sink.update_count("b", 9);
+        sink.update_count("c", 1);
+        sink.update_gauge("g", 5);
+        for _ in 0..3 {
+            sink.update_value("busy", 10);
+        }
+        sink.update_value("quiet", 10);
+
+        while let Ok(mframe) = msg_rx.try_recv() {
+            receiver.process_msg_frame(mframe);
+        }
+
+        let top = receiver.get_top(MeasurementKind::Counter, 2).into_vec();
+        let names = top.iter().map(|m| (m.name(), m.as_counter())).collect::<Vec<_>>();
+        assert_eq!(names, vec![("b", Some(9)), ("a", Some(3))]);
+
+        let top = receiver.get_top(MeasurementKind::ValueHistogram, 1).into_vec();
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].name(), "busy");
+
+        assert_eq!(receiver.get_top(MeasurementKind::Gauge, 10).into_vec().len(), 1);
+        assert!(receiver.get_top(MeasurementKind::CounterWide, 10).into_vec().is_empty());
+    }
+
+    #[test]
+    fn test_include_windowed_counts() {
+        let mut receiver = Receiver::<&'static str>::builder()
+            .histogram(Duration::from_secs(10), Duration::from_secs(1))
+            .include_windowed_counts(true)
+            .build();
+        let sink = receiver.get_sink();
+        sink.add_facet(Facet::windowed_count("recent"));
+        sink.update_count("requests", 3);
+        sink.update_count("recent", 2);
+        receiver.process_pending();
+
+        let snapshot = receiver.get_snapshot().into_simple();
+        assert_eq!(snapshot.count("requests"), Some(3));
+        assert_eq!(snapshot.windowed_count("requests"), Some(3));
+        assert_eq!(snapshot.count("recent"), None);
+        assert_eq!(snapshot.windowed_count("recent"), Some(2));
+
+        // Once the window has passed, only the cumulative count remains.
+        receiver.roll_windows(Instant::now() + Duration::from_secs(20), SystemTime::now());
+        sink.increment("requests");
+        receiver.process_pending();
+        let snapshot = receiver.get_snapshot().into_simple();
+        assert_eq!(snapshot.count("requests"), Some(4));
+        assert_eq!(snapshot.windowed_count("requests"), Some(1));
+
+        sink.remove_facet(Facet::count("requests"));
+        receiver.process_pending();
+        let snapshot = receiver.get_snapshot().into_simple();
+        assert_eq!(snapshot.count("requests"), None);
+        assert_eq!(snapshot.windowed_count("requests"), None);
+    }
+
+    #[test]
+    fn test_counter_rates() {
+        let mut receiver = Receiver::<&'static str>::builder().include_rates(true).build();
+        let msg_rx = receiver.msg_rx.take().unwrap();
+        let sink = receiver.get_sink();
+
+        // The first snapshot has nothing to compare against.
+        sink.update_count("requests", 10);
+        while let Ok(mframe) = msg_rx.try_recv() {
+            receiver.process_msg_frame(mframe);
+        }
+        let snapshot = receiver.get_snapshot().into_simple();
+        assert_eq!(snapshot.count("requests"), Some(10));
+        assert_eq!(snapshot.counter_rate("requests"), None);
+
+        // Pretend the last snapshot was taken two seconds ago.
+        receiver.last_counts_at = Some(Instant::now() - Duration::from_secs(2));
+        sink.update_count("requests", 20);
+        sink.update_count("errors", 4);
+        while let Ok(mframe) = msg_rx.try_recv() {
+            receiver.process_msg_frame(mframe);
+        }
+
+        let top = receiver.get_top(MeasurementKind::CounterRate, 1).into_vec();
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].name(), "requests");
+
+        let snapshot = receiver.get_snapshot().into_simple();
+        assert_eq!(snapshot.count("requests"), Some(30));
+        let rate = snapshot.counter_rate("requests").unwrap();
+        assert!(rate > 9.0 && rate <= 10.0, "rate was {}", rate);
+        let rate = snapshot.counter_rate("errors").unwrap();
+        assert!(rate > 1.8 && rate <= 2.0, "rate was {}", rate);
+
+        // Without rates enabled, none are reported.
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        receiver.get_snapshot();
+        let snapshot = receiver.get_snapshot().into_simple();
+        assert!(snapshot.counter_rates.is_empty());
+        assert!(receiver.get_top(MeasurementKind::CounterRate, 1).into_vec().is_empty());
+    }
+
+    #[test]
+    fn test_counter_rate_wraps() {
+        let mut receiver = Receiver::<&'static str>::builder().include_rates(true).build();
+        let msg_rx = receiver.msg_rx.take().unwrap();
+        let sink = receiver.get_sink();
+
+        sink.update_count("bytes", i64::max_value() - 5);
+        while let Ok(mframe) = msg_rx.try_recv() {
+            receiver.process_msg_frame(mframe);
+        }
+        receiver.get_snapshot();
+
+        // The counter wraps around past its largest value, but its rate is still the change in it.
+        receiver.last_counts_at = Some(Instant::now() - Duration::from_secs(2));
+        sink.update_count("bytes", 10);
+        while let Ok(mframe) = msg_rx.try_recv() {
+            receiver.process_msg_frame(mframe);
+        }
+
+        let snapshot = receiver.get_snapshot().into_simple();
+        assert_eq!(snapshot.count("bytes"), Some(i64::min_value() + 4));
+        let rate = snapshot.counter_rate("bytes").unwrap();
+        assert!(rate > 4.5 && rate <= 5.0, "rate was {}", rate);
+
+        // Ranking by rate wraps around the same way.
+        receiver.last_counts_at = Some(Instant::now() - Duration::from_secs(2));
+        sink.update_count("bytes", i64::max_value());
+        sink.update_count("requests", 1);
+        while let Ok(mframe) = msg_rx.try_recv() {
+            receiver.process_msg_frame(mframe);
+        }
+        let top = receiver.get_top(MeasurementKind::CounterRate, 1).into_vec();
+        assert_eq!(top[0].name(), "bytes");
+    }
+
+    #[test]
+    fn test_last_updates() {
+        let mut receiver = Receiver::<&'static str>::builder().track_last_update(true).build();
+        let msg_rx = receiver.msg_rx.take().unwrap();
+        let sink = receiver.get_sink();
+
+        let before = Instant::now();
+        sink.update_count("requests", 1);
+        sink.scoped("db").unwrap().update_gauge("connections", 5);
+        while let Ok(mframe) = msg_rx.try_recv() {
+            receiver.process_msg_frame(mframe);
+        }
+        let first = receiver.get_last_updates();
+        assert_eq!(first.len(), 2);
+        assert!(first["requests"] >= before);
+        assert!(first["db.connections"] >= before);
+
+        thread::sleep(Duration::from_millis(5));
+        sink.update_count("requests", 1);
+        while let Ok(mframe) = msg_rx.try_recv() {
+            receiver.process_msg_frame(mframe);
+        }
+        let second = receiver.get_last_updates();
+        assert!(second["requests"] > first["requests"]);
+        assert_eq!(second["db.connections"], first["db.connections"]);
+
+        // Nothing is tracked unless enabled.
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let msg_rx = receiver.msg_rx.take().unwrap();
+        receiver.get_sink().update_count("requests", 1);
+        while let Ok(mframe) = msg_rx.try_recv() {
+            receiver.process_msg_frame(mframe);
+        }
+        assert!(receiver.get_last_updates().is_empty());
+    }
+
+    #[test]
+    fn test_saturating_counter() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let msg_rx = receiver.msg_rx.take().unwrap();
+        let sink = receiver.get_sink();
+        sink.add_facet(Facet::count("bytes").saturating());
+        sink.update_count("bytes", i64::max_value());
+        sink.update_count("bytes", 10);
+        sink.update_count("wrapped", i64::max_value());
+        sink.update_count("wrapped", 1);
+
+        while let Ok(mframe) = msg_rx.try_recv() {
+            receiver.process_msg_frame(mframe);
+        }
+
+        let snapshot = receiver.get_snapshot().into_simple();
+        assert_eq!(snapshot.count("bytes"), Some(i64::max_value()));
+        assert_eq!(snapshot.count("wrapped"), Some(i64::min_value()));
+        let saturated = |metric| snapshot.gauge_labeled("hotmic.counters.saturated", &[("metric", metric)]);
+        assert_eq!(saturated("bytes"), Some(1));
+        assert_eq!(saturated("wrapped"), None);
+
+        let values = receiver.get_values(&[r#"hotmic.counters.saturated{metric="bytes"}"#.to_owned()]);
+        assert_eq!(values.len(), 1);
+    }
+
+    #[test]
+    fn test_histogram_buckets_count() {
+        let receiver = Receiver::<&'static str>::builder()
+            .histogram_buckets_count(Duration::from_secs(10), 5)
+            .build();
+        assert_eq!(receiver.config.histogram_window, Duration::from_secs(10));
+        assert_eq!(receiver.config.histogram_granularity, Duration::from_secs(2));
+
+        let receiver = Receiver::<&'static str>::builder()
+            .histogram_buckets_count(Duration::from_secs(10), 0)
+            .build();
+        assert_eq!(receiver.config.histogram_granularity, Duration::from_secs(10));
+
+        let receiver = Receiver::<&'static str>::builder()
+            .histogram_buckets_count(Duration::from_millis(10), 100)
+            .build();
+        assert_eq!(receiver.config.histogram_granularity, Duration::from_millis(1));
+    }
+
+    #[test]
+    fn test_zero_histogram_window() {
+        let mut receiver = Receiver::<&'static str>::builder()
+            .histogram(Duration::new(0, 0), Duration::new(0, 0))
+            .build();
+        let msg_rx = receiver.msg_rx.take().unwrap();
+        let sink = receiver.get_sink();
+        sink.record_latency_nanos("query", 100);
+        sink.update_timing("query", 0, 100);
+        while let Ok(mframe) = msg_rx.try_recv() {
+            receiver.process_msg_frame(mframe);
+        }
+        receiver.roll_windows(Instant::now(), SystemTime::now());
+
+        let snapshot = receiver.get_snapshot().into_simple();
+        assert_eq!(snapshot.timing_count("query"), Some(2));
+    }
+
+    #[test]
+    fn test_merged_histogram() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let sink = receiver.get_sink();
+        let a = sink.scoped(&["listener", "a"]).unwrap();
+        let b = sink
+            .scoped(&["listener", "b"])
+            .unwrap()
+            .with_labels(&[("region", "us")]);
+        let c = sink.scoped(&["listener", "c"]).unwrap();
+        c.add_facet(Facet::value_percentile("size").sketch());
+        for value in 1..=50 {
+            a.record_latency_nanos("latency", value);
+            b.record_latency_nanos("latency", value + 50);
+            a.update_value("size", value);
+            c.update_value("size", 1_000);
+        }
+        sink.scoped("listener")
+            .unwrap()
+            .record_latency_nanos("latency", 1_000_000);
+        receiver.process_pending();
+
+        let snapshot = receiver.get_merged_histogram("listener.*.latency");
+        assert_eq!(snapshot.measurements.len(), 1);
+        match &snapshot.measurements[0] {
+            TypedMeasurement::TimingHistogram(name, histogram) => {
+                assert_eq!(name, "listener.*.latency");
+                assert_eq!(histogram.count(), 100);
+                assert_eq!(histogram.sum(), (1..=100).sum::<u64>());
+            },
+            measurement => panic!("unexpected measurement {}", measurement),
+        }
+        let simple = snapshot.into_simple();
+        assert_eq!(simple.timing_histogram_raw("listener.*.latency", 100.0), Some(100));
+
+        // A sketch can't be merged with an HdrHistogram, so only the HdrHistogram is included.
+        let snapshot = receiver.get_merged_histogram("listener.*.size");
+        match &snapshot.measurements[..] {
+            [TypedMeasurement::ValueHistogram(_, histogram)] => assert_eq!(histogram.sum(), (1..=50).sum::<u64>()),
+            measurements => panic!("unexpected measurements {:?}", measurements),
+        }
+
+        assert!(receiver
+            .get_merged_histogram("listener.*.missing")
+            .measurements
+            .is_empty());
+    }
+
+    #[test]
+    fn test_facet_percentiles() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let sink = receiver.get_sink();
+        sink.add_facet(Facet::value_percentile("cache.hits").percentiles(&[50.0, 90.0]));
+        sink.add_facet(Facet::timing_percentile("query").percentiles(&[99.99]));
+        for value in 1..=100 {
+            sink.update_value("cache.hits", value);
+            sink.update_value("payload", value);
+            sink.record_latency_nanos("query", value);
+        }
+        receiver.process_pending();
+
+        let snapshot = receiver.get_snapshot().into_simple();
+        assert!(snapshot.value_histogram("cache.hits", 90.0).is_some());
+        assert_eq!(snapshot.value_histogram("cache.hits", 99.0), None);
+        assert!(snapshot.timing_histogram_raw("query", 99.99).is_some());
+        assert_eq!(snapshot.timing_histogram_raw("query", 50.0), None);
+
+        // Histograms without their own percentiles use the defaults.
+        assert!(snapshot.value_histogram("payload", 99.0).is_some());
+        assert_eq!(snapshot.value_histogram("payload", 90.0), None);
+
+        let values = receiver.get_values(&["cache.hits".to_owned()]);
+        match &values["cache.hits"] {
+            TypedMeasurement::ValueHistogram(_, histogram) => assert_eq!(histogram.measurements().len(), 2),
+            measurement => panic!("unexpected measurement {}", measurement),
+        }
+    }
+
+    #[test]
+    fn test_summarize_histograms() {
+        let mut receiver = Receiver::<&'static str>::builder()
+            .raw_histograms(true)
+            .sparse_histogram_threshold(3)
+            .interpolate_percentiles(true)
+            .histogram_buckets("payload", &[150])
+            .percentiles(&[50.0])
+            .build();
+        let sink = receiver.get_sink();
+        sink.update_value("payload", 100);
+        sink.update_value("payload", 200);
+        receiver.process_pending();
+
+        let snapshot = receiver.get_snapshot().into_vec();
+        let histogram = snapshot[0].as_histogram().unwrap();
+        assert_eq!(histogram.recorded(), Some(&[(100, 1), (200, 1)][..]));
+        assert!(histogram.is_sparse());
+        assert_eq!(
+            histogram.measurements().values().cloned().collect::<Vec<_>>(),
+            vec![150]
+        );
+        assert_eq!(histogram.buckets(), &[(150, 1)][..]);
+    }
+
+    #[test]
+    fn test_expose_count() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let msg_rx = receiver.msg_rx.take().unwrap();
+        let sink = receiver.get_sink().with_labels(&[("db", "a")]);
+        sink.add_facet(Facet::timing_percentile("query").expose_count());
+        sink.add_facet(Facet::value_percentile("rows").expose_count());
+        sink.update_timing_with_count("query", 0, 100, 10);
+        sink.record_latency_nanos("query", 100);
+        sink.update_value("rows", 10);
+        sink.record_latency_nanos("other", 100);
+        while let Ok(mframe) = msg_rx.try_recv() {
+            receiver.process_msg_frame(mframe);
+        }
+
+        let snapshot = receiver.get_snapshot().into_simple();
+        assert_eq!(snapshot.count_labeled("query.total", &[("db", "a")]), Some(2));
+        assert_eq!(snapshot.count_labeled("query", &[("db", "a")]), Some(11));
+        assert_eq!(snapshot.count_labeled("rows.total", &[("db", "a")]), Some(1));
+        assert_eq!(snapshot.count("other.total"), None);
+    }
+
+    #[test]
+    fn test_record_measurement() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let msg_rx = receiver.msg_rx.take().unwrap();
+        let sink = receiver.get_sink().scoped("db").unwrap();
+        sink.record(
+            "query",
+            Measurement::new().latency_nanos(100).value(7).outcome("query.ok"),
+        );
+        sink.record("query", Measurement::new().count(2).gauge(3));
+
+        // Each measurement is a single message.
+        assert_eq!(msg_rx.len(), 2);
+        while let Ok(mframe) = msg_rx.try_recv() {
+            receiver.process_msg_frame(mframe);
+        }
+
+        let snapshot = receiver.get_snapshot().into_simple();
+        assert_eq!(snapshot.count("db.query"), Some(3));
+        assert_eq!(snapshot.timing_count("db.query"), Some(1));
+        assert_eq!(snapshot.value_histogram("db.query", 100.0), Some(7));
+        assert_eq!(snapshot.gauge("db.query"), Some(3));
+        assert_eq!(snapshot.count("db.query.ok"), Some(1));
+    }
+
+    #[test]
+    fn test_sink_factory() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let msg_rx = receiver.msg_rx.take().unwrap();
+        let factory = receiver.get_sink_factory();
+        let cloned = factory.clone();
+        factory.sink().increment("root");
+        cloned.scoped("db").unwrap().increment("queries");
+        assert!(factory.scoped("").is_err());
+        while let Ok(mframe) = msg_rx.try_recv() {
+            receiver.process_msg_frame(mframe);
+        }
+
+        let snapshot = receiver.get_snapshot().into_simple();
+        assert_eq!(snapshot.count("root"), Some(1));
+        assert_eq!(snapshot.count("db.queries"), Some(1));
+
+        // Disabled factories hand out disabled sinks.
+        SinkFactory::<&'static str>::disabled().sink().increment("root");
+    }
+
+    #[test]
+    fn test_throughput() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let msg_rx = receiver.msg_rx.take().unwrap();
+        let sink = receiver.get_sink();
+        sink.add_facet(Facet::throughput("db.rows"));
+        sink.update_timing_with_count("db.rows", 0, 1_000_000_000, 100);
+        sink.update_timing_with_count("db.rows", 0, 3_000_000_000, 300);
+        sink.update_timing_with_count("db.other", 0, 1_000_000_000, 100);
+        while let Ok(mframe) = msg_rx.try_recv() {
+            receiver.process_msg_frame(mframe);
+        }
+
+        let snapshot = receiver.get_snapshot().into_simple();
+        assert_eq!(snapshot.count("db.rows"), Some(400));
+        assert_eq!(snapshot.timing_count("db.rows"), Some(2));
+        let throughput = snapshot.throughput("db.rows").unwrap();
+        assert!((throughput - 100.0).abs() < 1.0, "throughput was {}", throughput);
+        assert_eq!(snapshot.throughput("db.other"), None);
+
+        let top = receiver.get_top(MeasurementKind::Throughput, 1).into_vec();
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].name(), "db.rows");
+
+        sink.remove_facet(Facet::throughput("db.rows"));
+        while let Ok(mframe) = msg_rx.try_recv() {
+            receiver.process_msg_frame(mframe);
+        }
+        assert_eq!(receiver.get_snapshot().into_simple().throughput("db.rows"), None);
+    }
+
+    #[test]
+    fn test_update_timing_at() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let msg_rx = receiver.msg_rx.take().unwrap();
+        let sink = receiver.get_sink();
+        let start = Instant::now();
+
+        // Create the histogram, and then move an hour past it, so that earlier times can be built
+        // forward from the start, rather than by subtracting from an `Instant`, which can underflow.
+        sink.record_latency_nanos("replay", 100);
+        while let Ok(mframe) = msg_rx.try_recv() {
+            receiver.process_msg_frame(mframe);
+        }
+        receiver.roll_windows(start + Duration::from_secs(3601), SystemTime::now());
+
+        sink.update_timing_at("replay", 200, start + Duration::from_secs(3600));
+        sink.update_timing_at("replay", 300, start);
+        while let Ok(mframe) = msg_rx.try_recv() {
+            receiver.process_msg_frame(mframe);
+        }
+
+        // The sample from an hour ago is too old for the histogram, but still counted, along with
+        // the first one, which has since rolled out of the window.
+        let snapshot = receiver.get_snapshot().into_simple();
+        assert_eq!(snapshot.count("replay"), Some(3));
+        assert_eq!(snapshot.timing_count("replay"), Some(1));
+    }
+
+    #[test]
+    fn test_output_names() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let msg_rx = receiver.msg_rx.take().unwrap();
+        let sink = receiver.get_sink().scoped("api").unwrap();
+        sink.add_facet(Facet::count("ok").output_name("requests_ok_total"));
+        sink.increment("ok");
+        sink.record_latency_nanos("ok", 100);
+        sink.increment("err");
+        while let Ok(mframe) = msg_rx.try_recv() {
+            receiver.process_msg_frame(mframe);
+        }
+
+        // The output name replaces the key, for every type of metric with the key.
+        let snapshot = receiver.get_snapshot().into_simple();
+        assert_eq!(snapshot.count("api.requests_ok_total"), Some(2));
+        assert_eq!(snapshot.timing_count("api.requests_ok_total"), Some(1));
+        assert_eq!(snapshot.count("api.ok"), None);
+        assert_eq!(snapshot.count("api.err"), Some(1));
+
+        sink.remove_facet(Facet::count("ok"));
+        sink.increment("ok");
+        while let Ok(mframe) = msg_rx.try_recv() {
+            receiver.process_msg_frame(mframe);
+        }
+        let snapshot = receiver.get_snapshot().into_simple();
+        assert_eq!(snapshot.count("api.ok"), Some(1));
+        assert_eq!(snapshot.count("api.requests_ok_total"), None);
+
+        // Metrics already seen in a snapshot pick up output names added later.
+        sink.add_facet(Facet::count("err").output_name("requests_err_total"));
+        while let Ok(mframe) = msg_rx.try_recv() {
+            receiver.process_msg_frame(mframe);
+        }
+        let snapshot = receiver.get_snapshot().into_simple();
+        assert_eq!(snapshot.count("api.requests_err_total"), Some(1));
+        assert_eq!(snapshot.count("api.err"), None);
+    }
+
+    #[test]
+    fn test_get_values() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let controller = receiver.get_controller();
+        let sink = receiver.get_sink().scoped("db").unwrap();
+        sink.update_count("queries", 3);
+        sink.update_gauge("connections", 2);
+        sink.update_value("rows", 10);
+        sink.update_value("rows", 20);
+
+        thread::spawn(move || receiver.run());
+        sink.flush().expect("failed to flush");
+
+        let values = controller
+            .get_values(&["db.queries", "db.connections", "db.rows", "db.missing"])
+            .expect("failed to get values");
+        assert_eq!(values.len(), 3);
+        assert_eq!(values["db.queries"].as_counter(), Some(3));
+        assert_eq!(values["db.connections"].as_gauge(), Some(2));
+        assert_eq!(values["db.rows"].as_histogram().map(|h| h.count()), Some(2));
+        assert!(!values.contains_key("db.missing"));
+    }
+
+    #[test]
+    fn test_with_state() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let controller = receiver.get_controller();
+        let sink = receiver.get_sink().scoped("db").unwrap();
+        sink.update_count("queries", 3);
+        sink.update_count("errors", 1);
+        sink.update_value("rows", 10);
+
+        thread::spawn(move || receiver.run());
+        sink.flush().expect("failed to flush");
+
+        let result = controller
+            .with_state(|view| {
+                let mut total = 0;
+                view.counters(|name, value| {
+                    if name.starts_with("db.") {
+                        total += value;
+                    }
+                });
+
+                let mut rows = 0;
+                view.value_histograms(|name, histogram| {
+                    if name == "db.rows" {
+                        rows = histogram.sum();
+                    }
+                });
+
+                (total, rows)
+            })
+            .expect("failed to run closure");
+        assert_eq!(result, (4, 10));
+    }
+
+    #[test]
+    fn test_self_metrics() {
+        let mut receiver = Receiver::<&'static str>::builder().capacity(64).build();
+        let snapshot = receiver.get_snapshot().into_simple();
+        assert_eq!(snapshot.gauge("hotmic.channel.capacity"), None);
+
+        let mut receiver = Receiver::<&'static str>::builder()
+            .capacity(64)
+            .self_metrics(true)
+            .build();
+        let snapshot = receiver.get_snapshot().into_simple();
+        assert_eq!(snapshot.gauge("hotmic.channel.capacity"), Some(64));
+        assert_eq!(snapshot.gauge("hotmic.channel.len"), Some(0));
+    }
+
+    #[test]
+    fn test_max_min_gauges() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let msg_rx = receiver.msg_rx.take().unwrap();
+        let sink = receiver.get_sink();
+
+        sink.add_facet(Facet::max_gauge("peak"));
+        sink.add_facet(Facet::min_gauge("trough"));
+        for value in &[7, 42, 3, 12] {
+            sink.update_gauge("peak", *value);
+            sink.update_gauge("trough", *value);
+            sink.update_gauge("last", *value);
+        }
+
+        while let Ok(mframe) = msg_rx.try_recv() {
+            receiver.process_msg_frame(mframe);
+        }
+        let snapshot = receiver.get_snapshot().into_simple();
+        assert_eq!(snapshot.gauge("peak"), Some(42));
+        assert_eq!(snapshot.gauge("trough"), Some(3));
+        assert_eq!(snapshot.gauge("last"), Some(12));
+    }
+
+    #[test]
+    fn test_snapshot_clock_time() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let clock = receiver.get_sink().clock().clone();
+        assert_eq!(Snapshot::default().clock_time(), None);
+        assert_eq!(Snapshot::default().clock_to_wall(0), None);
+
+        let before = clock.now();
+        let snapshot = receiver.get_snapshot();
+        let after = clock.now();
+
+        let taken = snapshot.clock_time().unwrap();
+        let wall = snapshot.wall_time().unwrap();
+        assert!(before <= taken && taken <= after);
+        assert_eq!(snapshot.clock_to_wall(taken), Some(wall));
+        assert_eq!(
+            snapshot.clock_to_wall(taken + 1_500_000_000),
+            Some(wall + Duration::from_millis(1500))
+        );
+        assert_eq!(
+            snapshot.clock_to_wall(taken - 250),
+            Some(wall - Duration::from_nanos(250))
+        );
+    }
+
+    #[test]
+    fn test_clock_anomalies() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let msg_rx = receiver.msg_rx.take().unwrap();
+        let sink = receiver.get_sink();
+
+        sink.update_timing("ok", 100, 200);
+        while let Ok(mframe) = msg_rx.try_recv() {
+            receiver.process_msg_frame(mframe);
+        }
+        let snapshot = receiver.get_snapshot().into_simple();
+        assert_eq!(snapshot.count("hotmic.clock_anomalies"), None);
+
+        sink.update_timing("backwards", 200, 100);
+        sink.update_timing("stopped", 200, 200);
+        while let Ok(mframe) = msg_rx.try_recv() {
+            receiver.process_msg_frame(mframe);
+        }
+        let snapshot = receiver.get_snapshot().into_simple();
+        assert_eq!(snapshot.count("hotmic.clock_anomalies"), Some(2));
+        assert_eq!(snapshot.timing_count("backwards"), Some(1));
+    }
+
+    #[test]
+    fn test_allowed_labels() {
+        let mut receiver = Receiver::builder().build();
+        let msg_rx = receiver.msg_rx.take().unwrap();
+        let sink = receiver.get_sink().scoped("db").unwrap();
+        sink.add_facet(Facet::count("queries").allow_label_values("env", &["prod", "dev"]));
+
+        sink.increment("queries");
+        sink.with_labels(&[("env", "prod")]).increment("queries");
+        sink.with_labels(&[("env", "staging")]).increment("queries");
+        sink.with_labels(&[("user", "42")]).update_value("queries", 7);
+        sink.with_labels(&[("user", "42")]).increment("other");
+
+        // Allowed labels apply whatever labels the registering sink has.
+        let labeled = sink.with_labels(&[("env", "prod")]);
+        labeled.add_facet(Facet::gauge("connections").allow_label("env"));
+        labeled.update_gauge("connections", 3);
+        sink.with_labels(&[("host", "a")]).update_gauge("connections", 4);
+
+        while let Ok(mframe) = msg_rx.try_recv() {
+            receiver.process_msg_frame(mframe);
+        }
+        let snapshot = receiver.get_snapshot().into_simple();
+        assert_eq!(snapshot.count("db.queries"), Some(1));
+        assert_eq!(snapshot.count_labeled("db.queries", &[("env", "prod")]), Some(1));
+        assert_eq!(snapshot.count_labeled("db.queries", &[("env", "staging")]), None);
+        assert_eq!(
+            snapshot.value_histogram_labeled("db.queries", &[("user", "42")], 50.0),
+            None
+        );
+        assert_eq!(snapshot.count_labeled("db.other", &[("user", "42")]), Some(1));
+        assert_eq!(snapshot.gauge_labeled("db.connections", &[("env", "prod")]), Some(3));
+        assert_eq!(snapshot.gauge_labeled("db.connections", &[("host", "a")]), None);
+
+        let rejected = |metric| snapshot.count_labeled("hotmic.labels.rejected", &[("metric", metric)]);
+        assert_eq!(rejected("db.queries"), Some(2));
+        assert_eq!(rejected("db.connections"), Some(1));
+    }
+
+    #[test]
+    fn test_validate_facets() {
+        let mut receiver = Receiver::builder().validate_facets(true).build();
+        let sink = receiver.get_sink().scoped("db").unwrap();
+        sink.add_facets(&[
+            Facet::count("queries"),
+            Facet::max_gauge("connections"),
+            Facet::throughput("reads"),
+        ]);
+
+        sink.increment("queries");
+        sink.update_value("queries", 7);
+        sink.update_gauge("queries", 3);
+        sink.update_gauge("connections", 4);
+        sink.update_count("connections", 1);
+        sink.record_latency_nanos("reads", 1_000);
+        sink.update_value("unregistered", 5);
+        receiver.process_pending();
+
+        let snapshot = receiver.get_snapshot().into_simple();
+        assert_eq!(snapshot.count("db.queries"), Some(1));
+        assert_eq!(snapshot.value_count("db.queries"), None);
+        assert_eq!(snapshot.gauge("db.queries"), None);
+        assert_eq!(snapshot.gauge("db.connections"), Some(4));
+        assert_eq!(snapshot.count("db.connections"), None);
+        assert_eq!(snapshot.timing_count("db.reads"), Some(1));
+        assert_eq!(snapshot.value_count("db.unregistered"), Some(1));
+
+        let mismatched = |metric| snapshot.count_labeled("hotmic.facets.mismatched", &[("metric", metric)]);
+        assert_eq!(mismatched("db.queries"), Some(2));
+        assert_eq!(mismatched("db.connections"), Some(1));
+        assert_eq!(mismatched("db.reads"), None);
+    }
+
+    #[test]
+    fn test_remove_facet_drops_data() {
+        let mut receiver = Receiver::builder().build();
+        let msg_rx = receiver.msg_rx.take().unwrap();
+        let sink = receiver.get_sink();
+
+        let process = |receiver: &mut Receiver<&'static str>| {
+            while let Ok(mframe) = msg_rx.try_recv() {
+                receiver.process_msg_frame(mframe);
+            }
+            receiver.get_snapshot().into_simple()
+        };
+
+        sink.add_facet(Facet::value_percentile("buf_size"));
+        sink.add_facet(Facet::wide_count("bytes"));
+        sink.update_value("buf_size", 4096);
+        sink.update_count("bytes", 42);
+        let snapshot = process(&mut receiver);
+        assert_eq!(snapshot.value_count("buf_size"), Some(1));
+        assert_eq!(snapshot.wide_count("bytes"), Some(42));
+
+        sink.remove_facet(Facet::value_percentile("buf_size"));
+        sink.remove_facet(Facet::wide_count("bytes"));
+        let snapshot = process(&mut receiver);
+        assert_eq!(snapshot.value_count("buf_size"), None);
+        assert_eq!(snapshot.wide_count("bytes"), None);
+
+        // Re-adding the facet starts from scratch.
+        sink.add_facet(Facet::value_percentile("buf_size"));
+        sink.update_value("buf_size", 1024);
+        let snapshot = process(&mut receiver);
+        assert_eq!(snapshot.value_count("buf_size"), Some(1));
+        assert_eq!(
+            snapshot.value_histogram("buf_size", 100.0).map(|v| v < 2048),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn test_sketch_facet() {
+        let mut receiver = Receiver::<&'static str>::builder()
+            .capacity(2_048)
+            .histogram_max_value(1_000)
+            .build();
+        let msg_rx = receiver.msg_rx.take().unwrap();
+        let sink = receiver.get_sink();
+
+        sink.update_value("payload", 1);
+        sink.add_facet(Facet::value_percentile("payload").sketch());
+        for value in 1..=1_000 {
+            sink.update_value("payload", value * 1_000_000);
+        }
+        sink.update_value("other", 1_000_000);
+
+        while let Ok(mframe) = msg_rx.try_recv() {
+            receiver.process_msg_frame(mframe);
+        }
+        let snapshot = receiver.get_snapshot().into_simple();
+
+        // Values recorded before the facet was added are dropped, and values above the maximum
+        // value aren't clamped, unlike with an HdrHistogram.
+        assert_eq!(snapshot.value_count("payload"), Some(1_000));
+        assert_eq!(snapshot.value_histogram("payload", 0.0), Some(1_000_000));
+        assert_eq!(snapshot.value_histogram("payload", 100.0), Some(1_000_000_000));
+        let median = snapshot.value_histogram("payload", 50.0).unwrap();
+        assert!(
+            median >= 495_000_000 && median <= 505_000_000,
+            "unexpected median {}",
+            median
+        );
+        assert!(snapshot.value_histogram("other", 100.0).unwrap() <= 1_000);
+    }
+
+    #[test]
+    fn test_zero_snapshot_interval() {
+        let receiver = Receiver::<&'static str>::builder()
+            .on_snapshot(Duration::new(0, 0), |_| {})
+            .build();
+        let (interval, _) = receiver.config.snapshot_callback.as_ref().unwrap();
+        assert_eq!(*interval, Duration::from_millis(1));
+    }
+
+    #[test]
+    fn test_snapshot_callback() {
+        let (tx, rx) = bounded(1);
+        let mut receiver = Receiver::<&'static str>::builder()
+            .on_snapshot(Duration::from_millis(10), move |snapshot| {
+                let _ = tx.try_send(snapshot.to_string());
+            })
+            .build();
+        let sink = receiver.get_sink();
+        sink.update_gauge("total", 42);
+
+        thread::spawn(move || receiver.run());
+
+        // The first callback may race the gauge update, so wait until we see it.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let output = rx.recv_timeout(Duration::from_secs(5)).expect("callback never called");
+            if output.contains("gauge total: 42") {
+                break;
+            }
+            assert!(Instant::now() < deadline, "gauge never appeared in snapshot");
+        }
+    }
 }
@@ -1,10 +1,21 @@
 use parking_lot::RwLock;
 use std::collections::HashMap;
 
+/// A scope, and the labels bound to it, sorted.
+type Scope = (String, Vec<(String, String)>);
+
+/// A registered scope.
+struct Entry {
+    scope: Scope,
+
+    // The ID of the same scope, without any labels.
+    base_id: u64,
+}
+
 pub struct Inner {
     id: u64,
-    forward: HashMap<String, u64>,
-    backward: HashMap<u64, String>,
+    forward: HashMap<Scope, u64>,
+    backward: HashMap<u64, Entry>,
 }
 
 impl Inner {
@@ -28,7 +39,21 @@ impl Scopes {
         }
     }
 
-    pub fn register(&self, scope: String) -> u64 {
+    pub fn register(&self, scope: String, labels: &[(String, String)]) -> u64 {
+        // The root scope, without labels, is always scope ID 0.
+        if scope.is_empty() && labels.is_empty() {
+            return 0;
+        }
+
+        let base_id = if labels.is_empty() {
+            None
+        } else {
+            Some(self.register(scope.clone(), &[]))
+        };
+
+        let mut labels = labels.to_vec();
+        labels.sort();
+        let scope = (scope, labels);
         let mut wg = self.inner.write();
 
         // If the key is already registered, send back the existing scope ID.
@@ -39,15 +64,129 @@ impl Scopes {
         // Otherwise, take the current scope ID for this registration, store it, and increment
         // the scope ID counter for the next registration.
         let scope_id = wg.id;
-        let _ = wg.forward.insert(scope.clone(), scope_id);
-        let _ = wg.backward.insert(scope_id, scope);
+        let entry = Entry {
+            scope: scope.clone(),
+            base_id: base_id.unwrap_or(scope_id),
+        };
+        let _ = wg.forward.insert(scope, scope_id);
+        let _ = wg.backward.insert(scope_id, entry);
         wg.id += 1;
         scope_id
     }
 
-    pub fn get(&self, scope_id: u64) -> Option<String> {
+    pub fn get(&self, scope_id: u64) -> Option<(String, Vec<(String, String)>)> {
         // See if we have an entry for the scope ID, and clone the scope if so.
         let rg = self.inner.read();
-        rg.backward.get(&scope_id).cloned()
+        rg.backward.get(&scope_id).map(|entry| entry.scope.clone())
+    }
+
+    pub fn base_id(&self, scope_id: u64) -> u64 {
+        let rg = self.inner.read();
+        rg.backward
+            .get(&scope_id)
+            .map(|entry| entry.base_id)
+            .unwrap_or(scope_id)
+    }
+
+    /// Calls `f` with the ID of the unlabeled scope, and the labels, of a scope with labels.
+    ///
+    /// Returns `None`, without calling `f`, if the scope has no labels.
+    pub fn with_labels<F, R>(&self, scope_id: u64, f: F) -> Option<R>
+    where
+        F: FnOnce(u64, &[(String, String)]) -> R,
+    {
+        let rg = self.inner.read();
+        rg.backward
+            .get(&scope_id)
+            .filter(|entry| !entry.scope.1.is_empty())
+            .map(|entry| f(entry.base_id, &entry.scope.1))
+    }
+}
+
+/// Renders a set of labels in their canonical form, as they appear when a metric key is displayed.
+///
+/// Labels are rendered as `{key="value",...}`, sorted by key.  Backslashes, double quotes, and
+/// newlines in values are escaped.  An empty set of labels renders as an empty string.
+pub fn render_labels(labels: &[(String, String)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+
+    let mut sorted = labels.iter().collect::<Vec<_>>();
+    sorted.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut rendered = String::from("{");
+    for (i, (key, value)) in sorted.into_iter().enumerate() {
+        if i > 0 {
+            rendered.push(',');
+        }
+        rendered.push_str(key);
+        rendered.push_str("=\"");
+        for c in value.chars() {
+            match c {
+                '\\' => rendered.push_str("\\\\"),
+                '"' => rendered.push_str("\\\""),
+                '\n' => rendered.push_str("\\n"),
+                c => rendered.push(c),
+            }
+        }
+        rendered.push('"');
+    }
+    rendered.push('}');
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render_labels, Scopes};
+
+    #[test]
+    fn test_register_labels() {
+        let scopes = Scopes::new();
+        assert_eq!(scopes.register("".to_owned(), &[]), 0);
+
+        let labels = vec![("env".to_owned(), "prod".to_owned())];
+        let db = scopes.register("db".to_owned(), &[]);
+        let labeled = scopes.register("db".to_owned(), &labels);
+        assert_ne!(db, labeled);
+        assert_eq!(scopes.register("db".to_owned(), &labels), labeled);
+        assert_eq!(scopes.get(labeled), Some(("db".to_owned(), labels.clone())));
+
+        // Labels are registered in any order.
+        let both = vec![
+            ("region".to_owned(), "eu".to_owned()),
+            ("env".to_owned(), "prod".to_owned()),
+        ];
+        let reversed = both.iter().rev().cloned().collect::<Vec<_>>();
+        assert_eq!(
+            scopes.register("db".to_owned(), &both),
+            scopes.register("db".to_owned(), &reversed)
+        );
+
+        assert_eq!(scopes.base_id(labeled), db);
+        assert_eq!(scopes.base_id(db), db);
+        assert_eq!(
+            scopes.with_labels(labeled, |base, l| (base, l.to_vec())),
+            Some((db, labels.clone()))
+        );
+        assert_eq!(scopes.with_labels(db, |base, _| base), None);
+
+        // Labels on the root scope are based on the root scope.
+        let root = scopes.register("".to_owned(), &labels);
+        assert_eq!(scopes.base_id(root), 0);
+    }
+
+    #[test]
+    fn test_render_labels() {
+        assert_eq!(render_labels(&[]), "");
+
+        let labels = vec![
+            ("service".to_owned(), "api".to_owned()),
+            ("env".to_owned(), "prod".to_owned()),
+        ];
+        assert_eq!(render_labels(&labels), r#"{env="prod",service="api"}"#);
+
+        let labels = vec![("path".to_owned(), "C:\\\"x\"".to_owned())];
+        assert_eq!(render_labels(&labels), r#"{path="C:\\\"x\""}"#);
     }
 }
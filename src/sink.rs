@@ -1,23 +1,76 @@
 use crate::{
-    data::{Sample, ScopedKey},
-    helper::io_error,
+    data::{Facet, Sample, ScopedKey, SharedCounters, SharedValue},
+    helper::{io_error, next_clock_id},
     receiver::MessageFrame,
     scopes::Scopes,
+    transport::{Frame, TransportSender, TrySendError},
 };
-use crossbeam_channel::Sender;
+use crossbeam_channel::bounded;
+use fnv::FnvHashMap;
+use futures::{
+    task::{self, Task},
+    Async, Future, Poll,
+};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
 use quanta::Clock;
-use std::{fmt::Display, hash::Hash, sync::Arc};
+use std::{
+    fmt::{self, Display},
+    hash::Hash,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+lazy_static! {
+    /// The clock, and its identifier, shared by all disabled sinks, so that creating a disabled sink
+    /// doesn't pay for calibrating a clock of its own.
+    static ref DISABLED_CLOCK: (Clock, usize) = (Clock::new(), next_clock_id());
+}
 
-/// Erorrs during sink creation.
-#[derive(Debug)]
+/// Errors during sink creation or sending.
+#[derive(Debug, PartialEq)]
 pub enum SinkError {
     /// The scope value given was invalid i.e. empty or illegal characters.
     InvalidScope,
+
+    /// The receiver is no longer running, so the sample could not be sent.
+    ReceiverShutdown,
+}
+
+/// A future that resolves once a sample has been sent to the receiver.
+///
+/// Returned by the asynchronous send methods of [`Sink`], such as [`Sink::update_count_async`].
+///
+/// While the channel is full, this future waits for the receiver to take messages out of it before
+/// trying again, yielding to other tasks instead of blocking the thread.  If the receiver goes away
+/// in the meantime, the future fails with [`SinkError::ReceiverShutdown`].
+#[must_use = "futures do nothing unless polled"]
+pub struct SendFuture<T: Clone + Eq + Hash + Display> {
+    msg_tx: Option<Arc<dyn TransportSender<Frame<T>>>>,
+    waiters: Arc<SendWaiters>,
+    frame: Option<Frame<T>>,
+}
+
+/// Tasks waiting, via a [`SendFuture`], for room in the channel to send a sample.
+///
+/// Channels don't notify senders when room frees up, so the receiver wakes these tasks itself
+/// whenever it takes messages out of the channel.
+#[derive(Default)]
+pub(crate) struct SendWaiters {
+    // Set while any task is waiting, so that the receiver can skip taking the lock otherwise.
+    waiting: AtomicBool,
+    closed: AtomicBool,
+    tasks: Mutex<Vec<Task>>,
 }
 
 /// A value that can be used as a metric scope.
 pub trait AsScoped<'a> {
-    fn as_scoped(&'a self, base: String) -> String;
+    /// Appends this scope to `base`, returning [`SinkError::InvalidScope`] if any segment of the
+    /// scope is invalid.
+    fn as_scoped(&'a self, base: String) -> Result<String, SinkError>;
 }
 
 /// Handle for sending metric samples into the receiver.
@@ -25,40 +78,168 @@ pub trait AsScoped<'a> {
 /// [`Sink`] is cloneable, and can not only send metric samples but can register and deregister
 /// metric facets at any time.
 pub struct Sink<T: Clone + Eq + Hash + Display> {
-    msg_tx: Sender<MessageFrame<ScopedKey<T>>>,
+    msg_tx: Option<Arc<dyn TransportSender<Frame<T>>>>,
     clock: Clock,
     scopes: Arc<Scopes>,
     scope: String,
+    labels: Vec<(String, String)>,
     scope_id: u64,
+    base_scope_id: u64,
+    clock_id: usize,
+
+    // The number of live sinks bound to the receiver, which this sink counts towards.
+    sinks: Option<Arc<AtomicUsize>>,
+
+    // Counters registered via `shared_counter`, so that each is only shared once.
+    shared_counters: Option<Arc<SharedCounters<ScopedKey<T>>>>,
+
+    // Tasks waiting for room in the channel, which the receiver wakes up.
+    send_waiters: Arc<SendWaiters>,
+}
+
+/// A handle for creating sinks bound to a receiver, without holding the receiver itself.
+///
+/// Factories are cheap to clone, and can be stored and passed around in place of the receiver,
+/// such as when wiring up components that each need their own sink.  Sinks created by a factory
+/// behave exactly as if they were created by the receiver directly.
+///
+/// A factory doesn't keep its receiver running: once the receiver is gone, its factories keep
+/// creating sinks, but anything sent through them goes nowhere.
+#[derive(Clone)]
+pub struct SinkFactory<T: Clone + Eq + Hash + Display> {
+    root: Sink<T>,
+}
+
+/// A set of updates to metrics, sent to the receiver as a single message by [`Sink::record`].
+///
+/// Operations are often measured in more than one way, such as a timing along with a count of
+/// whether the operation succeeded.  Sending each as its own sample costs a trip through the
+/// channel apiece, whereas a measurement bundles them into one.
+///
+/// Updates are applied to the metric given to [`Sink::record`], except for outcomes, which are
+/// applied to the metric they name.
+pub struct Measurement<T> {
+    samples: Vec<Sample<Option<T>>>,
+}
+
+/// A buffer of gauge updates, which only sends the latest value of each gauge, on an interval.
+///
+/// Gauges are last-write-wins, so a gauge that's set many times between snapshots only needs its
+/// final value to reach the receiver.  Buffered values are sent once `interval` has passed since
+/// they were last sent, checked whenever a gauge is updated, and when the buffer is flushed or
+/// dropped.  A buffered value isn't visible to the receiver until it's sent, so a gauge that stops
+/// being updated keeps its last value in the buffer until then.
+///
+/// Buffers aren't shared: each thread updating hot gauges should create its own, via
+/// [`Sink::gauge_buffer`].
+pub struct GaugeBuffer<T: Clone + Eq + Hash + Display> {
+    sink: Sink<T>,
+    interval: Duration,
+    last_flush: Instant,
+    values: FnvHashMap<T, u64>,
+}
+
+/// A timing in progress under a scope, which is recorded when dropped.
+///
+/// Created by [`Sink::scoped_timer`].  The timer holds the scoped sink it was started with, so the
+/// scope lives for as long as the timer does, and the timing is recorded under it however the
+/// timer goes out of scope, including by an early return or a `?`.
+pub struct ScopedTimer<T: Clone + Eq + Hash + Display> {
+    sink: Sink<T>,
+    key: T,
+    timer: Timer,
+}
+
+/// A timing in progress, started with the clock of a [`Sink`].
+///
+/// Raw clock values are only meaningful to the clock that produced them, as the receiver scales
+/// them using that clock's calibration.  A `Timer` can only be started by a [`Sink`], and
+/// remembers which receiver's clock it came from, so that the timing is measured with the right
+/// clock from start to end.  See [`Sink::start_timer`].
+#[derive(Debug, Clone, Copy)]
+pub struct Timer {
+    start: u64,
+    clock_id: usize,
+}
+
+/// A counter which can be read directly, without asking the receiver.
+///
+/// Created by [`Sink::shared_counter`], which describes how its value relates to the value of the
+/// counter in snapshots.  Handles are cheap to clone, and every clone refers to the same counter.
+#[derive(Debug, Clone)]
+pub struct SharedCounter {
+    value: Arc<SharedValue>,
 }
 
 impl<T: Clone + Eq + Hash + Display> Sink<T> {
-    pub(crate) fn new(
-        msg_tx: Sender<MessageFrame<ScopedKey<T>>>, clock: Clock, scopes: Arc<Scopes>, scope: String,
+    /// Creates an unscoped sink bound to a receiver.
+    pub(crate) fn root(
+        msg_tx: Arc<dyn TransportSender<Frame<T>>>, clock: Clock, clock_id: usize, scopes: Arc<Scopes>,
+        sinks: Arc<AtomicUsize>, shared_counters: Arc<SharedCounters<ScopedKey<T>>>, send_waiters: Arc<SendWaiters>,
     ) -> Sink<T> {
-        let scope_id = scopes.register(scope.clone());
-
         Sink {
-            msg_tx,
+            msg_tx: Some(msg_tx),
             clock,
             scopes,
-            scope,
-            scope_id,
+            scope: "".to_owned(),
+            labels: Vec::new(),
+            scope_id: 0,
+            base_scope_id: 0,
+            clock_id,
+            sinks: attach(Some(sinks)),
+            shared_counters: Some(shared_counters),
+            send_waiters,
         }
     }
 
-    pub(crate) fn new_with_scope_id(
-        msg_tx: Sender<MessageFrame<ScopedKey<T>>>, clock: Clock, scopes: Arc<Scopes>, scope: String, scope_id: u64,
-    ) -> Sink<T> {
+    /// Creates a sink bound to the same receiver as this one, with the given scope and labels.
+    fn rescoped(&self, scope: String, labels: Vec<(String, String)>) -> Sink<T> {
+        let scope_id = self.scopes.register(scope.clone(), &labels);
+        let base_scope_id = self.scopes.base_id(scope_id);
+
         Sink {
-            msg_tx,
-            clock,
-            scopes,
+            msg_tx: self.msg_tx.clone(),
+            clock: self.clock.clone(),
+            scopes: self.scopes.clone(),
             scope,
+            labels,
             scope_id,
+            base_scope_id,
+            clock_id: self.clock_id,
+            sinks: attach(self.sinks.clone()),
+            shared_counters: self.shared_counters.clone(),
+            send_waiters: self.send_waiters.clone(),
+        }
+    }
+
+    /// Creates a disabled [`Sink`].
+    ///
+    /// A disabled sink is not bound to any receiver: all calls to send metrics are cheap no-ops
+    /// that return before touching a channel.  This allows libraries to accept a [`Sink`]
+    /// unconditionally, while letting callers opt out of metrics entirely at construction time.
+    ///
+    /// Scoped sinks and clones derived from a disabled sink are also disabled.
+    pub fn disabled() -> Sink<T> {
+        Sink {
+            msg_tx: None,
+            clock: DISABLED_CLOCK.0.clone(),
+            scopes: Arc::new(Scopes::new()),
+            scope: "".to_owned(),
+            labels: Vec::new(),
+            scope_id: 0,
+            base_scope_id: 0,
+            clock_id: DISABLED_CLOCK.1,
+            sinks: None,
+            shared_counters: None,
+            send_waiters: Arc::new(SendWaiters::default()),
         }
     }
 
+    /// Whether or not this [`Sink`] is disabled.
+    ///
+    /// See [`Sink::disabled`] for more information.
+    pub fn is_disabled(&self) -> bool { self.msg_tx.is_none() }
+
     /// Creates a scoped clone of this [`Sink`].
     ///
     /// Scoping controls the resulting metric name for any metrics sent by this [`Sink`].  For
@@ -79,49 +260,568 @@ impl<T: Clone + Eq + Hash + Display> Sink<T> {
     /// already scoped, the scopes will be merged together using a `.` as the string separator.
     /// This makes it easy to nest scopes.  Cloning a scoped [`Sink`], though, will inherit the
     /// same scope as the original.
-    pub fn scoped<'a, S: AsScoped<'a> + ?Sized>(&self, scope: &'a S) -> Sink<T> {
-        let new_scope = scope.as_scoped(self.scope.clone());
+    ///
+    /// Every segment of the given scope must be non-empty, and must not contain a `.`, as that
+    /// would make it indistinguishable from multiple segments.  Otherwise,
+    /// [`SinkError::InvalidScope`] is returned.
+    pub fn scoped<'a, S: AsScoped<'a> + ?Sized>(&self, scope: &'a S) -> Result<Sink<T>, SinkError> {
+        let new_scope = scope.as_scoped(self.scope.clone())?;
+        Ok(self.rescoped(new_scope, self.labels.clone()))
+    }
 
-        Sink::new(self.msg_tx.clone(), self.clock.clone(), self.scopes.clone(), new_scope)
+    /// Creates a sibling of this [`Sink`], scoped under the same parent.
+    ///
+    /// Where [`Sink::scoped`] nests the given scope under the scope of this sink, a sibling
+    /// replaces the last segment of this sink's scope with the given scope.  For example, given a
+    /// sink scoped to `listener.a`, calling `sibling("b")` produces a sink scoped to `listener.b`,
+    /// while calling `scoped("b")` would produce `listener.a.b`.
+    ///
+    /// Scope segments are always joined with a `.`, and the last segment is whatever follows the
+    /// final `.` in this sink's scope.  If the given scope has multiple segments, they're all nested
+    /// under the parent.  For an unscoped sink, this is equivalent to [`Sink::scoped`].
+    ///
+    /// The given scope is validated in the same way as for [`Sink::scoped`].
+    pub fn sibling<'a, S: AsScoped<'a> + ?Sized>(&self, scope: &'a S) -> Result<Sink<T>, SinkError> {
+        let parent = match self.scope.rfind('.') {
+            Some(idx) => self.scope[..idx].to_owned(),
+            None => String::new(),
+        };
+        let new_scope = scope.as_scoped(parent)?;
+        Ok(self.rescoped(new_scope, self.labels.clone()))
+    }
+
+    /// Creates a clone of this [`Sink`] with the given labels attached.
+    ///
+    /// Labels are key/value pairs which are attached to every metric sent by the resulting
+    /// [`Sink`], which is useful when a sink represents a single subsystem, or instance of one.
+    /// They're reported alongside the metric name in snapshots, via
+    /// [`MeasurementKey::labels`](crate::snapshot::MeasurementKey::labels), for exporters to render
+    /// in whatever way their format tags metrics.  Displayed, `requests` sent by a sink with the
+    /// labels `[("env", "prod")]` reads as `requests{env="prod"}`.  Metrics with different labels
+    /// are tracked independently.
+    ///
+    /// Labels are inherited and compose with scoping: scoped and sibling sinks created from a
+    /// labeled sink carry the same labels, and adding labels to a labeled sink merges the two sets,
+    /// with the given labels replacing any existing labels with the same key.
+    ///
+    /// Facets are registered per set of labels, so a facet registered on one sink does not apply to
+    /// metrics sent by a sink with different labels.  The exception is facets which restrict the
+    /// labels allowed on a metric, via [`Facet::allow_label`].
+    pub fn with_labels(&self, labels: &[(&str, &str)]) -> Sink<T> {
+        let mut merged = self.labels.clone();
+        for (key, value) in labels {
+            match merged.iter_mut().find(|(k, _)| k == key) {
+                Some(existing) => existing.1 = (*value).to_owned(),
+                None => merged.push(((*key).to_owned(), (*value).to_owned())),
+            }
+        }
+
+        self.rescoped(self.scope.clone(), merged)
     }
 
     /// Reference to the internal high-speed clock interface.
+    ///
+    /// Every sink bound to the same receiver shares the receiver's clock.  Raw values from this
+    /// clock, passed to [`update_timing`](Sink::update_timing), must only be sent to sinks bound to
+    /// the same receiver, as the receiver converts them using its own clock's calibration.
     pub fn clock(&self) -> &Clock { &self.clock }
 
+    /// Starts a timing with this sink's clock.
+    ///
+    /// The timing is ended, and recorded, with [`record_timer`](Sink::record_timer).  Unlike
+    /// passing raw clock values to [`update_timing`](Sink::update_timing), a [`Timer`] can't be
+    /// mixed up with values from a different clock.
+    pub fn start_timer(&self) -> Timer {
+        Timer {
+            start: self.clock.start(),
+            clock_id: self.clock_id,
+        }
+    }
+
+    /// Ends the given timing, and records it into the timing histogram for a given metric.
+    ///
+    /// The timer must have been started by a sink bound to the same receiver as this one, which is
+    /// checked in debug builds.
+    pub fn record_timer<K: Into<T>>(&self, key: K, timer: Timer) {
+        let end = self.clock.end();
+        debug_assert_eq!(
+            timer.clock_id, self.clock_id,
+            "timer was started by a sink bound to a different receiver"
+        );
+        self.update_timing(key, timer.start, end)
+    }
+
+    /// Creates a sink with the given scope, as with [`scoped`](Sink::scoped), and starts a timing
+    /// of the given metric in it.
+    ///
+    /// The timing ends, and is recorded, when the returned [`ScopedTimer`] is dropped.  This covers
+    /// the common case of timing a request handler under a scope for its route, without creating
+    /// the scoped sink and starting the timer separately.  Other metrics can be sent under the same
+    /// scope through [`ScopedTimer::sink`].
+    pub fn scoped_timer<'a, S, K>(&self, scope: &'a S, key: K) -> Result<ScopedTimer<T>, SinkError>
+    where
+        S: AsScoped<'a> + ?Sized,
+        K: Into<T>,
+    {
+        let sink = self.scoped(scope)?;
+        let timer = sink.start_timer();
+        Ok(ScopedTimer {
+            sink,
+            key: key.into(),
+            timer,
+        })
+    }
+
+    /// Registers a facet with the receiver.
+    ///
+    /// Facets are registered at the scope of this [`Sink`], and customize how the receiver tracks
+    /// the given metric.  Registering a facet for a metric that already has a facet of the same type
+    /// replaces the existing facet.
+    ///
+    /// Registration is idempotent: registering a facet identical to the one already registered is a
+    /// no-op, and leaves any data recorded for the metric as-is, so facets can safely be registered
+    /// every time a component that uses them starts up.  Replacing a facet with a different one also
+    /// keeps the recorded data, unless it changes how a histogram is stored, such as with
+    /// [`Facet::sketch`](crate::Facet::sketch), in which case the histogram starts fresh.
+    ///
+    /// Facets that restrict labels, via [`Facet::allow_label`], are the exception: they're
+    /// registered at the scope of this [`Sink`] without its labels, as they apply to the metric
+    /// across every set of labels.
+    pub fn add_facet(&self, facet: Facet<T>) {
+        let scope_id = self.facet_scope_id(&facet);
+        self.send_frame(MessageFrame::AddFacet(Box::new(facet.into_scoped(scope_id))))
+    }
+
+    /// Registers several facets with the receiver.
+    ///
+    /// This is the same as calling [`add_facet`](Sink::add_facet) for each of the facets, in order.
+    pub fn add_facets(&self, facets: &[Facet<T>]) {
+        for facet in facets {
+            self.add_facet(facet.clone());
+        }
+    }
+
+    /// Gets the facets registered at the scope of this [`Sink`].
+    ///
+    /// These are the facets which apply to metrics sent from this sink, including any that restrict
+    /// labels, which are registered without the labels of this sink.  The receiver is asked for
+    /// them through the same channel as samples, and so facets registered from this thread
+    /// beforehand are always included.
+    ///
+    /// This call blocks until the receiver has responded.  If the sink is disabled, or the receiver
+    /// has shut down, no facets are returned.
+    pub fn registered_facets(&self) -> Vec<Facet<T>> {
+        let msg_tx = match &self.msg_tx {
+            Some(msg_tx) => msg_tx,
+            None => return Vec::new(),
+        };
+
+        let (tx, rx) = bounded(0);
+        let frame = MessageFrame::GetFacets(self.scope_id, self.base_scope_id, tx);
+        if msg_tx.send(Frame(frame)).is_err() {
+            return Vec::new();
+        }
+
+        rx.recv()
+            .map(|facets| facets.into_iter().map(Facet::into_unscoped).collect())
+            .unwrap_or_default()
+    }
+
+    /// Waits for the receiver to process everything sent through this [`Sink`] so far.
+    ///
+    /// Sinks don't hold on to samples themselves: every update is enqueued with the receiver as it's
+    /// made, blocking while the channel is full.  The receiver processes them in the background,
+    /// though, so a snapshot taken right after an update may not include it yet, and a program that
+    /// exits right after an update may exit before it's been processed.  This call blocks until
+    /// every update made from this thread beforehand has been enqueued and processed.  That needs
+    /// the receiver to be running on another thread, so this can't be used with a receiver that's
+    /// driven by hand, such as a [`TestReceiver`](crate::TestReceiver), which would never respond.
+    ///
+    /// Updates buffered in a [`GaugeBuffer`] aren't sent until the buffer itself is flushed, with
+    /// [`GaugeBuffer::flush`].
+    ///
+    /// If the receiver has shut down, [`SinkError::ReceiverShutdown`] is returned.  Disabled sinks
+    /// have nothing to wait for, and return immediately.
+    pub fn flush(&self) -> Result<(), SinkError> {
+        let msg_tx = match &self.msg_tx {
+            Some(msg_tx) => msg_tx,
+            None => return Ok(()),
+        };
+
+        let (tx, rx) = bounded(0);
+        msg_tx
+            .send(Frame(MessageFrame::Flush(tx)))
+            .map_err(|_| SinkError::ReceiverShutdown)?;
+        rx.recv().map_err(|_| SinkError::ReceiverShutdown)
+    }
+
+    /// Deregisters a facet from the receiver.
+    ///
+    /// Any data recorded for the metric, of the facet's type, is dropped to free its memory, and so
+    /// the metric no longer appears in snapshots.  If the metric is updated again, or the facet is
+    /// registered again, it starts fresh.
+    pub fn remove_facet(&self, facet: Facet<T>) {
+        let scope_id = self.facet_scope_id(&facet);
+        let facet = facet.into_scoped(scope_id);
+        self.send_frame(MessageFrame::RemoveFacet(facet.kind, facet.key))
+    }
+
+    /// Gets the scope ID that the given facet is registered at.
+    fn facet_scope_id(&self, facet: &Facet<T>) -> u64 {
+        if facet.allowed_labels.is_some() {
+            self.base_scope_id
+        } else {
+            self.scope_id
+        }
+    }
+
     /// Updates the count for a given metric.
-    pub fn update_count(&self, key: T, delta: i64) { self.send(Sample::Count(key, delta)) }
+    pub fn update_count<K: Into<T>>(&self, key: K, delta: i64) { self.send(Sample::Count(key.into(), delta)) }
 
     /// Updates the value for a given metric.
     ///
     /// This can be used either for setting a gauge or updating a value histogram.
-    pub fn update_gauge(&self, key: T, value: u64) { self.send(Sample::Gauge(key, value)) }
+    pub fn update_gauge<K: Into<T>>(&self, key: K, value: u64) { self.send(Sample::Gauge(key.into(), value)) }
 
     /// Updates the timing histogram for a given metric.
-    pub fn update_timing(&self, key: T, start: u64, end: u64) { self.send(Sample::TimingHistogram(key, start, end, 1)) }
+    ///
+    /// `start` and `end` must be raw values from [`clock`](Sink::clock), or from the clock of
+    /// another sink bound to the same receiver, as they're converted to nanoseconds by the
+    /// receiver's clock.  Values from any other clock produce wrong timings, and there is no way
+    /// for the receiver to tell.  [`start_timer`](Sink::start_timer) avoids this entirely.
+    ///
+    /// If `end` is not after `start`, the timing is recorded as zero, and counted in the
+    /// `hotmic.clock_anomalies` counter, which is reported in snapshots once it is non-zero.  As the
+    /// clock is monotonic, this points to a misbehaving clock, or to mixed up arguments.
+    pub fn update_timing<K: Into<T>>(&self, key: K, start: u64, end: u64) {
+        self.send(Sample::TimingHistogram(key.into(), start, end))
+    }
 
     /// Updates the timing histogram for a given metric, with a count.
-    pub fn update_timing_with_count(&self, key: T, start: u64, end: u64, count: u64) {
-        self.send(Sample::TimingHistogram(key, start, end, count))
+    pub fn update_timing_with_count<K: Into<T>>(&self, key: K, start: u64, end: u64, count: u64) {
+        self.send(Sample::timing(key.into(), start, end, count))
+    }
+
+    /// Updates the timing histogram for a given metric, attaching an exemplar to the timing.
+    ///
+    /// An exemplar, such as the ID of the trace the timing was measured in, links the timing to the
+    /// event it came from, so that exporters can point from a percentile to an example of it.  Each
+    /// percentile in a snapshot carries the most recent exemplar within its range of values.  See
+    /// [`SummarizedHistogram::exemplars`](crate::snapshot::SummarizedHistogram::exemplars).
+    ///
+    /// `start` and `end` are the same as for [`update_timing`](Sink::update_timing).
+    pub fn update_timing_with_exemplar<K: Into<T>>(&self, key: K, start: u64, end: u64, exemplar: &str) {
+        self.send(Sample::TimingExemplar(
+            key.into(),
+            Box::new((start, end, exemplar.to_owned())),
+        ))
+    }
+
+    /// Records an already-measured duration, in nanoseconds, into the timing histogram for a given
+    /// metric.
+    ///
+    /// This is for durations measured with some other clock: the value is recorded as-is, without
+    /// going through this sink's clock.  Unlike [`update_value`](Sink::update_value), the duration
+    /// is treated as a timing, so it is converted to the unit of the timing histogram, if one was
+    /// set via [`Facet::unit`](crate::Facet::unit), and it increments the count for the metric.
+    pub fn record_latency_nanos<K: Into<T>>(&self, key: K, nanos: u64) {
+        self.send(Sample::TimingLatency(key.into(), nanos))
+    }
+
+    /// Records an already-measured duration, in nanoseconds, into the timing histogram for a given
+    /// metric, as of the time it was measured.
+    ///
+    /// This is [`record_latency_nanos`](Sink::record_latency_nanos) for samples that arrive late,
+    /// such as when replaying buffered events: the duration is recorded into the histogram bucket
+    /// covering `when`, so it counts towards the window it belongs to, rather than the current one.
+    /// Durations measured so long ago that their bucket has already rolled out of the histogram
+    /// window are dropped from the histogram, although they still increment the count for the
+    /// metric.
+    pub fn update_timing_at<K: Into<T>>(&self, key: K, nanos: u64, when: Instant) {
+        self.send(Sample::TimingLatencyAt(key.into(), Box::new((nanos, when))))
+    }
+
+    /// Runs the given closure, recording how long it took into the timing histogram for a given
+    /// metric, and returns its result along with the elapsed time.
+    ///
+    /// The closure is timed with this sink's clock.  Returning the elapsed time avoids measuring
+    /// the same block twice when the duration is also needed elsewhere, such as in a log line.
+    pub fn measure_timed<K, F, R>(&self, key: K, f: F) -> (R, Duration)
+    where
+        K: Into<T>,
+        F: FnOnce() -> R,
+    {
+        let start = self.clock.start();
+        let result = f();
+        let end = self.clock.end();
+
+        let nanos = self.clock.delta(start, end);
+        self.record_latency_nanos(key, nanos);
+        (result, Duration::from_nanos(nanos))
     }
 
     /// Updates the value histogram for a given metric.
-    pub fn update_value(&self, key: T, value: u64) { self.send(Sample::ValueHistogram(key, value)) }
+    pub fn update_value<K: Into<T>>(&self, key: K, value: u64) { self.send(Sample::ValueHistogram(key.into(), value)) }
+
+    /// Updates the value histogram for a given metric, attaching an exemplar to the value.
+    ///
+    /// See [`update_timing_with_exemplar`](Sink::update_timing_with_exemplar) for how exemplars are
+    /// reported.
+    pub fn update_value_with_exemplar<K: Into<T>>(&self, key: K, value: u64, exemplar: &str) {
+        self.send(Sample::ValueExemplar(
+            key.into(),
+            Box::new((value, exemplar.to_owned())),
+        ))
+    }
+
+    /// Updates the count for a given metric, asynchronously.
+    ///
+    /// Rather than blocking when the channel is full, the returned future waits for capacity.
+    pub fn update_count_async<K: Into<T>>(&self, key: K, delta: i64) -> SendFuture<T> {
+        self.send_async(Sample::Count(key.into(), delta))
+    }
+
+    /// Updates the value for a given metric, asynchronously.
+    ///
+    /// Rather than blocking when the channel is full, the returned future waits for capacity.
+    pub fn update_gauge_async<K: Into<T>>(&self, key: K, value: u64) -> SendFuture<T> {
+        self.send_async(Sample::Gauge(key.into(), value))
+    }
+
+    /// Updates the timing histogram for a given metric, asynchronously.
+    ///
+    /// Rather than blocking when the channel is full, the returned future waits for capacity.
+    pub fn update_timing_async<K: Into<T>>(&self, key: K, start: u64, end: u64) -> SendFuture<T> {
+        self.send_async(Sample::TimingHistogram(key.into(), start, end))
+    }
+
+    /// Updates the timing histogram for a given metric, with a count, asynchronously.
+    ///
+    /// Rather than blocking when the channel is full, the returned future waits for capacity.
+    pub fn update_timing_with_count_async<K: Into<T>>(
+        &self, key: K, start: u64, end: u64, count: u64,
+    ) -> SendFuture<T> {
+        self.send_async(Sample::timing(key.into(), start, end, count))
+    }
+
+    /// Updates the value histogram for a given metric, asynchronously.
+    ///
+    /// Rather than blocking when the channel is full, the returned future waits for capacity.
+    pub fn update_value_async<K: Into<T>>(&self, key: K, value: u64) -> SendFuture<T> {
+        self.send_async(Sample::ValueHistogram(key.into(), value))
+    }
 
     /// Increments the given metric by one.
-    pub fn increment(&self, key: T) { self.update_count(key, 1) }
+    pub fn increment<K: Into<T>>(&self, key: K) { self.update_count(key, 1) }
 
     /// Decrements the given metric by one.
-    pub fn decrement(&self, key: T) { self.update_count(key, -1) }
+    pub fn decrement<K: Into<T>>(&self, key: K) { self.update_count(key, -1) }
+
+    /// Records every update in the given [`Measurement`] for a given metric, as a single message.
+    ///
+    /// ```
+    /// # use hotmic::{Measurement, Receiver, Sink};
+    /// # let receiver = Receiver::builder().build();
+    /// # let sink: Sink<&'static str> = receiver.get_sink();
+    /// let start = sink.clock().start();
+    /// let succeeded = true;
+    /// let end = sink.clock().end();
+    /// let outcome = if succeeded { "query.ok" } else { "query.error" };
+    /// sink.record("query", Measurement::new().timing(start, end).outcome(outcome));
+    /// ```
+    pub fn record<K: Into<T>>(&self, key: K, measurement: Measurement<T>) {
+        let key = key.into();
+        let samples = measurement
+            .samples
+            .into_iter()
+            .map(|sample| {
+                let sample_key = sample.key().clone().unwrap_or_else(|| key.clone());
+                sample.with_key(sample_key).into_scoped(self.scope_id)
+            })
+            .collect();
+        self.send_frame(MessageFrame::DataMulti(samples))
+    }
+
+    /// Creates a [`GaugeBuffer`] that sends gauge updates through this sink every `interval`.
+    pub fn gauge_buffer(&self, interval: Duration) -> GaugeBuffer<T> {
+        GaugeBuffer {
+            sink: self.clone(),
+            interval,
+            last_flush: Instant::now(),
+            values: FnvHashMap::default(),
+        }
+    }
+
+    /// Creates a [`SharedCounter`] for the given metric, whose value can be read directly, without
+    /// asking the receiver.
+    ///
+    /// Reading a counter normally means taking a snapshot, which is a round-trip through the
+    /// receiver, and too slow for hot paths such as a rate limiter checking its count.  A shared
+    /// counter's value is shared by the receiver and every handle to the counter.  Updates made
+    /// through a handle are applied to it directly, and are visible to [`SharedCounter::value`]
+    /// straight away.  Updates sent by any sink with the same scope and labels as this one, however
+    /// they're sent, are applied to it by the receiver as it processes them, just like any other
+    /// sample.  Creating a shared counter for a metric which is already shared gives another handle
+    /// to the same counter.
+    ///
+    /// The tradeoff is consistency with the rest of the metrics.  Snapshots take the value of the
+    /// handles as of when they're taken, while other updates only show up in snapshots once the
+    /// receiver has processed them, so a snapshot may include updates made through a handle after
+    /// updates to other metrics that it doesn't yet include.  Updates sent before the counter was
+    /// shared are included in snapshots, but not in the shared value.  Updates made through a
+    /// handle also aren't checked against label restrictions or facets, as the receiver only sees
+    /// their sum.
+    ///
+    /// Counters stay shared for as long as the receiver exists.  The counters of a disabled sink
+    /// aren't shared with anything, but can still be updated and read.
+    pub fn shared_counter<K: Into<T>>(&self, key: K) -> SharedCounter {
+        let value = match &self.shared_counters {
+            Some(shared) => {
+                let key = ScopedKey::new(self.scope_id, key.into());
+                let (value, registered) = shared.register(key.clone());
+                if registered {
+                    self.send_frame(MessageFrame::ShareCounter(key, value.clone()));
+                }
+                value
+            },
+            None => Arc::new(SharedValue::default()),
+        };
+        SharedCounter { value }
+    }
 
     /// Sends a raw metric sample to the receiver.
-    fn send(&self, sample: Sample<T>) {
-        let _ = self
-            .msg_tx
-            .send(MessageFrame::Data(sample.into_scoped(self.scope_id)))
-            .map_err(|_| io_error("failed to send sample"));
+    fn send(&self, sample: Sample<T>) { self.send_frame(MessageFrame::Data(sample.into_scoped(self.scope_id))) }
+
+    /// Sends a raw metric sample to the receiver, asynchronously.
+    fn send_async(&self, sample: Sample<T>) -> SendFuture<T> {
+        SendFuture {
+            msg_tx: self.msg_tx.clone(),
+            waiters: self.send_waiters.clone(),
+            frame: Some(Frame(MessageFrame::Data(sample.into_scoped(self.scope_id)))),
+        }
+    }
+
+    /// Sends a raw message frame to the receiver.
+    fn send_frame(&self, frame: MessageFrame<ScopedKey<T>>) {
+        if let Some(msg_tx) = &self.msg_tx {
+            let _ = msg_tx.send(Frame(frame)).map_err(|_| io_error("failed to send sample"));
+        }
+    }
+}
+
+impl<T: Clone + Eq + Hash + Display> SinkFactory<T> {
+    pub(crate) fn new(root: Sink<T>) -> SinkFactory<T> { SinkFactory { root } }
+
+    /// Creates a factory for disabled sinks.
+    ///
+    /// Every sink it creates is disabled, as with [`Sink::disabled`].
+    pub fn disabled() -> SinkFactory<T> { SinkFactory::new(Sink::disabled()) }
+
+    /// Creates an unscoped [`Sink`].
+    pub fn sink(&self) -> Sink<T> { self.root.clone() }
+
+    /// Creates a [`Sink`] with the given scope, as with [`Sink::scoped`].
+    pub fn scoped<'a, S: AsScoped<'a> + ?Sized>(&self, scope: &'a S) -> Result<Sink<T>, SinkError> {
+        self.root.scoped(scope)
     }
 }
 
+impl<T> Measurement<T> {
+    /// Creates an empty measurement.
+    pub fn new() -> Measurement<T> { Measurement { samples: Vec::new() } }
+
+    /// Adds a count update, as with [`Sink::update_count`].
+    pub fn count(mut self, delta: i64) -> Self {
+        self.samples.push(Sample::Count(None, delta));
+        self
+    }
+
+    /// Adds a gauge update, as with [`Sink::update_gauge`].
+    pub fn gauge(mut self, value: u64) -> Self {
+        self.samples.push(Sample::Gauge(None, value));
+        self
+    }
+
+    /// Adds a timing, as with [`Sink::update_timing`].
+    pub fn timing(self, start: u64, end: u64) -> Self { self.timing_with_count(start, end, 1) }
+
+    /// Adds a timing with a count, as with [`Sink::update_timing_with_count`].
+    pub fn timing_with_count(mut self, start: u64, end: u64, count: u64) -> Self {
+        self.samples.push(Sample::timing(None, start, end, count));
+        self
+    }
+
+    /// Adds an already-measured duration, as with [`Sink::record_latency_nanos`].
+    pub fn latency_nanos(mut self, nanos: u64) -> Self {
+        self.samples.push(Sample::TimingLatency(None, nanos));
+        self
+    }
+
+    /// Adds a value histogram update, as with [`Sink::update_value`].
+    pub fn value(mut self, value: u64) -> Self {
+        self.samples.push(Sample::ValueHistogram(None, value));
+        self
+    }
+
+    /// Increments the given metric by one, such as a counter for whether the operation succeeded.
+    ///
+    /// Unlike the other updates, this applies to the given metric rather than the one the
+    /// measurement is recorded for, scoped like any other metric sent through the same sink.
+    pub fn outcome<K: Into<T>>(mut self, key: K) -> Self {
+        self.samples.push(Sample::Count(Some(key.into()), 1));
+        self
+    }
+}
+
+impl<T> Default for Measurement<T> {
+    fn default() -> Measurement<T> { Measurement::new() }
+}
+
+impl SharedCounter {
+    /// Adds `delta` to the counter, wrapping around if it overflows.
+    pub fn update(&self, delta: i64) { self.value.update(delta) }
+
+    /// Increments the counter by one.
+    pub fn increment(&self) { self.update(1) }
+
+    /// Decrements the counter by one.
+    pub fn decrement(&self) { self.update(-1) }
+
+    /// Gets the current value of the counter.
+    pub fn value(&self) -> i64 { self.value.value() }
+}
+
+impl<T: Clone + Eq + Hash + Display> GaugeBuffer<T> {
+    /// Updates the value for a given metric, sending all buffered values if the interval has passed.
+    pub fn update_gauge<K: Into<T>>(&mut self, key: K, value: u64) {
+        let _ = self.values.insert(key.into(), value);
+        if self.last_flush.elapsed() >= self.interval {
+            self.flush();
+        }
+    }
+
+    /// Sends all buffered values now.
+    pub fn flush(&mut self) {
+        for (key, value) in self.values.drain() {
+            self.sink.update_gauge(key, value);
+        }
+        self.last_flush = Instant::now();
+    }
+}
+
+impl<T: Clone + Eq + Hash + Display> Drop for GaugeBuffer<T> {
+    fn drop(&mut self) { self.flush(); }
+}
+
+impl<T: Clone + Eq + Hash + Display> ScopedTimer<T> {
+    /// Gets the scoped sink that the timing will be recorded through.
+    pub fn sink(&self) -> &Sink<T> { &self.sink }
+}
+
+impl<T: Clone + Eq + Hash + Display> Drop for ScopedTimer<T> {
+    fn drop(&mut self) { self.sink.record_timer(self.key.clone(), self.timer); }
+}
+
 impl<T: Clone + Eq + Hash + Display> Clone for Sink<T> {
     fn clone(&self) -> Sink<T> {
         Sink {
@@ -129,18 +829,121 @@ impl<T: Clone + Eq + Hash + Display> Clone for Sink<T> {
             clock: self.clock.clone(),
             scopes: self.scopes.clone(),
             scope: self.scope.clone(),
+            labels: self.labels.clone(),
             scope_id: self.scope_id,
+            base_scope_id: self.base_scope_id,
+            clock_id: self.clock_id,
+            sinks: attach(self.sinks.clone()),
+            shared_counters: self.shared_counters.clone(),
+            send_waiters: self.send_waiters.clone(),
         }
     }
 }
 
-impl<'a> AsScoped<'a> for str {
-    fn as_scoped(&'a self, mut base: String) -> String {
-        if !base.is_empty() {
-            base.push_str(".");
+impl<T: Clone + Eq + Hash + Display> Drop for Sink<T> {
+    fn drop(&mut self) {
+        if let Some(sinks) = &self.sinks {
+            let _ = sinks.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Counts a new sink towards the number of live sinks bound to its receiver.
+fn attach(sinks: Option<Arc<AtomicUsize>>) -> Option<Arc<AtomicUsize>> {
+    if let Some(sinks) = &sinks {
+        let _ = sinks.fetch_add(1, Ordering::Relaxed);
+    }
+    sinks
+}
+
+impl<T: Clone + Eq + Hash + Display> Future for SendFuture<T> {
+    type Error = SinkError;
+    type Item = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // Disabled sinks have nowhere to send to, so they're always done immediately.
+        let msg_tx = match &self.msg_tx {
+            Some(msg_tx) => msg_tx,
+            None => return Ok(Async::Ready(())),
+        };
+
+        let frame = match self.frame.take() {
+            Some(frame) => frame,
+            None => return Ok(Async::Ready(())),
+        };
+
+        let frame = match msg_tx.try_send(frame) {
+            Ok(()) => return Ok(Async::Ready(())),
+            Err(TrySendError::Full(frame)) => frame,
+            Err(TrySendError::Disconnected(_)) => return Err(SinkError::ReceiverShutdown),
+        };
+
+        if !self.waiters.register() {
+            return Err(SinkError::ReceiverShutdown);
+        }
+
+        // The receiver may have made room between our first attempt and registering, in which case
+        // nothing would wake us up, so try once more now that we're sure to be woken.
+        match msg_tx.try_send(frame) {
+            Ok(()) => Ok(Async::Ready(())),
+            Err(TrySendError::Full(frame)) => {
+                self.frame = Some(frame);
+                Ok(Async::NotReady)
+            },
+            Err(TrySendError::Disconnected(_)) => Err(SinkError::ReceiverShutdown),
         }
-        base.push_str(self);
-        base
+    }
+}
+
+impl SendWaiters {
+    /// Registers the current task to be woken up once there's room in the channel.
+    ///
+    /// Returns `false`, without registering the task, if the receiver has gone away.
+    fn register(&self) -> bool {
+        let mut tasks = self.tasks.lock();
+        if self.closed.load(Ordering::Acquire) {
+            return false;
+        }
+
+        if !tasks.iter().any(|task| task.will_notify_current()) {
+            tasks.push(task::current());
+        }
+        self.waiting.store(true, Ordering::Release);
+        true
+    }
+
+    /// Wakes up every waiting task.
+    pub fn notify(&self) {
+        if self.waiting.swap(false, Ordering::AcqRel) {
+            for task in self.tasks.lock().drain(..) {
+                task.notify();
+            }
+        }
+    }
+
+    /// Wakes up every waiting task for the last time, as the receiver has gone away.
+    pub fn close(&self) {
+        let mut tasks = self.tasks.lock();
+        self.closed.store(true, Ordering::Release);
+        for task in tasks.drain(..) {
+            task.notify();
+        }
+    }
+}
+
+impl fmt::Display for SinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SinkError::InvalidScope => write!(f, "the given scope was invalid"),
+            SinkError::ReceiverShutdown => write!(f, "the receiver is not currently running"),
+        }
+    }
+}
+
+impl<'a> AsScoped<'a> for str {
+    fn as_scoped(&'a self, mut base: String) -> Result<String, SinkError> {
+        push_scope_segment(&mut base, self)?;
+        Ok(base)
     }
 }
 
@@ -149,13 +952,433 @@ where
     &'a T: AsRef<[&'b str]>,
     T: 'a,
 {
-    fn as_scoped(&'a self, mut base: String) -> String {
+    fn as_scoped(&'a self, mut base: String) -> Result<String, SinkError> {
         for item in self.as_ref() {
-            if !base.is_empty() {
-                base.push('.');
-            }
-            base.push_str(item);
+            push_scope_segment(&mut base, item)?;
         }
-        base
+        Ok(base)
+    }
+}
+
+/// Appends a single scope segment to `base`, separated by a `.`.
+fn push_scope_segment(base: &mut String, segment: &str) -> Result<(), SinkError> {
+    if segment.is_empty() || segment.contains('.') {
+        return Err(SinkError::InvalidScope);
+    }
+
+    if !base.is_empty() {
+        base.push('.');
+    }
+    base.push_str(segment);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Measurement, Sink, SinkError};
+    use crate::{
+        data::{Facet, TimeUnit},
+        receiver::Receiver,
+    };
+    use futures::{
+        executor::{self, Notify, NotifyHandle},
+        Async, Future,
+    };
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    /// Counts how many times a task has been woken up.
+    struct CountingNotify(AtomicUsize);
+
+    impl Notify for CountingNotify {
+        fn notify(&self, _id: usize) { let _ = self.0.fetch_add(1, Ordering::SeqCst); }
+    }
+
+    #[test]
+    fn test_async_send() {
+        let receiver = Receiver::<&'static str>::builder().capacity(8).build();
+        let sink = receiver.get_sink();
+        assert_eq!(sink.update_count_async("widgets", 1).wait(), Ok(()));
+        assert_eq!(sink.update_gauge_async("red_balloons", 99).wait(), Ok(()));
+
+        drop(receiver);
+        assert_eq!(
+            sink.update_value_async("buf_size", 4096).wait(),
+            Err(SinkError::ReceiverShutdown)
+        );
+
+        let disabled = Sink::<&'static str>::disabled();
+        assert_eq!(disabled.update_count_async("widgets", 1).wait(), Ok(()));
+    }
+
+    #[test]
+    fn test_async_send_full() {
+        let mut receiver = Receiver::<&'static str>::builder().capacity(1).build();
+        let sink = receiver.get_sink();
+        sink.increment("widgets");
+
+        let notify = Arc::new(CountingNotify(AtomicUsize::new(0)));
+        let handle = NotifyHandle::from(notify.clone());
+        let mut send = executor::spawn(sink.update_count_async("widgets", 1));
+        let mut orphaned = executor::spawn(sink.update_count_async("widgets", 1));
+
+        // While the channel is full, the task isn't woken up until the receiver makes room.
+        assert_eq!(send.poll_future_notify(&handle, 0), Ok(Async::NotReady));
+        assert_eq!(send.poll_future_notify(&handle, 0), Ok(Async::NotReady));
+        assert_eq!(notify.0.load(Ordering::SeqCst), 0);
+
+        receiver.process_pending();
+        assert_eq!(notify.0.load(Ordering::SeqCst), 1);
+        assert_eq!(send.poll_future_notify(&handle, 0), Ok(Async::Ready(())));
+        receiver.process_pending();
+        assert_eq!(receiver.get_snapshot().into_simple().count("widgets"), Some(2));
+
+        // Tasks still waiting when the receiver goes away are woken up, and fail.
+        sink.increment("widgets");
+        assert_eq!(orphaned.poll_future_notify(&handle, 1), Ok(Async::NotReady));
+        drop(receiver);
+        assert_eq!(notify.0.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            orphaned.poll_future_notify(&handle, 1),
+            Err(SinkError::ReceiverShutdown)
+        );
+    }
+
+    #[test]
+    fn test_gauge_buffer() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let controller = receiver.get_controller();
+        let sink = receiver.get_sink().scoped("pool").unwrap();
+
+        let mut buffer = sink.gauge_buffer(Duration::from_secs(3600));
+        for depth in 0..1_000 {
+            buffer.update_gauge("depth", depth);
+        }
+        buffer.update_gauge("size", 8);
+
+        let mut snapshot = || {
+            receiver.poll_ready();
+            let snapshot = controller.get_snapshot_async().expect("failed to request snapshot");
+            receiver.poll_ready();
+            snapshot.wait().expect("failed to get snapshot").into_simple()
+        };
+
+        // Nothing is sent until the buffer is flushed, and then only the latest values are.
+        assert_eq!(snapshot().gauge("pool.depth"), None);
+        buffer.flush();
+        let current = snapshot();
+        assert_eq!(current.gauge("pool.depth"), Some(999));
+        assert_eq!(current.gauge("pool.size"), Some(8));
+
+        buffer.update_gauge("depth", 3);
+        drop(buffer);
+        assert_eq!(snapshot().gauge("pool.depth"), Some(3));
+
+        // Once the interval has passed, updates send everything buffered.
+        let mut buffer = sink.gauge_buffer(Duration::from_millis(10));
+        buffer.update_gauge("size", 16);
+        thread::sleep(Duration::from_millis(20));
+        buffer.update_gauge("depth", 4);
+        let current = snapshot();
+        assert_eq!(current.gauge("pool.depth"), Some(4));
+        assert_eq!(current.gauge("pool.size"), Some(16));
+    }
+
+    #[test]
+    fn test_shared_counter() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let sink = receiver.get_sink();
+        let permits = sink.shared_counter("permits");
+        permits.increment();
+        sink.update_count("permits", 2);
+        sink.scoped("other").unwrap().increment("permits");
+
+        // Updates through the handle are visible straight away, while updates through the sink
+        // show up once the receiver has processed them.
+        assert_eq!(permits.value(), 1);
+        receiver.process_pending();
+        assert_eq!(permits.value(), 3);
+        assert_eq!(sink.clone().shared_counter("permits").value(), 3);
+
+        let snapshot = receiver.get_snapshot().into_simple();
+        assert_eq!(snapshot.count("permits"), Some(3));
+        assert_eq!(snapshot.count("other.permits"), Some(1));
+
+        // Updates sent asynchronously or as part of a measurement are applied in the same way.
+        permits.decrement();
+        sink.record("permits", Measurement::new().count(5));
+        assert_eq!(sink.update_count_async("permits", 1).wait(), Ok(()));
+        receiver.process_pending();
+        assert_eq!(receiver.get_snapshot().into_simple().count("permits"), Some(8));
+        assert_eq!(permits.value(), 8);
+
+        let disabled = Sink::<&'static str>::disabled().shared_counter("permits");
+        disabled.update(4);
+        assert_eq!(disabled.value(), 4);
+    }
+
+    #[test]
+    fn test_timer() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let controller = receiver.get_controller();
+        let sink = receiver.get_sink();
+        let scoped = sink.scoped("db").unwrap();
+
+        // Timers can be ended by any sink bound to the same receiver.
+        let timer = sink.start_timer();
+        thread::sleep(Duration::from_millis(5));
+        scoped.record_timer("query", timer);
+        receiver.poll_ready();
+
+        let snapshot = controller.get_snapshot_async().expect("failed to request snapshot");
+        receiver.poll_ready();
+        let snapshot = snapshot.wait().expect("failed to get snapshot").into_simple();
+        let max = snapshot.timing_histogram("db.query", 100.0).expect("missing timing");
+        assert!(max.as_nanos() >= 5_000_000, "unexpected max {}", max);
+    }
+
+    #[test]
+    fn test_scoped_timer() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let controller = receiver.get_controller();
+        let sink = receiver.get_sink();
+
+        {
+            let timer = sink.scoped_timer(&["route", "users"], "handler").unwrap();
+            timer.sink().increment("requests");
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(sink.scoped_timer("", "handler").is_err());
+        receiver.poll_ready();
+
+        let snapshot = controller.get_snapshot_async().expect("failed to request snapshot");
+        receiver.poll_ready();
+        let snapshot = snapshot.wait().expect("failed to get snapshot").into_simple();
+        assert_eq!(snapshot.count("route.users.requests"), Some(1));
+        let max = snapshot
+            .timing_histogram("route.users.handler", 100.0)
+            .expect("missing timing");
+        assert!(max.as_nanos() >= 5_000_000, "unexpected max {}", max);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "different receiver")]
+    fn test_timer_from_other_receiver() {
+        let receiver = Receiver::<&'static str>::builder().build();
+        let other = Receiver::<&'static str>::builder().build();
+
+        let timer = other.get_sink().start_timer();
+        receiver.get_sink().record_timer("query", timer);
+    }
+
+    #[test]
+    fn test_into_keys() {
+        let mut receiver = Receiver::<String>::builder().build();
+        let controller = receiver.get_controller();
+        let sink = receiver.get_sink();
+        sink.update_count("widgets", 1);
+        sink.update_count(String::from("widgets"), 2);
+        receiver.poll_ready();
+
+        let snapshot = controller.get_snapshot_async().expect("failed to request snapshot");
+        receiver.poll_ready();
+        let snapshot = snapshot.wait().expect("failed to get snapshot").into_simple();
+        assert_eq!(snapshot.count("widgets"), Some(3));
+    }
+
+    #[test]
+    fn test_record_latency_nanos() {
+        let mut receiver = Receiver::builder().build();
+        let controller = receiver.get_controller();
+        let sink = receiver.get_sink();
+        sink.add_facet(Facet::timing_percentile("db.query").unit(TimeUnit::Microseconds));
+        sink.record_latency_nanos("db.query", 1_500_000);
+        receiver.poll_ready();
+
+        let snapshot = controller.get_snapshot_async().expect("failed to request snapshot");
+        receiver.poll_ready();
+        let snapshot = snapshot.wait().expect("failed to get snapshot").into_simple();
+        let max = snapshot
+            .timing_histogram_raw("db.query", 100.0)
+            .expect("missing timing");
+        assert!(max >= 1_500 && max < 1_510, "unexpected max {}", max);
+        assert_eq!(snapshot.count("db.query"), Some(1));
+    }
+
+    #[test]
+    fn test_measure_timed() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let controller = receiver.get_controller();
+        let sink = receiver.get_sink();
+        let (result, elapsed) = sink.measure_timed("work", || {
+            thread::sleep(Duration::from_millis(5));
+            42
+        });
+        assert_eq!(result, 42);
+        assert!(elapsed >= Duration::from_millis(5), "unexpected elapsed {:?}", elapsed);
+        receiver.poll_ready();
+
+        let snapshot = controller.get_snapshot_async().expect("failed to request snapshot");
+        receiver.poll_ready();
+        let snapshot = snapshot.wait().expect("failed to get snapshot").into_simple();
+
+        // Histograms only keep three significant digits.
+        let max = snapshot
+            .timing_histogram("work", 100.0)
+            .expect("missing timing")
+            .as_nanos();
+        let nanos = elapsed.as_secs() * 1_000_000_000 + u64::from(elapsed.subsec_nanos());
+        assert!(
+            max >= nanos - nanos / 100 && max <= nanos + nanos / 100,
+            "unexpected max {}",
+            max
+        );
+        assert_eq!(snapshot.count("work"), Some(1));
+    }
+
+    #[test]
+    fn test_with_labels() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let controller = receiver.get_controller();
+        let sink = receiver.get_sink();
+
+        let labeled = sink.with_labels(&[("service", "api"), ("env", "prod")]);
+        labeled.increment("requests");
+        labeled.scoped("db").unwrap().increment("queries");
+        labeled.with_labels(&[("env", "dev")]).increment("requests");
+        sink.increment("requests");
+        receiver.poll_ready();
+
+        let snapshot = controller.get_snapshot_async().expect("failed to request snapshot");
+        receiver.poll_ready();
+        let snapshot = snapshot.wait().expect("failed to get snapshot").into_simple();
+        let prod = [("env", "prod"), ("service", "api")];
+        assert_eq!(snapshot.count("requests"), Some(1));
+        assert_eq!(snapshot.count_labeled("requests", &prod), Some(1));
+        assert_eq!(
+            snapshot.count_labeled("requests", &[("env", "dev"), ("service", "api")]),
+            Some(1)
+        );
+        assert_eq!(snapshot.count_labeled("db.queries", &prod), Some(1));
+    }
+
+    #[test]
+    fn test_sibling_scopes() {
+        let root = Sink::<&'static str>::disabled();
+
+        let a = root.scoped(&["listener", "a"]).unwrap();
+        assert_eq!(a.scope, "listener.a");
+
+        let b = a.sibling("b").unwrap();
+        assert_eq!(b.scope, "listener.b");
+        assert_ne!(a.scope_id, b.scope_id);
+
+        let nested = a.sibling(&["c", "d"]).unwrap();
+        assert_eq!(nested.scope, "listener.c.d");
+
+        let top = root.sibling("top").unwrap();
+        assert_eq!(top.scope, "top");
+        assert_eq!(top.sibling("other").unwrap().scope, "other");
+    }
+
+    #[test]
+    fn test_invalid_scopes() {
+        let root = Sink::<&'static str>::disabled();
+
+        assert_eq!(root.scoped("").err(), Some(SinkError::InvalidScope));
+        assert_eq!(root.scoped(&["listener", ""]).err(), Some(SinkError::InvalidScope));
+        assert_eq!(root.scoped("listener.a").err(), Some(SinkError::InvalidScope));
+        assert_eq!(root.scoped(&["listener", "a.b"]).err(), Some(SinkError::InvalidScope));
+        assert_eq!(root.sibling("").err(), Some(SinkError::InvalidScope));
+        assert_eq!(root.sibling("a.b").err(), Some(SinkError::InvalidScope));
+
+        assert!(root.scoped("listener").is_ok());
+    }
+
+    #[test]
+    fn test_disabled_sink() {
+        let sink = Sink::<&'static str>::disabled();
+        assert!(sink.is_disabled());
+
+        // None of these should block or panic, even though there's no receiver.
+        sink.increment("widgets");
+        sink.update_gauge("red_balloons", 99);
+        sink.update_value("buf_size", 4096);
+
+        let start = sink.clock().start();
+        let end = sink.clock().end();
+        sink.update_timing("db.gizmo_query", start, end);
+
+        let scoped = sink.scoped("secret").unwrap();
+        assert!(scoped.is_disabled());
+        assert!(scoped.clone().is_disabled());
+        scoped.decrement("widgets");
+    }
+
+    #[test]
+    fn test_registered_facets() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let sink = receiver.get_sink();
+        let scoped = sink.scoped("db").unwrap();
+        let labeled = scoped.with_labels(&[("env", "prod")]);
+        thread::spawn(move || receiver.run());
+
+        sink.add_facets(&[Facet::wide_count("bytes"), Facet::max_gauge("peak")]);
+        scoped.add_facet(Facet::timing_percentile("query").unit(TimeUnit::Microseconds));
+        labeled.add_facets(&[Facet::gauge("connections"), Facet::count("rows").allow_label("env")]);
+
+        let mut facets = sink.registered_facets();
+        facets.sort_by_key(|f| *f.key());
+        assert_eq!(facets, vec![Facet::wide_count("bytes"), Facet::max_gauge("peak")]);
+
+        // Facets that restrict labels apply to the scope without labels, too.
+        let mut facets = scoped.registered_facets();
+        facets.sort_by_key(|f| *f.key());
+        let expected = vec![
+            Facet::timing_percentile("query").unit(TimeUnit::Microseconds),
+            Facet::count("rows").allow_label("env"),
+        ];
+        assert_eq!(facets, expected);
+
+        let mut facets = labeled.registered_facets();
+        facets.sort_by_key(|f| *f.key());
+        assert_eq!(
+            facets,
+            vec![Facet::gauge("connections"), Facet::count("rows").allow_label("env")]
+        );
+
+        assert!(Sink::<&'static str>::disabled().registered_facets().is_empty());
+    }
+
+    #[test]
+    fn test_flush() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let controller = receiver.get_controller();
+        let sink = receiver.get_sink();
+        thread::spawn(move || receiver.run());
+
+        for _ in 0..1_000 {
+            sink.increment("widgets");
+        }
+        sink.update_gauge("depth", 7);
+
+        // Once flushed, everything sent beforehand is in the very next snapshot.
+        assert_eq!(sink.flush(), Ok(()));
+        let snapshot = controller.get_snapshot().expect("failed to get snapshot").into_simple();
+        assert_eq!(snapshot.count("widgets"), Some(1_000));
+        assert_eq!(snapshot.gauge("depth"), Some(7));
+
+        let receiver = Receiver::<&'static str>::builder().build();
+        let sink = receiver.get_sink();
+        drop(receiver);
+        assert_eq!(sink.flush(), Err(SinkError::ReceiverShutdown));
+        assert_eq!(Sink::<&'static str>::disabled().flush(), Ok(()));
     }
 }
@@ -0,0 +1,101 @@
+//! Helpers for testing instrumented code.
+use crate::{
+    configuration::Configuration,
+    data::Snapshot,
+    receiver::Receiver,
+    sink::Sink,
+    transport::{Frame, Transport, TransportReceiver, TransportSender},
+};
+use crossbeam_channel::unbounded;
+use std::{fmt::Display, hash::Hash};
+
+/// A receiver for tests, which processes samples synchronously when a snapshot is taken.
+///
+/// A regular [`Receiver`] runs on its own thread, and so a test has no way of knowing when the
+/// samples it sent have been processed, short of polling for them to show up in a snapshot.  A
+/// test receiver has no thread: samples wait in its channel until
+/// [`snapshot`](TestReceiver::snapshot) is called, which processes every one of them before taking
+/// the snapshot.  Aggregation is the same as for a regular receiver, so the snapshot is exactly
+/// what a regular receiver would have reported once it had caught up.
+///
+/// ```
+/// # use hotmic::TestReceiver;
+/// let mut receiver = TestReceiver::<&'static str>::new();
+/// let sink = receiver.get_sink();
+/// sink.update_count("widgets", 3);
+/// assert_eq!(receiver.snapshot().into_simple().count("widgets"), Some(3));
+/// ```
+///
+/// The channel is unbounded, so sinks never block on it, no matter how many samples are sent
+/// between snapshots.  Histogram windows never roll over, and there are no controllers or snapshot
+/// callbacks, as nothing runs in the background.  For the same reason,
+/// [`Sink::registered_facets`] must not be called, as it would wait forever for a response.
+pub struct TestReceiver<T: Clone + Eq + Hash + Display + Send> {
+    receiver: Receiver<T>,
+}
+
+impl<T: Clone + Eq + Hash + Display + Send + 'static> TestReceiver<T> {
+    /// Creates a test receiver with the default configuration.
+    pub fn new() -> TestReceiver<T> { TestReceiver::from_config(Configuration::new()) }
+
+    /// Creates a test receiver from the given configuration.
+    ///
+    /// Any transport set on the configuration is replaced with an unbounded channel.
+    pub fn from_config(config: Configuration<T>) -> TestReceiver<T> {
+        TestReceiver {
+            receiver: config.transport(UnboundedTransport).build(),
+        }
+    }
+
+    /// Creates a [`Sink`] bound to this receiver.
+    pub fn get_sink(&self) -> Sink<T> { self.receiver.get_sink() }
+
+    /// Processes every sample sent so far, and takes a snapshot of the current metrics.
+    pub fn snapshot(&mut self) -> Snapshot {
+        self.receiver.process_pending();
+        self.receiver.get_snapshot()
+    }
+}
+
+impl<T: Clone + Eq + Hash + Display + Send + 'static> Default for TestReceiver<T> {
+    fn default() -> TestReceiver<T> { TestReceiver::new() }
+}
+
+/// A transport over an unbounded crossbeam channel, so that sinks never block on it.
+struct UnboundedTransport;
+
+impl<T: Send + 'static> Transport<Frame<T>> for UnboundedTransport {
+    fn channel(&self, _capacity: usize) -> (Box<dyn TransportSender<Frame<T>>>, Box<dyn TransportReceiver<Frame<T>>>) {
+        let (tx, rx) = unbounded();
+        (Box::new(tx), Box::new(rx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TestReceiver;
+    use crate::{Configuration, Facet};
+
+    #[test]
+    fn test_test_receiver() {
+        let mut receiver = TestReceiver::from_config(Configuration::new().percentiles(&[100.0]));
+        let sink = receiver.get_sink();
+        let scoped = sink.scoped("db").unwrap();
+        scoped.add_facet(Facet::max_gauge("depth"));
+
+        // More samples than the default capacity, which would block a bounded channel.
+        for i in 0..1_000 {
+            scoped.increment("queries");
+            scoped.update_gauge("depth", i);
+        }
+        sink.update_value("size", 42);
+
+        let snapshot = receiver.snapshot().into_simple();
+        assert_eq!(snapshot.count("db.queries"), Some(1_000));
+        assert_eq!(snapshot.gauge("db.depth"), Some(999));
+        assert_eq!(snapshot.value_histogram("size", 100.0), Some(42));
+
+        scoped.update_count("queries", -1);
+        assert_eq!(receiver.snapshot().into_simple().count("db.queries"), Some(999));
+    }
+}
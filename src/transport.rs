@@ -0,0 +1,144 @@
+//! Transports, which carry samples from sinks to their receiver.
+//!
+//! By default, sinks and their receiver are connected by a bounded
+//! [`crossbeam-channel`](https://docs.rs/crossbeam-channel) channel.  A different channel, such as
+//! a specialized ring buffer, can be used instead by implementing [`Transport`] and handing it to
+//! [`Configuration::transport`](crate::Configuration::transport).
+use crate::{data::ScopedKey, receiver::MessageFrame};
+use crossbeam_channel::{bounded, Receiver, Sender};
+use std::time::Duration;
+
+pub use crossbeam_channel::{RecvTimeoutError, TryRecvError, TrySendError};
+
+/// A message from a sink to its receiver.
+///
+/// Frames are opaque: a transport only has to carry them from sinks to the receiver, without
+/// reordering the frames sent from any one thread.
+pub struct Frame<T>(pub(crate) MessageFrame<ScopedKey<T>>);
+
+/// A factory for the channel between sinks and their receiver.
+pub trait Transport<M>: Send + Sync {
+    /// Creates a channel which holds at most `capacity` messages at a time.
+    fn channel(&self, capacity: usize) -> (Box<dyn TransportSender<M>>, Box<dyn TransportReceiver<M>>);
+}
+
+/// The sending half of a transport, which is shared by every sink bound to a receiver.
+pub trait TransportSender<M>: Send + Sync {
+    /// Sends a message, blocking while the channel is full.
+    ///
+    /// If the receiving half is gone, the message is handed back as an error.
+    fn send(&self, msg: M) -> Result<(), M>;
+
+    /// Sends a message if the channel has room for it, without blocking.
+    fn try_send(&self, msg: M) -> Result<(), TrySendError<M>>;
+}
+
+/// The receiving half of a transport, which is owned by the receiver.
+///
+/// The receiver never blocks on anything but [`recv_timeout`](TransportReceiver::recv_timeout):
+/// controllers wake it up by sending a message through the transport once they've made a request,
+/// so transports don't need to support waiting on anything else.
+pub trait TransportReceiver<M>: Send {
+    /// Receives a message, if one is waiting, without blocking.
+    fn try_recv(&self) -> Result<M, TryRecvError>;
+
+    /// Receives a message, waiting at most `timeout` for one to arrive.
+    fn recv_timeout(&self, timeout: Duration) -> Result<M, RecvTimeoutError>;
+
+    /// Gets the number of messages waiting in the channel.
+    ///
+    /// This is used for self metrics, and by [`Receiver::poll_ready`](crate::Receiver::poll_ready)
+    /// to only process the messages that were waiting when it was called.
+    fn len(&self) -> usize;
+
+    /// Whether or not the channel is empty.
+    fn is_empty(&self) -> bool { self.len() == 0 }
+}
+
+/// The default transport: a bounded crossbeam channel.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BoundedTransport;
+
+impl<M: Send + 'static> Transport<M> for BoundedTransport {
+    fn channel(&self, capacity: usize) -> (Box<dyn TransportSender<M>>, Box<dyn TransportReceiver<M>>) {
+        let (tx, rx) = bounded(capacity);
+        (Box::new(tx), Box::new(rx))
+    }
+}
+
+impl<M: Send> TransportSender<M> for Sender<M> {
+    fn send(&self, msg: M) -> Result<(), M> { Sender::send(self, msg).map_err(|e| e.into_inner()) }
+
+    fn try_send(&self, msg: M) -> Result<(), TrySendError<M>> { Sender::try_send(self, msg) }
+}
+
+impl<M: Send> TransportReceiver<M> for Receiver<M> {
+    fn try_recv(&self) -> Result<M, TryRecvError> { Receiver::try_recv(self) }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<M, RecvTimeoutError> { Receiver::recv_timeout(self, timeout) }
+
+    fn len(&self) -> usize { Receiver::len(self) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BoundedTransport, Transport, TransportReceiver, TransportSender, TrySendError};
+    use crate::Receiver;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    /// A transport that counts how many messages were sent through it.
+    struct CountingTransport(Arc<AtomicUsize>);
+
+    struct CountingSender<M>(Box<dyn TransportSender<M>>, Arc<AtomicUsize>);
+
+    impl<M: Send + 'static> Transport<M> for CountingTransport {
+        fn channel(&self, capacity: usize) -> (Box<dyn TransportSender<M>>, Box<dyn TransportReceiver<M>>) {
+            let (tx, rx) = BoundedTransport.channel(capacity);
+            (Box::new(CountingSender(tx, self.0.clone())), rx)
+        }
+    }
+
+    impl<M: Send> TransportSender<M> for CountingSender<M> {
+        fn send(&self, msg: M) -> Result<(), M> {
+            let _ = self.1.fetch_add(1, Ordering::SeqCst);
+            self.0.send(msg)
+        }
+
+        fn try_send(&self, msg: M) -> Result<(), TrySendError<M>> {
+            let _ = self.1.fetch_add(1, Ordering::SeqCst);
+            self.0.try_send(msg)
+        }
+    }
+
+    #[test]
+    fn test_custom_transport() {
+        let sent = Arc::new(AtomicUsize::new(0));
+        let mut receiver = Receiver::<&'static str>::builder()
+            .transport(CountingTransport(sent.clone()))
+            .build();
+        let sink = receiver.get_sink();
+        let controller = receiver.get_controller();
+        thread::spawn(move || receiver.run());
+
+        // The receiver is blocked on the transport, with nothing to do until its next upkeep, but
+        // the controller wakes it up through the transport, so the snapshot is taken promptly.
+        let snapshot = controller.get_snapshot_timeout(Duration::from_millis(100));
+        assert!(snapshot.is_ok());
+
+        sink.update_count("widgets", 3);
+        sink.update_gauge("depth", 7);
+        sink.flush().expect("failed to flush");
+
+        let snapshot = controller.get_snapshot().expect("failed to get snapshot").into_simple();
+        assert_eq!(snapshot.count("widgets"), Some(3));
+        assert_eq!(snapshot.gauge("depth"), Some(7));
+        assert!(sent.load(Ordering::SeqCst) >= 3);
+    }
+}
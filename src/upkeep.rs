@@ -0,0 +1,118 @@
+//! Periodic work done by the receiver.
+use crate::receiver::Receiver;
+use std::{
+    fmt::Display,
+    hash::{BuildHasher, Hash},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// The state of the receiver as of when upkeep tasks are run.
+pub(crate) struct Tick {
+    /// The time the tasks are being run at.
+    pub now: Instant,
+
+    /// The wall-clock time the tasks are being run at.
+    pub wall: SystemTime,
+
+    /// The number of messages waiting in the data channel.
+    pub channel_len: usize,
+}
+
+/// A task that the receiver runs periodically.
+///
+/// Anything which needs doing on a timer, rather than in response to a sample, such as rolling over
+/// histogram windows, is an upkeep task.  Tasks are run on the receiver's own thread, between
+/// batches of samples, once their interval has passed since they last ran.  They're given the
+/// receiver itself, so they can do anything that the receiver can, without sharing its state.
+///
+/// The schedule is best-effort: a receiver that's busy with samples may run a task late, and a late
+/// task is only run once, so tasks which need to account for every interval that has passed must
+/// work that out from the tick themselves.
+pub(crate) trait Upkeep<T: Clone + Eq + Hash + Display + Send, H: BuildHasher>: Send {
+    /// Gets how often this task should run.
+    fn interval(&self) -> Duration;
+
+    /// Runs the task.
+    fn run(&mut self, receiver: &mut Receiver<T, H>, tick: &Tick);
+}
+
+/// The upkeep tasks of a receiver, along with when each of them last ran.
+pub(crate) struct UpkeepTasks<T: Clone + Eq + Hash + Display + Send, H: BuildHasher> {
+    tasks: Vec<(Box<dyn Upkeep<T, H>>, Instant)>,
+}
+
+impl<T: Clone + Eq + Hash + Display + Send, H: BuildHasher> UpkeepTasks<T, H> {
+    pub fn new() -> Self { UpkeepTasks { tasks: Vec::new() } }
+
+    /// Adds a task, which first runs once its interval has passed from now.
+    pub fn add<U: Upkeep<T, H> + 'static>(&mut self, task: U) { self.tasks.push((Box::new(task), Instant::now())); }
+
+    /// Gets when the next task is due to run, if there are any tasks.
+    pub fn next_due(&self) -> Option<Instant> { self.tasks.iter().map(|(task, last)| *last + task.interval()).min() }
+
+    /// Runs every task that has come due, in the order they were added.
+    pub fn run_due(&mut self, receiver: &mut Receiver<T, H>, tick: &Tick) {
+        for (task, last) in self.tasks.iter_mut() {
+            if tick.now.duration_since(*last) >= task.interval() {
+                *last = tick.now;
+                task.run(receiver, tick);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Tick, Upkeep, UpkeepTasks};
+    use crate::Receiver;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant, SystemTime},
+    };
+
+    struct CountRuns(Duration, Arc<AtomicUsize>);
+
+    impl Upkeep<&'static str, fnv::FnvBuildHasher> for CountRuns {
+        fn interval(&self) -> Duration { self.0 }
+
+        fn run(&mut self, _: &mut Receiver<&'static str>, _: &Tick) { let _ = self.1.fetch_add(1, Ordering::SeqCst); }
+    }
+
+    #[test]
+    fn test_run_due() {
+        let mut receiver = Receiver::<&'static str>::builder().build();
+        let often = Arc::new(AtomicUsize::new(0));
+        let rarely = Arc::new(AtomicUsize::new(0));
+
+        let mut tasks = UpkeepTasks::new();
+        assert_eq!(tasks.next_due(), None);
+        tasks.add(CountRuns(Duration::from_millis(10), often.clone()));
+        tasks.add(CountRuns(Duration::from_secs(3600), rarely.clone()));
+
+        let due = tasks.next_due().unwrap();
+        assert!(due <= Instant::now() + Duration::from_millis(10));
+
+        let tick = |now| {
+            Tick {
+                now,
+                wall: SystemTime::now(),
+                channel_len: 0,
+            }
+        };
+        tasks.run_due(&mut receiver, &tick(Instant::now()));
+        assert_eq!(often.load(Ordering::SeqCst), 0);
+
+        tasks.run_due(&mut receiver, &tick(due));
+        tasks.run_due(&mut receiver, &tick(due));
+        assert_eq!(often.load(Ordering::SeqCst), 1);
+        assert_eq!(rarely.load(Ordering::SeqCst), 0);
+        assert_eq!(tasks.next_due(), Some(due + Duration::from_millis(10)));
+
+        tasks.run_due(&mut receiver, &tick(due + Duration::from_secs(3600)));
+        assert_eq!(often.load(Ordering::SeqCst), 2);
+        assert_eq!(rarely.load(Ordering::SeqCst), 1);
+    }
+}